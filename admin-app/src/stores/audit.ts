@@ -4,27 +4,37 @@ import { api } from '@/services/api'
 
 export interface AuditEvent {
   id: string
-  user_id?: string
+  user_id?: string | null
+  org?: string | null
   event_type: string
-  description: string
-  ip_address?: string
-  user_agent?: string
+  ip_address?: string | null
+  user_agent?: string | null
   metadata?: Record<string, any>
-  timestamp: string
+  created_at: string
 }
 
 export interface AuditQuery {
   user_id?: string
   event_type?: string
+  org?: string
+  ip?: string
   from?: string
   to?: string
   limit?: number
 }
 
+interface AuditPage {
+  events: AuditEvent[]
+  next_cursor: string | null
+}
+
 export const useAuditStore = defineStore('audit', () => {
   const events = ref<AuditEvent[]>([])
   const isLoading = ref(false)
   const filters = ref<AuditQuery>({})
+  // Cursor of the page after the loaded events; null once all are loaded
+  const nextCursor = ref<string | null>(null)
+  const lastQuery = ref<AuditQuery>({})
 
   const filteredEvents = computed(() => {
     let result = events.value
@@ -45,19 +55,44 @@ export const useAuditStore = defineStore('audit', () => {
     return Array.from(types).sort()
   })
 
+  const fetchPage = async (query: AuditQuery, cursor: string | null): Promise<AuditPage> => {
+    const params = new URLSearchParams()
+
+    if (query.user_id) params.append('user_id', query.user_id)
+    if (query.event_type) params.append('event_type', query.event_type)
+    if (query.org) params.append('org', query.org)
+    if (query.ip) params.append('ip', query.ip)
+    if (query.from) params.append('from', query.from)
+    if (query.to) params.append('to', query.to)
+    if (query.limit) params.append('limit', query.limit.toString())
+    if (cursor) params.append('cursor', cursor)
+
+    const response = await api.get(`/api/audit?${params}`)
+    return response.data
+  }
+
   const loadAuditEvents = async (query: AuditQuery = {}): Promise<void> => {
     isLoading.value = true
     try {
-      const params = new URLSearchParams()
-
-      if (query.user_id) params.append('user_id', query.user_id)
-      if (query.event_type) params.append('event_type', query.event_type)
-      if (query.from) params.append('from', query.from)
-      if (query.to) params.append('to', query.to)
-      if (query.limit) params.append('limit', query.limit.toString())
+      const page = await fetchPage(query, null)
+      events.value = page.events
+      nextCursor.value = page.next_cursor
+      lastQuery.value = query
+    } catch (error) {
+      console.error('Failed to load audit events:', error)
+      throw error
+    } finally {
+      isLoading.value = false
+    }
+  }
 
-      const response = await api.get(`/api/audit/query?${params}`)
-      events.value = response.data
+  const loadMoreAuditEvents = async (): Promise<void> => {
+    if (!nextCursor.value) return
+    isLoading.value = true
+    try {
+      const page = await fetchPage(lastQuery.value, nextCursor.value)
+      events.value = [...events.value, ...page.events]
+      nextCursor.value = page.next_cursor
     } catch (error) {
       console.error('Failed to load audit events:', error)
       throw error
@@ -78,9 +113,11 @@ export const useAuditStore = defineStore('audit', () => {
     events,
     isLoading,
     filters,
+    nextCursor,
     filteredEvents,
     eventTypes,
     loadAuditEvents,
+    loadMoreAuditEvents,
     setFilters,
     clearFilters
   }
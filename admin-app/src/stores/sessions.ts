@@ -2,17 +2,27 @@ import { defineStore } from 'pinia'
 import { ref, computed } from 'vue'
 import type { ApiResponse } from './auth'
 
+export interface RefreshTokenLink {
+  jti: string
+  client_id: string | null
+  issued_at: string
+  expires_at: string
+}
+
 export interface ActiveSession {
   id: string
   user_id: string
   user_email: string
   user_name: string
   organization: string
-  ip_address: string
-  user_agent: string
+  client_id: string | null
+  ip_address: string | null
+  user_agent: string | null
+  amr: string[]
   created_at: string
-  last_activity: string
+  last_seen_at: string
   expires_at: string
+  refresh_tokens: RefreshTokenLink[]
   is_current?: boolean
 }
 
@@ -40,13 +50,7 @@ export const useSessionsStore = defineStore('sessions', () => {
         throw new Error(`HTTP ${response.status}: ${response.statusText}`)
       }
 
-      const data: ApiResponse<ActiveSession[]> = await response.json()
-
-      if (data.success && data.data) {
-        sessions.value = data.data
-      } else {
-        throw new Error(data.message || 'Failed to load sessions')
-      }
+      sessions.value = await response.json()
     } catch (err) {
       error.value = err instanceof Error ? err.message : 'Failed to load active sessions'
       console.error('Failed to load active sessions:', err)
@@ -34,10 +34,73 @@ export interface User {
   admin: string[]
   org: string
   claims: Record<string, any>
+  mfa_enabled: boolean
+  mfa_recovery_codes_remaining: number
+  passkeys: Passkey[]
+  federated_identities: FederatedIdentity[]
   created_at: string
   updated_at: string
 }
 
+export interface AccountLockout {
+  locked: boolean
+  locked_until: number | null
+  failures: number
+}
+
+export interface Impersonation {
+  id: string
+  admin_id: string
+  admin_email: string
+  user_id: string
+  org: string
+  reason: string
+  started_at: string
+  expires_at: string
+  ended_at: string | null
+}
+
+export interface ImpersonationStart {
+  impersonation: Impersonation
+  access_token: string
+  token_type: string
+  expires_in: number
+  login_url: string
+}
+
+export interface Passkey {
+  nickname: string
+  transports: string[]
+  created_at: string
+  last_used_at: string | null
+}
+
+export interface FederatedIdentity {
+  provider: string
+  subject: string
+  linked_at: string
+}
+
+export interface PasswordPolicy {
+  min_length: number
+  min_character_classes: number
+  forbid_personal_data: boolean
+  check_breached: boolean
+}
+
+// Session limits; fields left null keep the value of the level below
+export interface SessionPolicy {
+  idle_timeout: number | null
+  max_lifetime: number | null
+  max_concurrent: number | null
+  on_limit: 'evict_oldest' | 'refuse' | null
+}
+
+export interface SecurityPolicy {
+  require_passkey_for_admins: boolean
+  password: PasswordPolicy
+}
+
 export interface CreateUserRequest {
   email: string
   password: string
@@ -74,6 +137,23 @@ export interface Organization {
   created_at: string
 }
 
+export interface RegistrationPolicy {
+  enabled: boolean
+  allowed_email_domains: string[]
+  invite_code: string | null
+  default_claims: Record<string, any>
+}
+
+export interface OrganizationSettings {
+  id: string
+  name: string
+  description: string
+  require_verified_email: boolean
+  registration: RegistrationPolicy
+  password_policy: PasswordPolicy | null
+  session_policy: SessionPolicy | null
+}
+
 // Group Management Types
 export interface Group {
   id: string
@@ -105,9 +185,20 @@ export interface Client {
   allowed_scopes: string[]
   require_pkce: boolean
   grant_types: string[]
+  access_token_format: AccessTokenFormat
+  subject_type: SubjectType
+  sector_identifier_uri?: string
+  min_acr?: Acr
+  max_auth_age?: number
+  session_policy?: SessionPolicy | null
   created_at: string
 }
 
+export type AccessTokenFormat = 'jwt' | 'opaque'
+export type SubjectType = 'public' | 'pairwise'
+// Login strength, weakest first: password, two factors, phishing-resistant passkey
+export type Acr = 'pwd' | 'mfa' | 'phr'
+
 export interface CreateClientRequest {
   client_id: string
   name: string
@@ -116,6 +207,12 @@ export interface CreateClientRequest {
   allowed_scopes: string[]
   require_pkce?: boolean
   grant_types?: string[]
+  access_token_format?: AccessTokenFormat
+  subject_type?: SubjectType
+  sector_identifier_uri?: string
+  min_acr?: Acr
+  max_auth_age?: number
+  session_policy?: SessionPolicy
 }
 
 export interface UpdateClientRequest {
@@ -124,6 +221,12 @@ export interface UpdateClientRequest {
   allowed_scopes?: string[]
   require_pkce?: boolean
   grant_types?: string[]
+  access_token_format?: AccessTokenFormat
+  subject_type?: SubjectType
+  sector_identifier_uri?: string
+  min_acr?: Acr | null
+  max_auth_age?: number | null
+  session_policy?: SessionPolicy | null
 }
 
 export interface ClientSecret {
@@ -131,6 +234,43 @@ export interface ClientSecret {
   expires_at?: string
 }
 
+// SAML Service Provider Types
+export interface ServiceProvider {
+  id: string
+  entity_id: string
+  name: string
+  acs_urls: string[]
+  name_id_format: NameIdFormat
+  attributes: AttributeRelease[]
+  created_at: string
+}
+
+export type NameIdFormat = 'persistent' | 'email'
+
+// source: id, email, given_name, family_name, name, org or a registry claim
+export interface AttributeRelease {
+  name: string
+  friendly_name?: string
+  source: string
+}
+
+export interface CreateServiceProviderRequest {
+  id: string
+  entity_id: string
+  name: string
+  acs_urls: string[]
+  name_id_format?: NameIdFormat
+  attributes?: AttributeRelease[]
+}
+
+export interface UpdateServiceProviderRequest {
+  entity_id?: string
+  name?: string
+  acs_urls?: string[]
+  name_id_format?: NameIdFormat
+  attributes?: AttributeRelease[]
+}
+
 // Claims Registry Types
 export interface ClaimsRegistry {
   claims: Record<string, ClaimDefinition>
@@ -189,7 +329,6 @@ export interface SystemConfig {
     issuer: string
   }
   security: {
-    password_min_length: number
     access_token_ttl: number
     refresh_token_ttl: number
     require_mfa: boolean
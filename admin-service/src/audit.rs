@@ -0,0 +1,70 @@
+use axum::http::StatusCode;
+use tracing::{error, info};
+
+use crate::models::AuditEvent;
+use crate::storage::AdminStorage;
+
+/// Write a security-relevant event to admin-service's audit log and the
+/// service log. A request whose event cannot be written fails with 500.
+pub async fn record(storage: &AdminStorage, event: &AuditEvent) -> Result<(), StatusCode> {
+    if let Err(e) = storage.record_audit(event).await {
+        error!(
+            service = "admin-service",
+            event = "audit_write_failed",
+            event_type = %event.event_type,
+            error = %format!("{:#}", e)
+        );
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    info!(
+        service = "admin-service",
+        event = "audit",
+        event_type = %event.event_type,
+        audit_id = %event.id,
+        user_id = ?event.user_id
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit_query::{self, AuditFilter};
+    use crate::config::AuditConfig;
+    use serde_json::{json, Value};
+
+    #[tokio::test]
+    async fn test_admin_events_are_chained_and_queryable() {
+        let data_dir = std::env::temp_dir()
+            .join(format!("um-oic-admin-audit-{}", uuid::Uuid::new_v4().simple()))
+            .to_string_lossy()
+            .to_string();
+        tokio::fs::create_dir_all(&data_dir).await.unwrap();
+        tokio::fs::write(format!("{}/claims.json", data_dir), "{}").await.unwrap();
+        tokio::fs::write(format!("{}/clients.json", data_dir), r#"{"clients": []}"#).await.unwrap();
+        let config = AuditConfig { signing_key: format!("{}/keys/audit.pem", data_dir) };
+        let storage = AdminStorage::load(&data_dir, "/nonexistent.pid", &config).await.unwrap();
+
+        for event_type in ["account_unlocked", "mfa_reset"] {
+            let mut event = AuditEvent::new(event_type.to_string(), Some("user-1".to_string()), Some("default".to_string()));
+            event.metadata.insert("requested_by".to_string(), json!("admin-1"));
+            record(&storage, &event).await.unwrap();
+        }
+
+        // Durable by the time `record` returns, chained in admin-service's own file
+        let today = time::OffsetDateTime::now_utc().date();
+        let log = tokio::fs::read_to_string(format!("{}/audit/admin-service/{}.jsonl", data_dir, today)).await.unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let records: Vec<Value> = lines.iter().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(records[0]["prev_hash"], audit_log::GENESIS_HASH);
+        assert_eq!(records[1]["prev_hash"], audit_log::hash_line(lines[0]).as_str());
+
+        // Listed by /api/audit like auth-service's events
+        let page = audit_query::query(&data_dir, &AuditFilter::default(), None, 10).unwrap();
+        let types: Vec<&str> = page.events.iter().map(|e| e.event_type.as_str()).collect();
+        assert_eq!(types, ["mfa_reset", "account_unlocked"]);
+
+        tokio::fs::remove_dir_all(&data_dir).await.unwrap();
+    }
+}
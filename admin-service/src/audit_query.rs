@@ -0,0 +1,547 @@
+use anyhow::{Context, Result};
+use flate2::read::GzDecoder;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use time::{Date, OffsetDateTime, UtcOffset};
+use tracing::warn;
+
+use crate::models::AuditEvent;
+
+/// Bytes read at a time while walking a file backwards
+const BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Where `auth-ops archive` moves closed day files, as
+/// `{service}/{date}.jsonl.gz`
+const ARCHIVE_DIR: &str = "audit-archive";
+
+/// Which audit events a query returns.
+#[derive(Debug, Default, Clone)]
+pub struct AuditFilter {
+    pub user_id: Option<String>,
+    pub event_type: Option<String>,
+    pub org: Option<String>,
+    pub ip_address: Option<String>,
+    pub from: Option<OffsetDateTime>,
+    pub to: Option<OffsetDateTime>,
+    /// Organizations the caller may see events of; `None` for all. Events
+    /// without an organization are only visible with `None`.
+    pub visible_orgs: Option<Vec<String>>,
+}
+
+impl AuditFilter {
+    fn matches(&self, event: &AuditEvent) -> bool {
+        let equals = |wanted: &Option<String>, actual: Option<&str>| {
+            wanted.as_deref().is_none_or(|wanted| actual == Some(wanted))
+        };
+        equals(&self.user_id, event.user_id.as_deref())
+            && equals(&self.event_type, Some(&event.event_type))
+            && equals(&self.org, event.org.as_deref())
+            && equals(&self.ip_address, event.ip_address.as_deref())
+            && self.from.is_none_or(|from| event.created_at >= from)
+            && self.to.is_none_or(|to| event.created_at <= to)
+            && self.visible_orgs.as_ref().is_none_or(|orgs| {
+                event.org.as_ref().is_some_and(|org| orgs.contains(org))
+            })
+    }
+}
+
+/// Where the next page of a query starts: the day being read, and for each
+/// service's file of that day how many bytes at its start are still unread.
+///
+/// Written as `2024-05-01:auth-service=1234,admin-service=0`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditCursor {
+    day: Date,
+    positions: BTreeMap<String, u64>,
+}
+
+impl AuditCursor {
+    pub fn parse(cursor: &str) -> Option<Self> {
+        let (day, positions) = cursor.split_once(':')?;
+        let day = Date::parse(day, &time::format_description::well_known::Iso8601::DATE).ok()?;
+        let positions = positions
+            .split(',')
+            .filter(|p| !p.is_empty())
+            .map(|p| {
+                let (source, offset) = p.split_once('=')?;
+                Some((source.to_string(), offset.parse().ok()?))
+            })
+            .collect::<Option<_>>()?;
+        Some(Self { day, positions })
+    }
+}
+
+impl std::fmt::Display for AuditCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let positions: Vec<String> = self.positions.iter().map(|(s, o)| format!("{}={}", s, o)).collect();
+        write!(f, "{}:{}", self.day, positions.join(","))
+    }
+}
+
+pub struct AuditPage {
+    pub events: Vec<AuditEvent>,
+    /// Absent once nothing older is left
+    pub next_cursor: Option<AuditCursor>,
+}
+
+/// Up to `limit` events matching `filter` from `audit/{service}/{day}.jsonl`,
+/// newest first, starting at `cursor`. Files are read backwards a block at
+/// a time and the services' files of a day merged by time, so a page costs
+/// memory for the page only, however large the range.
+///
+/// Days archived to `audit-archive/{service}/{day}.jsonl.gz` are included
+/// until their retention deletes them. Those are decompressed whole, a
+/// day at a time; cursor offsets count decompressed bytes, so a day that is
+/// archived between two pages resumes where it left off.
+///
+/// Blocking; call it off the async runtime.
+pub fn query(data_dir: &str, filter: &AuditFilter, cursor: Option<&AuditCursor>, limit: usize) -> Result<AuditPage> {
+    let mut days = audit_days(Path::new(data_dir))?;
+    days.retain(|(day, _)| {
+        cursor.is_none_or(|c| *day <= c.day)
+            && filter.from.is_none_or(|from| *day >= from.to_offset(UtcOffset::UTC).date())
+            && filter.to.is_none_or(|to| *day <= to.to_offset(UtcOffset::UTC).date())
+    });
+
+    let mut events = Vec::new();
+    let mut days = days.into_iter().rev().peekable();
+    while let Some((day, files)) = days.next() {
+        let resume = cursor.filter(|c| c.day == day);
+        let mut sources = Vec::new();
+        for (source, path) in files {
+            let end = resume.map(|c| c.positions.get(&source).copied().unwrap_or(0));
+            let mut source = DaySource::open(source, &path, end)?;
+            source.advance()?;
+            sources.push(source);
+        }
+
+        loop {
+            if events.len() == limit {
+                if days.peek().is_none() && sources.iter().all(|s| s.peeked.is_none()) {
+                    break;
+                }
+                let positions = sources.iter().map(|s| (s.name.clone(), s.resume_at())).collect();
+                return Ok(AuditPage { events, next_cursor: Some(AuditCursor { day, positions }) });
+            }
+            let newest = sources
+                .iter_mut()
+                .filter(|s| s.peeked.is_some())
+                .max_by_key(|s| s.peeked.as_ref().map(|(event, _)| event.created_at));
+            let Some(source) = newest else {
+                break;
+            };
+            let Some((event, _)) = source.peeked.take() else {
+                break;
+            };
+            source.advance()?;
+            if filter.matches(&event) {
+                events.push(event);
+            }
+        }
+    }
+
+    Ok(AuditPage { events, next_cursor: None })
+}
+
+/// Each service's file of a day
+type DayFiles = Vec<(String, PathBuf)>;
+
+/// Days with audit files, oldest first. A day both archived and still
+/// live, as after an interrupted archive run, is read from the live file.
+fn audit_days(data_dir: &Path) -> Result<Vec<(Date, DayFiles)>> {
+    let mut days: BTreeMap<Date, BTreeMap<String, PathBuf>> = BTreeMap::new();
+    for (root, suffix) in [(ARCHIVE_DIR, ".jsonl.gz"), ("audit", ".jsonl")] {
+        let services = match std::fs::read_dir(data_dir.join(root)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {} directory", root)),
+        };
+        for service in services {
+            let service = service?;
+            if !service.file_type()?.is_dir() {
+                continue;
+            }
+            let name = service.file_name().to_string_lossy().to_string();
+            for file in std::fs::read_dir(service.path())? {
+                let path = file?.path();
+                let day = path
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .and_then(|n| n.strip_suffix(suffix))
+                    .and_then(|d| Date::parse(d, &time::format_description::well_known::Iso8601::DATE).ok());
+                if let Some(day) = day {
+                    days.entry(day).or_default().insert(name.clone(), path);
+                }
+            }
+        }
+    }
+    Ok(days.into_iter().map(|(day, files)| (day, files.into_iter().collect())).collect())
+}
+
+/// One service's file of a day, read from the end.
+struct DaySource {
+    name: String,
+    lines: ReverseLines,
+    /// The next event, with the offset its line ends at
+    peeked: Option<(AuditEvent, u64)>,
+}
+
+impl DaySource {
+    fn open(name: String, path: &Path, end: Option<u64>) -> Result<Self> {
+        let lines = ReverseLines::open(path, end).with_context(|| format!("Failed to open {}", path.display()))?;
+        Ok(Self { name, lines, peeked: None })
+    }
+
+    /// Read the next event into `peeked`; lines that are no event, like the
+    /// checkpoint closing a day, are skipped.
+    fn advance(&mut self) -> Result<()> {
+        loop {
+            let end = self.lines.position();
+            let Some(line) = self.lines.next_line()? else {
+                self.peeked = None;
+                return Ok(());
+            };
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            match serde_json::from_slice::<AuditEvent>(&line) {
+                Ok(event) => {
+                    self.peeked = Some((event, end));
+                    return Ok(());
+                }
+                Err(_) if is_checkpoint(&line) => {}
+                Err(e) => warn!(
+                    service = "admin-service",
+                    event = "audit_line_unreadable",
+                    source = %self.name,
+                    offset = self.lines.position(),
+                    error = %e
+                ),
+            }
+        }
+    }
+
+    /// Offset to continue from, the peeked event included.
+    fn resume_at(&self) -> u64 {
+        self.peeked.as_ref().map_or(self.lines.position(), |(_, end)| *end)
+    }
+}
+
+fn is_checkpoint(line: &[u8]) -> bool {
+    serde_json::from_slice::<serde_json::Value>(line).is_ok_and(|line| line.get("checkpoint").is_some())
+}
+
+/// A file to read backwards: live ones from disk, archived ones decompressed.
+trait Source: Read + Seek {}
+
+impl<T: Read + Seek> Source for T {}
+
+/// Lines of a file, last first.
+struct ReverseLines {
+    file: Box<dyn Source>,
+    /// Offset of `chunk[0]` in the file
+    start: u64,
+    /// Bytes before the lines handed out so far, back to `start`
+    chunk: Vec<u8>,
+}
+
+impl ReverseLines {
+    /// Read the lines before offset `end`, or of the whole file.
+    fn open(path: &Path, end: Option<u64>) -> Result<Self> {
+        let mut file: Box<dyn Source> = if path.extension().is_some_and(|e| e == "gz") {
+            let mut content = Vec::new();
+            GzDecoder::new(File::open(path)?).read_to_end(&mut content)?;
+            Box::new(Cursor::new(content))
+        } else {
+            Box::new(File::open(path)?)
+        };
+        let length = file.seek(SeekFrom::End(0))?;
+        let start = end.map_or(length, |end| end.min(length));
+        Ok(Self { file, start, chunk: Vec::new() })
+    }
+
+    /// Offset up to which the file is still unread.
+    fn position(&self) -> u64 {
+        self.start + self.chunk.len() as u64
+    }
+
+    fn next_line(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            let body_end = match self.chunk.last() {
+                Some(b'\n') => self.chunk.len() - 1,
+                _ => self.chunk.len(),
+            };
+            if let Some(newline) = self.chunk[..body_end].iter().rposition(|b| *b == b'\n') {
+                let line = self.chunk[newline + 1..body_end].to_vec();
+                self.chunk.truncate(newline + 1);
+                return Ok(Some(line));
+            }
+            if self.start == 0 {
+                if self.chunk.is_empty() {
+                    return Ok(None);
+                }
+                let line = self.chunk[..body_end].to_vec();
+                self.chunk.clear();
+                return Ok(Some(line));
+            }
+
+            let from = self.start.saturating_sub(BLOCK_SIZE);
+            let mut block = vec![0; (self.start - from) as usize];
+            self.file.seek(SeekFrom::Start(from))?;
+            self.file.read_exact(&mut block)?;
+            block.append(&mut self.chunk);
+            self.chunk = block;
+            self.start = from;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use time::format_description::well_known::Rfc3339;
+
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("um-oic-audit-query-{}", uuid::Uuid::new_v4().simple()));
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+
+        /// Append `events` to the service's file of their day, as the writer would.
+        fn append(&self, service: &str, events: &[AuditEvent]) {
+            use std::io::Write;
+            for event in events {
+                let dir = self.0.join("audit").join(service);
+                std::fs::create_dir_all(&dir).unwrap();
+                let path = dir.join(format!("{}.jsonl", event.created_at.date()));
+                let mut line = serde_json::to_value(event).unwrap();
+                line["prev_hash"] = json!(audit_log::GENESIS_HASH);
+                let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path).unwrap();
+                writeln!(file, "{}", line).unwrap();
+            }
+        }
+    }
+
+    impl TestDir {
+        /// Move a service's file of `day` to the archive, as `auth-ops archive` does.
+        fn archive(&self, service: &str, day: &str) {
+            use flate2::{write::GzEncoder, Compression};
+            use std::io::Write;
+            let live = self.0.join("audit").join(service).join(format!("{}.jsonl", day));
+            let dir = self.0.join(ARCHIVE_DIR).join(service);
+            std::fs::create_dir_all(&dir).unwrap();
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+            encoder.write_all(&std::fs::read(&live).unwrap()).unwrap();
+            std::fs::write(dir.join(format!("{}.jsonl.gz", day)), encoder.finish().unwrap()).unwrap();
+            std::fs::remove_file(live).unwrap();
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn event(id: &str, event_type: &str, user_id: &str, org: Option<&str>, created_at: OffsetDateTime) -> AuditEvent {
+        let mut event = AuditEvent::new(event_type.to_string(), Some(user_id.to_string()), org.map(str::to_string));
+        event.id = id.to_string();
+        event.created_at = created_at;
+        event
+    }
+
+    fn at(timestamp: &str) -> OffsetDateTime {
+        OffsetDateTime::parse(timestamp, &Rfc3339).unwrap()
+    }
+
+    fn ids(events: &[AuditEvent]) -> Vec<&str> {
+        events.iter().map(|e| e.id.as_str()).collect()
+    }
+
+    /// Every page of a query, each resumed from the previous cursor's text.
+    fn pages(data_dir: &str, filter: &AuditFilter, limit: usize) -> Vec<Vec<String>> {
+        let mut pages = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = query(data_dir, filter, cursor.as_ref(), limit).unwrap();
+            pages.push(page.events.iter().map(|e| e.id.clone()).collect());
+            let Some(next) = page.next_cursor else {
+                return pages;
+            };
+            cursor = Some(AuditCursor::parse(&next.to_string()).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_pages_merge_services_and_days() {
+        let dir = TestDir::new();
+        dir.append("auth-service", &[
+            event("a1", "login_succeeded", "user-1", Some("school"), at("2024-05-01T08:00:00Z")),
+            event("a2", "logout", "user-1", Some("school"), at("2024-05-01T10:00:00Z")),
+            event("a3", "login_succeeded", "user-2", Some("other"), at("2024-05-02T09:00:00Z")),
+        ]);
+        dir.append("admin-service", &[
+            event("b1", "mfa_reset", "user-1", Some("school"), at("2024-05-01T09:00:00Z")),
+            event("b2", "account_unlocked", "user-2", Some("other"), at("2024-05-02T08:00:00Z")),
+        ]);
+        // The checkpoint closing a day is no event
+        let closed = dir.0.join("audit/auth-service/2024-05-01.jsonl");
+        let mut log = std::fs::read_to_string(&closed).unwrap();
+        log.push_str(&json!({"checkpoint": {"records": 2}, "prev_hash": "x", "signature": "y"}).to_string());
+        log.push('\n');
+        std::fs::write(&closed, log).unwrap();
+
+        let all = AuditFilter::default();
+        let page = query(dir.path(), &all, None, 10).unwrap();
+        assert_eq!(ids(&page.events), ["a3", "b2", "a2", "b1", "a1"]);
+        assert!(page.next_cursor.is_none());
+
+        // Page boundaries inside a day, at the end of a day, and at the very end
+        assert_eq!(pages(dir.path(), &all, 2), [vec!["a3", "b2"], vec!["a2", "b1"], vec!["a1"]]);
+        assert_eq!(pages(dir.path(), &all, 1).concat(), ["a3", "b2", "a2", "b1", "a1"]);
+        let exact = query(dir.path(), &all, None, 5).unwrap();
+        assert_eq!(exact.events.len(), 5);
+        assert!(exact.next_cursor.is_none());
+        assert!(query(dir.path(), &all, None, 0).unwrap().next_cursor.is_some());
+    }
+
+    #[test]
+    fn test_resumed_cursor_skips_newer_events() {
+        let dir = TestDir::new();
+        let day = at("2024-05-01T00:00:00Z");
+        let events: Vec<AuditEvent> = (1..=4)
+            .map(|i| event(&format!("e{}", i), "logout", "user-1", None, day + time::Duration::hours(i)))
+            .collect();
+        dir.append("auth-service", &events);
+
+        let first = query(dir.path(), &AuditFilter::default(), None, 2).unwrap();
+        assert_eq!(ids(&first.events), ["e4", "e3"]);
+        let cursor = first.next_cursor.unwrap();
+        assert_eq!(cursor.to_string(), AuditCursor::parse(&cursor.to_string()).unwrap().to_string());
+
+        // Written after the first page: neither repeated nor shifting the page
+        dir.append("auth-service", &[event("e5", "logout", "user-1", None, day + time::Duration::hours(5))]);
+        dir.append("admin-service", &[event("f1", "mfa_reset", "user-1", None, day + time::Duration::minutes(90))]);
+        let second = query(dir.path(), &AuditFilter::default(), Some(&cursor), 10).unwrap();
+        assert_eq!(ids(&second.events), ["e2", "e1"]);
+        assert!(second.next_cursor.is_none());
+
+        assert!(AuditCursor::parse("2024-05-01").is_none());
+        assert!(AuditCursor::parse("2024-05-01:auth-service=x").is_none());
+    }
+
+    #[test]
+    fn test_lines_spanning_blocks() {
+        let dir = TestDir::new();
+        let day = at("2024-05-01T00:00:00Z");
+        // Lines of a third, two thirds and twice a block cross block borders
+        let events: Vec<AuditEvent> = [BLOCK_SIZE / 3, BLOCK_SIZE * 2 / 3, BLOCK_SIZE * 2, 10, BLOCK_SIZE / 2]
+            .iter()
+            .enumerate()
+            .map(|(i, size)| {
+                let mut event = event(&format!("e{}", i), "logout", "user-1", None, day + time::Duration::minutes(i as i64));
+                event.metadata.insert("padding".to_string(), json!("x".repeat(*size as usize)));
+                event
+            })
+            .collect();
+        dir.append("auth-service", &events);
+
+        assert_eq!(pages(dir.path(), &AuditFilter::default(), 2).concat(), ["e4", "e3", "e2", "e1", "e0"]);
+        let page = query(dir.path(), &AuditFilter::default(), None, 10).unwrap();
+        assert_eq!(page.events.len(), events.len());
+        for (read, written) in page.events.iter().zip(events.iter().rev()) {
+            assert_eq!(read.metadata["padding"], written.metadata["padding"]);
+        }
+    }
+
+    #[test]
+    fn test_archived_days() {
+        let dir = TestDir::new();
+        let events: Vec<AuditEvent> = ["2024-05-01T08:00:00Z", "2024-05-01T09:00:00Z", "2024-05-02T08:00:00Z"]
+            .iter()
+            .enumerate()
+            .map(|(i, created_at)| event(&format!("e{}", i + 1), "logout", "user-1", None, at(created_at)))
+            .collect();
+        dir.append("auth-service", &events);
+        dir.append("admin-service", &[event("f1", "mfa_reset", "user-1", None, at("2024-05-01T08:30:00Z"))]);
+
+        // Archived between two pages, the day resumes where it left off
+        let first = query(dir.path(), &AuditFilter::default(), None, 2).unwrap();
+        assert_eq!(ids(&first.events), ["e3", "e2"]);
+        dir.archive("auth-service", "2024-05-01");
+        dir.archive("admin-service", "2024-05-01");
+        let rest = query(dir.path(), &AuditFilter::default(), first.next_cursor.as_ref(), 10).unwrap();
+        assert_eq!(ids(&rest.events), ["f1", "e1"]);
+        assert_eq!(pages(dir.path(), &AuditFilter::default(), 2), [vec!["e3", "e2"], vec!["f1", "e1"]]);
+
+        // A day left both archived and live is read once, from the live file
+        dir.append("auth-service", &events[..2]);
+        assert_eq!(pages(dir.path(), &AuditFilter::default(), 10).concat(), ["e3", "e2", "f1", "e1"]);
+    }
+
+    #[test]
+    fn test_filters() {
+        let dir = TestDir::new();
+        let mut from_school = event("e1", "login_failed", "user-1", Some("school"), at("2024-05-01T12:00:00Z"));
+        from_school.ip_address = Some("10.0.0.1".to_string());
+        let mut from_other = event("e2", "login_failed", "user-2", Some("other"), at("2024-05-01T23:30:00Z"));
+        from_other.ip_address = Some("10.0.0.2".to_string());
+        dir.append("auth-service", &[
+            from_school,
+            event("e3", "login_succeeded", "user-1", Some("school"), at("2024-05-02T00:30:00Z")),
+            from_other,
+            event("e4", "key_rotated", "system", None, at("2024-05-03T12:00:00Z")),
+        ]);
+
+        let found = |filter: AuditFilter| ids(&query(dir.path(), &filter, None, 10).unwrap().events)
+            .into_iter()
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        let text = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(found(AuditFilter { user_id: Some("user-1".into()), ..Default::default() }), text(&["e3", "e1"]));
+        assert_eq!(found(AuditFilter { event_type: Some("login_failed".into()), ..Default::default() }), text(&["e2", "e1"]));
+        assert_eq!(found(AuditFilter { org: Some("other".into()), ..Default::default() }), text(&["e2"]));
+        assert_eq!(found(AuditFilter { ip_address: Some("10.0.0.1".into()), ..Default::default() }), text(&["e1"]));
+        assert_eq!(
+            found(AuditFilter { user_id: Some("user-1".into()), event_type: Some("login_failed".into()), ..Default::default() }),
+            text(&["e1"])
+        );
+        assert!(found(AuditFilter { org: Some("school".into()), ip_address: Some("10.0.0.2".into()), ..Default::default() }).is_empty());
+
+        // Bounds are inclusive; given in another offset they still find the UTC day's file
+        assert_eq!(
+            found(AuditFilter { from: Some(at("2024-05-02T01:30:00+02:00")), ..Default::default() }),
+            text(&["e4", "e3", "e2"])
+        );
+        assert_eq!(
+            found(AuditFilter { to: Some(at("2024-05-01T21:30:00-03:00")), ..Default::default() }),
+            text(&["e3", "e2", "e1"])
+        );
+        assert_eq!(
+            found(AuditFilter {
+                from: Some(at("2024-05-01T12:00:00Z")),
+                to: Some(at("2024-05-02T00:30:00Z")),
+                user_id: Some("user-1".into()),
+                ..Default::default()
+            }),
+            text(&["e3", "e1"])
+        );
+
+        // Org admins see their organizations' events, never those without one
+        let visible = Some(vec!["school".to_string()]);
+        assert_eq!(found(AuditFilter { visible_orgs: visible.clone(), ..Default::default() }), text(&["e3", "e1"]));
+        assert!(found(AuditFilter { visible_orgs: visible, org: Some("other".into()), ..Default::default() }).is_empty());
+        assert_eq!(found(AuditFilter::default()).len(), 4);
+    }
+}
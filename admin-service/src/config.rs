@@ -5,6 +5,8 @@ use serde::{Deserialize, Serialize};
 pub struct Config {
     pub jwt_secret: String,
     pub instance: InstanceConfig,
+    pub security: SecurityConfig,
+    pub audit: AuditConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,6 +17,24 @@ pub struct InstanceConfig {
     pub auth_service_url: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SecurityConfig {
+    /// Lifetime in seconds of the reset link an admin sends to a user
+    pub password_reset_ttl: u64,
+    /// Refuse admin tokens from logins without a second factor
+    pub require_admin_mfa: bool,
+    /// Lifetime in seconds of the token an admin gets to act as a user
+    pub impersonation_ttl: u64,
+}
+
+/// The `[audit]` section auth-service reads too: admin-service signs the
+/// checkpoints of `audit/admin-service` with the same key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditConfig {
+    /// PEM Ed25519 private key; created when missing
+    pub signing_key: String,
+}
+
 impl Config {
     pub async fn load(path: &str) -> Result<Self> {
         let content = tokio::fs::read_to_string(path).await?;
@@ -33,6 +53,14 @@ impl Default for Config {
                 base_url: "https://localhost:8445".to_string(),
                 auth_service_url: "https://localhost:8443".to_string(),
             },
+            security: SecurityConfig {
+                password_reset_ttl: 86400,
+                require_admin_mfa: false,
+                impersonation_ttl: 1800,
+            },
+            audit: AuditConfig {
+                signing_key: "./certs/audit-signing-key.pem".to_string(),
+            },
         }
     }
 }
\ No newline at end of file
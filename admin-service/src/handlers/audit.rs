@@ -7,55 +7,99 @@ use axum::{
 use serde::Deserialize;
 use serde_json::{json, Value};
 use std::sync::Arc;
+use time::{format_description::well_known::Iso8601, OffsetDateTime};
 use tokio::sync::RwLock;
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::{
+    audit_query::{AuditCursor, AuditFilter},
     config::Config,
     jwt::JwtVerifier,
-    models::{Claims, AuditEvent},
+    models::Claims,
     storage::AdminStorage,
 };
 
 type AppState = (Arc<RwLock<AdminStorage>>, Arc<JwtVerifier>, Config);
 
+/// Largest page a query returns
+const MAX_LIMIT: usize = 500;
+
 #[derive(Debug, Deserialize)]
 pub struct AuditQuery {
     user_id: Option<String>,
     event_type: Option<String>,
+    org: Option<String>,
+    ip: Option<String>,
+    /// ISO 8601 timestamps, both inclusive
     from: Option<String>,
     to: Option<String>,
-    limit: Option<u32>,
+    limit: Option<usize>,
+    /// `next_cursor` of the previous page
+    cursor: Option<String>,
 }
 
+/// Audit events newest first, a page at a time. Org admins see the events
+/// of their organizations only.
 pub async fn query(
     Query(query): Query<AuditQuery>,
     State((storage, _jwt_verifier, _config)): State<AppState>,
     Extension(claims): Extension<Claims>,
-) -> Result<Json<Vec<Value>>, StatusCode> {
+) -> Result<Json<Value>, StatusCode> {
     info!(
         service = "admin-service",
         event = "audit_query_requested",
         requested_by = %claims.sub,
         user_id = ?query.user_id,
         event_type = ?query.event_type,
+        org = ?query.org,
         limit = ?query.limit
     );
 
-    let storage_guard = storage.read().await;
-    let audit_events = storage_guard.query_audit_events(
-        query.user_id.as_deref(),
-        query.event_type.as_deref(),
-        query.from.as_deref(),
-        query.to.as_deref(),
-        query.limit.unwrap_or(50)
-    );
+    let timestamp = |value: &Option<String>| match value {
+        Some(value) => OffsetDateTime::parse(value, &Iso8601::DEFAULT).map(Some).map_err(|_| {
+            warn!(
+                service = "admin-service",
+                event = "audit_query_rejected",
+                reason = "invalid_timestamp",
+                value = %value
+            );
+            StatusCode::BAD_REQUEST
+        }),
+        None => Ok(None),
+    };
+    let from = timestamp(&query.from)?;
+    let to = timestamp(&query.to)?;
+    let cursor = match &query.cursor {
+        Some(cursor) => Some(AuditCursor::parse(cursor).ok_or(StatusCode::BAD_REQUEST)?),
+        None => None,
+    };
 
-    drop(storage_guard);
+    let storage_guard = storage.read().await;
+    let admin = storage_guard.get_user(&claims.sub).ok_or(StatusCode::FORBIDDEN)?;
+    let visible_orgs = (!admin.admin.iter().any(|org| org == "all")).then(|| admin.admin.clone());
+    if let (Some(org), Some(visible)) = (&query.org, &visible_orgs) {
+        if !visible.contains(org) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+    }
 
-    let events: Vec<Value> = audit_events.into_iter()
-        .map(|event| serde_json::to_value(event).unwrap_or_default())
-        .collect();
+    let filter = AuditFilter {
+        user_id: query.user_id,
+        event_type: query.event_type,
+        org: query.org,
+        ip_address: query.ip,
+        from,
+        to,
+        visible_orgs,
+    };
+    let limit = query.limit.unwrap_or(50).clamp(1, MAX_LIMIT);
+    let page = storage_guard.query_audit_events(filter, cursor, limit).await.map_err(|e| {
+        tracing::error!("Failed to query audit events: {:#}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
 
-    Ok(Json(events))
-}
\ No newline at end of file
+    Ok(Json(json!({
+        "events": page.events,
+        "next_cursor": page.next_cursor.map(|cursor| cursor.to_string()),
+    })))
+}
@@ -7,12 +7,12 @@ use axum::{
 use serde_json::{json, Value};
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::{
     config::Config,
     jwt::JwtVerifier,
-    models::{Claims, Client},
+    models::{Claims, Client, SubjectType, ACR_VALUES},
     storage::AdminStorage,
 };
 
@@ -87,9 +87,32 @@ pub async fn create(
         allowed_scopes: request.allowed_scopes,
         require_pkce: request.require_pkce.unwrap_or(false),
         grant_types: request.grant_types.unwrap_or_else(|| vec!["authorization_code".to_string()]),
+        access_token_format: request.access_token_format.unwrap_or_default(),
+        subject_type: request.subject_type.unwrap_or_default(),
+        sector_identifier_uri: request.sector_identifier_uri,
+        min_acr: request.min_acr,
+        max_auth_age: request.max_auth_age,
+        session_policy: request.session_policy,
         created_at: time::OffsetDateTime::now_utc(),
     };
 
+    let valid = match validate_sector(&client)
+        .and_then(|_| validate_min_acr(&client))
+        .and_then(|_| validate_session_policy(&client))
+    {
+        Ok(()) => validate_sector_document(&client).await,
+        invalid => invalid,
+    };
+    if let Err(reason) = valid {
+        warn!(
+            service = "admin-service",
+            event = "client_create_rejected",
+            client_id = %request.client_id,
+            reason = %reason
+        );
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
     let mut storage_guard = storage.write().await;
 
     // Check if client already exists
@@ -127,12 +150,13 @@ pub async fn update(
         updated_by = %claims.sub
     );
 
-    let mut storage_guard = storage.write().await;
-
-    // Get existing client
-    let existing_client = storage_guard.get_client(&client_id)
+    // Read first: validating may fetch the sector identifier document,
+    // which must not hold up other writes
+    let existing_client = storage.read().await
+        .get_client(&client_id)
         .ok_or(StatusCode::NOT_FOUND)?
         .clone();
+    let snapshot = serde_json::to_value(&existing_client).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
     // Update client with provided fields
     let updated_client = Client {
@@ -144,9 +168,39 @@ pub async fn update(
         allowed_scopes: request.allowed_scopes.unwrap_or(existing_client.allowed_scopes),
         require_pkce: request.require_pkce.unwrap_or(existing_client.require_pkce),
         grant_types: request.grant_types.unwrap_or(existing_client.grant_types),
+        access_token_format: request.access_token_format.unwrap_or(existing_client.access_token_format),
+        subject_type: request.subject_type.unwrap_or(existing_client.subject_type),
+        sector_identifier_uri: request.sector_identifier_uri.or(existing_client.sector_identifier_uri),
+        min_acr: request.min_acr.unwrap_or(existing_client.min_acr),
+        max_auth_age: request.max_auth_age.unwrap_or(existing_client.max_auth_age),
+        session_policy: request.session_policy.unwrap_or(existing_client.session_policy),
         created_at: existing_client.created_at,
     };
 
+    let valid = match validate_sector(&updated_client)
+        .and_then(|_| validate_min_acr(&updated_client))
+        .and_then(|_| validate_session_policy(&updated_client))
+    {
+        Ok(()) => validate_sector_document(&updated_client).await,
+        invalid => invalid,
+    };
+    if let Err(reason) = valid {
+        warn!(
+            service = "admin-service",
+            event = "client_update_rejected",
+            client_id = %client_id,
+            reason = %reason
+        );
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let mut storage_guard = storage.write().await;
+    // Changed by someone else while this update was validated
+    let current = storage_guard.get_client(&client_id).ok_or(StatusCode::NOT_FOUND)?;
+    if serde_json::to_value(current).ok().as_ref() != Some(&snapshot) {
+        return Err(StatusCode::CONFLICT);
+    }
+
     match storage_guard.update_client(&client_id, updated_client.clone()).await {
         Ok(client) => {
             info!(
@@ -258,4 +312,130 @@ pub async fn rotate_secret(
             Err(StatusCode::INTERNAL_SERVER_ERROR)
         }
     }
-}
\ No newline at end of file
+}
+/// Pairwise clients need an unambiguous sector: either an explicit
+/// sector_identifier_uri or redirect URIs that all share one host.
+fn validate_sector(client: &Client) -> Result<(), String> {
+    if client.subject_type != SubjectType::Pairwise {
+        return Ok(());
+    }
+
+    let host_of = |uri: &str| -> Result<String, String> {
+        uri.parse::<axum::http::Uri>()
+            .map_err(|e| format!("invalid URI {}: {}", uri, e))?
+            .host()
+            .map(|h| h.to_lowercase())
+            .ok_or_else(|| format!("URI has no host: {}", uri))
+    };
+
+    if let Some(uri) = &client.sector_identifier_uri {
+        return host_of(uri).map(|_| ());
+    }
+
+    let hosts = client.redirect_uris
+        .iter()
+        .map(|u| host_of(u))
+        .collect::<Result<std::collections::HashSet<_>, _>>()?;
+
+    match hosts.len() {
+        1 => Ok(()),
+        0 => Err("pairwise client without redirect URIs needs sector_identifier_uri".to_string()),
+        _ => Err("redirect URIs span several hosts; set sector_identifier_uri".to_string()),
+    }
+}
+
+/// OIDC Core §8.1: a pairwise client's sector_identifier_uri must be an
+/// https URL serving a JSON array that lists every one of its redirect URIs.
+async fn validate_sector_document(client: &Client) -> Result<(), String> {
+    let uri = match &client.sector_identifier_uri {
+        Some(uri) if client.subject_type == SubjectType::Pairwise => uri,
+        _ => return Ok(()),
+    };
+    if !uri.starts_with("https://") {
+        return Err(format!("sector_identifier_uri {} is not an https URL", uri));
+    }
+
+    let http = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(10))
+        .build()
+        .map_err(|e| format!("cannot fetch sector identifier document: {}", e))?;
+    let listed: Vec<String> = http.get(uri)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("cannot fetch sector identifier document {}: {}", uri, e))?
+        .json()
+        .await
+        .map_err(|e| format!("sector identifier document {} is no JSON array of URIs: {}", uri, e))?;
+
+    check_sector_document(client, &listed)
+}
+
+fn check_sector_document(client: &Client, listed: &[String]) -> Result<(), String> {
+    match client.redirect_uris.iter().find(|uri| !listed.contains(uri)) {
+        Some(missing) => Err(format!("redirect URI {} is not listed in the sector identifier document", missing)),
+        None => Ok(()),
+    }
+}
+
+fn validate_min_acr(client: &Client) -> Result<(), String> {
+    match &client.min_acr {
+        Some(acr) if !ACR_VALUES.contains(&acr.as_str()) => {
+            Err(format!("unknown min_acr {}; expected one of {}", acr, ACR_VALUES.join(", ")))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn validate_session_policy(client: &Client) -> Result<(), String> {
+    match client.session_policy.as_ref().and_then(|policy| policy.problem()) {
+        Some(problem) => Err(problem.to_string()),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairwise_client(redirect_uris: &[&str], sector_identifier_uri: Option<&str>) -> Client {
+        serde_json::from_value(json!({
+            "client_id": "partner",
+            "client_secret_hash": null,
+            "name": "Partner",
+            "client_type": "public",
+            "redirect_uris": redirect_uris,
+            "allowed_scopes": ["openid"],
+            "require_pkce": true,
+            "grant_types": ["authorization_code"],
+            "subject_type": "pairwise",
+            "sector_identifier_uri": sector_identifier_uri,
+            "min_acr": null,
+            "max_auth_age": null,
+            "created_at": "2024-01-01T00:00:00Z"
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_sector_document_must_list_every_redirect_uri() {
+        let client = pairwise_client(
+            &["https://a.partner.example/cb", "https://b.partner.example/cb"],
+            Some("https://partner.example/sector.json"),
+        );
+        let listed = |uris: &[&str]| uris.iter().map(|u| u.to_string()).collect::<Vec<_>>();
+
+        assert!(check_sector_document(&client, &listed(&["https://a.partner.example/cb", "https://b.partner.example/cb"])).is_ok());
+        assert!(check_sector_document(&client, &listed(&["https://a.partner.example/cb"])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sector_identifier_uri_must_be_https() {
+        let client = pairwise_client(&["https://a.partner.example/cb"], Some("http://partner.example/sector.json"));
+        assert!(validate_sector_document(&client).await.is_err());
+
+        // Several hosts need a sector identifier; one host does not
+        assert!(validate_sector(&pairwise_client(&["https://a.example/cb", "https://b.example/cb"], None)).is_err());
+        assert!(validate_sector(&pairwise_client(&["https://a.example/cb", "https://a.example/cb2"], None)).is_ok());
+    }
+}
@@ -0,0 +1,256 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Json,
+    Extension,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{collections::HashMap, sync::Arc};
+use time::OffsetDateTime;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::{
+    audit,
+    config::Config,
+    jwt::{self, JwtVerifier},
+    models::{Actor, AuditEvent, Claims, ImpersonateRequest, Impersonation, User},
+    storage::AdminStorage,
+};
+
+type AppState = (Arc<RwLock<AdminStorage>>, Arc<JwtVerifier>, Config);
+
+/// What auth-service would put in a login token of the user, minus admin
+/// scopes and authentication details, plus `act` naming the admin.
+#[derive(Serialize)]
+struct ImpersonationClaims<'a> {
+    sub: &'a str,
+    email: &'a str,
+    name: String,
+    org: &'a str,
+    admin: Vec<String>,
+    #[serde(flatten)]
+    user_claims: HashMap<&'a String, &'a Value>,
+    iss: &'a str,
+    aud: Vec<&'a str>,
+    exp: u64,
+    iat: u64,
+    jti: &'a str,
+    act: Actor,
+}
+
+/// Give the calling admin a token to act as user `id` of an organization
+/// they administer. Super-admins are never impersonated.
+pub async fn start(
+    Path(user_id): Path<String>,
+    State((storage, _, config)): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(request): Json<ImpersonateRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let reason = request.reason.trim();
+    if reason.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let storage_guard = storage.read().await;
+    let user = storage_guard.get_user(&user_id).ok_or(StatusCode::NOT_FOUND)?;
+
+    let denied = |reason: &str, status: StatusCode| {
+        warn!(
+            service = "admin-service",
+            event = "impersonation_denied",
+            user_id = %user_id,
+            requested_by = %claims.sub,
+            reason = reason
+        );
+        status
+    };
+
+    // The stored scopes, not the token's: they may have shrunk since login
+    let admin = storage_guard.get_user(&claims.sub)
+        .filter(|admin| admin.is_admin_for_org(&user.org))
+        .ok_or_else(|| denied("not_admin_for_org", StatusCode::FORBIDDEN))?;
+    if user.admin.iter().any(|scope| scope == "all") {
+        return Err(denied("super_admin", StatusCode::FORBIDDEN));
+    }
+    if user.id == admin.id {
+        return Err(denied("self", StatusCode::BAD_REQUEST));
+    }
+    if !user.is_active() {
+        return Err(denied("user_inactive", StatusCode::CONFLICT));
+    }
+
+    let now = OffsetDateTime::now_utc();
+    let ttl = config.security.impersonation_ttl;
+    let impersonation = Impersonation {
+        id: uuid::Uuid::new_v4().to_string(),
+        admin_id: admin.id.clone(),
+        admin_email: admin.email.clone(),
+        user_id: user.id.clone(),
+        org: user.org.clone(),
+        reason: reason.to_string(),
+        started_at: now,
+        expires_at: now + time::Duration::seconds(ttl as i64),
+        ended_at: None,
+    };
+
+    let failed = |step: &str, e: anyhow::Error| {
+        warn!(
+            service = "admin-service",
+            event = "impersonation_failed",
+            user_id = %user_id,
+            step = step,
+            error = %e
+        );
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+
+    let token = jwt::sign(&config.jwt_secret, &impersonation_claims(&config, &storage_guard, user, &impersonation))
+        .map_err(|e| failed("sign", e))?;
+    storage_guard.put_impersonation(&impersonation).await
+        .map_err(|e| failed("persist", e))?;
+
+    let mut event = AuditEvent::new("impersonation_started".to_string(), Some(user.id.clone()), Some(user.org.clone()));
+    event.metadata.insert("impersonation_id".to_string(), json!(impersonation.id));
+    event.metadata.insert("admin_id".to_string(), json!(admin.id));
+    event.metadata.insert("admin_email".to_string(), json!(admin.email));
+    event.metadata.insert("reason".to_string(), json!(impersonation.reason));
+    event.metadata.insert("expires_at".to_string(), json!(impersonation.expires_at.unix_timestamp()));
+    audit::record(&storage_guard, &event).await?;
+
+    info!(
+        service = "admin-service",
+        event = "impersonation_started",
+        impersonation_id = %impersonation.id,
+        user_id = %user.id,
+        requested_by = %admin.id
+    );
+
+    Ok(Json(json!({
+        "impersonation": impersonation,
+        "access_token": token,
+        "token_type": "Bearer",
+        "expires_in": ttl,
+        "login_url": format!("{}/#impersonation={}", config.instance.auth_service_url.trim_end_matches('/'), token),
+    })))
+}
+
+/// Open impersonations in the organizations the caller administers.
+pub async fn list(
+    State((storage, _, _)): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<Impersonation>>, StatusCode> {
+    let storage_guard = storage.read().await;
+    let admin = storage_guard.get_user(&claims.sub).ok_or(StatusCode::FORBIDDEN)?;
+
+    let now = OffsetDateTime::now_utc();
+    let impersonations = storage_guard.list_impersonations().await
+        .map_err(|e| {
+            tracing::error!("Failed to list impersonations: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .into_iter()
+        .filter(|i| i.is_active(now) && admin.is_admin_for_org(&i.org))
+        .collect();
+
+    Ok(Json(impersonations))
+}
+
+/// End an impersonation before its token runs out; auth-service stops
+/// accepting the token at once.
+pub async fn end(
+    Path(id): Path<String>,
+    State((storage, _, _)): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Impersonation>, StatusCode> {
+    let storage_guard = storage.read().await;
+    let mut impersonation = storage_guard.get_impersonation(&id).await
+        .map_err(|e| {
+            tracing::error!("Failed to read impersonation: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let allowed = impersonation.admin_id == claims.sub
+        || storage_guard.get_user(&claims.sub).is_some_and(|admin| admin.is_admin_for_org(&impersonation.org));
+    if !allowed {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if !impersonation.is_active(OffsetDateTime::now_utc()) {
+        return Ok(Json(impersonation));
+    }
+
+    impersonation.ended_at = Some(OffsetDateTime::now_utc());
+    storage_guard.put_impersonation(&impersonation).await
+        .map_err(|e| {
+            tracing::error!("Failed to end impersonation: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    record_end(&storage_guard, &impersonation, "ended", Some(&claims.sub)).await?;
+
+    info!(
+        service = "admin-service",
+        event = "impersonation_ended",
+        impersonation_id = %impersonation.id,
+        user_id = %impersonation.user_id,
+        ended_by = %claims.sub
+    );
+
+    Ok(Json(impersonation))
+}
+
+/// Audit the end of an impersonation, by an admin or because it expired.
+pub async fn record_end(
+    storage: &AdminStorage,
+    impersonation: &Impersonation,
+    reason: &str,
+    ended_by: Option<&str>,
+) -> Result<(), StatusCode> {
+    let mut event = AuditEvent::new(
+        "impersonation_ended".to_string(),
+        Some(impersonation.user_id.clone()),
+        Some(impersonation.org.clone()),
+    );
+    event.metadata.insert("impersonation_id".to_string(), json!(impersonation.id));
+    event.metadata.insert("admin_id".to_string(), json!(impersonation.admin_id));
+    event.metadata.insert("reason".to_string(), json!(reason));
+    if let Some(ended_by) = ended_by {
+        event.metadata.insert("ended_by".to_string(), json!(ended_by));
+    }
+    audit::record(storage, &event).await
+}
+
+fn impersonation_claims<'a>(
+    config: &'a Config,
+    storage: &'a AdminStorage,
+    user: &'a User,
+    impersonation: &'a Impersonation,
+) -> ImpersonationClaims<'a> {
+    let registry = storage.get_claims();
+    let user_claims = user.claims.iter()
+        .filter(|(key, _)| {
+            registry.claims.get(*key)
+                .is_some_and(|definition| definition.default_allowed && definition.admin_only != Some(true))
+        })
+        .collect();
+
+    ImpersonationClaims {
+        sub: &user.id,
+        email: &user.email,
+        name: user.full_name(),
+        org: &user.org,
+        admin: Vec::new(),
+        user_claims,
+        iss: &config.instance.issuer,
+        aud: vec!["auth-service"],
+        exp: impersonation.expires_at.unix_timestamp() as u64,
+        iat: impersonation.started_at.unix_timestamp() as u64,
+        jti: &impersonation.id,
+        act: Actor {
+            sub: impersonation.admin_id.clone(),
+            email: impersonation.admin_email.clone(),
+        },
+    }
+}
@@ -1,5 +1,7 @@
 pub mod users;
+pub mod impersonation;
 pub mod clients;
+pub mod service_providers;
 pub mod organizations;
 pub mod system;
 pub mod audit;
@@ -13,7 +13,7 @@ use tracing::{info, warn};
 use crate::{
     config::Config,
     jwt::JwtVerifier,
-    models::{Claims, UserResponse, Organization, CreateOrganizationRequest, UpdateOrganizationRequest},
+    models::{Claims, UserResponse, Organization, CreateOrganizationRequest, UpdateOrganizationRequest, PasswordPolicy, SessionPolicy},
     storage::AdminStorage,
 };
 
@@ -82,6 +82,10 @@ pub async fn create(
         name: request.name,
         description: request.description,
         metadata: request.metadata.unwrap_or_default(),
+        require_verified_email: request.require_verified_email.unwrap_or_default(),
+        registration: request.registration.unwrap_or_default(),
+        password_policy: request.password_policy,
+        session_policy: request.session_policy,
         created_at: time::OffsetDateTime::now_utc(),
     };
 
@@ -92,6 +96,11 @@ pub async fn create(
         return Err(StatusCode::CONFLICT);
     }
 
+    if let Some(policy) = &organization.password_policy {
+        check_password_policy(&storage_guard, policy, &organization.id)?;
+    }
+    check_session_policy(organization.session_policy.as_ref(), &organization.id)?;
+
     match storage_guard.add_organization(organization.clone()).await {
         Ok(created_org) => {
             info!(
@@ -135,9 +144,18 @@ pub async fn update(
         name: request.name.unwrap_or(existing_org.name),
         description: request.description.unwrap_or(existing_org.description),
         metadata: request.metadata.unwrap_or(existing_org.metadata),
+        require_verified_email: request.require_verified_email.unwrap_or(existing_org.require_verified_email),
+        registration: request.registration.unwrap_or(existing_org.registration),
+        password_policy: request.password_policy.unwrap_or(existing_org.password_policy),
+        session_policy: request.session_policy.unwrap_or(existing_org.session_policy),
         created_at: existing_org.created_at,
     };
 
+    if let Some(policy) = &updated_org.password_policy {
+        check_password_policy(&storage_guard, policy, &org_id)?;
+    }
+    check_session_policy(updated_org.session_policy.as_ref(), &org_id)?;
+
     match storage_guard.update_organization(&org_id, updated_org.clone()).await {
         Ok(org) => {
             info!(
@@ -189,4 +207,31 @@ pub async fn delete(
             Err(StatusCode::INTERNAL_SERVER_ERROR)
         }
     }
-}
\ No newline at end of file
+}
+
+fn check_password_policy(storage: &AdminStorage, policy: &PasswordPolicy, org_id: &str) -> Result<(), StatusCode> {
+    storage.check_password_policy(policy).map_err(|e| {
+        warn!(
+            service = "admin-service",
+            event = "organization_password_policy_rejected",
+            org_id = %org_id,
+            reason = %e
+        );
+        StatusCode::BAD_REQUEST
+    })
+}
+
+fn check_session_policy(policy: Option<&SessionPolicy>, org_id: &str) -> Result<(), StatusCode> {
+    match policy.and_then(SessionPolicy::problem) {
+        Some(reason) => {
+            warn!(
+                service = "admin-service",
+                event = "organization_session_policy_rejected",
+                org_id = %org_id,
+                reason = reason
+            );
+            Err(StatusCode::BAD_REQUEST)
+        }
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,213 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Json,
+    Extension,
+};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::{
+    config::Config,
+    jwt::JwtVerifier,
+    models::{
+        Claims, CreateServiceProviderRequest, ServiceProvider, UpdateServiceProviderRequest,
+        USER_ATTRIBUTE_SOURCES,
+    },
+    storage::AdminStorage,
+};
+
+type AppState = (Arc<RwLock<AdminStorage>>, Arc<JwtVerifier>, Config);
+
+pub async fn list(
+    State((storage, _, _)): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Vec<ServiceProvider>>, StatusCode> {
+    let storage_guard = storage.read().await;
+
+    let service_providers: Vec<ServiceProvider> = storage_guard.get_all_service_providers()
+        .cloned()
+        .collect();
+
+    info!(
+        service = "admin-service",
+        event = "service_providers_listed",
+        requested_by = %claims.sub,
+        count = service_providers.len()
+    );
+
+    Ok(Json(service_providers))
+}
+
+pub async fn get(
+    Path(id): Path<String>,
+    State((storage, _, _)): State<AppState>,
+) -> Result<Json<ServiceProvider>, StatusCode> {
+    let storage_guard = storage.read().await;
+
+    storage_guard.get_service_provider(&id)
+        .cloned()
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+pub async fn create(
+    State((storage, _, _)): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(request): Json<CreateServiceProviderRequest>,
+) -> Result<Json<ServiceProvider>, StatusCode> {
+    let service_provider = ServiceProvider {
+        id: request.id,
+        entity_id: request.entity_id,
+        name: request.name,
+        acs_urls: request.acs_urls,
+        name_id_format: request.name_id_format.unwrap_or_default(),
+        attributes: request.attributes.unwrap_or_default(),
+        created_at: time::OffsetDateTime::now_utc(),
+    };
+
+    let mut storage_guard = storage.write().await;
+
+    if let Err(reason) = validate(&service_provider, &storage_guard) {
+        warn!(
+            service = "admin-service",
+            event = "service_provider_create_rejected",
+            service_provider_id = %service_provider.id,
+            reason = %reason
+        );
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    if storage_guard.get_service_provider(&service_provider.id).is_some() {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let created = storage_guard.put_service_provider(service_provider).await.map_err(|e| {
+        tracing::error!("Failed to create service provider: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    info!(
+        service = "admin-service",
+        event = "service_provider_created",
+        service_provider_id = %created.id,
+        entity_id = %created.entity_id,
+        created_by = %claims.sub
+    );
+
+    Ok(Json(created))
+}
+
+pub async fn update(
+    Path(id): Path<String>,
+    State((storage, _, _)): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(request): Json<UpdateServiceProviderRequest>,
+) -> Result<Json<ServiceProvider>, StatusCode> {
+    let mut storage_guard = storage.write().await;
+
+    let existing = storage_guard.get_service_provider(&id)
+        .ok_or(StatusCode::NOT_FOUND)?
+        .clone();
+
+    let updated = ServiceProvider {
+        entity_id: request.entity_id.unwrap_or(existing.entity_id),
+        name: request.name.unwrap_or(existing.name),
+        acs_urls: request.acs_urls.unwrap_or(existing.acs_urls),
+        name_id_format: request.name_id_format.unwrap_or(existing.name_id_format),
+        attributes: request.attributes.unwrap_or(existing.attributes),
+        ..existing
+    };
+
+    if let Err(reason) = validate(&updated, &storage_guard) {
+        warn!(
+            service = "admin-service",
+            event = "service_provider_update_rejected",
+            service_provider_id = %id,
+            reason = %reason
+        );
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let updated = storage_guard.put_service_provider(updated).await.map_err(|e| {
+        tracing::error!("Failed to update service provider: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    info!(
+        service = "admin-service",
+        event = "service_provider_updated",
+        service_provider_id = %id,
+        updated_by = %claims.sub
+    );
+
+    Ok(Json(updated))
+}
+
+pub async fn delete(
+    Path(id): Path<String>,
+    State((storage, _, _)): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<StatusCode, StatusCode> {
+    let mut storage_guard = storage.write().await;
+
+    if storage_guard.get_service_provider(&id).is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    storage_guard.delete_service_provider(&id).await.map_err(|e| {
+        tracing::error!("Failed to delete service provider: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    info!(
+        service = "admin-service",
+        event = "service_provider_deleted",
+        service_provider_id = %id,
+        deleted_by = %claims.sub
+    );
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The id names the provider's file; the entityID must be unique because
+/// auth-service finds providers by the Issuer of their requests. Assertions
+/// are only ever posted to the registered HTTPS endpoints.
+fn validate(service_provider: &ServiceProvider, storage: &AdminStorage) -> Result<(), String> {
+    let id = &service_provider.id;
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(format!("invalid id {:?}", id));
+    }
+    if service_provider.entity_id.is_empty() {
+        return Err("entity_id is empty".to_string());
+    }
+    if storage.get_all_service_providers().any(|sp| sp.entity_id == service_provider.entity_id && sp.id != *id) {
+        return Err(format!("entity_id {} is registered already", service_provider.entity_id));
+    }
+
+    if service_provider.acs_urls.is_empty() {
+        return Err("at least one ACS URL is required".to_string());
+    }
+    for url in &service_provider.acs_urls {
+        let uri = url.parse::<axum::http::Uri>().map_err(|e| format!("invalid ACS URL {}: {}", url, e))?;
+        let local = matches!(uri.host(), Some("localhost" | "127.0.0.1"));
+        match uri.scheme_str() {
+            Some("https") => {}
+            Some("http") if local => {}
+            _ => return Err(format!("ACS URL must use https: {}", url)),
+        }
+    }
+
+    let registry = storage.get_claims();
+    for attribute in &service_provider.attributes {
+        if attribute.name.is_empty() {
+            return Err("attribute without name".to_string());
+        }
+        let source = attribute.source.as_str();
+        if !USER_ATTRIBUTE_SOURCES.contains(&source) && !registry.claims.contains_key(source) {
+            return Err(format!("unknown attribute source {}", source));
+        }
+    }
+    Ok(())
+}
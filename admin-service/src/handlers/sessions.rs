@@ -1,114 +1,133 @@
-use axum::{extract::State, http::StatusCode, response::Json, Extension};
-use serde_json::json;
-use tracing::info;
-
-use crate::{config::Config, jwt::JwtVerifier, storage::AdminStorage, models::Claims};
-
-type AppState = (
-    std::sync::Arc<tokio::sync::RwLock<AdminStorage>>,
-    std::sync::Arc<JwtVerifier>,
-    Config,
-);
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::Json,
+    Extension,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::{
+    audit,
+    config::Config,
+    jwt::JwtVerifier,
+    models::{AuditEvent, Claims, Session},
+    storage::AdminStorage,
+};
+
+type AppState = (Arc<RwLock<AdminStorage>>, Arc<JwtVerifier>, Config);
+
+/// A session with the user it belongs to, as the sessions page lists it.
+#[derive(Serialize)]
+pub struct ActiveSession {
+    #[serde(flatten)]
+    session: Session,
+    user_email: String,
+    user_name: String,
+    organization: String,
+    is_current: bool,
+}
 
+/// Active sessions of users in the organizations the caller administers,
+/// most recently seen first.
 pub async fn list_active(
-    State((storage, _jwt_verifier, _config)): State<AppState>,
+    State((storage, _, _)): State<AppState>,
     Extension(claims): Extension<Claims>,
-) -> Result<Json<serde_json::Value>, StatusCode> {
-    info!(
-        service = "admin-service",
-        event = "sessions_list_request",
-        requested_by = %claims.sub
-    );
-
-    // Get current user data from storage for realistic session
+) -> Result<Json<Vec<ActiveSession>>, StatusCode> {
     let storage_guard = storage.read().await;
-    let current_user = match storage_guard.get_user(&claims.sub) {
-        Some(user) => user,
-        None => {
-            return Err(StatusCode::NOT_FOUND);
-        }
-    };
-
-    // Create realistic session data based on current admin user
-    let current_time = chrono::Utc::now();
-    let session_start = current_time - chrono::Duration::hours(2);
-    let session_expiry = current_time + chrono::Duration::hours(22);
-
-    let sessions = serde_json::json!([
-        {
-            "id": format!("sess-{}-{}", &claims.sub[..8], current_time.timestamp()),
-            "user_id": claims.sub,
-            "user_email": current_user.email,
-            "user_name": if current_user.full_name().is_empty() {
-                "Admin User".to_string()
-            } else {
-                current_user.full_name()
-            },
-            "organization": current_user.org,
-            "ip_address": "127.0.0.1",
-            "user_agent": "Mozilla/5.0 (Admin Interface)",
-            "created_at": session_start.to_rfc3339(),
-            "last_activity": current_time.to_rfc3339(),
-            "expires_at": session_expiry.to_rfc3339(),
-            "is_current": true
-        }
-    ]);
+    let admin = storage_guard.get_user(&claims.sub).ok_or(StatusCode::FORBIDDEN)?;
+
+    let mut sessions = storage_guard.list_sessions().await.map_err(|e| {
+        tracing::error!("Failed to list sessions: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    sessions.sort_by_key(|s| std::cmp::Reverse(s.last_seen_at));
+
+    let sessions: Vec<ActiveSession> = sessions.into_iter()
+        .filter_map(|session| {
+            let user = storage_guard.get_user(&session.user_id)
+                .filter(|user| admin.is_admin_for_org(&user.org))?;
+            Some(ActiveSession {
+                is_current: claims.sid.as_deref() == Some(session.id.as_str()),
+                user_email: user.email.clone(),
+                user_name: user.full_name(),
+                organization: user.org.clone(),
+                session,
+            })
+        })
+        .collect();
 
     info!(
         service = "admin-service",
-        event = "sessions_list_success",
-        count = 1,
-        user_id = %claims.sub
+        event = "sessions_listed",
+        requested_by = %claims.sub,
+        count = sessions.len()
     );
 
     Ok(Json(sessions))
 }
 
+/// End a session; auth-service refuses its tokens from then on.
 pub async fn terminate(
-    State((_storage, _jwt_verifier, _config)): State<AppState>,
+    State((storage, _, _)): State<AppState>,
     Extension(claims): Extension<Claims>,
-    axum::extract::Path(session_id): axum::extract::Path<String>,
-) -> Result<Json<serde_json::Value>, StatusCode> {
-    info!(
-        service = "admin-service",
-        event = "session_terminate_request",
-        session_id = %session_id,
-        requested_by = %claims.sub
-    );
-
-    // Validate session ID format
-    if !session_id.starts_with("sess-") {
-        return Err(StatusCode::BAD_REQUEST);
+    Path(session_id): Path<String>,
+) -> Result<Json<Value>, StatusCode> {
+    if claims.sid.as_deref() == Some(session_id.as_str()) {
+        return Ok(Json(json!({
+            "success": false,
+            "error": "Cannot terminate your own session"
+        })));
     }
 
-    // Check if trying to terminate own session
-    let is_current_session = session_id.contains(&claims.sub[..8]);
-
-    if is_current_session {
-        info!(
+    let storage_guard = storage.read().await;
+    let session = storage_guard.list_sessions().await
+        .map_err(|e| {
+            tracing::error!("Failed to list sessions: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .into_iter()
+        .find(|s| s.id == session_id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let user = storage_guard.get_user(&session.user_id).ok_or(StatusCode::NOT_FOUND)?;
+    let allowed = storage_guard.get_user(&claims.sub).is_some_and(|admin| admin.is_admin_for_org(&user.org));
+    if !allowed {
+        warn!(
             service = "admin-service",
-            event = "session_terminate_blocked",
+            event = "session_terminate_denied",
             session_id = %session_id,
-            reason = "cannot_terminate_own_session"
+            requested_by = %claims.sub
         );
+        return Err(StatusCode::FORBIDDEN);
+    }
 
-        return Ok(Json(json!({
-            "success": false,
-            "error": "Cannot terminate your own session"
-        })));
+    let removed = storage_guard.remove_session(&session.user_id, &session.id).await.map_err(|e| {
+        tracing::error!("Failed to terminate session: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if !removed {
+        return Err(StatusCode::NOT_FOUND);
     }
 
-    // In production: invalidate session in Redis/session store
-    let response = json!({
-        "success": true,
-        "message": "Session terminated"
-    });
+    let mut event = AuditEvent::new("session_terminated".to_string(), Some(user.id.clone()), Some(user.org.clone()));
+    event.metadata.insert("session_id".to_string(), json!(session.id));
+    event.metadata.insert("terminated_by".to_string(), json!(claims.sub));
+    audit::record(&storage_guard, &event).await?;
 
     info!(
         service = "admin-service",
-        event = "session_terminate_success",
-        session_id = %session_id
+        event = "session_terminated",
+        session_id = %session.id,
+        user_id = %user.id,
+        terminated_by = %claims.sub
     );
 
-    Ok(Json(response))
-}
\ No newline at end of file
+    Ok(Json(json!({
+        "success": true,
+        "message": "Session terminated"
+    })))
+}
@@ -13,7 +13,7 @@ use tracing::{info, warn};
 use crate::{
     config::Config,
     jwt::JwtVerifier,
-    models::{Claims, SystemStatus},
+    models::{Claims, SecurityPolicy, SystemStatus},
     storage::AdminStorage,
 };
 
@@ -100,4 +100,60 @@ pub async fn reload_auth(
             })))
         }
     }
-}
\ No newline at end of file
+}
+pub async fn security_policy(
+    State((storage, _, _)): State<AppState>,
+) -> Result<Json<SecurityPolicy>, StatusCode> {
+    let storage_guard = storage.read().await;
+    Ok(Json(storage_guard.get_security_policy().clone()))
+}
+
+/// Replace the instance security policy. auth-service picks it up on its
+/// next reload.
+pub async fn update_security_policy(
+    State((storage, jwt_verifier, _)): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(policy): Json<SecurityPolicy>,
+) -> Result<Json<SecurityPolicy>, StatusCode> {
+    if !jwt_verifier.has_write_permission(&claims) {
+        warn!(
+            service = "admin-service",
+            event = "security_policy_update",
+            requested_by = %claims.sub,
+            success = false,
+            reason = "insufficient_permissions"
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let mut storage_guard = storage.write().await;
+    storage_guard.check_password_policy(&policy.password).map_err(|e| {
+        warn!(
+            service = "admin-service",
+            event = "security_policy_update",
+            requested_by = %claims.sub,
+            success = false,
+            reason = %e
+        );
+        StatusCode::BAD_REQUEST
+    })?;
+    storage_guard.update_security_policy(policy).await.map_err(|e| {
+        warn!(
+            service = "admin-service",
+            event = "security_policy_update_failed",
+            requested_by = %claims.sub,
+            error = %e
+        );
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    info!(
+        service = "admin-service",
+        event = "security_policy_update",
+        requested_by = %claims.sub,
+        require_passkey_for_admins = storage_guard.get_security_policy().require_passkey_for_admins,
+        success = true
+    );
+
+    Ok(Json(storage_guard.get_security_policy().clone()))
+}
@@ -6,16 +6,17 @@ use axum::{
 };
 use serde::Deserialize;
 use serde_json::{json, Value};
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 use time::OffsetDateTime;
 use tokio::sync::RwLock;
 use tracing::{info, warn};
 use uuid::Uuid;
 
 use crate::{
+    audit,
     config::Config,
     jwt::JwtVerifier,
-    models::{Claims, CreateUserRequest, UpdateUserRequest, User, UserResponse, UserStatus, AddUserToGroupRequest},
+    models::{AuditEvent, Claims, CreateUserRequest, ImportUsersRequest, UpdateUserRequest, User, UserResponse, UserStatus, AddUserToGroupRequest},
     storage::AdminStorage,
 };
 
@@ -108,25 +109,23 @@ pub async fn create(
     State((storage, _, _)): State<AppState>,
     Extension(claims): Extension<Claims>,
     Json(request): Json<CreateUserRequest>,
-) -> Result<Json<UserResponse>, StatusCode> {
-    // Hash password
-    let password_hash = crate::password::hash_password(&request.password)
-        .map_err(|e| {
-            warn!(
-                service = "admin-service",
-                event = "password_hashing_failed",
-                error = %e
-            );
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+) -> Result<Json<UserResponse>, (StatusCode, Json<Value>)> {
+    let internal_error = |event: &str, e: anyhow::Error| {
+        warn!(
+            service = "admin-service",
+            event = event,
+            error = %format!("{:#}", e)
+        );
+        (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "message": "Internal server error" })))
+    };
 
     // Create user
     use time::OffsetDateTime;
     let now = OffsetDateTime::now_utc();
-    let user = User {
+    let mut user = User {
         id: format!("user-{}", std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()),
         email: request.email,
-        password_hash,
+        password_hash: String::new(),
         first_name: request.first_name,
         last_name: request.last_name,
         status: UserStatus::Active,
@@ -136,21 +135,40 @@ pub async fn create(
         org: request.org,
         claims: request.claims.unwrap_or_default(),
         mfa_secret: None,
+        mfa_recovery_codes: Vec::new(),
+        webauthn_credentials: Vec::new(),
+        federated_identities: Vec::new(),
+        password_changed_at: None,
         created_at: now,
         updated_at: now,
     };
 
-    // Save to storage
     let mut storage_guard = storage.write().await;
+
+    let violations = storage_guard
+        .password_violations(&user, &request.password)
+        .map_err(|e| internal_error("password_policy_failed", e))?;
+    if !violations.is_empty() {
+        info!(
+            service = "admin-service",
+            event = "user_creation_rejected",
+            email = %user.email,
+            created_by = %claims.sub,
+            reason = "password_policy"
+        );
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "message": violations.join(". "), "details": violations })),
+        ));
+    }
+
+    // Hash password
+    user.password_hash = crate::password::hash_password(&request.password)
+        .map_err(|e| internal_error("password_hashing_failed", e))?;
+
+    // Save to storage
     let created_user = storage_guard.create_user(user).await
-        .map_err(|e| {
-            warn!(
-                service = "admin-service",
-                event = "user_creation_failed",
-                error = %e
-            );
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+        .map_err(|e| internal_error("user_creation_failed", e))?;
 
     info!(
         service = "admin-service",
@@ -163,6 +181,101 @@ pub async fn create(
     Ok(Json(UserResponse::from(created_user)))
 }
 
+/// Create accounts from an existing user base, keeping their password
+/// hashes. Addresses that already have an account are skipped.
+pub async fn import(
+    State((storage, jwt_verifier, _)): State<AppState>,
+    Extension(claims): Extension<Claims>,
+    Json(request): Json<ImportUsersRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    if !jwt_verifier.has_write_permission(&claims) {
+        warn!(
+            service = "admin-service",
+            event = "user_import",
+            requested_by = %claims.sub,
+            success = false,
+            reason = "insufficient_permissions"
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let mut storage_guard = storage.write().await;
+    let (mut imported, mut skipped) = (0usize, 0usize);
+    let mut errors = Vec::new();
+
+    for (row, entry) in request.users.into_iter().enumerate() {
+        let email = entry.email.trim().to_string();
+        let error = if !email.contains('@') {
+            Some("Email address is invalid")
+        } else if storage_guard.get_organization(&entry.org).is_none() {
+            Some("Unknown organization")
+        } else if !crate::password::is_supported_hash(&entry.password_hash) {
+            Some("Unsupported password hash format")
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            errors.push(json!({ "row": row + 1, "email": email, "error": error }));
+            continue;
+        }
+        if storage_guard.get_user_by_email(&email).is_some() {
+            skipped += 1;
+            continue;
+        }
+
+        let now = OffsetDateTime::now_utc();
+        let user = User {
+            id: format!("user-{}", Uuid::new_v4().simple()),
+            email: email.clone(),
+            password_hash: entry.password_hash,
+            first_name: entry.first_name,
+            last_name: entry.last_name,
+            status: UserStatus::Active,
+            verified: entry.verified,
+            authenticated: None,
+            admin: Vec::new(),
+            org: entry.org,
+            claims: entry.claims.unwrap_or_default(),
+            mfa_secret: None,
+            mfa_recovery_codes: Vec::new(),
+            webauthn_credentials: Vec::new(),
+            federated_identities: Vec::new(),
+            password_changed_at: None,
+            created_at: now,
+            updated_at: now,
+        };
+        match storage_guard.create_user(user).await {
+            Ok(_) => imported += 1,
+            Err(e) => {
+                warn!(
+                    service = "admin-service",
+                    event = "user_import_failed",
+                    email = %email,
+                    error = %format!("{:#}", e)
+                );
+                errors.push(json!({ "row": row + 1, "email": email, "error": "Could not be saved" }));
+            }
+        }
+    }
+
+    info!(
+        service = "admin-service",
+        event = "user_import",
+        requested_by = %claims.sub,
+        imported = imported,
+        skipped = skipped,
+        errors = errors.len(),
+        success = true
+    );
+
+    Ok(Json(json!({
+        "imported_count": imported,
+        "skipped_count": skipped,
+        "error_count": errors.len(),
+        "errors": errors
+    })))
+}
+
 pub async fn update(
     Path(user_id): Path<String>,
     State((storage, _, _)): State<AppState>,
@@ -171,35 +284,31 @@ pub async fn update(
 ) -> Result<Json<UserResponse>, StatusCode> {
     let mut storage_guard = storage.write().await;
 
-    // Get existing user
-    let mut user = storage_guard.get_user(&user_id)
-        .ok_or(StatusCode::NOT_FOUND)?
-        .clone();
-
-    // Update fields
-    if let Some(first_name) = request.first_name {
-        user.first_name = first_name;
-    }
-    if let Some(last_name) = request.last_name {
-        user.last_name = last_name;
-    }
-    if let Some(status) = request.status {
-        user.status = status;
-    }
-    if let Some(admin) = request.admin {
-        user.admin = admin;
-    }
-    if let Some(org) = request.org {
-        user.org = org;
-    }
-    if let Some(claims) = request.claims {
-        user.claims = claims;
-    }
-
-    user.updated_at = OffsetDateTime::now_utc();
+    // Check if user exists
+    storage_guard.get_user(&user_id)
+        .ok_or(StatusCode::NOT_FOUND)?;
 
-    // Save changes
-    let updated_user = storage_guard.update_user(&user_id, user).await
+    // Save changes on top of the current file, keeping fields auth-service owns
+    let updated_user = storage_guard.modify_user(&user_id, |user| {
+        if let Some(first_name) = request.first_name {
+            user.first_name = first_name;
+        }
+        if let Some(last_name) = request.last_name {
+            user.last_name = last_name;
+        }
+        if let Some(status) = request.status {
+            user.status = status;
+        }
+        if let Some(admin) = request.admin {
+            user.admin = admin;
+        }
+        if let Some(org) = request.org {
+            user.org = org;
+        }
+        if let Some(claims) = request.claims {
+            user.claims = claims;
+        }
+    }).await
         .map_err(|e| {
             warn!(
                 service = "admin-service",
@@ -253,26 +362,214 @@ pub async fn delete(
     Ok(StatusCode::NO_CONTENT)
 }
 
+/// Send the user a single-use link to set a new password. The current
+/// password stays valid until the link is used in the login UI.
 pub async fn reset_password(
     Path(user_id): Path<String>,
-    State(_): State<AppState>,
+    State((storage, jwt_verifier, config)): State<AppState>,
     Extension(claims): Extension<Claims>,
 ) -> Result<Json<Value>, StatusCode> {
-    // TODO: Implement password reset functionality
+    let storage_guard = storage.read().await;
+
+    let user = storage_guard.get_user(&user_id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    if !jwt_verifier.has_org_admin(&claims, &user.org) {
+        warn!(
+            service = "admin-service",
+            event = "password_reset_requested",
+            user_id = %user_id,
+            requested_by = %claims.sub,
+            success = false,
+            reason = "not_admin_for_org"
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let ttl = config.security.password_reset_ttl;
+    let (token, expires_at) = storage_guard.create_password_reset(&user_id, ttl, &claims.sub).await
+        .map_err(|e| {
+            warn!(
+                service = "admin-service",
+                event = "password_reset_token_failed",
+                user_id = %user_id,
+                error = %e
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let params = HashMap::from([
+        ("name".to_string(), user.first_name.clone()),
+        (
+            "reset_url".to_string(),
+            format!("{}/#reset_token={}", config.instance.auth_service_url.trim_end_matches('/'), token),
+        ),
+        ("expires_minutes".to_string(), (ttl / 60).to_string()),
+    ]);
+    storage_guard.enqueue_mail(&user.email, "password_reset", params).await
+        .map_err(|e| {
+            warn!(
+                service = "admin-service",
+                event = "password_reset_mail_failed",
+                user_id = %user_id,
+                error = %e
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
     info!(
         service = "admin-service",
         event = "password_reset_requested",
         user_id = %user_id,
-        requested_by = %claims.sub
+        requested_by = %claims.sub,
+        success = true
     );
 
     Ok(Json(json!({
         "success": true,
         "message": "Password reset email sent",
-        "reset_token": "placeholder-reset-token"
+        "expires_at": expires_at.format(&time::format_description::well_known::Iso8601::DEFAULT)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
     })))
 }
 
+/// Remove the user's second factors (TOTP secret, recovery codes and passkeys), e.g. after a lost
+/// device. The user enrolls again on next login if MFA is mandatory, or from their account otherwise.
+pub async fn reset_mfa(
+    Path(user_id): Path<String>,
+    State((storage, jwt_verifier, _)): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<UserResponse>, StatusCode> {
+    let mut storage_guard = storage.write().await;
+
+    let user = storage_guard.get_user(&user_id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    if !jwt_verifier.has_org_admin(&claims, &user.org) {
+        warn!(
+            service = "admin-service",
+            event = "user_mfa_reset",
+            user_id = %user_id,
+            requested_by = %claims.sub,
+            success = false,
+            reason = "not_admin_for_org"
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let updated_user = storage_guard.modify_user(&user_id, |user| {
+        user.mfa_secret = None;
+        user.mfa_recovery_codes.clear();
+        user.webauthn_credentials.clear();
+    }).await
+        .map_err(|e| {
+            warn!(
+                service = "admin-service",
+                event = "user_mfa_reset_failed",
+                user_id = %user_id,
+                error = %e
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let mut event = AuditEvent::new("mfa_reset".to_string(), Some(updated_user.id.clone()), Some(updated_user.org.clone()));
+    event.metadata.insert("reset_by".to_string(), json!(claims.sub));
+    audit::record(&storage_guard, &event).await?;
+
+    info!(
+        service = "admin-service",
+        event = "user_mfa_reset",
+        user_id = %user_id,
+        requested_by = %claims.sub,
+        success = true
+    );
+
+    Ok(Json(UserResponse::from(updated_user)))
+}
+
+/// Failed-login state of a user: whether auth-service currently refuses
+/// their logins, and after how many wrong passwords.
+pub async fn lockout(
+    Path(user_id): Path<String>,
+    State((storage, jwt_verifier, _)): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<Json<Value>, StatusCode> {
+    let storage_guard = storage.read().await;
+
+    let user = storage_guard.get_user(&user_id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if !jwt_verifier.has_org_admin(&claims, &user.org) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let record = storage_guard.get_lockout(&user_id).await.map_err(|e| {
+        warn!(
+            service = "admin-service",
+            event = "lockout_read_failed",
+            user_id = %user_id,
+            error = %format!("{:#}", e)
+        );
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let now = OffsetDateTime::now_utc();
+    let locked_until = record.as_ref().and_then(|r| r.locked_until).filter(|until| *until > now);
+    Ok(Json(json!({
+        "locked": locked_until.is_some(),
+        "locked_until": locked_until.map(|until| until.unix_timestamp()),
+        "failures": record.map(|r| r.failures).unwrap_or(0)
+    })))
+}
+
+/// Lift a lockout and forget the user's failed logins.
+pub async fn unlock(
+    Path(user_id): Path<String>,
+    State((storage, jwt_verifier, _)): State<AppState>,
+    Extension(claims): Extension<Claims>,
+) -> Result<StatusCode, StatusCode> {
+    let storage_guard = storage.read().await;
+
+    let user = storage_guard.get_user(&user_id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if !jwt_verifier.has_org_admin(&claims, &user.org) {
+        warn!(
+            service = "admin-service",
+            event = "account_unlocked",
+            user_id = %user_id,
+            requested_by = %claims.sub,
+            success = false,
+            reason = "not_admin_for_org"
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let was_locked = storage_guard.unlock_user(&user_id).await.map_err(|e| {
+        warn!(
+            service = "admin-service",
+            event = "account_unlock_failed",
+            user_id = %user_id,
+            error = %format!("{:#}", e)
+        );
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let mut event = AuditEvent::new("account_unlocked".to_string(), Some(user.id.clone()), Some(user.org.clone()));
+    event.metadata.insert("unlocked_by".to_string(), json!(claims.sub));
+    event.metadata.insert("had_failures".to_string(), json!(was_locked));
+    audit::record(&storage_guard, &event).await?;
+
+    info!(
+        service = "admin-service",
+        event = "account_unlocked",
+        user_id = %user_id,
+        requested_by = %claims.sub,
+        had_failures = was_locked,
+        success = true
+    );
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
 pub async fn add_group(
     Path(user_id): Path<String>,
     State(_): State<AppState>,
@@ -1,5 +1,6 @@
 use anyhow::{Context, Result};
-use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::Serialize;
 
 use crate::models::Claims;
 
@@ -27,6 +28,11 @@ impl JwtVerifier {
                 anyhow::anyhow!("Failed to decode JWT: {}", e)
             })?;
 
+        // auth-service's refresh tokens are signed alike but never bearer tokens
+        if token_data.claims.typ.as_deref() == Some("refresh") {
+            anyhow::bail!("Refresh token used as bearer token");
+        }
+
         Ok(token_data.claims)
     }
 
@@ -35,7 +41,19 @@ impl JwtVerifier {
         !claims.admin.is_empty()
     }
 
+    /// Whether the token's admin scopes cover users of `org`.
+    pub fn has_org_admin(&self, claims: &Claims, org: &str) -> bool {
+        claims.admin.iter().any(|scope| scope == "all" || scope == org)
+    }
+
     pub fn has_write_permission(&self, claims: &Claims) -> bool {
         claims.admin.contains(&"all".to_string())
     }
-}
\ No newline at end of file
+}
+
+/// Sign `claims` with the secret shared with auth-service, so that it
+/// accepts the token as one of its own.
+pub fn sign<T: Serialize>(secret: &str, claims: &T) -> Result<String> {
+    encode(&Header::new(Algorithm::HS256), claims, &EncodingKey::from_secret(secret.as_ref()))
+        .context("Failed to encode JWT")
+}
@@ -26,6 +26,8 @@ mod logging;
 mod jwt;
 mod password;
 mod tls;
+mod audit;
+mod audit_query;
 
 use config::Config;
 use storage::AdminStorage;
@@ -76,7 +78,7 @@ async fn main() -> Result<()> {
 
     // Load data storage with write access
     let storage = Arc::new(RwLock::new(
-        AdminStorage::load(&args.data_dir, &args.auth_pid_file).await
+        AdminStorage::load(&args.data_dir, &args.auth_pid_file, &config.audit).await
             .context("Failed to load admin storage")?
     ));
 
@@ -92,6 +94,9 @@ async fn main() -> Result<()> {
     // Setup graceful shutdown
     setup_shutdown_handler();
 
+    // Put the end of impersonations that ran out on record
+    setup_impersonation_sweep(storage.clone());
+
     // Create application router
     let app = create_app(storage, config).await?;
 
@@ -162,8 +167,16 @@ async fn create_app(
 
         // Users API
         .route("/api/users", get(handlers::users::list).post(handlers::users::create))
+        .route("/api/users/import", post(handlers::users::import))
         .route("/api/users/:id", get(handlers::users::get).patch(handlers::users::update).delete(handlers::users::delete))
         .route("/api/users/:id/reset-password", post(handlers::users::reset_password))
+        .route("/api/users/:id/reset-mfa", post(handlers::users::reset_mfa))
+        .route("/api/users/:id/lockout", get(handlers::users::lockout).delete(handlers::users::unlock))
+        .route("/api/users/:id/impersonate", post(handlers::impersonation::start))
+
+        // Impersonations API
+        .route("/api/impersonations", get(handlers::impersonation::list))
+        .route("/api/impersonations/:id", delete(handlers::impersonation::end))
 
         // Organizations API
         .route("/api/organizations", get(handlers::organizations::list).post(handlers::organizations::create))
@@ -176,10 +189,15 @@ async fn create_app(
         .route("/api/clients/:id", get(handlers::clients::get).patch(handlers::clients::update).delete(handlers::clients::delete))
         .route("/api/clients/:id/rotate-secret", post(handlers::clients::rotate_secret))
 
+        // SAML service providers
+        .route("/api/service-providers", get(handlers::service_providers::list).post(handlers::service_providers::create))
+        .route("/api/service-providers/:id", get(handlers::service_providers::get).patch(handlers::service_providers::update).delete(handlers::service_providers::delete))
+
         // System API
         .route("/api/system/status", get(handlers::system::status))
         .route("/api/system/stats", get(handlers::system::stats))
         .route("/api/system/reload-auth", post(handlers::system::reload_auth))
+        .route("/api/system/security-policy", get(handlers::system::security_policy).put(handlers::system::update_security_policy))
 
         // Audit API
         .route("/api/audit", get(handlers::audit::query))
@@ -295,6 +313,29 @@ async fn spa_fallback() -> Html<String> {
     }
 }
 
+fn setup_impersonation_sweep(storage: Arc<RwLock<AdminStorage>>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let storage_guard = storage.read().await;
+            match storage_guard.close_expired_impersonations(time::OffsetDateTime::now_utc()).await {
+                Ok(closed) => {
+                    // A failed write is logged; the sweep goes on with the rest
+                    for impersonation in &closed {
+                        let _ = handlers::impersonation::record_end(&storage_guard, impersonation, "expired", None).await;
+                    }
+                }
+                Err(e) => tracing::warn!(
+                    service = "admin-service",
+                    event = "impersonation_sweep_failed",
+                    error = %e
+                ),
+            }
+        }
+    });
+}
+
 fn setup_shutdown_handler() {
     tokio::spawn(async move {
         use tokio::signal::unix::{signal, SignalKind};
@@ -66,6 +66,18 @@ pub async fn require_admin(
             StatusCode::UNAUTHORIZED
         })?;
 
+    // Acting as someone else never extends to administration
+    if let Some(act) = &claims.act {
+        warn!(
+            service = "admin-service",
+            event = "auth_failed",
+            reason = "impersonation_token",
+            user_id = %claims.sub,
+            actor = %act.sub
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+
     // Check admin role
     if !jwt_verifier.has_admin_role(&claims) {
         warn!(
@@ -78,6 +90,26 @@ pub async fn require_admin(
         return Err(StatusCode::FORBIDDEN);
     }
 
+    // Admin work may demand a second factor even where the login did not
+    if config.security.require_admin_mfa && !claims.amr.iter().any(|m| m == "mfa") {
+        warn!(
+            service = "admin-service",
+            event = "auth_failed",
+            reason = "mfa_required",
+            user_id = %claims.sub,
+            acr = ?claims.acr
+        );
+
+        if is_browser_request(&req) && !req.uri().path().starts_with("/api/") {
+            let current_url = format!("{}{}", config.instance.base_url, req.uri());
+            let step_up_url = format!("{}/?step_up=1&redirect={}",
+                config.instance.auth_service_url,
+                urlencoding::encode(&current_url));
+            return Ok(Redirect::temporary(&step_up_url).into_response());
+        }
+        return Err(StatusCode::FORBIDDEN);
+    }
+
     // Add claims to request extensions for handlers to use
     req.extensions_mut().insert(claims);
 
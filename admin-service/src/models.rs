@@ -2,7 +2,9 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use time::OffsetDateTime;
-use uuid::Uuid;
+
+pub use audit_log::AuditEvent;
+pub use password_policy::PasswordPolicy;
 
 // Include shared models from auth-service
 
@@ -20,6 +22,16 @@ pub struct User {
     pub org: String, // Primary organization
     pub claims: HashMap<String, serde_json::Value>, // Registry-validated claims
     pub mfa_secret: Option<String>,
+    #[serde(default)]
+    pub mfa_recovery_codes: Vec<String>, // Argon2 hashes of unused recovery codes
+    #[serde(default)]
+    pub webauthn_credentials: Vec<WebAuthnCredential>,
+    /// Accounts at upstream identity providers that log in as this user
+    #[serde(default)]
+    pub federated_identities: Vec<FederatedIdentity>,
+    /// Tokens issued before this instant are no longer accepted
+    #[serde(default, with = "time::serde::iso8601::option")]
+    pub password_changed_at: Option<OffsetDateTime>,
     #[serde(with = "time::serde::iso8601")]
     pub created_at: OffsetDateTime,
     #[serde(with = "time::serde::iso8601")]
@@ -34,6 +46,116 @@ pub enum UserStatus {
     Suspended,
 }
 
+/// Link between a user and their `sub` at an upstream provider.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FederatedIdentity {
+    pub provider: String,
+    pub subject: String,
+    #[serde(with = "time::serde::iso8601")]
+    pub linked_at: OffsetDateTime,
+}
+
+/// A registered WebAuthn credential (passkey or security key).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebAuthnCredential {
+    pub credential_id: String, // base64url
+    pub public_key: String, // base64url, uncompressed P-256 point
+    pub algorithm: i64, // COSE algorithm, -7 = ES256
+    pub sign_count: u32,
+    #[serde(default)]
+    pub transports: Vec<String>,
+    pub nickname: String,
+    #[serde(with = "time::serde::iso8601")]
+    pub created_at: OffsetDateTime,
+    #[serde(default, with = "time::serde::iso8601::option")]
+    pub last_used_at: Option<OffsetDateTime>,
+}
+
+/// Instance-wide security policy, edited in admin-service
+/// (`security_policy.json`).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SecurityPolicy {
+    /// Users holding any admin scope must use a passkey as second factor
+    #[serde(default)]
+    pub require_passkey_for_admins: bool,
+    /// Rules for new passwords; organizations may override them
+    #[serde(default)]
+    pub password: PasswordPolicy,
+}
+
+/// Session limits, enforced by auth-service. In org and client overrides
+/// each field left out keeps the value of the level below.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionPolicy {
+    /// Seconds without use of the session's tokens
+    pub idle_timeout: Option<u64>,
+    /// Seconds after the login, however busy
+    pub max_lifetime: Option<u64>,
+    pub max_concurrent: Option<usize>,
+    pub on_limit: Option<SessionLimitAction>,
+}
+
+impl SessionPolicy {
+    /// Why a setting of the policy is unusable, if one is.
+    pub fn problem(&self) -> Option<&'static str> {
+        if self.idle_timeout == Some(0) || self.max_lifetime == Some(0) {
+            return Some("session timeouts must be at least one second");
+        }
+        if self.max_concurrent == Some(0) {
+            return Some("max_concurrent must allow at least one session");
+        }
+        None
+    }
+}
+
+/// What a login beyond `max_concurrent` does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionLimitAction {
+    #[default]
+    EvictOldest,
+    Refuse,
+}
+
+/// A pending password reset in `password_resets/`, redeemed by auth-service.
+/// Only the SHA-256 of the token is stored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PasswordResetToken {
+    pub token_hash: String,
+    pub user_id: String,
+    #[serde(with = "time::serde::iso8601")]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::iso8601")]
+    pub expires_at: OffsetDateTime,
+    pub created_by: Option<String>,
+}
+
+/// Failed logins of an account as auth-service records them in
+/// `lockouts/{user_id}.json`. Deleting the file unlocks the account.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountLockout {
+    pub user_id: String,
+    pub failures: u32,
+    #[serde(with = "time::serde::iso8601")]
+    pub last_failure_at: OffsetDateTime,
+    #[serde(default, with = "time::serde::iso8601::option")]
+    pub locked_until: Option<OffsetDateTime>,
+}
+
+/// A message for auth-service's mail outbox (`mail/outbox/`). auth-service
+/// renders the template and delivers it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboundMail {
+    pub id: String,
+    pub to: String,
+    pub template: String,
+    pub locale: Option<String>,
+    pub params: HashMap<String, String>,
+    #[serde(with = "time::serde::iso8601")]
+    pub created_at: OffsetDateTime,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Group {
     pub id: String,
@@ -50,10 +172,37 @@ pub struct Organization {
     pub name: String,
     pub description: String,
     pub metadata: HashMap<String, serde_json::Value>,
+    /// Members may not log in before confirming their email address
+    #[serde(default)]
+    pub require_verified_email: bool,
+    #[serde(default)]
+    pub registration: RegistrationPolicy,
+    /// Replaces the instance-wide password policy for members
+    #[serde(default)]
+    pub password_policy: Option<PasswordPolicy>,
+    /// Overrides auth-service's `[security.sessions]` for members
+    #[serde(default)]
+    pub session_policy: Option<SessionPolicy>,
     #[serde(with = "time::serde::iso8601")]
     pub created_at: OffsetDateTime,
 }
 
+/// Who may create an account in an organization through auth-service's
+/// `/api/auth/register`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RegistrationPolicy {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Empty accepts any domain
+    #[serde(default)]
+    pub allowed_email_domains: Vec<String>,
+    #[serde(default)]
+    pub invite_code: Option<String>,
+    /// Claims every new account gets; registrants cannot set them
+    #[serde(default)]
+    pub default_claims: HashMap<String, serde_json::Value>,
+}
+
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Client {
@@ -65,10 +214,26 @@ pub struct Client {
     pub allowed_scopes: Vec<String>,
     pub require_pkce: bool,
     pub grant_types: Vec<String>,
+    #[serde(default)]
+    pub access_token_format: AccessTokenFormat,
+    #[serde(default)]
+    pub subject_type: SubjectType,
+    pub sector_identifier_uri: Option<String>,
+    /// Weakest login (`acr`) the client accepts tokens for
+    pub min_acr: Option<String>,
+    /// Seconds after which the client needs a fresh login
+    pub max_auth_age: Option<u64>,
+    /// Overrides the organization's session limits for sessions in use
+    /// by this client
+    #[serde(default)]
+    pub session_policy: Option<SessionPolicy>,
     #[serde(with = "time::serde::iso8601")]
     pub created_at: OffsetDateTime,
 }
 
+/// Login strengths auth-service distinguishes, weakest first
+pub const ACR_VALUES: &[&str] = &["pwd", "mfa", "phr"];
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum ClientType {
@@ -76,19 +241,71 @@ pub enum ClientType {
     Confidential,
 }
 
+/// How access tokens for a client are represented. `Jwt` tokens are
+/// self-contained; `Opaque` tokens are random references resolved through
+/// introspection and revocable immediately.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessTokenFormat {
+    #[default]
+    Jwt,
+    Opaque,
+}
+
+/// Which `sub` a client sees. `Public` is the global user id; `Pairwise`
+/// derives a per-sector identifier so clients cannot correlate users.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SubjectType {
+    #[default]
+    Public,
+    Pairwise,
+}
+
+/// A SAML 2.0 service provider that receives signed assertions.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct AuditEvent {
+pub struct ServiceProvider {
+    /// Short name, used as file name and in admin URLs
     pub id: String,
-    pub user_id: Option<String>,
-    pub org: Option<String>,
-    pub event_type: String,
-    pub ip_address: Option<String>,
-    pub user_agent: Option<String>,
-    pub metadata: HashMap<String, serde_json::Value>,
+    /// The provider's entityID, the Issuer of its AuthnRequests
+    pub entity_id: String,
+    pub name: String,
+    /// AssertionConsumerService URLs (HTTP-POST binding); requests that
+    /// name none are answered at the first
+    pub acs_urls: Vec<String>,
+    #[serde(default)]
+    pub name_id_format: NameIdFormat,
+    /// Attributes released in assertions
+    #[serde(default)]
+    pub attributes: Vec<AttributeRelease>,
     #[serde(with = "time::serde::iso8601")]
     pub created_at: OffsetDateTime,
 }
 
+/// What identifies the user in the assertion's `NameID`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NameIdFormat {
+    /// The user id, stable across address changes
+    #[default]
+    Persistent,
+    Email,
+}
+
+/// One attribute of an assertion, filled from a user field (`id`, `email`,
+/// `given_name`, `family_name`, `name`, `org`) or a registry claim.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttributeRelease {
+    /// SAML attribute name, e.g. `urn:oid:0.9.2342.19200300.100.1.3`
+    pub name: String,
+    #[serde(default)]
+    pub friendly_name: Option<String>,
+    pub source: String,
+}
+
+/// Sources of `AttributeRelease` that are user fields rather than claims
+pub const USER_ATTRIBUTE_SOURCES: &[&str] = &["id", "email", "given_name", "family_name", "name", "org"];
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClaimsRegistry {
     #[serde(flatten)]
@@ -123,6 +340,10 @@ impl User {
             org,
             claims: HashMap::new(),
             mfa_secret: None,
+            mfa_recovery_codes: Vec::new(),
+            webauthn_credentials: Vec::new(),
+            federated_identities: Vec::new(),
+            password_changed_at: None,
             created_at: now,
             updated_at: now,
         }
@@ -164,6 +385,25 @@ pub struct CreateUserRequest {
     pub claims: Option<HashMap<String, serde_json::Value>>,
 }
 
+/// Accounts migrated from another system with their existing password
+/// hashes, which auth-service upgrades at the first login.
+#[derive(Debug, Deserialize)]
+pub struct ImportUsersRequest {
+    pub users: Vec<ImportUser>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportUser {
+    pub email: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub org: String,
+    pub password_hash: String,
+    #[serde(default)]
+    pub verified: bool,
+    pub claims: Option<HashMap<String, serde_json::Value>>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct UpdateUserRequest {
     pub first_name: Option<String>,
@@ -180,6 +420,10 @@ pub struct CreateOrganizationRequest {
     pub name: String,
     pub description: String,
     pub metadata: Option<HashMap<String, serde_json::Value>>,
+    pub require_verified_email: Option<bool>,
+    pub registration: Option<RegistrationPolicy>,
+    pub password_policy: Option<PasswordPolicy>,
+    pub session_policy: Option<SessionPolicy>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -187,6 +431,22 @@ pub struct UpdateOrganizationRequest {
     pub name: Option<String>,
     pub description: Option<String>,
     pub metadata: Option<HashMap<String, serde_json::Value>>,
+    pub require_verified_email: Option<bool>,
+    pub registration: Option<RegistrationPolicy>,
+    /// Absent keeps the override, `null` removes it
+    #[serde(default, deserialize_with = "present")]
+    pub password_policy: Option<Option<PasswordPolicy>>,
+    #[serde(default, deserialize_with = "present")]
+    pub session_policy: Option<Option<SessionPolicy>>,
+}
+
+/// Tells an explicit `null` apart from a missing field.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
 }
 
 #[derive(Debug, Deserialize)]
@@ -219,6 +479,12 @@ pub struct CreateClientRequest {
     pub allowed_scopes: Vec<String>,
     pub require_pkce: Option<bool>,
     pub grant_types: Option<Vec<String>>,
+    pub access_token_format: Option<AccessTokenFormat>,
+    pub subject_type: Option<SubjectType>,
+    pub sector_identifier_uri: Option<String>,
+    pub min_acr: Option<String>,
+    pub max_auth_age: Option<u64>,
+    pub session_policy: Option<SessionPolicy>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -228,6 +494,35 @@ pub struct UpdateClientRequest {
     pub allowed_scopes: Option<Vec<String>>,
     pub require_pkce: Option<bool>,
     pub grant_types: Option<Vec<String>>,
+    pub access_token_format: Option<AccessTokenFormat>,
+    pub subject_type: Option<SubjectType>,
+    pub sector_identifier_uri: Option<String>,
+    /// Absent keeps the requirement, `null` removes it
+    #[serde(default, deserialize_with = "present")]
+    pub min_acr: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub max_auth_age: Option<Option<u64>>,
+    #[serde(default, deserialize_with = "present")]
+    pub session_policy: Option<Option<SessionPolicy>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateServiceProviderRequest {
+    pub id: String,
+    pub entity_id: String,
+    pub name: String,
+    pub acs_urls: Vec<String>,
+    pub name_id_format: Option<NameIdFormat>,
+    pub attributes: Option<Vec<AttributeRelease>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateServiceProviderRequest {
+    pub entity_id: Option<String>,
+    pub name: Option<String>,
+    pub acs_urls: Option<Vec<String>>,
+    pub name_id_format: Option<NameIdFormat>,
+    pub attributes: Option<Vec<AttributeRelease>>,
 }
 
 
@@ -291,9 +586,13 @@ pub struct UserResponse {
     pub org: String,
     pub admin: Vec<String>,
     pub claims: HashMap<String, serde_json::Value>,
+    pub mfa_enabled: bool,
+    pub mfa_recovery_codes_remaining: usize,
+    pub passkeys: Vec<PasskeySummary>,
+    pub federated_identities: Vec<FederatedIdentity>,
     pub created_at: OffsetDateTime,
     pub updated_at: OffsetDateTime,
-    // Password hash is never included in responses
+    // Password hash and MFA secret are never included in responses
 }
 
 impl From<User> for UserResponse {
@@ -308,13 +607,39 @@ impl From<User> for UserResponse {
             org: user.org,
             admin: user.admin,
             claims: user.claims,
+            mfa_enabled: user.mfa_secret.is_some(),
+            mfa_recovery_codes_remaining: user.mfa_recovery_codes.len(),
+            passkeys: user.webauthn_credentials.iter().map(PasskeySummary::from).collect(),
+            federated_identities: user.federated_identities,
             created_at: user.created_at,
             updated_at: user.updated_at,
         }
     }
 }
 
-// JWT Claims for verification (no issuing in admin service)
+/// A user's passkey as shown to admins, without key material.
+#[derive(Debug, Serialize)]
+pub struct PasskeySummary {
+    pub nickname: String,
+    pub transports: Vec<String>,
+    #[serde(with = "time::serde::iso8601")]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::iso8601::option")]
+    pub last_used_at: Option<OffsetDateTime>,
+}
+
+impl From<&WebAuthnCredential> for PasskeySummary {
+    fn from(credential: &WebAuthnCredential) -> Self {
+        Self {
+            nickname: credential.nickname.clone(),
+            transports: credential.transports.clone(),
+            created_at: credential.created_at,
+            last_used_at: credential.last_used_at,
+        }
+    }
+}
+
+// JWT Claims for verification; admin-service only issues impersonation tokens
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String, // user_id
@@ -327,4 +652,91 @@ pub struct Claims {
     pub exp: u64, // expiration
     pub iat: u64, // issued at
     pub jti: String, // JWT ID
+    #[serde(default)]
+    pub auth_time: Option<u64>, // last interactive authentication
+    #[serde(default)]
+    pub acr: Option<String>, // authentication context class
+    #[serde(default)]
+    pub amr: Vec<String>, // authentication methods
+    #[serde(default)]
+    pub act: Option<Actor>, // admin acting as the user (impersonation)
+    #[serde(default)]
+    pub sid: Option<String>, // login session
+    #[serde(default)]
+    pub typ: Option<String>, // "access" or "refresh"; absent in impersonation tokens
+}
+
+/// The admin behind an impersonation token, as the `act` claim (RFC 8693)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Actor {
+    pub sub: String,
+    pub email: String,
+}
+
+/// An admin acting as another user, in `impersonations/{id}.json`. The id is
+/// the `jti` of the token; auth-service accepts the token only while the
+/// record is open.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Impersonation {
+    pub id: String,
+    pub admin_id: String,
+    pub admin_email: String,
+    pub user_id: String,
+    pub org: String,
+    pub reason: String,
+    #[serde(with = "time::serde::iso8601")]
+    pub started_at: OffsetDateTime,
+    #[serde(with = "time::serde::iso8601")]
+    pub expires_at: OffsetDateTime,
+    #[serde(default, with = "time::serde::iso8601::option")]
+    pub ended_at: Option<OffsetDateTime>,
+}
+
+impl Impersonation {
+    pub fn is_active(&self, now: OffsetDateTime) -> bool {
+        self.ended_at.is_none() && self.expires_at > now
+    }
+}
+
+/// A login session, kept by auth-service in `sessions/{user_id}.json`.
+/// Ending one here refuses every token of the login there.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    pub user_id: String,
+    #[serde(default)]
+    pub client_id: Option<String>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub amr: Vec<String>,
+    #[serde(with = "time::serde::iso8601")]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::iso8601")]
+    pub last_seen_at: OffsetDateTime,
+    #[serde(with = "time::serde::iso8601")]
+    pub expires_at: OffsetDateTime,
+    #[serde(default)]
+    pub refresh_tokens: Vec<RefreshTokenLink>,
+}
+
+impl Session {
+    pub fn is_active(&self, now: OffsetDateTime) -> bool {
+        self.expires_at > now
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshTokenLink {
+    pub jti: String,
+    pub client_id: Option<String>,
+    #[serde(with = "time::serde::iso8601")]
+    pub issued_at: OffsetDateTime,
+    #[serde(with = "time::serde::iso8601")]
+    pub expires_at: OffsetDateTime,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImpersonateRequest {
+    /// Why the admin needs to act as the user; kept in the audit trail
+    pub reason: String,
 }
\ No newline at end of file
@@ -18,6 +18,26 @@ pub fn hash_password(password: &str) -> Result<String> {
     Ok(password_hash)
 }
 
+/// Whether auth-service can verify `hash`: an Argon2 PHC string or one of
+/// the legacy schemes it upgrades at login (bcrypt, PBKDF2-SHA256 in PHC,
+/// passlib or Django notation, SHA-crypt). Only the shape is checked.
+pub fn is_supported_hash(hash: &str) -> bool {
+    let fields = hash.split('$').count();
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash).is_ok()
+    } else if ["$2a$", "$2b$", "$2y$"].iter().any(|prefix| hash.starts_with(prefix)) {
+        hash.len() == 60
+    } else if hash.starts_with("$pbkdf2-sha256$") {
+        fields == 5
+    } else if hash.starts_with("pbkdf2_sha256$") {
+        fields == 4
+    } else if hash.starts_with("$5$") || hash.starts_with("$6$") {
+        fields == 4 || (fields == 5 && hash[3..].starts_with("rounds="))
+    } else {
+        false
+    }
+}
+
 pub fn verify_password(password: &str, hash: &str) -> Result<bool> {
     let parsed_hash = PasswordHash::new(hash)
         .map_err(|e| anyhow!("Invalid password hash: {}", e))?;
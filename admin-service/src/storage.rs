@@ -1,14 +1,23 @@
 use anyhow::{Context, Result};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
 use std::time::SystemTime;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use time::OffsetDateTime;
+use tokio::io::AsyncWriteExt;
 use tracing::{info, warn, error};
 use uuid::Uuid;
 
+use audit_log::AuditLog;
+
 // Import shared models from our models module
-use crate::models::{User, Client, Organization, ClaimsRegistry, ClaimDefinition, UserStatus, ClientType, AuditEvent};
+use crate::models::{User, Client, ServiceProvider, Organization, ClaimsRegistry, ClaimDefinition, UserStatus, ClientType, SecurityPolicy, PasswordPolicy, PasswordResetToken, OutboundMail, AccountLockout, Impersonation, Session, AuditEvent};
+use crate::config::AuditConfig;
+use crate::audit_query::{self, AuditCursor, AuditFilter, AuditPage};
 
 
 // File format structures
@@ -34,7 +43,9 @@ pub struct AdminStorage {
     users: HashMap<String, User>, // user_id -> User
     organizations: HashMap<String, Organization>, // org_id -> Organization
     clients: HashMap<String, Client>,
+    service_providers: HashMap<String, ServiceProvider>,
     claims_registry: ClaimsRegistry,
+    security_policy: SecurityPolicy,
 
     // Computed indices
     email_index: HashMap<String, String>, // email -> user_id
@@ -43,10 +54,12 @@ pub struct AdminStorage {
     data_dir: String,
     auth_pid_file: String,
     sync_state: DataSyncState,
+    /// `audit/admin-service`, next to auth-service's log
+    audit: Arc<AuditLog>,
 }
 
 impl AdminStorage {
-    pub async fn load(data_dir: &str, auth_pid_file: &str) -> Result<Self> {
+    pub async fn load(data_dir: &str, auth_pid_file: &str, audit: &AuditConfig) -> Result<Self> {
         info!(
             event = "admin_storage_load_start",
             data_dir = data_dir
@@ -63,6 +76,9 @@ impl AdminStorage {
 
         // Load clients
         let clients = load_clients_file(data_dir).await?;
+        let service_providers = load_service_providers(data_dir).await?;
+
+        let security_policy = load_security_policy(data_dir).await?;
 
         // Convert to HashMaps
         let users_map: HashMap<String, User> = users
@@ -103,11 +119,14 @@ impl AdminStorage {
             users: users_map,
             organizations: organizations_map,
             clients: clients_map,
+            service_providers: service_providers.into_iter().map(|sp| (sp.id.clone(), sp)).collect(),
             claims_registry,
+            security_policy,
             email_index,
             data_dir: data_dir.to_string(),
             auth_pid_file: auth_pid_file.to_string(),
             sync_state,
+            audit: Arc::new(AuditLog::open(data_dir, "admin-service", &audit.signing_key)),
         })
     }
 
@@ -181,19 +200,36 @@ impl AdminStorage {
         Ok(user)
     }
 
-    pub async fn update_user(&mut self, user_id: &str, user: User) -> Result<User> {
-        // Remove old user from indices
-        if let Some(old_user) = self.users.get(user_id) {
-            self.email_index.remove(&old_user.email);
+    /// Apply `modify` to the on-disk record of `user_id` and persist it.
+    ///
+    /// auth-service writes some fields itself (e.g. `mfa_secret` on
+    /// enrollment), so the file is re-read under the shared users lock rather
+    /// than overwritten with our possibly stale in-memory copy.
+    pub async fn modify_user<F>(&mut self, user_id: &str, modify: F) -> Result<User>
+    where
+        F: FnOnce(&mut User),
+    {
+        let old_user = self.users.get(user_id)
+            .ok_or_else(|| anyhow::anyhow!("User not found: {}", user_id))?
+            .clone();
+        let old_path = format!("{}/users/{}/{}.json", self.data_dir, old_user.org, user_id);
+
+        let _lock = UsersLock::acquire(&self.data_dir).await?;
+
+        let mut user: User = load_json_file(&old_path).await?;
+
+        modify(&mut user);
+        user.updated_at = OffsetDateTime::now_utc();
+
+        self.write_user_file(&user).await?;
+        if user.org != old_user.org {
+            tokio::fs::remove_file(&old_path).await
+                .with_context(|| format!("Failed to remove moved user file: {}", old_path))?;
         }
 
-        // Update indices
+        self.email_index.remove(&old_user.email);
         self.email_index.insert(user.email.clone(), user.id.clone());
-
         self.users.insert(user_id.to_string(), user.clone());
-
-        // Immediate persistence
-        self.persist_user(&user).await?;
         self.sync_state.last_data_update = SystemTime::now();
 
         info!(
@@ -410,6 +446,47 @@ impl AdminStorage {
         Ok(())
     }
 
+    // SAML service providers
+    pub fn get_all_service_providers(&self) -> impl Iterator<Item = &ServiceProvider> {
+        self.service_providers.values()
+    }
+
+    pub fn get_service_provider(&self, id: &str) -> Option<&ServiceProvider> {
+        self.service_providers.get(id)
+    }
+
+    /// Saves a new or changed service provider.
+    pub async fn put_service_provider(&mut self, service_provider: ServiceProvider) -> Result<ServiceProvider> {
+        let dir = format!("{}/service_providers", self.data_dir);
+        tokio::fs::create_dir_all(&dir).await
+            .context("Failed to create service_providers directory")?;
+
+        let path = format!("{}/{}.json", dir, service_provider.id);
+        let temp_path = format!("{}.tmp", path);
+        tokio::fs::write(&temp_path, serde_json::to_string_pretty(&service_provider)?)
+            .await
+            .context("Failed to write service provider temp file")?;
+        tokio::fs::rename(temp_path, path)
+            .await
+            .context("Failed to rename service provider file")?;
+
+        self.service_providers.insert(service_provider.id.clone(), service_provider.clone());
+        self.sync_state.last_data_update = SystemTime::now();
+        Ok(service_provider)
+    }
+
+    pub async fn delete_service_provider(&mut self, id: &str) -> Result<()> {
+        if self.service_providers.remove(id).is_some() {
+            let path = format!("{}/service_providers/{}.json", self.data_dir, id);
+            if Path::new(&path).exists() {
+                tokio::fs::remove_file(&path).await
+                    .context("Failed to delete service provider file")?;
+            }
+            self.sync_state.last_data_update = SystemTime::now();
+        }
+        Ok(())
+    }
+
     // Claims registry
     pub fn get_claims(&self) -> &ClaimsRegistry {
         &self.claims_registry
@@ -479,21 +556,318 @@ impl AdminStorage {
         Ok(())
     }
 
+    // Security policy
+    pub fn get_security_policy(&self) -> &SecurityPolicy {
+        &self.security_policy
+    }
+
+    /// The password policy for members of `org_id`.
+    pub fn password_policy(&self, org_id: &str) -> &PasswordPolicy {
+        self.organizations
+            .get(org_id)
+            .and_then(|org| org.password_policy.as_ref())
+            .unwrap_or(&self.security_policy.password)
+    }
+
+    /// Reasons `password` may not be set for `user`; empty if it is acceptable.
+    pub fn password_violations(&self, user: &User, password: &str) -> Result<Vec<String>> {
+        password_policy::violations(
+            self.password_policy(&user.org),
+            password,
+            &[&user.email, &user.first_name, &user.last_name],
+            &self.data_dir,
+        )
+    }
+
+    /// Refuse a policy auth-service could not load, i.e. one asking for the
+    /// breached-password check without the corpus in place.
+    pub fn check_password_policy(&self, policy: &PasswordPolicy) -> Result<()> {
+        let corpus = Path::new(&self.data_dir).join(password_policy::BREACHED_CORPUS_FILE);
+        if policy.check_breached && !corpus.is_file() {
+            anyhow::bail!("The breached-password check needs {}, which does not exist", corpus.display());
+        }
+        Ok(())
+    }
+
+    pub async fn update_security_policy(&mut self, policy: SecurityPolicy) -> Result<()> {
+        self.persist_security_policy(&policy).await?;
+        self.security_policy = policy;
+        self.sync_state.last_data_update = SystemTime::now();
+
+        info!(
+            service = "admin-storage",
+            event = "security_policy_updated",
+            require_passkey_for_admins = self.security_policy.require_passkey_for_admins
+        );
+
+        Ok(())
+    }
+
+    // Password reset and mail, both handed over to auth-service through files
+    /// Issue a reset token for `user_id` valid for `ttl` seconds, replacing
+    /// any earlier one. Returns the token; only its hash is stored.
+    pub async fn create_password_reset(&self, user_id: &str, ttl: u64, created_by: &str) -> Result<(String, OffsetDateTime)> {
+        let resets_dir = format!("{}/password_resets", self.data_dir);
+        tokio::fs::create_dir_all(&resets_dir).await
+            .context("Failed to create password_resets directory")?;
+
+        let now = OffsetDateTime::now_utc();
+        let mut entries = tokio::fs::read_dir(&resets_dir).await
+            .context("Failed to read password_resets directory")?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let existing: PasswordResetToken = match tokio::fs::read_to_string(&path).await {
+                Ok(content) => serde_json::from_str(&content)
+                    .with_context(|| format!("Failed to parse {}", path.display()))?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+            };
+            if existing.user_id == user_id || existing.expires_at <= now {
+                match tokio::fs::remove_file(&path).await {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e).with_context(|| format!("Failed to remove {}", path.display())),
+                }
+            }
+        }
+
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let token_hash: String = Sha256::digest(token.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect();
+
+        let record = PasswordResetToken {
+            token_hash,
+            user_id: user_id.to_string(),
+            created_at: now,
+            expires_at: now + time::Duration::seconds(ttl as i64),
+            created_by: Some(created_by.to_string()),
+        };
+
+        let path = format!("{}/{}.json", resets_dir, record.token_hash);
+        let temp_path = format!("{}.tmp", path);
+        tokio::fs::write(&temp_path, serde_json::to_string_pretty(&record)?)
+            .await
+            .context("Failed to write password reset temp file")?;
+        tokio::fs::rename(temp_path, path)
+            .await
+            .context("Failed to rename password reset file")?;
+
+        Ok((token, record.expires_at))
+    }
+
+    /// Queue `template` for auth-service to render and send to `to`.
+    pub async fn enqueue_mail(&self, to: &str, template: &str, params: HashMap<String, String>) -> Result<String> {
+        let outbox_dir = format!("{}/mail/outbox", self.data_dir);
+        tokio::fs::create_dir_all(&outbox_dir).await
+            .context("Failed to create mail outbox directory")?;
+
+        let now = OffsetDateTime::now_utc();
+        let mail = OutboundMail {
+            id: format!("{}-{}", now.unix_timestamp(), Uuid::new_v4().simple()),
+            to: to.to_string(),
+            template: template.to_string(),
+            locale: None,
+            params,
+            created_at: now,
+        };
+
+        // auth-service only picks up `*.json`, never the temp file. The
+        // message may carry a reset link, so only the service user reads it
+        let path = format!("{}/{}.json", outbox_dir, mail.id);
+        let temp_path = format!("{}.tmp", path);
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&temp_path)
+            .await
+            .context("Failed to create outbox temp file")?;
+        file.write_all(serde_json::to_string_pretty(&mail)?.as_bytes())
+            .await
+            .context("Failed to write outbox temp file")?;
+        file.flush()
+            .await
+            .context("Failed to write outbox temp file")?;
+        tokio::fs::rename(temp_path, path)
+            .await
+            .context("Failed to rename outbox file")?;
+
+        Ok(mail.id)
+    }
+
+    // Login lockouts, recorded by auth-service
+    pub async fn get_lockout(&self, user_id: &str) -> Result<Option<AccountLockout>> {
+        let path = format!("{}/lockouts/{}.json", self.data_dir, user_id);
+        match tokio::fs::read_to_string(&path).await {
+            Ok(content) => Ok(Some(serde_json::from_str(&content).context("Failed to parse lockout record")?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context("Failed to read lockout record"),
+        }
+    }
+
+    /// Forget the failed logins of `user_id`; `false` if there were none.
+    pub async fn unlock_user(&self, user_id: &str) -> Result<bool> {
+        let path = format!("{}/lockouts/{}.json", self.data_dir, user_id);
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e).context("Failed to remove lockout record"),
+        }
+    }
+
+    // Impersonations, checked by auth-service on every use of their token
+    pub async fn list_impersonations(&self) -> Result<Vec<Impersonation>> {
+        let dir = format!("{}/impersonations", self.data_dir);
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).context("Failed to read impersonations directory"),
+        };
+
+        let mut impersonations = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let content = tokio::fs::read_to_string(&path).await
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            impersonations.push(serde_json::from_str(&content)
+                .with_context(|| format!("Failed to parse {}", path.display()))?);
+        }
+        Ok(impersonations)
+    }
+
+    pub async fn get_impersonation(&self, id: &str) -> Result<Option<Impersonation>> {
+        // Ids come from request paths and name the file
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Ok(None);
+        }
+        let path = format!("{}/impersonations/{}.json", self.data_dir, id);
+        match tokio::fs::read_to_string(&path).await {
+            Ok(content) => Ok(Some(serde_json::from_str(&content).context("Failed to parse impersonation record")?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context("Failed to read impersonation record"),
+        }
+    }
+
+    pub async fn put_impersonation(&self, impersonation: &Impersonation) -> Result<()> {
+        let dir = format!("{}/impersonations", self.data_dir);
+        tokio::fs::create_dir_all(&dir).await
+            .context("Failed to create impersonations directory")?;
+
+        let path = format!("{}/{}.json", dir, impersonation.id);
+        let temp_path = format!("{}.tmp", path);
+        tokio::fs::write(&temp_path, serde_json::to_string_pretty(impersonation)?)
+            .await
+            .context("Failed to write impersonation temp file")?;
+        tokio::fs::rename(temp_path, path)
+            .await
+            .context("Failed to rename impersonation file")?;
+        Ok(())
+    }
+
+    /// Close the records of impersonations whose token ran out, so that their
+    /// end is on record too. Returns the records closed.
+    pub async fn close_expired_impersonations(&self, now: OffsetDateTime) -> Result<Vec<Impersonation>> {
+        let mut closed = Vec::new();
+        for mut impersonation in self.list_impersonations().await? {
+            if impersonation.ended_at.is_none() && impersonation.expires_at <= now {
+                impersonation.ended_at = Some(impersonation.expires_at);
+                self.put_impersonation(&impersonation).await?;
+                closed.push(impersonation);
+            }
+        }
+        Ok(closed)
+    }
+
+    /// Active sessions of all users, as auth-service keeps them.
+    pub async fn list_sessions(&self) -> Result<Vec<Session>> {
+        let dir = format!("{}/sessions", self.data_dir);
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).context("Failed to read sessions directory"),
+        };
+
+        let now = OffsetDateTime::now_utc();
+        let mut sessions = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let content = tokio::fs::read_to_string(&path).await
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            let user_sessions: Vec<Session> = serde_json::from_str(&content)
+                .with_context(|| format!("Failed to parse {}", path.display()))?;
+            sessions.extend(user_sessions.into_iter().filter(|s| s.is_active(now)));
+        }
+        Ok(sessions)
+    }
+
+    /// End session `id` of `user_id`; `false` if it was not there.
+    pub async fn remove_session(&self, user_id: &str, id: &str) -> Result<bool> {
+        // User ids come from the session files, but name a file again here
+        if user_id.is_empty() || !user_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Ok(false);
+        }
+        let dir = format!("{}/sessions", self.data_dir);
+        let _lock = SessionsLock::acquire(&dir, user_id).await?;
+
+        let path = format!("{}/{}.json", dir, user_id);
+        let mut sessions: Vec<Session> = match tokio::fs::read_to_string(&path).await {
+            Ok(content) => serde_json::from_str(&content).context("Failed to parse sessions file")?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e).context("Failed to read sessions file"),
+        };
+        let before = sessions.len();
+        sessions.retain(|s| s.id != id);
+        if sessions.len() == before {
+            return Ok(false);
+        }
+
+        let temp_path = format!("{}.tmp", path);
+        tokio::fs::write(&temp_path, serde_json::to_string_pretty(&sessions)?)
+            .await
+            .context("Failed to write sessions temp file")?;
+        tokio::fs::rename(temp_path, path)
+            .await
+            .context("Failed to rename sessions file")?;
+        Ok(true)
+    }
+
     // Audit log operations
-    pub fn query_audit_events(
+
+    /// Durably append `event` to admin-service's audit log.
+    pub async fn record_audit(&self, event: &AuditEvent) -> Result<()> {
+        self.audit.append(event).await
+    }
+
+    pub async fn query_audit_events(
         &self,
-        user_id: Option<&str>,
-        event_type: Option<&str>,
-        from: Option<&str>,
-        to: Option<&str>,
-        limit: u32
-    ) -> Vec<AuditEvent> {
-        // Return empty for now - in production this would read from JSONL files
-        Vec::new()
+        filter: AuditFilter,
+        cursor: Option<AuditCursor>,
+        limit: usize,
+    ) -> Result<AuditPage> {
+        let data_dir = self.data_dir.clone();
+        tokio::task::spawn_blocking(move || audit_query::query(&data_dir, &filter, cursor.as_ref(), limit))
+            .await
+            .context("Audit query panicked")?
     }
 
     // Persistence operations
     async fn persist_user(&self, user: &User) -> Result<()> {
+        let _lock = UsersLock::acquire(&self.data_dir).await?;
+        self.write_user_file(user).await
+    }
+
+    async fn write_user_file(&self, user: &User) -> Result<()> {
         let org_dir = format!("{}/users/{}", self.data_dir, user.org);
 
         // Ensure org directory exists
@@ -604,6 +978,21 @@ impl AdminStorage {
         Ok(())
     }
 
+    async fn persist_security_policy(&self, policy: &SecurityPolicy) -> Result<()> {
+        let policy_path = format!("{}/security_policy.json", self.data_dir);
+        let temp_path = format!("{}.tmp", policy_path);
+
+        tokio::fs::write(&temp_path, serde_json::to_string_pretty(policy)?)
+            .await
+            .context("Failed to write security policy temp file")?;
+
+        tokio::fs::rename(temp_path, policy_path)
+            .await
+            .context("Failed to rename security policy file")?;
+
+        Ok(())
+    }
+
 }
 
 // File loading functions
@@ -618,6 +1007,21 @@ async fn load_claims_registry(data_dir: &str) -> Result<ClaimsRegistry> {
     Ok(registry)
 }
 
+/// A missing file means the default policy; an unreadable one is an error,
+/// as auth-service would refuse to load it as well.
+async fn load_security_policy(data_dir: &str) -> Result<SecurityPolicy> {
+    let path = format!("{}/security_policy.json", data_dir);
+    if !Path::new(&path).exists() {
+        return Ok(SecurityPolicy::default());
+    }
+
+    let content = tokio::fs::read_to_string(&path).await
+        .context("Failed to read security policy")?;
+
+    serde_json::from_str(&content)
+        .context("Failed to parse security policy")
+}
+
 async fn load_org_based_users(data_dir: &str, claims_registry: &ClaimsRegistry) -> Result<Vec<User>> {
     let users_dir = format!("{}/users", data_dir);
     let mut all_users = Vec::new();
@@ -756,10 +1160,76 @@ async fn load_clients_file(data_dir: &str) -> Result<Vec<Client>> {
     Ok(clients_file.clients)
 }
 
+/// One file per provider in `service_providers/`; none registered without the directory.
+async fn load_service_providers(data_dir: &str) -> Result<Vec<ServiceProvider>> {
+    let dir = format!("{}/service_providers", data_dir);
+    let mut service_providers = Vec::new();
+    let Ok(mut entries) = tokio::fs::read_dir(&dir).await else {
+        return Ok(service_providers);
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            service_providers.push(load_json_file(&path.to_string_lossy()).await?);
+        }
+    }
+    Ok(service_providers)
+}
+
 async fn load_json_file<T: for<'de> Deserialize<'de>>(path: &str) -> Result<T> {
     let content = tokio::fs::read_to_string(path).await
         .with_context(|| format!("Failed to read file: {}", path))?;
 
     serde_json::from_str(&content)
         .with_context(|| format!("Failed to parse JSON in file: {}", path))
-}
\ No newline at end of file
+}
+
+/// Exclusive advisory lock on `{data_dir}/users/.lock`, shared with
+/// auth-service so read-modify-write cycles on user files never interleave.
+/// Released when dropped.
+struct UsersLock {
+    _file: std::fs::File,
+}
+
+/// Exclusive advisory lock on `sessions/.{user_id}.lock`, shared with
+/// auth-service's session store. Released when dropped.
+struct SessionsLock {
+    _file: std::fs::File,
+}
+
+impl SessionsLock {
+    async fn acquire(dir: &str, user_id: &str) -> Result<Self> {
+        Ok(Self { _file: lock_exclusive(Path::new(dir).join(format!(".{}.lock", user_id))).await? })
+    }
+}
+
+impl UsersLock {
+    async fn acquire(data_dir: &str) -> Result<Self> {
+        Ok(Self { _file: lock_exclusive(Path::new(data_dir).join("users").join(".lock")).await? })
+    }
+}
+
+/// Open the lock file at `path` and wait for an exclusive `flock` on it.
+/// The wait happens on a blocking thread, so other requests go on meanwhile.
+async fn lock_exclusive(path: PathBuf) -> Result<std::fs::File> {
+    tokio::task::spawn_blocking(move || {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open lock {}", path.display()))?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Failed to lock {}", path.display()));
+        }
+
+        Ok(file)
+    })
+    .await
+    .context("Lock task panicked")?
+}
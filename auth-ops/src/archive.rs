@@ -0,0 +1,332 @@
+use anyhow::{bail, Context, Result};
+use flate2::{write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use time::{Date, Duration, OffsetDateTime};
+
+use crate::audit::{self, Segment, ARCHIVE_DIR};
+
+/// Inventory of `audit-archive`, kept in `audit-archive/manifest.json`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ArchiveManifest {
+    #[serde(default, with = "time::serde::iso8601::option")]
+    pub updated_at: Option<OffsetDateTime>,
+    pub archives: Vec<ArchiveEntry>,
+    /// Per service, the end of the chain in archives deleted after their
+    /// retention; the oldest remaining segment continues from there
+    #[serde(default)]
+    pub anchors: BTreeMap<String, ChainAnchor>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveEntry {
+    pub service: String,
+    /// Day of the archived file, `YYYY-MM-DD`
+    pub date: String,
+    /// Relative to the archive directory
+    pub file: String,
+    /// SHA-256 of the compressed file
+    pub sha256: String,
+    pub size: u64,
+    pub records: u64,
+    /// Hash of the file's last line, its checkpoint
+    pub last_hash: String,
+    #[serde(with = "time::serde::iso8601")]
+    pub archived_at: OffsetDateTime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainAnchor {
+    pub date: String,
+    pub last_hash: String,
+}
+
+impl ArchiveManifest {
+    pub async fn load(data_dir: &str) -> Result<Self> {
+        let path = manifest_path(data_dir);
+        match tokio::fs::read_to_string(&path).await {
+            Ok(content) => serde_json::from_str(&content)
+                .with_context(|| format!("Failed to parse {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    async fn save(&mut self, data_dir: &str) -> Result<()> {
+        self.updated_at = Some(OffsetDateTime::now_utc());
+        self.archives.sort_by(|a, b| (&a.service, &a.date).cmp(&(&b.service, &b.date)));
+        let content = serde_json::to_string_pretty(self)?;
+        write_atomically(&manifest_path(data_dir), content.as_bytes()).await
+    }
+
+    pub fn entry(&self, service: &str, date: Date) -> Option<&ArchiveEntry> {
+        let date = date.to_string();
+        self.archives.iter().find(|a| a.service == service && a.date == date)
+    }
+}
+
+pub fn manifest_path(data_dir: &str) -> PathBuf {
+    Path::new(data_dir).join(ARCHIVE_DIR).join("manifest.json")
+}
+
+pub struct ArchiveOptions {
+    pub older_than_days: u32,
+    /// Days archives are kept; forever if unset
+    pub retention_days: Option<u32>,
+    pub dry_run: bool,
+}
+
+/// `auth-ops archive`: compress closed audit files older than the threshold
+/// into `audit-archive/{service}/{date}.jsonl.gz`, delete archives past
+/// their retention and record both in the manifest. admin-service's
+/// `/api/audit` lists archived days until they are deleted.
+///
+/// Prints one tab-separated line per file to stdout (`archive`, `skip`,
+/// `delete`), a summary to stderr, and changes nothing with `dry_run`.
+pub async fn archive_old_logs(data_dir: &str, options: &ArchiveOptions) -> Result<()> {
+    if options.retention_days.is_some_and(|days| days < options.older_than_days) {
+        bail!("--retention-days must not be shorter than --older-than-days");
+    }
+    let today = OffsetDateTime::now_utc().date();
+    let archive_before = today - Duration::days(options.older_than_days.into());
+    let mut manifest = ArchiveManifest::load(data_dir).await?;
+    let (mut archived, mut deleted) = (0, 0);
+
+    for (service, segments) in audit::segments(data_dir).await? {
+        for segment in segments.iter().filter(|s| !s.is_archived() && s.date < archive_before) {
+            let content = segment.read().await?;
+            let Some((records, last_hash)) = closed_segment(&content) else {
+                println!("skip\t{}\tnot closed by a checkpoint", segment.path.display());
+                continue;
+            };
+            let file = format!("{}/{}.jsonl.gz", service, segment.date);
+            let target = Path::new(data_dir).join(ARCHIVE_DIR).join(&file);
+            if options.dry_run {
+                println!("archive\t{}\t{}\t-", segment.path.display(), target.display());
+                archived += 1;
+                continue;
+            }
+
+            let compressed = compress(&content)?;
+            let sha256 = format!("{:x}", Sha256::digest(&compressed));
+            write_atomically(&target, &compressed).await?;
+            manifest.archives.retain(|a| !(a.service == service && a.date == segment.date.to_string()));
+            manifest.archives.push(ArchiveEntry {
+                service: service.clone(),
+                date: segment.date.to_string(),
+                file,
+                sha256: sha256.clone(),
+                size: compressed.len() as u64,
+                records,
+                last_hash,
+                archived_at: OffsetDateTime::now_utc(),
+            });
+            // Recorded before the original goes, so a crash leaves both
+            manifest.save(data_dir).await?;
+            tokio::fs::remove_file(&segment.path).await
+                .with_context(|| format!("Failed to remove {}", segment.path.display()))?;
+            println!("archive\t{}\t{}\t{}", segment.path.display(), target.display(), sha256);
+            archived += 1;
+        }
+
+        if let Some(retention_days) = options.retention_days {
+            let delete_before = today - Duration::days(retention_days.into());
+            let expired: Vec<&Segment> = segments.iter().filter(|s| s.is_archived() && s.date < delete_before).collect();
+            for segment in expired {
+                println!("delete\t{}", segment.path.display());
+                deleted += 1;
+                if options.dry_run {
+                    continue;
+                }
+                expire(data_dir, &mut manifest, &service, segment).await?;
+            }
+        }
+    }
+
+    let mode = if options.dry_run { "dry run, nothing changed" } else { "done" };
+    eprintln!("{} archived, {} deleted ({})", archived, deleted, mode);
+    Ok(())
+}
+
+/// Delete an archive past its retention, keeping the end of its chain as
+/// the anchor later segments are verified from.
+async fn expire(data_dir: &str, manifest: &mut ArchiveManifest, service: &str, segment: &Segment) -> Result<()> {
+    let last_hash = match manifest.entry(service, segment.date) {
+        Some(entry) => entry.last_hash.clone(),
+        None => {
+            let content = segment.read().await?;
+            closed_segment(&content).map(|(_, hash)| hash)
+                .with_context(|| format!("{} is not closed by a checkpoint", segment.path.display()))?
+        }
+    };
+    let date = segment.date.to_string();
+    if manifest.anchors.get(service).is_none_or(|anchor| anchor.date < date) {
+        manifest.anchors.insert(service.to_string(), ChainAnchor { date: date.clone(), last_hash });
+    }
+    manifest.archives.retain(|a| !(a.service == service && a.date == date));
+    manifest.save(data_dir).await?;
+    tokio::fs::remove_file(&segment.path).await
+        .with_context(|| format!("Failed to remove {}", segment.path.display()))
+}
+
+/// Records and hash of the last line of a file a checkpoint closes.
+fn closed_segment(content: &[u8]) -> Option<(u64, String)> {
+    let body = content.strip_suffix(b"\n")?;
+    let last = body.rsplit(|b| *b == b'\n').next()?;
+    let checkpoint: serde_json::Value = serde_json::from_slice(last).ok()?;
+    let records = checkpoint.get("checkpoint")?.get("records")?.as_u64()?;
+    Some((records, audit::hash_line(last)))
+}
+
+fn compress(content: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(content)?;
+    Ok(encoder.finish()?)
+}
+
+/// Write through a temporary file, synced before it replaces `path`.
+async fn write_atomically(path: &Path, content: &[u8]) -> Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    let temp = path.with_extension("tmp");
+    let mut file = tokio::fs::File::create(&temp).await
+        .with_context(|| format!("Failed to create {}", temp.display()))?;
+    file.write_all(content).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&temp, path).await
+        .with_context(|| format!("Failed to replace {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    const SERVICE: &str = "auth-service";
+
+    /// Closed days 40, 39 and 20 days ago, yesterday, and today's open file.
+    async fn audit_days(data_dir: &str) -> (Vec<(Date, String)>, String) {
+        let mut chain = Chain::new(SERVICE);
+        let today = OffsetDateTime::now_utc().date();
+        let mut days = Vec::new();
+        for ago in [40, 39, 20, 1, 0] {
+            let date = today - Duration::days(ago);
+            days.push((date, chain.write_day(data_dir, date, 2, ago > 0).await));
+        }
+        (days, chain.write_public_key(data_dir).await)
+    }
+
+    fn options(retention_days: Option<u32>) -> ArchiveOptions {
+        ArchiveOptions { older_than_days: 7, retention_days, dry_run: false }
+    }
+
+    fn archive_path(data_dir: &str, date: Date) -> PathBuf {
+        Path::new(data_dir).join(ARCHIVE_DIR).join(SERVICE).join(format!("{}.jsonl.gz", date))
+    }
+
+    #[tokio::test]
+    async fn test_archive_round_trip() {
+        let dir = TempDir::new("archive");
+        let (days, public_key) = audit_days(dir.path()).await;
+        let mut originals = Vec::new();
+        for (_, path) in &days {
+            originals.push(tokio::fs::read(path).await.unwrap());
+        }
+
+        let dry_run = ArchiveOptions { dry_run: true, ..options(Some(30)) };
+        archive_old_logs(dir.path(), &dry_run).await.unwrap();
+        assert!(days.iter().all(|(_, path)| Path::new(path).exists()));
+        assert!(!manifest_path(dir.path()).exists());
+
+        archive_old_logs(dir.path(), &options(None)).await.unwrap();
+        let manifest = ArchiveManifest::load(dir.path()).await.unwrap();
+        assert_eq!(manifest.archives.len(), 3);
+        for ((date, path), original) in days.iter().zip(&originals) {
+            let archived = archive_path(dir.path(), *date);
+            if *date >= OffsetDateTime::now_utc().date() - Duration::days(7) {
+                assert!(Path::new(path).exists() && !archived.exists());
+                continue;
+            }
+            // Gone from audit, the same bytes once decompressed, as the manifest has them
+            assert!(!Path::new(path).exists());
+            let compressed = tokio::fs::read(&archived).await.unwrap();
+            let mut content = Vec::new();
+            GzDecoder::new(&compressed[..]).read_to_end(&mut content).unwrap();
+            assert_eq!(&content, original);
+            let entry = manifest.entry(SERVICE, *date).unwrap();
+            assert_eq!(entry.sha256, format!("{:x}", Sha256::digest(&compressed)));
+            assert_eq!((entry.size, entry.records), (compressed.len() as u64, 2));
+            assert_eq!(Some(entry.last_hash.clone()), closed_segment(original).map(|(_, hash)| hash));
+        }
+
+        // The chain still holds across archived and live files; a second run has nothing to do
+        audit::verify(dir.path(), &public_key).await.unwrap();
+        archive_old_logs(dir.path(), &options(None)).await.unwrap();
+        assert_eq!(ArchiveManifest::load(dir.path()).await.unwrap().archives.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_checksum_mismatch() {
+        let dir = TempDir::new("archive-checksum");
+        let (days, public_key) = audit_days(dir.path()).await;
+        archive_old_logs(dir.path(), &options(None)).await.unwrap();
+
+        // Recompressed from the very same records: the chain holds, the checksum does not
+        let archived = archive_path(dir.path(), days[1].0);
+        let mut content = Vec::new();
+        GzDecoder::new(&tokio::fs::read(&archived).await.unwrap()[..]).read_to_end(&mut content).unwrap();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(&content).unwrap();
+        write_file(archived.to_str().unwrap(), encoder.finish().unwrap()).await;
+        assert!(audit::verify(dir.path(), &public_key).await.is_err());
+
+        // Nor may archives unknown to the manifest slip in
+        let dir = TempDir::new("archive-unlisted");
+        let (_, public_key) = audit_days(dir.path()).await;
+        archive_old_logs(dir.path(), &options(None)).await.unwrap();
+        let mut manifest = ArchiveManifest::load(dir.path()).await.unwrap();
+        manifest.archives.remove(0);
+        manifest.save(dir.path()).await.unwrap();
+        assert!(audit::verify(dir.path(), &public_key).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retention_keeps_chain_anchored() {
+        let dir = TempDir::new("archive-retention");
+        let (days, public_key) = audit_days(dir.path()).await;
+        assert!(archive_old_logs(dir.path(), &ArchiveOptions { retention_days: Some(5), ..options(None) }).await.is_err());
+
+        // Archived in the first run, past the retention of 30 days in the next
+        archive_old_logs(dir.path(), &options(Some(30))).await.unwrap();
+        assert!(archive_path(dir.path(), days[0].0).exists());
+        archive_old_logs(dir.path(), &options(Some(30))).await.unwrap();
+        assert!(!archive_path(dir.path(), days[0].0).exists() && !archive_path(dir.path(), days[1].0).exists());
+        assert!(archive_path(dir.path(), days[2].0).exists());
+
+        // The anchor is the newest deleted day's last line, where the oldest archive left continues
+        let manifest = ArchiveManifest::load(dir.path()).await.unwrap();
+        assert_eq!(manifest.archives.len(), 1);
+        let anchor = &manifest.anchors[SERVICE];
+        assert_eq!(anchor.date, days[1].0.to_string());
+        let continued = audit::segments(dir.path()).await.unwrap().remove(SERVICE).unwrap();
+        let first = String::from_utf8(continued[0].read().await.unwrap()).unwrap();
+        let first: serde_json::Value = serde_json::from_str(first.lines().next().unwrap()).unwrap();
+        assert_eq!(first["prev_hash"], anchor.last_hash.as_str());
+        audit::verify(dir.path(), &public_key).await.unwrap();
+
+        // An anchor that does not lead to the remaining files breaks the chain
+        let mut manifest = ArchiveManifest::load(dir.path()).await.unwrap();
+        manifest.anchors.get_mut(SERVICE).unwrap().last_hash = audit::GENESIS_HASH.to_string();
+        manifest.save(dir.path()).await.unwrap();
+        assert!(audit::verify(dir.path(), &public_key).await.is_err());
+    }
+}
@@ -0,0 +1,481 @@
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use flate2::read::GzDecoder;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use time::{Date, OffsetDateTime};
+
+pub use audit_log::{checkpoint_message, hash_line, GENESIS_HASH};
+
+use crate::archive::ArchiveManifest;
+
+/// Directory below the data directory that archived segments move to, as
+/// `{service}/{date}.jsonl.gz`
+pub const ARCHIVE_DIR: &str = "audit-archive";
+
+/// A day's audit file of one service, live or archived.
+pub struct Segment {
+    pub date: Date,
+    pub path: PathBuf,
+}
+
+impl Segment {
+    pub fn is_archived(&self) -> bool {
+        self.path.extension().is_some_and(|e| e == "gz")
+    }
+
+    pub async fn read(&self) -> Result<Vec<u8>> {
+        let content = tokio::fs::read(&self.path).await
+            .with_context(|| format!("Failed to read {}", self.path.display()))?;
+        if !self.is_archived() {
+            return Ok(content);
+        }
+        decompress(&content).with_context(|| format!("Failed to decompress {}", self.path.display()))
+    }
+}
+
+fn decompress(content: &[u8]) -> Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    GzDecoder::new(content).read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+/// Segments of every service, oldest first. A day both archived and still
+/// live, as after an interrupted archive run, is read from the live file.
+pub async fn segments(data_dir: &str) -> Result<BTreeMap<String, Vec<Segment>>> {
+    let mut services: BTreeMap<String, BTreeMap<Date, PathBuf>> = BTreeMap::new();
+    for (root, suffix) in [(ARCHIVE_DIR, ".jsonl.gz"), ("audit", ".jsonl")] {
+        let root = Path::new(data_dir).join(root);
+        let mut entries = match tokio::fs::read_dir(&root).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", root.display())),
+        };
+        while let Some(service) = entries.next_entry().await? {
+            if !service.file_type().await?.is_dir() {
+                continue;
+            }
+            let days = services.entry(service.file_name().to_string_lossy().to_string()).or_default();
+            let mut files = tokio::fs::read_dir(service.path()).await?;
+            while let Some(file) = files.next_entry().await? {
+                let name = file.file_name().to_string_lossy().to_string();
+                let date = name
+                    .strip_suffix(suffix)
+                    .and_then(|d| Date::parse(d, &time::format_description::well_known::Iso8601::DATE).ok());
+                if let Some(date) = date {
+                    days.insert(date, file.path());
+                }
+            }
+        }
+    }
+    Ok(services
+        .into_iter()
+        .map(|(service, days)| (service, days.into_iter().map(|(date, path)| Segment { date, path }).collect()))
+        .collect())
+}
+
+/// The first place a chain does not hold.
+pub struct Break {
+    pub path: PathBuf,
+    /// 1-based; 0 for the file as a whole
+    pub line: usize,
+    pub reason: String,
+}
+
+/// How far a service's chain was followed.
+#[derive(Default)]
+pub struct ChainSummary {
+    pub files: usize,
+    pub records: u64,
+    /// Date of the newest file if no checkpoint closes it yet
+    pub open_day: Option<Date>,
+    /// Hash of the last line
+    pub last_hash: String,
+}
+
+/// Follow one service's chain from `start_hash` through `segments`. Every
+/// line must name the hash of the line before it; every file but the newest
+/// must end with a checkpoint signed by `public_key` over that file's
+/// records.
+pub async fn verify_chain(
+    service: &str,
+    segments: &[Segment],
+    start_hash: &str,
+    public_key: &[u8],
+) -> Result<Result<ChainSummary, Break>> {
+    let key = UnparsedPublicKey::new(&ED25519, public_key);
+    let mut summary = ChainSummary { last_hash: start_hash.to_string(), ..Default::default() };
+
+    for (index, segment) in segments.iter().enumerate() {
+        let newest = index + 1 == segments.len();
+        let content = segment.read().await?;
+        let day = Day { service, date: segment.date, path: &segment.path, newest };
+        if let Err(at) = day.verify(&content, &key, &mut summary) {
+            return Ok(Err(at));
+        }
+    }
+    Ok(Ok(summary))
+}
+
+/// One file of a chain being verified.
+struct Day<'a> {
+    service: &'a str,
+    date: Date,
+    path: &'a Path,
+    /// Only the newest file may lack its checkpoint or end mid-line
+    newest: bool,
+}
+
+impl Day<'_> {
+    /// Follow the chain through `content` from `summary.last_hash`, adding
+    /// the file to `summary`.
+    fn verify(&self, content: &[u8], key: &UnparsedPublicKey<&[u8]>, summary: &mut ChainSummary) -> Result<(), Break> {
+        let broken = |line: usize, reason: String| Err(Break { path: self.path.to_path_buf(), line, reason });
+
+        let mut lines: Vec<&[u8]> = content.split(|b| *b == b'\n').collect();
+        // After the final newline; in the open file, possibly a line still being written
+        let tail = lines.pop().unwrap_or_default();
+        if !tail.is_empty() && !self.newest {
+            return broken(lines.len() + 1, "last line is incomplete".to_string());
+        }
+
+        let mut records = 0u64;
+        let mut closed = false;
+        for (number, line) in lines.iter().enumerate().map(|(n, line)| (n + 1, *line)) {
+            if closed {
+                return broken(number, "line after the checkpoint".to_string());
+            }
+            let record: Value = match serde_json::from_slice(line) {
+                Ok(record) => record,
+                Err(e) => return broken(number, format!("not a JSON record: {}", e)),
+            };
+            let prev_hash = record["prev_hash"].as_str().unwrap_or_default();
+            if prev_hash != summary.last_hash {
+                return broken(number, format!("prev_hash {} does not match the line before ({})", prev_hash, summary.last_hash));
+            }
+
+            if let Some(checkpoint) = record.get("checkpoint") {
+                if checkpoint["service"] != self.service || checkpoint["date"] != self.date.to_string() {
+                    return broken(number, format!("checkpoint belongs to {} {}", checkpoint["service"], checkpoint["date"]));
+                }
+                if checkpoint["records"].as_u64() != Some(records) {
+                    return broken(number, format!("checkpoint counts {} records, the file has {}", checkpoint["records"], records));
+                }
+                let signature = record["signature"].as_str().and_then(|s| STANDARD.decode(s).ok()).unwrap_or_default();
+                let message = checkpoint_message(self.service, self.date, records, prev_hash);
+                if key.verify(message.as_bytes(), &signature).is_err() {
+                    return broken(number, "checkpoint signature is invalid".to_string());
+                }
+                closed = true;
+            } else {
+                records += 1;
+            }
+            summary.last_hash = hash_line(line);
+        }
+
+        if !closed && !self.newest {
+            return broken(0, "file is not closed by a checkpoint".to_string());
+        }
+        summary.files += 1;
+        summary.records += records;
+        summary.open_day = (!closed).then_some(self.date);
+        Ok(())
+    }
+}
+
+/// The Ed25519 public key auth-service wrote next to its signing key.
+pub async fn load_public_key(path: &str) -> Result<Vec<u8>> {
+    let encoded = tokio::fs::read_to_string(path).await
+        .with_context(|| format!("Failed to read public key {}", path))?;
+    let key = STANDARD.decode(encoded.trim())
+        .map_err(|e| anyhow!("Public key {} is not base64: {}", path, e))?;
+    if key.len() != 32 {
+        bail!("Public key {} is no Ed25519 key", path);
+    }
+    Ok(key)
+}
+
+/// An archived segment whose checksum differs from the manifest's.
+async fn altered_archive(manifest: &ArchiveManifest, service: &str, segments: &[Segment]) -> Result<Option<Break>> {
+    for segment in segments.iter().filter(|s| s.is_archived()) {
+        let content = tokio::fs::read(&segment.path).await
+            .with_context(|| format!("Failed to read {}", segment.path.display()))?;
+        let reason = match manifest.entry(service, segment.date) {
+            None => "archive is not in the manifest",
+            Some(entry) if entry.sha256 != format!("{:x}", Sha256::digest(&content)) => "archive checksum does not match the manifest",
+            Some(_) => continue,
+        };
+        return Ok(Some(Break { path: segment.path.clone(), line: 0, reason: reason.to_string() }));
+    }
+    Ok(None)
+}
+
+/// `auth-ops audit verify`: check every service's chain and report the
+/// first break of each. Fails if any chain is broken.
+///
+/// Chains start at the genesis hash, or where archives deleted after their
+/// retention left off, as the archive manifest records.
+pub async fn verify(data_dir: &str, public_key: &str) -> Result<()> {
+    let public_key = load_public_key(public_key).await?;
+    let manifest = ArchiveManifest::load(data_dir).await?;
+    let services = segments(data_dir).await?;
+    if services.is_empty() {
+        println!("ℹ️  No audit logs found in {}", data_dir);
+        return Ok(());
+    }
+
+    let mut broken = 0;
+    for (service, segments) in &services {
+        let start_hash = manifest.anchors.get(service).map_or(GENESIS_HASH, |anchor| anchor.last_hash.as_str());
+        let result = match altered_archive(&manifest, service, segments).await? {
+            Some(at) => Err(at),
+            None => verify_chain(service, segments, start_hash, &public_key).await?,
+        };
+        match result {
+            Ok(summary) => {
+                let open = summary.open_day.map(|day| format!(", {} still open", day)).unwrap_or_default();
+                println!("✅ {}: {} files, {} records, chain intact{}", service, summary.files, summary.records, open);
+            }
+            Err(at) => {
+                broken += 1;
+                println!("❌ {}: chain broken at {}:{}: {}", service, at.path.display(), at.line, at.reason);
+            }
+        }
+    }
+    if broken > 0 {
+        bail!("{} audit chain(s) broken", broken);
+    }
+    Ok(())
+}
+
+/// `auth-ops audit verify <file>`: check a single day file, plain or
+/// gzip-compressed, `-` reading it from stdin. The chain is followed from
+/// the first line's `prev_hash`, as what came before is not at hand; service
+/// and date are those its checkpoint names, and a file without one is
+/// checked as still open.
+pub async fn verify_file(input: &str, public_key: &str) -> Result<()> {
+    let public_key = load_public_key(public_key).await?;
+    let content = if input == "-" {
+        use tokio::io::AsyncReadExt;
+        let mut content = Vec::new();
+        tokio::io::stdin().read_to_end(&mut content).await.context("Failed to read stdin")?;
+        content
+    } else {
+        tokio::fs::read(input).await.with_context(|| format!("Failed to read {}", input))?
+    };
+    let (name, summary) = verify_content(input, &content, &public_key)?;
+    match summary {
+        Ok(summary) => {
+            let open = if summary.open_day.is_some() { ", not closed by a checkpoint" } else { "" };
+            println!("✅ {}: {} records, chain intact{}", name, summary.records, open);
+            Ok(())
+        }
+        Err(at) => {
+            println!("❌ {}: chain broken at {}:{}: {}", name, at.path.display(), at.line, at.reason);
+            bail!("audit chain broken")
+        }
+    }
+}
+
+/// Verify the day file `content` read from `input`, returning what it was
+/// identified as: the service and date of its checkpoint, or `input`.
+fn verify_content(input: &str, content: &[u8], public_key: &[u8]) -> Result<(String, Result<ChainSummary, Break>)> {
+    // Gzip magic; archived segments are piped in as they are
+    let content = if content.starts_with(&[0x1f, 0x8b]) {
+        decompress(content).with_context(|| format!("Failed to decompress {}", input))?
+    } else {
+        content.to_vec()
+    };
+    let records: Vec<Value> = content
+        .split(|b| *b == b'\n')
+        .filter_map(|line| serde_json::from_slice(line).ok())
+        .collect();
+    let start_hash = records.first().and_then(|r| r["prev_hash"].as_str()).unwrap_or(GENESIS_HASH).to_string();
+    let checkpoint = records.iter().find_map(|r| {
+        let checkpoint = r.get("checkpoint")?;
+        let date = Date::parse(checkpoint["date"].as_str()?, &time::format_description::well_known::Iso8601::DATE).ok()?;
+        Some((checkpoint["service"].as_str()?.to_string(), date))
+    });
+
+    let path = Path::new(if input == "-" { "<stdin>" } else { input });
+    let (name, service, date) = match &checkpoint {
+        Some((service, date)) => (format!("{} {}", service, date), service.as_str(), *date),
+        None => (path.display().to_string(), "", OffsetDateTime::now_utc().date()),
+    };
+    let day = Day { service, date, path, newest: checkpoint.is_none() };
+    let mut summary = ChainSummary { last_hash: start_hash, ..Default::default() };
+    let result = day.verify(&content, &UnparsedPublicKey::new(&ED25519, public_key), &mut summary);
+    Ok((name, result.map(|()| summary)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+    use time::Duration;
+
+    /// Days 2024-05-01 to 2024-05-03 of auth-service, the last still open.
+    async fn three_days(data_dir: &str) -> (Vec<String>, String) {
+        let mut chain = Chain::new("auth-service");
+        let first = Date::from_calendar_date(2024, time::Month::May, 1).unwrap();
+        let mut paths = Vec::new();
+        for (offset, closed) in [(0, true), (1, true), (2, false)] {
+            paths.push(chain.write_day(data_dir, first + Duration::days(offset), 3, closed).await);
+        }
+        let public_key = chain.write_public_key(data_dir).await;
+        (paths, public_key)
+    }
+
+    async fn check(data_dir: &str, public_key: &str) -> Result<ChainSummary, Break> {
+        let public_key = load_public_key(public_key).await.unwrap();
+        let services = segments(data_dir).await.unwrap();
+        verify_chain("auth-service", &services["auth-service"], GENESIS_HASH, &public_key).await.unwrap()
+    }
+
+    /// Rewrite the lines of the file at `path`.
+    async fn edit_lines(path: &str, edit: impl FnOnce(&mut Vec<String>)) {
+        let content = tokio::fs::read_to_string(path).await.unwrap();
+        let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+        edit(&mut lines);
+        write_file(path, lines.iter().map(|line| format!("{}\n", line)).collect::<String>()).await;
+    }
+
+    fn broken_at(result: Result<ChainSummary, Break>) -> (PathBuf, usize, String) {
+        let at = result.err().expect("chain should be broken");
+        (at.path, at.line, at.reason)
+    }
+
+    #[tokio::test]
+    async fn test_intact_chain() {
+        let dir = TempDir::new("verify-intact");
+        let (_, public_key) = three_days(dir.path()).await;
+
+        let summary = check(dir.path(), &public_key).await.ok().unwrap();
+        assert_eq!((summary.files, summary.records), (3, 9));
+        assert_eq!(summary.open_day.unwrap().to_string(), "2024-05-03");
+        verify(dir.path(), &public_key).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tampered_record() {
+        let dir = TempDir::new("verify-tampered");
+        let (paths, public_key) = three_days(dir.path()).await;
+        edit_lines(&paths[0], |lines| lines[1] = lines[1].replace("logout", "login_succeeded")).await;
+
+        // The edited line still names its predecessor; the one after it no longer matches
+        let (path, line, reason) = broken_at(check(dir.path(), &public_key).await);
+        assert_eq!((path.to_str().unwrap(), line), (paths[0].as_str(), 3));
+        assert!(reason.contains("prev_hash"), "{}", reason);
+        assert!(verify(dir.path(), &public_key).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_deleted_and_reordered_lines() {
+        let dir = TempDir::new("verify-deleted");
+        let (paths, public_key) = three_days(dir.path()).await;
+        edit_lines(&paths[1], |lines| {
+            lines.remove(1);
+        })
+        .await;
+        let (path, line, _) = broken_at(check(dir.path(), &public_key).await);
+        assert_eq!((path.to_str().unwrap(), line), (paths[1].as_str(), 2));
+
+        let dir = TempDir::new("verify-reordered");
+        let (paths, public_key) = three_days(dir.path()).await;
+        edit_lines(&paths[2], |lines| lines.swap(0, 1)).await;
+        let (path, line, _) = broken_at(check(dir.path(), &public_key).await);
+        assert_eq!((path.to_str().unwrap(), line), (paths[2].as_str(), 1));
+    }
+
+    #[tokio::test]
+    async fn test_bad_checkpoint() {
+        // Signed with another key
+        let dir = TempDir::new("verify-foreign-key");
+        let (paths, _) = three_days(dir.path()).await;
+        let foreign = Chain::new("auth-service").write_public_key(&format!("{}/other", dir.path())).await;
+        let (path, line, reason) = broken_at(check(dir.path(), &foreign).await);
+        assert_eq!((path.to_str().unwrap(), line), (paths[0].as_str(), 4));
+        assert_eq!(reason, "checkpoint signature is invalid");
+
+        // A signature that was altered
+        let dir = TempDir::new("verify-bad-signature");
+        let (paths, public_key) = three_days(dir.path()).await;
+        edit_lines(&paths[1], |lines| {
+            let mut checkpoint: Value = serde_json::from_str(&lines[3]).unwrap();
+            checkpoint["signature"] = Value::from(STANDARD.encode([0u8; 64]));
+            lines[3] = checkpoint.to_string();
+        })
+        .await;
+        let (path, line, reason) = broken_at(check(dir.path(), &public_key).await);
+        assert_eq!((path.to_str().unwrap(), line), (paths[1].as_str(), 4));
+        assert_eq!(reason, "checkpoint signature is invalid");
+
+        // A closed day that lost its checkpoint
+        let dir = TempDir::new("verify-no-checkpoint");
+        let (paths, public_key) = three_days(dir.path()).await;
+        edit_lines(&paths[0], |lines| {
+            lines.pop();
+        })
+        .await;
+        let (path, line, reason) = broken_at(check(dir.path(), &public_key).await);
+        assert_eq!((path.to_str().unwrap(), line), (paths[0].as_str(), 0));
+        assert_eq!(reason, "file is not closed by a checkpoint");
+    }
+
+    #[tokio::test]
+    async fn test_missing_day_file() {
+        let dir = TempDir::new("verify-missing-day");
+        let (paths, public_key) = three_days(dir.path()).await;
+        tokio::fs::remove_file(&paths[1]).await.unwrap();
+
+        // The day after the gap does not continue from the day before it
+        let (path, line, _) = broken_at(check(dir.path(), &public_key).await);
+        assert_eq!((path.to_str().unwrap(), line), (paths[2].as_str(), 1));
+
+        // Nor does the first remaining day once the oldest is gone
+        let dir = TempDir::new("verify-missing-first-day");
+        let (paths, public_key) = three_days(dir.path()).await;
+        tokio::fs::remove_file(&paths[0]).await.unwrap();
+        let (path, line, _) = broken_at(check(dir.path(), &public_key).await);
+        assert_eq!((path.to_str().unwrap(), line), (paths[1].as_str(), 1));
+    }
+
+    #[tokio::test]
+    async fn test_single_file_input() {
+        let dir = TempDir::new("verify-file");
+        let (paths, public_key) = three_days(dir.path()).await;
+        let key = load_public_key(&public_key).await.unwrap();
+        let day = tokio::fs::read(&paths[1]).await.unwrap();
+
+        // A closed day, plain or as archived, identified by its checkpoint
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&day).unwrap();
+        for content in [day.clone(), encoder.finish().unwrap()] {
+            let (name, result) = verify_content("-", &content, &key).unwrap();
+            assert_eq!(name, "auth-service 2024-05-02");
+            assert_eq!(result.ok().unwrap().records, 3);
+        }
+        verify_file(&paths[1], &public_key).await.unwrap();
+
+        // The open day has no checkpoint to sign it
+        let open = tokio::fs::read(&paths[2]).await.unwrap();
+        let (name, result) = verify_content(&paths[2], &open, &key).unwrap();
+        assert_eq!(name, paths[2]);
+        assert!(result.ok().unwrap().open_day.is_some());
+
+        // Breaks are reported against stdin
+        let mut lines: Vec<&[u8]> = day.split(|b| *b == b'\n').collect();
+        lines.swap(1, 2);
+        let (_, result) = verify_content("-", &lines.join(&b'\n'), &key).unwrap();
+        let (path, line, _) = broken_at(result);
+        assert_eq!((path.to_str().unwrap(), line), ("<stdin>", 2));
+        let foreign = Chain::new("auth-service").write_public_key(&format!("{}/other", dir.path())).await;
+        assert!(verify_file(&paths[1], &foreign).await.is_err());
+    }
+}
@@ -9,9 +9,13 @@ mod storage;
 mod models;
 mod password;
 mod backup;
+mod audit;
+mod archive;
+#[cfg(test)]
+mod testing;
 
 use storage::FileStorage;
-use models::{User, UserStatus, Group, Client, ClientType};
+use models::{User, UserStatus, Group, Client, ClientType, AccessTokenFormat, SubjectType};
 
 #[derive(Parser)]
 #[command(name = "auth-ops")]
@@ -75,10 +79,23 @@ enum Commands {
         cmd: ClientCommands,
     },
 
-    /// Archive old audit logs
+    /// Audit log operations
+    Audit {
+        #[command(subcommand)]
+        cmd: AuditCommands,
+    },
+
+    /// Compress closed audit logs into audit-archive and expire old archives
     Archive {
+        /// Archive day files older than this
         #[arg(long)]
         older_than_days: u32,
+        /// Delete archives older than this; keep them forever if unset
+        #[arg(long, env = "AUTH_AUDIT_RETENTION_DAYS")]
+        retention_days: Option<u32>,
+        /// Print what would be done, change nothing
+        #[arg(long)]
+        dry_run: bool,
     },
 
     /// Show system status
@@ -119,6 +136,20 @@ enum UserCommands {
     },
 }
 
+#[derive(Subcommand)]
+enum AuditCommands {
+    /// Check the hash chain and signed checkpoints of the audit logs,
+    /// archived ones included; reports the first break per service
+    Verify {
+        /// `<signing_key>.pub` written by auth-service
+        #[arg(long, env = "AUTH_AUDIT_PUBLIC_KEY")]
+        public_key: String,
+        /// A single day file to check instead of the data directory's logs,
+        /// plain or gzip-compressed; `-` reads it from stdin
+        file: Option<String>,
+    },
+}
+
 #[derive(Subcommand)]
 enum GroupCommands {
     /// Create a new group
@@ -178,8 +209,12 @@ async fn main() -> Result<()> {
         Commands::Client { cmd } => {
             handle_client_command(cmd, &cli.data_dir).await?;
         }
-        Commands::Archive { older_than_days } => {
-            archive_old_logs(&cli.data_dir, older_than_days).await?;
+        Commands::Audit { cmd } => {
+            handle_audit_command(cmd, &cli.data_dir).await?;
+        }
+        Commands::Archive { older_than_days, retention_days, dry_run } => {
+            let options = archive::ArchiveOptions { older_than_days, retention_days, dry_run };
+            archive::archive_old_logs(&cli.data_dir, &options).await?;
         }
         Commands::Status => {
             show_status(&cli.data_dir, &cli.auth_pid_file).await?;
@@ -252,6 +287,7 @@ async fn handle_user_command(cmd: UserCommands, data_dir: &str) -> Result<()> {
 
     match cmd {
         UserCommands::Create { email, password, first_name, last_name, roles } => {
+            check_password_policy(data_dir, &password, &[&email, &first_name, &last_name]).await?;
             let password_hash = password::hash_password(&password)?;
             let now = OffsetDateTime::now_utc();
 
@@ -265,6 +301,10 @@ async fn handle_user_command(cmd: UserCommands, data_dir: &str) -> Result<()> {
                 roles,
                 group_memberships: vec![],
                 mfa_secret: None,
+                mfa_recovery_codes: Vec::new(),
+                webauthn_credentials: Vec::new(),
+                federated_identities: Vec::new(),
+                password_changed_at: None,
                 created_at: now,
                 updated_at: now,
             };
@@ -299,9 +339,11 @@ async fn handle_user_command(cmd: UserCommands, data_dir: &str) -> Result<()> {
         }
         UserCommands::ResetPassword { email, new_password } => {
             if let Some(user) = storage.get_user_by_email(&email) {
+                check_password_policy(data_dir, &new_password, &[&user.email, &user.first_name, &user.last_name]).await?;
                 let mut updated_user = user.clone();
                 updated_user.password_hash = password::hash_password(&new_password)?;
                 updated_user.updated_at = OffsetDateTime::now_utc();
+                updated_user.password_changed_at = Some(updated_user.updated_at);
 
                 storage.update_user(&updated_user.id.clone(), updated_user).await?;
                 storage.persist(data_dir).await?;
@@ -315,6 +357,18 @@ async fn handle_user_command(cmd: UserCommands, data_dir: &str) -> Result<()> {
     Ok(())
 }
 
+/// Fail with every policy violation of `password`. Users here belong to no
+/// organization, so the instance-wide policy applies.
+async fn check_password_policy(data_dir: &str, password: &str, personal: &[&str]) -> Result<()> {
+    let policy = storage::load_security_policy(data_dir).await
+        .context("Failed to load security policy")?;
+    let violations = password_policy::violations(&policy.password, password, personal, data_dir)?;
+    if !violations.is_empty() {
+        anyhow::bail!("Password rejected by policy:\n  - {}", violations.join("\n  - "));
+    }
+    Ok(())
+}
+
 async fn handle_group_command(cmd: GroupCommands, data_dir: &str) -> Result<()> {
     let mut storage = FileStorage::load(data_dir).await?;
 
@@ -360,6 +414,12 @@ async fn handle_client_command(cmd: ClientCommands, data_dir: &str) -> Result<()
                 allowed_scopes: vec!["openid".to_string(), "profile".to_string(), "email".to_string()],
                 require_pkce: true,
                 grant_types: vec!["authorization_code".to_string(), "refresh_token".to_string()],
+                access_token_format: AccessTokenFormat::default(),
+                subject_type: SubjectType::default(),
+                sector_identifier_uri: None,
+                min_acr: None,
+                max_auth_age: None,
+                session_policy: None,
                 created_at: OffsetDateTime::now_utc(),
             };
 
@@ -380,15 +440,16 @@ async fn handle_client_command(cmd: ClientCommands, data_dir: &str) -> Result<()
     Ok(())
 }
 
-async fn archive_old_logs(_data_dir: &str, _older_than_days: u32) -> Result<()> {
-    println!("🗂️  Archiving audit logs older than {} days...", _older_than_days);
-
-    // TODO: Implement audit log archival
-    // - Scan audit/ directory
-    // - Find files older than threshold
-    // - Compress and move to archive
+async fn handle_audit_command(cmd: AuditCommands, data_dir: &str) -> Result<()> {
+    match cmd {
+        AuditCommands::Verify { public_key, file: Some(file) } => {
+            audit::verify_file(&file, &public_key).await?;
+        }
+        AuditCommands::Verify { public_key, file: None } => {
+            audit::verify(data_dir, &public_key).await?;
+        }
+    }
 
-    println!("✅ Log archival completed (not yet implemented)");
     Ok(())
 }
 
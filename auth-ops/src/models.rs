@@ -3,6 +3,8 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use time::OffsetDateTime;
 
+pub use password_policy::PasswordPolicy;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub id: String,
@@ -14,12 +16,29 @@ pub struct User {
     pub roles: Vec<String>,
     pub group_memberships: Vec<String>,
     pub mfa_secret: Option<String>,
+    #[serde(default)]
+    pub mfa_recovery_codes: Vec<String>, // Argon2 hashes of unused recovery codes
+    #[serde(default)]
+    pub webauthn_credentials: Vec<WebAuthnCredential>,
+    /// Accounts at upstream identity providers that log in as this user
+    #[serde(default)]
+    pub federated_identities: Vec<FederatedIdentity>,
+    /// Tokens issued before this instant are no longer accepted
+    #[serde(default, with = "time::serde::iso8601::option")]
+    pub password_changed_at: Option<OffsetDateTime>,
     #[serde(with = "time::serde::iso8601")]
     pub created_at: OffsetDateTime,
     #[serde(with = "time::serde::iso8601")]
     pub updated_at: OffsetDateTime,
 }
 
+/// The part of `security_policy.json` auth-ops applies.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SecurityPolicy {
+    #[serde(default)]
+    pub password: PasswordPolicy,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum UserStatus {
@@ -28,6 +47,31 @@ pub enum UserStatus {
     Suspended,
 }
 
+/// Link between a user and their `sub` at an upstream provider.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FederatedIdentity {
+    pub provider: String,
+    pub subject: String,
+    #[serde(with = "time::serde::iso8601")]
+    pub linked_at: OffsetDateTime,
+}
+
+/// A registered WebAuthn credential (passkey or security key).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebAuthnCredential {
+    pub credential_id: String, // base64url
+    pub public_key: String, // base64url, uncompressed P-256 point
+    pub algorithm: i64, // COSE algorithm, -7 = ES256
+    pub sign_count: u32,
+    #[serde(default)]
+    pub transports: Vec<String>,
+    pub nickname: String,
+    #[serde(with = "time::serde::iso8601")]
+    pub created_at: OffsetDateTime,
+    #[serde(default, with = "time::serde::iso8601::option")]
+    pub last_used_at: Option<OffsetDateTime>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Group {
     pub id: String,
@@ -56,10 +100,40 @@ pub struct Client {
     pub allowed_scopes: Vec<String>,
     pub require_pkce: bool,
     pub grant_types: Vec<String>,
+    #[serde(default)]
+    pub access_token_format: AccessTokenFormat,
+    #[serde(default)]
+    pub subject_type: SubjectType,
+    pub sector_identifier_uri: Option<String>,
+    /// Weakest login (`acr`) the client accepts tokens for
+    pub min_acr: Option<String>,
+    /// Seconds after which the client needs a fresh login
+    pub max_auth_age: Option<u64>,
+    /// Session limits for sessions in use by this client
+    #[serde(default)]
+    pub session_policy: Option<SessionPolicy>,
     #[serde(with = "time::serde::iso8601")]
     pub created_at: OffsetDateTime,
 }
 
+/// Session limits, enforced by auth-service; kept here so that rewriting
+/// `clients.json` preserves them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionPolicy {
+    pub idle_timeout: Option<u64>,
+    pub max_lifetime: Option<u64>,
+    pub max_concurrent: Option<usize>,
+    pub on_limit: Option<SessionLimitAction>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionLimitAction {
+    EvictOldest,
+    Refuse,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ClientType {
@@ -67,6 +141,27 @@ pub enum ClientType {
     Confidential,
 }
 
+/// How access tokens for a client are represented. `Jwt` tokens are
+/// self-contained; `Opaque` tokens are random references resolved through
+/// introspection and revocable immediately.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessTokenFormat {
+    #[default]
+    Jwt,
+    Opaque,
+}
+
+/// Which `sub` a client sees. `Public` is the global user id; `Pairwise`
+/// derives a per-sector identifier so clients cannot correlate users.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SubjectType {
+    #[default]
+    Public,
+    Pairwise,
+}
+
 impl User {
     pub fn full_name(&self) -> String {
         format!("{} {}", self.first_name, self.last_name)
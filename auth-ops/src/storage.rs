@@ -4,7 +4,7 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use tracing::info;
 
-use crate::models::{User, Group, Client, Role};
+use crate::models::{User, Group, Client, Role, SecurityPolicy};
 
 // File format structures
 #[derive(Debug, Serialize, Deserialize)]
@@ -271,6 +271,15 @@ async fn load_clients_file(data_dir: &str) -> Result<Vec<Client>> {
     Ok(clients_file.clients)
 }
 
+/// The instance security policy; defaults apply while the file is absent.
+pub async fn load_security_policy(data_dir: &str) -> Result<SecurityPolicy> {
+    let path = format!("{}/security_policy.json", data_dir);
+    if !std::path::Path::new(&path).exists() {
+        return Ok(SecurityPolicy::default());
+    }
+    load_json_file(&path).await
+}
+
 async fn load_json_file<T: for<'de> Deserialize<'de>>(path: &str) -> Result<T> {
     let content = tokio::fs::read_to_string(path).await
         .with_context(|| format!("Failed to read file: {}", path))?;
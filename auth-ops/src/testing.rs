@@ -0,0 +1,112 @@
+//! Fixtures for tests of the audit commands: a throwaway data directory and
+//! day files chained and signed the way the services write them.
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde_json::json;
+use std::path::Path;
+use time::Date;
+
+use crate::audit::{checkpoint_message, hash_line, GENESIS_HASH};
+
+/// A data directory under the system temp dir, removed when dropped.
+pub struct TempDir(String);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("um-oic-ops-{}-{}", name, uuid::Uuid::new_v4().simple()));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path.to_string_lossy().to_string())
+    }
+
+    pub fn path(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Write `content` to `path`, creating the directories above it.
+pub async fn write_file(path: &str, content: impl AsRef<[u8]>) {
+    if let Some(parent) = Path::new(path).parent() {
+        tokio::fs::create_dir_all(parent).await.unwrap();
+    }
+    tokio::fs::write(path, content).await.unwrap();
+}
+
+/// One service's chain, written a day file at a time.
+pub struct Chain {
+    service: &'static str,
+    key: Ed25519KeyPair,
+    last_hash: String,
+}
+
+impl Chain {
+    pub fn new(service: &'static str) -> Self {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        Self { service, key, last_hash: GENESIS_HASH.to_string() }
+    }
+
+    /// Write the public key the way the services do, returning its path.
+    pub async fn write_public_key(&self, data_dir: &str) -> String {
+        let path = format!("{}/keys/audit.pem.pub", data_dir);
+        write_file(&path, format!("{}\n", STANDARD.encode(self.key.public_key().as_ref()))).await;
+        path
+    }
+
+    /// The file of `date` with `records` events continuing the chain, closed
+    /// with a signed checkpoint if `closed`.
+    pub fn day(&mut self, date: Date, records: u64, closed: bool) -> String {
+        let mut content = String::new();
+        for n in 0..records {
+            let line = json!({
+                "id": format!("evt-{}-{}", date, n),
+                "user_id": "user-1",
+                "org": "default",
+                "event_type": "logout",
+                "ip_address": null,
+                "user_agent": null,
+                "metadata": {},
+                "created_at": format!("{}T12:00:00Z", date),
+                "prev_hash": self.last_hash,
+            })
+            .to_string();
+            self.push(&mut content, line);
+        }
+        if closed {
+            let message = checkpoint_message(self.service, date, records, &self.last_hash);
+            let line = json!({
+                "checkpoint": {
+                    "service": self.service,
+                    "date": date.to_string(),
+                    "records": records,
+                    "closed_at": format!("{}T00:00:01Z", date.next_day().unwrap()),
+                },
+                "prev_hash": self.last_hash,
+                "signature": STANDARD.encode(self.key.sign(message.as_bytes())),
+            })
+            .to_string();
+            self.push(&mut content, line);
+        }
+        content
+    }
+
+    /// Write the file of `date` to `audit/{service}/{date}.jsonl`.
+    pub async fn write_day(&mut self, data_dir: &str, date: Date, records: u64, closed: bool) -> String {
+        let path = format!("{}/audit/{}/{}.jsonl", data_dir, self.service, date);
+        write_file(&path, self.day(date, records, closed)).await;
+        path
+    }
+
+    fn push(&mut self, content: &mut String, line: String) {
+        self.last_hash = hash_line(&line);
+        content.push_str(&line);
+        content.push('\n');
+    }
+}
@@ -0,0 +1,151 @@
+use serde::{Deserialize, Serialize};
+
+/// Authentication methods (RFC 8176) a login can be made of
+pub const AMR_PASSWORD: &str = "pwd";
+pub const AMR_OTP: &str = "otp";
+pub const AMR_HARDWARE_KEY: &str = "hwk";
+pub const AMR_USER_VERIFICATION: &str = "user";
+pub const AMR_MULTI_FACTOR: &str = "mfa";
+/// Login at an upstream identity provider
+pub const AMR_FEDERATED: &str = "fed";
+
+/// Authentication context classes from weakest to strongest: password
+/// only, two factors, two factors with a phishing-resistant passkey
+pub const ACR_VALUES: &[&str] = &["pwd", "mfa", "phr"];
+
+/// What a client or scope demands of the login behind a token.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AuthRequirement {
+    /// Weakest acceptable entry of `ACR_VALUES`
+    pub min_acr: Option<String>,
+    /// Seconds since the last interactive authentication
+    pub max_auth_age: Option<u64>,
+}
+
+impl AuthRequirement {
+    /// The stricter of both requirements on each dimension.
+    pub fn and(&self, other: &AuthRequirement) -> AuthRequirement {
+        let min_acr = match (&self.min_acr, &other.min_acr) {
+            (Some(a), Some(b)) => Some(if rank(b) > rank(a) { b.clone() } else { a.clone() }),
+            (a, b) => a.clone().or_else(|| b.clone()),
+        };
+        let max_auth_age = match (self.max_auth_age, other.max_auth_age) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        AuthRequirement { min_acr, max_auth_age }
+    }
+
+    /// Whether a login with context class `acr` at `auth_time` meets the
+    /// requirement at `now`. Logins of unknown strength or age meet none, and
+    /// an unknown `min_acr` is never met.
+    pub fn is_met(&self, acr: Option<&str>, auth_time: Option<u64>, now: u64) -> bool {
+        let strong_enough = match &self.min_acr {
+            Some(min_acr) => match (rank(min_acr), acr.and_then(rank)) {
+                (Some(required), Some(actual)) => actual >= required,
+                _ => false,
+            },
+            None => true,
+        };
+        let recent_enough = match self.max_auth_age {
+            Some(max_age) => auth_time.is_some_and(|t| now.saturating_sub(t) <= max_age),
+            None => true,
+        };
+        strong_enough && recent_enough
+    }
+}
+
+/// `amr` after passing another factor `method`. Two factors of different
+/// kinds (knowledge, possession, inherence or an upstream login) add `mfa`.
+pub fn add_method(amr: &[String], method: &str) -> Vec<String> {
+    let mut methods: Vec<String> = amr.iter().filter(|m| *m != AMR_MULTI_FACTOR).cloned().collect();
+    if !methods.iter().any(|m| m == method) {
+        methods.push(method.to_string());
+    }
+
+    let mut kinds: Vec<&str> = methods.iter().filter_map(|m| factor_kind(m)).collect();
+    kinds.sort();
+    kinds.dedup();
+    if kinds.len() >= 2 {
+        methods.push(AMR_MULTI_FACTOR.to_string());
+    }
+    methods
+}
+
+/// Context class of a login made with `amr`.
+pub fn acr_for(amr: &[String]) -> &'static str {
+    let has = |method: &str| amr.iter().any(|m| m == method);
+    match (has(AMR_MULTI_FACTOR), has(AMR_HARDWARE_KEY)) {
+        (true, true) => "phr",
+        (true, false) => "mfa",
+        _ => "pwd",
+    }
+}
+
+pub fn is_known_acr(acr: &str) -> bool {
+    rank(acr).is_some()
+}
+
+fn rank(acr: &str) -> Option<usize> {
+    ACR_VALUES.iter().position(|known| *known == acr)
+}
+
+fn factor_kind(method: &str) -> Option<&'static str> {
+    match method {
+        AMR_PASSWORD => Some("knowledge"),
+        AMR_OTP | AMR_HARDWARE_KEY => Some("possession"),
+        AMR_USER_VERIFICATION => Some("inherence"),
+        // Whatever the provider asked for counts as one factor
+        AMR_FEDERATED => Some("federated"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requirement(min_acr: Option<&str>, max_auth_age: Option<u64>) -> AuthRequirement {
+        AuthRequirement { min_acr: min_acr.map(str::to_string), max_auth_age }
+    }
+
+    #[test]
+    fn test_methods_build_up_to_mfa() {
+        let password = add_method(&[], AMR_PASSWORD);
+        assert_eq!(acr_for(&password), "pwd");
+
+        let totp = add_method(&password, AMR_OTP);
+        assert_eq!(totp, ["pwd", "otp", "mfa"]);
+        assert_eq!(acr_for(&totp), "mfa");
+        assert_eq!(add_method(&totp, AMR_OTP), totp);
+
+        let passkey = add_method(&password, AMR_HARDWARE_KEY);
+        assert_eq!(acr_for(&passkey), "phr");
+
+        // A passkey with user verification is two factors on its own
+        let passwordless = add_method(&add_method(&[], AMR_HARDWARE_KEY), AMR_USER_VERIFICATION);
+        assert_eq!(acr_for(&passwordless), "phr");
+        assert_eq!(acr_for(&add_method(&[], AMR_HARDWARE_KEY)), "pwd");
+
+        let federated = add_method(&add_method(&[], AMR_FEDERATED), AMR_OTP);
+        assert_eq!(acr_for(&federated), "mfa");
+    }
+
+    #[test]
+    fn test_requirements() {
+        let now = 10_000;
+        assert!(requirement(None, None).is_met(None, None, now));
+        assert!(requirement(Some("mfa"), None).is_met(Some("phr"), None, now));
+        assert!(!requirement(Some("mfa"), None).is_met(Some("pwd"), None, now));
+        assert!(!requirement(Some("mfa"), None).is_met(None, None, now));
+        assert!(!requirement(Some("gold"), None).is_met(Some("phr"), None, now));
+
+        assert!(requirement(None, Some(300)).is_met(Some("pwd"), Some(now - 300), now));
+        assert!(!requirement(None, Some(300)).is_met(Some("pwd"), Some(now - 301), now));
+        assert!(!requirement(None, Some(300)).is_met(Some("pwd"), None, now));
+
+        let combined = requirement(Some("phr"), Some(600)).and(&requirement(Some("mfa"), Some(300)));
+        assert_eq!(combined.min_acr.as_deref(), Some("phr"));
+        assert_eq!(combined.max_auth_age, Some(300));
+    }
+}
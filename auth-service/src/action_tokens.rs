@@ -0,0 +1,180 @@
+use anyhow::{Context, Result};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use time::{Duration, OffsetDateTime};
+
+use crate::tokens::{hash_token, to_hex};
+
+/// A pending one-time action such as a password reset or an email
+/// verification. Only the SHA-256 of the token is stored; the token itself
+/// only ever appears in the mail to the user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActionToken {
+    pub token_hash: String,
+    pub user_id: String,
+    #[serde(with = "time::serde::iso8601")]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::iso8601")]
+    pub expires_at: OffsetDateTime,
+    /// Admin who requested the action, `None` for self-service requests
+    #[serde(default)]
+    pub created_by: Option<String>,
+}
+
+impl ActionToken {
+    pub fn is_expired(&self, now: OffsetDateTime) -> bool {
+        self.expires_at <= now
+    }
+}
+
+/// Action tokens of one kind in a directory under `data_dir`, one file per
+/// token named after its hash. Redeeming removes the file, so a token works
+/// once even with several processes racing for it.
+#[derive(Debug, Clone)]
+pub struct ActionTokenStore {
+    dir: PathBuf,
+}
+
+impl ActionTokenStore {
+    /// `{data_dir}/password_resets`; admin-service issues tokens there too.
+    pub fn password_resets(data_dir: &str) -> Self {
+        Self {
+            dir: Path::new(data_dir).join("password_resets"),
+        }
+    }
+
+    /// `{data_dir}/email_verifications`
+    pub fn email_verifications(data_dir: &str) -> Self {
+        Self {
+            dir: Path::new(data_dir).join("email_verifications"),
+        }
+    }
+
+    /// Issue a token for `user_id`, valid for `ttl` seconds. Earlier tokens
+    /// of the user stop working.
+    pub async fn issue(&self, user_id: &str, ttl: u64, created_by: Option<&str>) -> Result<String> {
+        tokio::fs::create_dir_all(&self.dir).await
+            .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+
+        let now = OffsetDateTime::now_utc();
+        for (path, record) in self.entries().await? {
+            if record.user_id == user_id || record.is_expired(now) {
+                remove_if_present(&path).await?;
+            }
+        }
+
+        let mut bytes = [0u8; 32];
+        rand::rngs::OsRng.fill_bytes(&mut bytes);
+        let token = to_hex(&bytes);
+
+        let record = ActionToken {
+            token_hash: hash_token(&token),
+            user_id: user_id.to_string(),
+            created_at: now,
+            expires_at: now + Duration::seconds(ttl as i64),
+            created_by: created_by.map(str::to_string),
+        };
+
+        let path = self.path_for(&record.token_hash);
+        let temp_path = path.with_extension("json.tmp");
+        tokio::fs::write(&temp_path, serde_json::to_string_pretty(&record)?).await
+            .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+        tokio::fs::rename(&temp_path, &path).await
+            .with_context(|| format!("Failed to rename {}", temp_path.display()))?;
+
+        Ok(token)
+    }
+
+    /// The unexpired record behind `token`, without using it up.
+    pub async fn peek(&self, token: &str, now: OffsetDateTime) -> Result<Option<ActionToken>> {
+        let path = self.path_for(&hash_token(token));
+        let record = match tokio::fs::read_to_string(&path).await {
+            Ok(content) => serde_json::from_str::<ActionToken>(&content)
+                .with_context(|| format!("Failed to parse {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+
+        Ok(Some(record).filter(|r| !r.is_expired(now)))
+    }
+
+    /// Use up `token`. Returns `None` if it is unknown, expired or was
+    /// redeemed concurrently.
+    pub async fn redeem(&self, token: &str, now: OffsetDateTime) -> Result<Option<ActionToken>> {
+        let Some(record) = self.peek(token, now).await? else {
+            return Ok(None);
+        };
+
+        let removed = remove_if_present(&self.path_for(&record.token_hash)).await?;
+        Ok(Some(record).filter(|_| removed))
+    }
+
+    async fn entries(&self) -> Result<Vec<(PathBuf, ActionToken)>> {
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", self.dir.display())),
+        };
+
+        let mut records = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let content = match tokio::fs::read_to_string(&path).await {
+                Ok(content) => content,
+                // Redeemed by someone else in the meantime
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+            };
+            let record = serde_json::from_str::<ActionToken>(&content)
+                .with_context(|| format!("Failed to parse {}", path.display()))?;
+            records.push((path, record));
+        }
+
+        Ok(records)
+    }
+
+    fn path_for(&self, token_hash: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", token_hash))
+    }
+}
+
+/// Remove `path`; `false` if it was already gone.
+async fn remove_if_present(path: &Path) -> Result<bool> {
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("Failed to remove {}", path.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reset_token_is_single_use_and_replaced_by_newer() {
+        let data_dir = std::env::temp_dir()
+            .join(format!("um-oic-resets-{}", uuid::Uuid::new_v4().simple()))
+            .to_string_lossy()
+            .to_string();
+        let store = ActionTokenStore::password_resets(&data_dir);
+        let now = OffsetDateTime::now_utc();
+
+        let first = store.issue("user-1", 600, None).await.unwrap();
+        let second = store.issue("user-1", 600, Some("user-admin")).await.unwrap();
+        assert!(store.peek(&first, now).await.unwrap().is_none());
+
+        let record = store.peek(&second, now).await.unwrap().unwrap();
+        assert_eq!(record.created_by.as_deref(), Some("user-admin"));
+        assert!(store.peek(&second, now + Duration::seconds(601)).await.unwrap().is_none());
+
+        assert_eq!(store.redeem(&second, now).await.unwrap().unwrap().user_id, "user-1");
+        assert!(store.redeem(&second, now).await.unwrap().is_none());
+
+        tokio::fs::remove_dir_all(&data_dir).await.unwrap();
+    }
+}
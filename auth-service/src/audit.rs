@@ -0,0 +1,177 @@
+use axum::http::StatusCode;
+use tracing::{error, info};
+
+pub use audit_log::AuditLog;
+
+use crate::config::AuditConfig;
+use crate::models::{AuditEvent, User};
+use crate::sessions::ClientInfo;
+
+/// Directory below `<data_dir>/audit` and name in checkpoints
+const SERVICE: &str = "auth-service";
+
+/// Start writing `audit/auth-service`. Needs a running Tokio runtime.
+pub fn open(data_dir: &str, config: &AuditConfig) -> AuditLog {
+    AuditLog::open(data_dir, SERVICE, &config.signing_key)
+}
+
+/// An event about `user`, with the address and user agent of the request.
+pub fn event(event_type: &str, user: &User, client: &ClientInfo) -> AuditEvent {
+    let mut event = AuditEvent::new(event_type.to_string(), Some(user.id.clone()), Some(user.org.clone()));
+    event.ip_address = client.ip.map(|ip| ip.to_string());
+    event.user_agent = client.user_agent.clone();
+    event
+}
+
+/// Write a security-relevant event to the audit log and the service log.
+/// A request whose event cannot be written fails with 500.
+pub async fn record(log: &AuditLog, event: &AuditEvent) -> Result<(), StatusCode> {
+    if let Err(e) = log.append(event).await {
+        error!(
+            service = "auth-service",
+            event = "audit_write_failed",
+            event_type = %event.event_type,
+            error = %format!("{:#}", e)
+        );
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    info!(
+        service = "auth-service",
+        event = "audit",
+        event_type = %event.event_type,
+        audit_id = %event.id,
+        user_id = ?event.user_id
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use audit_log::{checkpoint_message, hash_line, GENESIS_HASH};
+    use crate::config::Config;
+    use crate::testing::*;
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+        Router,
+    };
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use ring::signature::{UnparsedPublicKey, ED25519};
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let dir = TempDir::new("audit");
+        let data_dir = dir.path();
+        write_user(data_dir, json!({})).await;
+
+        let login = |app: Router, password: &'static str| async move {
+            let body = json!({"email": "anna@example.com", "password": password});
+            let request = Request::post("/api/auth/login")
+                .header("content-type", "application/json")
+                .header("user-agent", "Schul-Tablet")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let response = app.oneshot(request).await.unwrap();
+            (response.status(), body_json(response).await)
+        };
+        let (app, _, _) = test_app(data_dir, &Config::default()).await;
+
+        let (_, failed) = login(app.clone(), "wrong horse battery").await;
+        assert_eq!(failed["success"], false);
+        let (_, tokens) = login(app.clone(), PASSWORD).await;
+        let token = tokens["access_token"].as_str().unwrap();
+        let (status, _) = post_json_as(&app, "/api/auth/logout", json!({}), Some(token)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Written by the time the requests are answered
+        let today = time::OffsetDateTime::now_utc().date();
+        let log = tokio::fs::read_to_string(format!("{}/audit/auth-service/{}.jsonl", data_dir, today)).await.unwrap();
+        let events: Vec<Value> = log.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        let types: Vec<&str> = events.iter().map(|e| e["event_type"].as_str().unwrap()).collect();
+        assert_eq!(types, ["login_failed", "login_succeeded", "logout"]);
+        assert_eq!(events[0]["metadata"]["reason"], "invalid_password");
+        assert_eq!(events[1]["metadata"]["methods"], json!(["pwd"]));
+        assert!(events.iter().all(|e| e["user_id"] == "user-1"));
+        assert!(events.iter().all(|e| e["ip_address"] == "127.0.0.1"));
+        assert!(events[..2].iter().all(|e| e["user_agent"] == "Schul-Tablet"));
+
+        // Where the event cannot be written, the login fails outright
+        let broken = TempDir::new("audit-broken");
+        let broken_dir = broken.path();
+        write_user(broken_dir, json!({})).await;
+        write_file(&format!("{}/audit/auth-service", broken_dir), "").await;
+        let (app, _, _) = test_app(broken_dir, &Config::default()).await;
+        let (status, body) = login(app, PASSWORD).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body["access_token"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_audit_chain() {
+        let dir = TempDir::new("audit-chain");
+        let data_dir = dir.path();
+        write_user(data_dir, json!({})).await;
+
+        // Yesterday's file, left open as if the service was down at midnight
+        let today = time::OffsetDateTime::now_utc().date();
+        let yesterday = today.previous_day().unwrap();
+        let mut last_hash = GENESIS_HASH.to_string();
+        let mut earlier = String::new();
+        for id in ["audit-1", "audit-2"] {
+            let line = json!({
+                "id": id,
+                "user_id": "user-1",
+                "org": "default",
+                "event_type": "logout",
+                "ip_address": null,
+                "user_agent": null,
+                "metadata": {},
+                "created_at": format!("{}T12:00:00Z", yesterday),
+                "prev_hash": last_hash,
+            })
+            .to_string();
+            last_hash = hash_line(&line);
+            earlier.push_str(&line);
+            earlier.push('\n');
+        }
+        write_file(&format!("{}/audit/auth-service/{}.jsonl", data_dir, yesterday), earlier).await;
+
+        let mut config = Config::default();
+        config.audit.signing_key = format!("{}/keys/audit.pem", data_dir);
+        let login = |config: Config| async move {
+            let (app, _, _) = test_app(data_dir, &config).await;
+            let tokens = post_json(&app, "/api/auth/login", credentials("anna@example.com")).await;
+            assert_eq!(tokens["success"], true);
+        };
+        login(config.clone()).await;
+
+        // Closed with a checkpoint over the last record, signed with the new key
+        let closed = tokio::fs::read_to_string(format!("{}/audit/auth-service/{}.jsonl", data_dir, yesterday)).await.unwrap();
+        let checkpoint_line = closed.lines().last().unwrap();
+        let checkpoint: Value = serde_json::from_str(checkpoint_line).unwrap();
+        assert_eq!(checkpoint["checkpoint"]["records"], 2);
+        assert_eq!(checkpoint["prev_hash"], last_hash.as_str());
+        let public_key = tokio::fs::read_to_string(format!("{}.pub", config.audit.signing_key)).await.unwrap();
+        let public_key = STANDARD.decode(public_key.trim()).unwrap();
+        let signature = STANDARD.decode(checkpoint["signature"].as_str().unwrap()).unwrap();
+        let message = checkpoint_message("auth-service", yesterday, 2, &last_hash);
+        UnparsedPublicKey::new(&ED25519, &public_key).verify(message.as_bytes(), &signature).unwrap();
+        let forged = checkpoint_message("auth-service", yesterday, 1, &last_hash);
+        assert!(UnparsedPublicKey::new(&ED25519, &public_key).verify(forged.as_bytes(), &signature).is_err());
+
+        // Today's records continue the chain, across a restart too
+        login(config.clone()).await;
+        let log = tokio::fs::read_to_string(format!("{}/audit/auth-service/{}.jsonl", data_dir, today)).await.unwrap();
+        let mut previous = hash_line(checkpoint_line);
+        let mut count = 0;
+        for line in log.lines() {
+            let record: Value = serde_json::from_str(line).unwrap();
+            assert_eq!(record["prev_hash"], previous.as_str());
+            previous = hash_line(line);
+            count += 1;
+        }
+        assert_eq!(count, 2);
+    }
+}
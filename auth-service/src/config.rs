@@ -1,5 +1,10 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::acr::{self, AuthRequirement};
+use crate::mail::Locale;
+use crate::models::SessionPolicy;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -7,6 +12,14 @@ pub struct Config {
     pub instance: InstanceConfig,
     pub security: SecurityConfig,
     pub features: FeaturesConfig,
+    pub webauthn: WebAuthnConfig,
+    pub mail: MailConfig,
+    pub federation: FederationConfig,
+    /// Organizations whose members log in against a directory, by org id
+    pub ldap: HashMap<String, LdapDirectoryConfig>,
+    pub saml: SamlConfig,
+    pub oidc: OidcConfig,
+    pub audit: AuditConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -20,10 +33,62 @@ pub struct InstanceConfig {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SecurityConfig {
-    pub password_min_length: u32,
     pub access_token_ttl: u64,
     pub refresh_token_ttl: u64,
     pub require_mfa: bool,
+    /// Secret salt for pairwise subject identifiers; required once any
+    /// client uses `subject_type = "pairwise"`.
+    pub pairwise_salt: Option<String>,
+    /// Lifetime in seconds of the `mfa_session` handed out after the
+    /// password step, and of an unconfirmed TOTP enrollment.
+    pub mfa_session_ttl: u64,
+    /// Wrong codes accepted per `mfa_session` before it is burned.
+    pub mfa_max_attempts: u32,
+    /// Number of one-time recovery codes issued with a TOTP factor.
+    pub mfa_recovery_codes: usize,
+    /// Lifetime in seconds of a self-service password reset link.
+    pub password_reset_ttl: u64,
+    /// Lifetime in seconds of the link confirming a new account's email.
+    pub email_verification_ttl: u64,
+    /// Cost of new password hashes. Stored hashes with other parameters,
+    /// or in a legacy scheme, are replaced at the user's next login.
+    pub argon2: Argon2Config,
+    pub lockout: LockoutConfig,
+    pub login_throttle: LoginThrottleConfig,
+    /// Session limits; organizations and clients override them per field
+    pub sessions: SessionPolicy,
+    /// Login strength and age demanded by scopes, e.g. `grades:write`, on
+    /// top of what the client demands
+    pub scope_requirements: HashMap<String, AuthRequirement>,
+}
+
+/// Temporary lock of an account after repeated wrong passwords.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockoutConfig {
+    /// Consecutive failures that lock the account
+    pub max_failures: u32,
+    /// Seconds of the first lock; every further failure doubles it
+    pub base_duration: u64,
+    /// Upper bound in seconds for a lock, and how long a streak of
+    /// failures is remembered
+    pub max_duration: u64,
+}
+
+/// Login attempts accepted per client address.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoginThrottleConfig {
+    /// Attempts possible in quick succession
+    pub burst: u32,
+    /// Attempts regained per minute
+    pub per_minute: u32,
+}
+
+/// Argon2id cost parameters, see RFC 9106 section 4.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Argon2Config {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -32,10 +97,262 @@ pub struct FeaturesConfig {
     pub allow_password_reset: bool,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebAuthnConfig {
+    /// Relying party ID: the registrable domain passkeys are bound to
+    pub rp_id: String,
+    /// Origin the login page is served from, as the browser reports it
+    pub origin: String,
+    /// Seconds a registration or login challenge stays valid
+    pub challenge_ttl: u64,
+}
+
+/// Upstream OpenID Connect providers users can log in with.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FederationConfig {
+    #[serde(default)]
+    pub providers: Vec<UpstreamProviderConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpstreamProviderConfig {
+    /// Short name used in URLs, e.g. `microsoft`
+    pub id: String,
+    /// Label of the login button
+    pub name: String,
+    /// Issuer whose discovery document describes the provider
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    #[serde(default = "default_upstream_scopes")]
+    pub scopes: Vec<String>,
+    /// Organization new users are created in at their first login; without
+    /// it only existing accounts can use the provider
+    pub provision_org: Option<String>,
+    /// Treat the email address as verified even without `email_verified`.
+    /// Only for providers that vouch for every address they issue, such as
+    /// a school's own Entra ID tenant.
+    #[serde(default)]
+    pub trust_email: bool,
+    #[serde(default)]
+    pub claims: UpstreamClaimMapping,
+}
+
+/// Which upstream ID token claims fill the local account.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UpstreamClaimMapping {
+    pub email: String,
+    pub email_verified: String,
+    pub given_name: String,
+    pub family_name: String,
+    /// Local registry claim => upstream claim, copied at provisioning
+    pub extra: HashMap<String, String>,
+}
+
+impl Default for UpstreamClaimMapping {
+    fn default() -> Self {
+        Self {
+            email: "email".to_string(),
+            email_verified: "email_verified".to_string(),
+            given_name: "given_name".to_string(),
+            family_name: "family_name".to_string(),
+            extra: HashMap::new(),
+        }
+    }
+}
+
+/// An LDAP or Active Directory server that checks the passwords of an
+/// organization's members with a bind in place of `password_hash`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LdapDirectoryConfig {
+    /// `ldap://` or `ldaps://` URL of the server
+    pub url: String,
+    /// Upgrade `ldap://` connections with StartTLS
+    #[serde(default)]
+    pub starttls: bool,
+    /// DN to bind as, e.g. `uid={username},ou=people,dc=example,dc=org`, or
+    /// `{email}` for Active Directory user principal names. `{username}` is
+    /// the part of the login address before the `@`.
+    pub bind_dn: String,
+    /// Where the entry of the bound user is looked up
+    pub search_base: String,
+    /// Filter for that entry, with the same placeholders as `bind_dn`
+    #[serde(default = "default_ldap_search_filter")]
+    pub search_filter: String,
+    /// Addresses in these domains belong to the directory even before their
+    /// first login, which creates the account
+    #[serde(default)]
+    pub email_domains: Vec<String>,
+    #[serde(default)]
+    pub attributes: LdapAttributeMapping,
+    /// Seconds to wait for the server
+    #[serde(default = "default_ldap_timeout")]
+    pub timeout: u64,
+}
+
+/// Which directory attributes fill the local account at every login.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LdapAttributeMapping {
+    pub given_name: String,
+    pub family_name: String,
+    /// Local registry claim => directory attribute
+    pub claims: HashMap<String, String>,
+}
+
+impl Default for LdapAttributeMapping {
+    fn default() -> Self {
+        Self {
+            given_name: "givenName".to_string(),
+            family_name: "sn".to_string(),
+            claims: HashMap::new(),
+        }
+    }
+}
+
+impl LdapDirectoryConfig {
+    /// Whether `email` belongs to this directory by its domain.
+    pub fn covers(&self, email: &str) -> bool {
+        email.rsplit_once('@').is_some_and(|(_, domain)| {
+            self.email_domains.iter().any(|d| d.eq_ignore_ascii_case(domain))
+        })
+    }
+}
+
+/// This instance as SAML 2.0 identity provider.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SamlConfig {
+    /// EntityID; by default the metadata URL below `instance.issuer`
+    #[serde(default)]
+    pub entity_id: Option<String>,
+    /// PEM certificate and RSA or P-256 private key that sign assertions.
+    /// A self-signed pair is created when both files are missing.
+    pub certificate: String,
+    pub private_key: String,
+    /// Seconds an assertion is accepted for
+    #[serde(default = "default_assertion_lifetime")]
+    pub assertion_lifetime: u64,
+}
+
+/// This instance as OpenID provider.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OidcConfig {
+    /// PEM P-256 private key (PKCS#8) that signs ID tokens; created when
+    /// missing. Clients find its public key at `/oauth2/jwks`.
+    pub signing_key: String,
+}
+
+/// Tamper evidence of the audit log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditConfig {
+    /// PEM Ed25519 private key that signs the checkpoint closing each day's
+    /// file. Created when missing, with its public key next to it as
+    /// `<signing_key>.pub` for `auth-ops audit verify`.
+    pub signing_key: String,
+}
+
+fn default_assertion_lifetime() -> u64 {
+    300
+}
+
+fn default_ldap_search_filter() -> String {
+    "(mail={email})".to_string()
+}
+
+fn default_ldap_timeout() -> u64 {
+    5
+}
+
+fn default_upstream_scopes() -> Vec<String> {
+    vec!["openid".to_string(), "email".to_string(), "profile".to_string()]
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailConfig {
+    /// Sender, e.g. `"Schule Auth <noreply@example.com>"`
+    pub from: String,
+    /// Locale for messages queued without one
+    pub default_locale: Locale,
+    /// Seconds between outbox scans
+    pub poll_interval: u64,
+    /// Delivery attempts before a message is moved to `mail/failed`
+    pub max_attempts: u32,
+    /// Seconds before the first retry; doubles with every further attempt
+    pub retry_delay: u64,
+    /// Days a message given up on is kept in `mail/failed`
+    pub failed_retention_days: u64,
+    pub transport: MailTransportConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum MailTransportConfig {
+    Smtp {
+        host: String,
+        port: u16,
+        security: SmtpSecurity,
+        username: Option<String>,
+        password: Option<String>,
+    },
+    /// Deliver into a local Maildir instead of sending, for development
+    Maildir { path: String },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// Implicit TLS, usually port 465
+    Tls,
+    /// Plain connection upgraded with STARTTLS, usually port 587
+    Starttls,
+    /// Unencrypted, only for a relay on localhost
+    None,
+}
+
 impl Config {
     pub async fn load(path: &str) -> Result<Self> {
         let content = tokio::fs::read_to_string(path).await?;
-        let config: Config = toml::from_str(&content)?;
+        let raw: toml::Value = toml::from_str(&content)?;
+        // Ignoring it would silently lower the minimum to the policy's default
+        if raw.get("security").and_then(|s| s.get("password_min_length")).is_some() {
+            anyhow::bail!(
+                "security.password_min_length is no longer supported; set min_length in the password \
+                 section of security_policy.json in the data directory instead"
+            );
+        }
+        let config: Config = raw.try_into()?;
+        config.security.argon2.params().context("Invalid [security.argon2] settings")?;
+        let mut provider_ids = std::collections::HashSet::new();
+        for provider in &config.federation.providers {
+            if provider.id.is_empty() || !provider.id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                anyhow::bail!("Invalid federation provider id {:?}", provider.id);
+            }
+            if !provider_ids.insert(provider.id.as_str()) {
+                anyhow::bail!("Federation provider {} is configured twice", provider.id);
+            }
+        }
+        for (org, directory) in &config.ldap {
+            if !directory.url.starts_with("ldap://") && !directory.url.starts_with("ldaps://") {
+                anyhow::bail!("LDAP URL for org {} must start with ldap:// or ldaps://", org);
+            }
+            if !directory.bind_dn.contains("{username}") && !directory.bind_dn.contains("{email}") {
+                anyhow::bail!("LDAP bind_dn for org {} needs a {{username}} or {{email}} placeholder", org);
+            }
+        }
+        if let Some(problem) = config.security.sessions.problem() {
+            anyhow::bail!("Invalid [security.sessions] settings: {}", problem);
+        }
+        for (scope, requirement) in &config.security.scope_requirements {
+            if let Some(min_acr) = requirement.min_acr.as_deref().filter(|a| !acr::is_known_acr(a)) {
+                anyhow::bail!(
+                    "Unknown min_acr {:?} for scope {}; expected one of {:?}",
+                    min_acr,
+                    scope,
+                    acr::ACR_VALUES
+                );
+            }
+        }
         Ok(config)
     }
 }
@@ -52,15 +369,86 @@ impl Default for Config {
                 admin_client_url: "https://localhost:8445/".to_string(),
             },
             security: SecurityConfig {
-                password_min_length: 12,
                 access_token_ttl: 3600,      // 1 hour
                 refresh_token_ttl: 2592000,  // 30 days
                 require_mfa: false,
+                pairwise_salt: None,
+                mfa_session_ttl: 300,        // 5 minutes
+                mfa_max_attempts: 5,
+                mfa_recovery_codes: 10,
+                password_reset_ttl: 1800,
+                email_verification_ttl: 172800,
+                argon2: Argon2Config {
+                    memory_kib: 19456,
+                    iterations: 2,
+                    parallelism: 1,
+                },
+                lockout: LockoutConfig {
+                    max_failures: 5,
+                    base_duration: 60,
+                    max_duration: 3600,
+                },
+                login_throttle: LoginThrottleConfig {
+                    burst: 20,
+                    per_minute: 10,
+                },
+                sessions: SessionPolicy::default(),
+                scope_requirements: HashMap::new(),
             },
             features: FeaturesConfig {
                 allow_registration: false,
                 allow_password_reset: true,
             },
+            webauthn: WebAuthnConfig {
+                rp_id: "localhost".to_string(),
+                origin: "https://localhost:8443".to_string(),
+                challenge_ttl: 300,
+            },
+            mail: MailConfig {
+                from: "Auth Service <noreply@localhost>".to_string(),
+                default_locale: Locale::De,
+                poll_interval: 10,
+                max_attempts: 8,
+                retry_delay: 60,
+                failed_retention_days: 30,
+                transport: MailTransportConfig::Maildir {
+                    path: "./data/mail/maildir".to_string(),
+                },
+            },
+            federation: FederationConfig::default(),
+            ldap: HashMap::new(),
+            saml: SamlConfig {
+                entity_id: None,
+                certificate: "./certs/saml-cert.pem".to_string(),
+                private_key: "./certs/saml-key.pem".to_string(),
+                assertion_lifetime: 300,
+            },
+            oidc: OidcConfig {
+                signing_key: "./certs/oidc-signing-key.pem".to_string(),
+            },
+            audit: AuditConfig {
+                signing_key: "./certs/audit-signing-key.pem".to_string(),
+            },
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{write_file, TempDir};
+
+    #[tokio::test]
+    async fn test_removed_password_min_length_is_refused() {
+        let dir = TempDir::new("config");
+        let path = format!("{}/config.toml", dir.path());
+        let mut config = toml::Value::try_from(Config::default()).unwrap();
+        write_file(&path, toml::to_string(&config).unwrap()).await;
+        assert!(Config::load(&path).await.is_ok());
+
+        config["security"].as_table_mut().unwrap().insert("password_min_length".to_string(), toml::Value::Integer(12));
+        write_file(&path, toml::to_string(&config).unwrap()).await;
+        let error = Config::load(&path).await.unwrap_err();
+        assert!(error.to_string().contains("security_policy.json"), "{:#}", error);
+    }
+}
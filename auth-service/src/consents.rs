@@ -0,0 +1,103 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
+
+use crate::sessions::{load_list, save_list};
+
+/// A client a user let act on their behalf: recorded whenever the token
+/// endpoint issues tokens for it. Revoking it refuses refresh tokens from
+/// before the revocation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Consent {
+    pub client_id: String,
+    pub scope: String,
+    #[serde(with = "time::serde::iso8601")]
+    pub granted_at: OffsetDateTime,
+    #[serde(with = "time::serde::iso8601")]
+    pub last_used_at: OffsetDateTime,
+    #[serde(default, with = "time::serde::iso8601::option")]
+    pub revoked_at: Option<OffsetDateTime>,
+}
+
+impl Consent {
+    pub fn is_active(&self) -> bool {
+        self.revoked_at.is_none_or(|revoked| self.granted_at > revoked)
+    }
+}
+
+/// Consents per user in `consents/{user_id}.json`.
+#[derive(Debug)]
+pub struct ConsentStore {
+    dir: PathBuf,
+    update: tokio::sync::Mutex<()>,
+}
+
+impl ConsentStore {
+    pub fn new(data_dir: &str) -> Self {
+        Self {
+            dir: Path::new(data_dir).join("consents"),
+            update: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Note tokens for `client_id` with `scope`, granting consent anew if it
+    /// was revoked.
+    pub async fn record_use(&self, user_id: &str, client_id: &str, scope: &str, now: OffsetDateTime) -> Result<()> {
+        let _guard = self.update.lock().await;
+        let path = self.path_for(user_id);
+        let mut consents: Vec<Consent> = load_list(&path).await?;
+        match consents.iter_mut().find(|c| c.client_id == client_id) {
+            Some(consent) => {
+                if !consent.is_active() {
+                    consent.granted_at = now;
+                }
+                consent.scope = scope.to_string();
+                consent.last_used_at = now;
+            }
+            None => consents.push(Consent {
+                client_id: client_id.to_string(),
+                scope: scope.to_string(),
+                granted_at: now,
+                last_used_at: now,
+                revoked_at: None,
+            }),
+        }
+        save_list(&path, &consents).await
+    }
+
+    /// Whether a refresh token issued at `iat` may still be used by
+    /// `client_id`. Like a password change, a revocation covers tokens
+    /// from its own second.
+    pub async fn allows_refresh(&self, user_id: &str, client_id: &str, iat: u64) -> Result<bool> {
+        let consents: Vec<Consent> = load_list(&self.path_for(user_id)).await?;
+        Ok(!consents.iter().any(|c| {
+            c.client_id == client_id && c.revoked_at.is_some_and(|revoked| iat as i64 <= revoked.unix_timestamp())
+        }))
+    }
+
+    /// Active consents of `user_id`, most recently used first.
+    pub async fn list(&self, user_id: &str) -> Result<Vec<Consent>> {
+        let mut consents: Vec<Consent> = load_list(&self.path_for(user_id)).await?;
+        consents.retain(Consent::is_active);
+        consents.sort_by_key(|c| std::cmp::Reverse(c.last_used_at));
+        Ok(consents)
+    }
+
+    /// Withdraw the consent for `client_id`; `false` if there was none.
+    pub async fn revoke(&self, user_id: &str, client_id: &str, now: OffsetDateTime) -> Result<bool> {
+        let _guard = self.update.lock().await;
+        let path = self.path_for(user_id);
+        let mut consents: Vec<Consent> = load_list(&path).await?;
+        let Some(consent) = consents.iter_mut().find(|c| c.client_id == client_id && c.is_active()) else {
+            return Ok(false);
+        };
+        consent.revoked_at = Some(now);
+        save_list(&path, &consents).await?;
+        Ok(true)
+    }
+
+    fn path_for(&self, user_id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", user_id))
+    }
+}
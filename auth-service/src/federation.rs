@@ -0,0 +1,505 @@
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use rand::RngCore;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{config::UpstreamProviderConfig, models::LoginResponse};
+
+/// Time the user has at the upstream provider before the login is dropped
+const PENDING_LOGIN_TTL: Duration = Duration::from_secs(600);
+/// Time the login page has to pick up the result of a federated login
+const HANDOFF_TTL: Duration = Duration::from_secs(60);
+/// Discovery documents and keys are fetched again after this long, or
+/// earlier when an ID token names a key that is not known yet
+const METADATA_TTL: Duration = Duration::from_secs(3600);
+
+/// Logins through upstream OpenID Connect providers: their metadata, the
+/// authorization requests in flight and finished logins waiting for the
+/// login page. Everything is kept in memory only.
+pub struct FederationState {
+    http: reqwest::Client,
+    metadata: Mutex<HashMap<String, ProviderMetadata>>,
+    pending: Mutex<HashMap<String, PendingLogin>>,
+    handoffs: Mutex<HashMap<String, Handoff>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Clone)]
+struct ProviderMetadata {
+    discovery: Discovery,
+    keys: JwkSet,
+    fetched_at: Instant,
+}
+
+/// An authorization request sent to a provider, keyed by its `state`.
+#[derive(Debug)]
+pub struct PendingLogin {
+    pub provider: String,
+    nonce: String,
+    code_verifier: String,
+    /// Query string of the login page the user started from
+    pub return_to: String,
+    expires_at: Instant,
+}
+
+struct Handoff {
+    response: LoginResponse,
+    expires_at: Instant,
+}
+
+/// The person an upstream provider vouched for, with the configured claim
+/// mapping applied.
+#[derive(Debug, Clone)]
+pub struct UpstreamIdentity {
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+    /// Local claim name to upstream value, for the mapped claims present
+    pub claims: HashMap<String, Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+impl FederationState {
+    pub fn new() -> Result<Self> {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .context("Failed to build HTTP client for upstream providers")?;
+
+        Ok(Self {
+            http,
+            metadata: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+            handoffs: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// URL at `provider` to send the user to, remembering the request until
+    /// the user comes back to `redirect_uri`.
+    pub async fn authorization_url(
+        &self,
+        provider: &UpstreamProviderConfig,
+        redirect_uri: &str,
+        return_to: String,
+    ) -> Result<String> {
+        let metadata = self.metadata(provider, false).await?;
+
+        let state = random_token();
+        let nonce = random_token();
+        let code_verifier = random_token();
+        let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+
+        let url = reqwest::Url::parse_with_params(
+            &metadata.discovery.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", provider.client_id.as_str()),
+                ("redirect_uri", redirect_uri),
+                ("scope", provider.scopes.join(" ").as_str()),
+                ("state", state.as_str()),
+                ("nonce", nonce.as_str()),
+                ("code_challenge", code_challenge.as_str()),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .with_context(|| format!("Invalid authorization endpoint of provider {}", provider.id))?;
+
+        let now = Instant::now();
+        let mut pending = lock(&self.pending);
+        pending.retain(|_, login| login.expires_at > now);
+        pending.insert(state, PendingLogin {
+            provider: provider.id.clone(),
+            nonce,
+            code_verifier,
+            return_to,
+            expires_at: now + PENDING_LOGIN_TTL,
+        });
+
+        Ok(url.to_string())
+    }
+
+    /// Remove and return the request `state` belongs to if it is still valid.
+    pub fn take_pending(&self, state: &str) -> Option<PendingLogin> {
+        lock(&self.pending)
+            .remove(state)
+            .filter(|login| login.expires_at > Instant::now())
+    }
+
+    /// Redeem the authorization `code` the provider returned for `login` and
+    /// verify the ID token it answers with.
+    pub async fn exchange(
+        &self,
+        provider: &UpstreamProviderConfig,
+        login: &PendingLogin,
+        code: &str,
+        redirect_uri: &str,
+    ) -> Result<UpstreamIdentity> {
+        let metadata = self.metadata(provider, false).await?;
+
+        let response = self.http
+            .post(&metadata.discovery.token_endpoint)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", redirect_uri),
+                ("client_id", provider.client_id.as_str()),
+                ("client_secret", provider.client_secret.as_str()),
+                ("code_verifier", login.code_verifier.as_str()),
+            ])
+            .send()
+            .await
+            .context("Token request failed")?;
+        if !response.status().is_success() {
+            bail!("Token endpoint answered {}", response.status());
+        }
+        let tokens: TokenResponse = response.json().await.context("Invalid token response")?;
+
+        let claims = self.verify_id_token(provider, metadata, &tokens.id_token).await?;
+        if claims.get("nonce").and_then(Value::as_str) != Some(login.nonce.as_str()) {
+            bail!("ID token nonce does not match the authorization request");
+        }
+        map_claims(provider, &claims)
+    }
+
+    /// Keep the result of a finished login for the login page, which picks it
+    /// up with the returned single-use code.
+    pub fn hand_off(&self, response: LoginResponse) -> String {
+        let code = random_token();
+        let now = Instant::now();
+        let mut handoffs = lock(&self.handoffs);
+        handoffs.retain(|_, handoff| handoff.expires_at > now);
+        handoffs.insert(code.clone(), Handoff { response, expires_at: now + HANDOFF_TTL });
+        code
+    }
+
+    pub fn take_handoff(&self, code: &str) -> Option<LoginResponse> {
+        lock(&self.handoffs)
+            .remove(code)
+            .filter(|handoff| handoff.expires_at > Instant::now())
+            .map(|handoff| handoff.response)
+    }
+
+    async fn verify_id_token(
+        &self,
+        provider: &UpstreamProviderConfig,
+        mut metadata: ProviderMetadata,
+        id_token: &str,
+    ) -> Result<Map<String, Value>> {
+        let header = jsonwebtoken::decode_header(id_token).context("Malformed ID token")?;
+        // Shared-secret algorithms would let anyone holding the client
+        // secret mint tokens; providers sign with their own keys
+        if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+            bail!("ID token signed with {:?}", header.alg);
+        }
+
+        let find_key = |keys: &JwkSet| match &header.kid {
+            Some(kid) => keys.find(kid).cloned(),
+            None => keys.keys.first().cloned(),
+        };
+        let jwk = match find_key(&metadata.keys) {
+            Some(jwk) => jwk,
+            None => {
+                // Keys rotated since they were fetched
+                metadata = self.metadata(provider, true).await?;
+                find_key(&metadata.keys).ok_or_else(|| anyhow!("ID token signed with an unknown key"))?
+            }
+        };
+        let key = DecodingKey::from_jwk(&jwk).context("Unusable provider key")?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&metadata.discovery.issuer]);
+        validation.set_audience(&[&provider.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        let token = jsonwebtoken::decode::<Map<String, Value>>(id_token, &key, &validation)
+            .context("ID token rejected")?;
+        Ok(token.claims)
+    }
+
+    /// Discovery document and keys of `provider`, from the cache unless it is
+    /// stale or `refresh` is set.
+    async fn metadata(&self, provider: &UpstreamProviderConfig, refresh: bool) -> Result<ProviderMetadata> {
+        if !refresh {
+            let cached = lock(&self.metadata).get(&provider.id).cloned();
+            if let Some(metadata) = cached.filter(|m| m.fetched_at.elapsed() < METADATA_TTL) {
+                return Ok(metadata);
+            }
+        }
+
+        let discovery_url = format!("{}/.well-known/openid-configuration", provider.issuer.trim_end_matches('/'));
+        let discovery: Discovery = self.get_json(&discovery_url).await?;
+        if discovery.issuer.trim_end_matches('/') != provider.issuer.trim_end_matches('/') {
+            bail!("Provider {} claims to be issuer {}", provider.id, discovery.issuer);
+        }
+        let keys: JwkSet = self.get_json(&discovery.jwks_uri).await?;
+
+        let metadata = ProviderMetadata { discovery, keys, fetched_at: Instant::now() };
+        lock(&self.metadata).insert(provider.id.clone(), metadata.clone());
+        Ok(metadata)
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T> {
+        let response = self.http.get(url).send().await.with_context(|| format!("Request to {} failed", url))?;
+        if !response.status().is_success() {
+            bail!("{} answered {}", url, response.status());
+        }
+        response.json().await.with_context(|| format!("Invalid JSON from {}", url))
+    }
+}
+
+fn map_claims(provider: &UpstreamProviderConfig, claims: &Map<String, Value>) -> Result<UpstreamIdentity> {
+    let mapping = &provider.claims;
+    let string = |name: &str| {
+        claims.get(name)
+            .and_then(Value::as_str)
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+    };
+
+    let subject = string("sub").ok_or_else(|| anyhow!("ID token without subject"))?;
+    // Some providers send the flag as a string
+    let email_verified = match claims.get(&mapping.email_verified) {
+        Some(Value::Bool(verified)) => *verified,
+        Some(Value::String(verified)) => verified == "true",
+        _ => false,
+    };
+    let extra = mapping.extra.iter()
+        .filter_map(|(local, upstream)| Some((local.clone(), claims.get(upstream)?.clone())))
+        .collect();
+
+    Ok(UpstreamIdentity {
+        subject,
+        email: string(&mapping.email),
+        email_verified,
+        given_name: string(&mapping.given_name),
+        family_name: string(&mapping.family_name),
+        claims: extra,
+    })
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Upstream provider for tests: serves discovery, keys and a token endpoint
+/// on a local port and signs ES256 ID tokens for the codes a test issues.
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use axum::{extract::{Form, State}, http::StatusCode, routing::{get, post}, Json, Router};
+    use jsonwebtoken::{EncodingKey, Header};
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use serde_json::json;
+    use std::sync::Arc;
+
+    use crate::config::UpstreamClaimMapping;
+
+    pub const CLIENT_ID: &str = "um-oic";
+    pub const CLIENT_SECRET: &str = "upstream-secret";
+    const KEY_ID: &str = "mock-1";
+
+    struct Provider {
+        issuer: String,
+        key: EncodingKey,
+        jwk: Value,
+        /// Authorization codes not redeemed yet
+        codes: Mutex<HashMap<String, Grant>>,
+    }
+
+    struct Grant {
+        claims: Map<String, Value>,
+        code_challenge: String,
+    }
+
+    pub struct MockProvider {
+        provider: Arc<Provider>,
+    }
+
+    impl MockProvider {
+        pub async fn start() -> Self {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let issuer = format!("http://{}", listener.local_addr().unwrap());
+
+            let rng = SystemRandom::new();
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+            let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng).unwrap();
+            // Uncompressed point: 0x04 || x || y
+            let point = key_pair.public_key().as_ref();
+            let jwk = json!({
+                "kty": "EC",
+                "crv": "P-256",
+                "alg": "ES256",
+                "use": "sig",
+                "kid": KEY_ID,
+                "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+                "y": URL_SAFE_NO_PAD.encode(&point[33..65])
+            });
+
+            let provider = Arc::new(Provider {
+                issuer,
+                key: EncodingKey::from_ec_der(pkcs8.as_ref()),
+                jwk,
+                codes: Mutex::new(HashMap::new()),
+            });
+            let app = Router::new()
+                .route("/.well-known/openid-configuration", get(discovery))
+                .route("/jwks", get(jwks))
+                .route("/token", post(token))
+                .with_state(provider.clone());
+            tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+            Self { provider }
+        }
+
+        /// Configuration for this provider under `id`.
+        pub fn config(&self, id: &str, provision_org: Option<&str>) -> UpstreamProviderConfig {
+            UpstreamProviderConfig {
+                id: id.to_string(),
+                name: "Mock".to_string(),
+                issuer: self.provider.issuer.clone(),
+                client_id: CLIENT_ID.to_string(),
+                client_secret: CLIENT_SECRET.to_string(),
+                scopes: vec!["openid".to_string(), "email".to_string()],
+                provision_org: provision_org.map(str::to_string),
+                trust_email: false,
+                claims: UpstreamClaimMapping::default(),
+            }
+        }
+
+        /// Log in at the provider for an `authorization_url` it was sent to:
+        /// the `state` to return with and a code for an ID token with `claims`.
+        pub fn authorize(&self, authorization_url: &str, claims: Value) -> (String, String) {
+            let url = reqwest::Url::parse(authorization_url).unwrap();
+            let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+            assert_eq!(params["client_id"], CLIENT_ID);
+            assert_eq!(params["code_challenge_method"], "S256");
+
+            let mut claims = claims.as_object().unwrap().clone();
+            claims.insert("nonce".to_string(), json!(params["nonce"]));
+            let code = random_token();
+            let code_challenge = params["code_challenge"].clone();
+            lock(&self.provider.codes).insert(code.clone(), Grant { claims, code_challenge });
+            (params["state"].clone(), code)
+        }
+    }
+
+    async fn discovery(State(provider): State<Arc<Provider>>) -> Json<Value> {
+        let issuer = &provider.issuer;
+        Json(json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+            "jwks_uri": format!("{}/jwks", issuer)
+        }))
+    }
+
+    async fn jwks(State(provider): State<Arc<Provider>>) -> Json<Value> {
+        Json(json!({ "keys": [provider.jwk] }))
+    }
+
+    async fn token(
+        State(provider): State<Arc<Provider>>,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> Result<Json<Value>, StatusCode> {
+        if form.get("client_secret").map(String::as_str) != Some(CLIENT_SECRET) {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        let code = form.get("code").ok_or(StatusCode::BAD_REQUEST)?;
+        let Grant { mut claims, code_challenge } = lock(&provider.codes).remove(code).ok_or(StatusCode::BAD_REQUEST)?;
+        let verifier = form.get("code_verifier").ok_or(StatusCode::BAD_REQUEST)?;
+        if URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())) != code_challenge {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+
+        let now = time::OffsetDateTime::now_utc().unix_timestamp();
+        claims.insert("iss".to_string(), json!(provider.issuer));
+        claims.insert("aud".to_string(), json!(CLIENT_ID));
+        claims.insert("iat".to_string(), json!(now));
+        claims.insert("exp".to_string(), json!(now + 300));
+
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some(KEY_ID.to_string());
+        let id_token = jsonwebtoken::encode(&header, &claims, &provider.key).unwrap();
+        Ok(Json(json!({ "access_token": "opaque", "token_type": "Bearer", "id_token": id_token })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UpstreamClaimMapping;
+    use serde_json::json;
+
+    fn provider() -> UpstreamProviderConfig {
+        let mut claims = UpstreamClaimMapping::default();
+        claims.extra.insert("employee_id".to_string(), "employeeId".to_string());
+        UpstreamProviderConfig {
+            id: "corp".to_string(),
+            name: "Corp".to_string(),
+            issuer: "https://idp.example.com".to_string(),
+            client_id: "um-oic".to_string(),
+            client_secret: "secret".to_string(),
+            scopes: vec!["openid".to_string()],
+            provision_org: None,
+            trust_email: false,
+            claims,
+        }
+    }
+
+    #[test]
+    fn test_claim_mapping() {
+        let claims = json!({
+            "sub": "abc",
+            "email": " anna@example.com ",
+            "email_verified": "true",
+            "given_name": "Anna",
+            "employeeId": 42
+        });
+        let identity = map_claims(&provider(), claims.as_object().unwrap()).unwrap();
+        assert_eq!(identity.subject, "abc");
+        assert_eq!(identity.email.as_deref(), Some("anna@example.com"));
+        assert!(identity.email_verified);
+        assert_eq!(identity.family_name, None);
+        assert_eq!(identity.claims["employee_id"], json!(42));
+
+        assert!(map_claims(&provider(), json!({"email": "x@example.com"}).as_object().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_handoffs_are_single_use() {
+        let state = FederationState::new().unwrap();
+        let code = state.hand_off(LoginResponse::failed());
+        assert!(state.take_handoff(&code).is_some());
+        assert!(state.take_handoff(&code).is_none());
+        assert!(state.take_pending("unknown").is_none());
+    }
+}
@@ -0,0 +1,620 @@
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::Json,
+};
+use serde_json::{json, Map, Value};
+use std::{collections::HashMap, sync::Arc};
+use time::OffsetDateTime;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::{
+    audit,
+    config::Config,
+    handlers::{
+        auth::{mfa_requirement, record_password_failure},
+        oauth::bearer_user,
+    },
+    jwt::JwtService,
+    mail::templates::MailTemplate,
+    models::{
+        ChangePasswordRequest, ClaimDefinition, Claims, MfaPurpose, UpdateAccountClaimsRequest,
+        User,
+    },
+    password::{self, PasswordCheck},
+    runtime::Runtime,
+    sessions::ClientInfo,
+    storage::FileStorage,
+};
+
+type AppState = (Arc<RwLock<FileStorage>>, Arc<JwtService>, Config, Arc<Runtime>);
+
+/// Seconds since the last login within which a factor may be removed
+const REAUTHENTICATION_WINDOW: u64 = 600;
+
+/// Profile, claims the user may edit and the state of their factors.
+pub async fn profile(
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    let storage_guard = storage.read().await;
+    let (_, user) = account_user(&headers, &storage_guard, &jwt_service, &runtime, &config).await?;
+
+    let editable: Map<String, Value> = editable_claims(&storage_guard, &user)
+        .into_iter()
+        .map(|(key, definition)| {
+            let value = user.claims.get(&key).cloned().unwrap_or(Value::Null);
+            (key, json!({ "definition": definition, "value": value }))
+        })
+        .collect();
+    let passkeys: Vec<Value> = user.webauthn_credentials.iter()
+        .map(|c| json!({
+            "credential_id": c.credential_id,
+            "nickname": c.nickname,
+            "created_at": c.created_at.unix_timestamp(),
+            "last_used_at": c.last_used_at.map(|t| t.unix_timestamp()),
+        }))
+        .collect();
+
+    Ok(Json(json!({
+        "id": user.id,
+        "email": user.email,
+        "first_name": user.first_name,
+        "last_name": user.last_name,
+        "org": user.org,
+        "verified": user.verified,
+        "claims": user.claims,
+        "editable_claims": editable,
+        "password": {
+            "can_change": password_refusal(&user, &config).is_none(),
+            "changed_at": user.password_changed_at.map(|t| t.unix_timestamp()),
+        },
+        "mfa": {
+            "totp": user.mfa_secret.is_some(),
+            "recovery_codes_remaining": user.mfa_recovery_codes.len(),
+            "passkeys": passkeys,
+        },
+    })))
+}
+
+/// Change the password with the current one. Every session ends, this one
+/// included, and the user logs in again with the new password.
+pub async fn change_password(
+    client: ClientInfo,
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<ChangePasswordRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let (_, user) = account_user(&headers, &*storage.read().await, &jwt_service, &runtime, &config).await?;
+
+    if let Some(reason) = password_refusal(&user, &config) {
+        return Ok(refused("change_password", &user, reason));
+    }
+
+    // A locked account fails like a wrong password, as at the login
+    let now = OffsetDateTime::now_utc();
+    let locked = runtime.lockouts.locked_until(&user.id, now).await
+        .map_err(|e| state_error("lockout", e))?
+        .is_some();
+    let check = password::check_password(&request.current_password, &user.password_hash, Some(&config.security.argon2))
+        .map_err(|e| {
+            warn!(
+                service = "auth-service",
+                event = "password_verification_error",
+                user_id = %user.id,
+                error = %e,
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    if locked || check == PasswordCheck::Mismatch {
+        if !locked {
+            record_password_failure(&user, &client, now, &config, &runtime).await?;
+        }
+        return Ok(refused("change_password", &user, "invalid_password"));
+    }
+
+    let violations = storage.read().await
+        .password_violations(&user, &request.new_password)
+        .map_err(|e| {
+            warn!(
+                service = "auth-service",
+                event = "password_policy_failed",
+                error = %format!("{:#}", e)
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    if !violations.is_empty() {
+        return Ok(Json(json!({
+            "success": false,
+            "error": "password_policy",
+            "message": violations.join(". "),
+            "errors": violations
+        })));
+    }
+
+    let password_hash = password::hash_password_with(&request.new_password, &config.security.argon2).map_err(|e| {
+        warn!(
+            service = "auth-service",
+            event = "password_hashing_failed",
+            error = %e
+        );
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let user = storage.write().await
+        .modify_user(&user.id, |u| {
+            u.password_hash = password_hash;
+            u.password_changed_at = Some(now);
+            Ok(())
+        })
+        .await
+        .map_err(|e| state_error("user", e))?;
+
+    let revoked_tokens = runtime.tokens.write().await.revoke_user(&user.id).await
+        .map_err(|e| state_error("tokens", e))?;
+    let ended_sessions = runtime.sessions.revoke_all(&user.id, None).await
+        .map_err(|e| state_error("sessions", e))?;
+    runtime.lockouts.clear(&user.id).await.map_err(|e| state_error("lockout", e))?;
+
+    let params = HashMap::from([("name".to_string(), user.first_name.clone())]);
+    if let Err(e) = runtime.outbox.enqueue(&user.email, MailTemplate::PasswordChanged, None, params).await {
+        warn!(
+            service = "auth-service",
+            event = "password_changed_mail_failed",
+            user_id = %user.id,
+            error = %format!("{:#}", e)
+        );
+    }
+
+    let mut event = audit::event("password_changed", &user, &client);
+    event.metadata.insert("revoked_tokens".to_string(), json!(revoked_tokens));
+    event.metadata.insert("ended_sessions".to_string(), json!(ended_sessions));
+    audit::record(&runtime.audit, &event).await?;
+
+    info!(
+        service = "auth-service",
+        event = "change_password",
+        user_id = %user.id,
+        success = true
+    );
+
+    Ok(Json(json!({
+        "success": true,
+        "message": "Password changed, please log in again"
+    })))
+}
+
+/// Set or, with `null`, remove claims the registry lets users edit.
+pub async fn update_claims(
+    client: ClientInfo,
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<UpdateAccountClaimsRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let mut storage_guard = storage.write().await;
+    let (_, user) = account_user(&headers, &storage_guard, &jwt_service, &runtime, &config).await?;
+
+    let editable = editable_claims(&storage_guard, &user);
+    let mut merged: HashMap<String, Value> = user.claims.iter()
+        .filter(|(key, _)| editable.contains_key(*key))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    for (key, value) in &request.claims {
+        match value {
+            Value::Null => merged.remove(key),
+            value => merged.insert(key.clone(), value.clone()),
+        };
+    }
+
+    // Claims the user cannot set count as given, so only their own
+    // required claims are asked for
+    let registry = storage_guard.get_claims_registry();
+    let mut fixed = fixed_claims(&storage_guard, &user);
+    for key in registry.claims.keys() {
+        if !editable.contains_key(key) && !request.claims.contains_key(key) {
+            fixed.entry(key.clone()).or_insert(Value::Null);
+        }
+    }
+    let errors = registry.registration_violations(&merged, &fixed);
+    if !errors.is_empty() {
+        return Ok(Json(json!({
+            "success": false,
+            "error": "invalid_claims",
+            "message": errors.join(". "),
+            "errors": errors
+        })));
+    }
+
+    let user = storage_guard
+        .modify_user(&user.id, |u| {
+            u.claims.retain(|key, _| !editable.contains_key(key));
+            u.claims.extend(merged);
+            Ok(())
+        })
+        .await
+        .map_err(|e| state_error("user", e))?;
+
+    let mut event = audit::event("account_claims_updated", &user, &client);
+    let mut changed: Vec<&String> = request.claims.keys().collect();
+    changed.sort();
+    event.metadata.insert("claims".to_string(), json!(changed));
+    audit::record(&runtime.audit, &event).await?;
+
+    Ok(Json(json!({
+        "success": true,
+        "claims": user.claims
+    })))
+}
+
+/// Remove the TOTP factor and its recovery codes.
+pub async fn remove_totp(
+    client: ClientInfo,
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    let mut storage_guard = storage.write().await;
+    let (claims, user) = account_user(&headers, &storage_guard, &jwt_service, &runtime, &config).await?;
+    if user.mfa_secret.is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let mut remaining = user.clone();
+    remaining.mfa_secret = None;
+    remaining.mfa_recovery_codes.clear();
+    if let Some(reason) = factor_removal_refusal(&claims, &remaining, &storage_guard, &config) {
+        return Ok(refused("remove_totp", &user, reason));
+    }
+
+    let user = storage_guard
+        .modify_user(&user.id, |u| {
+            u.mfa_secret = None;
+            u.mfa_recovery_codes.clear();
+            Ok(())
+        })
+        .await
+        .map_err(|e| state_error("user", e))?;
+    runtime.mfa.write().await.reset_steps(&user.id);
+
+    audit::record(&runtime.audit, &audit::event("mfa_totp_removed", &user, &client)).await?;
+
+    Ok(Json(json!({ "success": true })))
+}
+
+pub async fn remove_passkey(
+    client: ClientInfo,
+    Path(credential_id): Path<String>,
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    let mut storage_guard = storage.write().await;
+    let (claims, user) = account_user(&headers, &storage_guard, &jwt_service, &runtime, &config).await?;
+    if !user.webauthn_credentials.iter().any(|c| c.credential_id == credential_id) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let mut remaining = user.clone();
+    remaining.webauthn_credentials.retain(|c| c.credential_id != credential_id);
+    if let Some(reason) = factor_removal_refusal(&claims, &remaining, &storage_guard, &config) {
+        return Ok(refused("remove_passkey", &user, reason));
+    }
+
+    let user = storage_guard
+        .modify_user(&user.id, |u| {
+            u.webauthn_credentials.retain(|c| c.credential_id != credential_id);
+            Ok(())
+        })
+        .await
+        .map_err(|e| state_error("user", e))?;
+
+    let mut event = audit::event("passkey_removed", &user, &client);
+    event.metadata.insert("credential_id".to_string(), json!(credential_id));
+    audit::record(&runtime.audit, &event).await?;
+
+    Ok(Json(json!({ "success": true })))
+}
+
+/// Active sessions; the one of the calling token is marked `current`.
+pub async fn sessions(
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Value>>, StatusCode> {
+    let (claims, user) = account_user(&headers, &*storage.read().await, &jwt_service, &runtime, &config).await?;
+
+    let sessions = runtime.sessions.list(&user.id).await.map_err(|e| state_error("sessions", e))?;
+    Ok(Json(sessions.into_iter()
+        .map(|s| {
+            let current = claims.sid.as_deref() == Some(s.id.as_str());
+            let mut value = json!(s);
+            value["current"] = json!(current);
+            value
+        })
+        .collect()))
+}
+
+pub async fn revoke_session(
+    client: ClientInfo,
+    Path(id): Path<String>,
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    headers: HeaderMap,
+) -> Result<StatusCode, StatusCode> {
+    let (_, user) = account_user(&headers, &*storage.read().await, &jwt_service, &runtime, &config).await?;
+
+    if !runtime.sessions.revoke(&user.id, &id).await.map_err(|e| state_error("sessions", e))? {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let mut event = audit::event("session_revoked", &user, &client);
+    event.metadata.insert("session_id".to_string(), json!(id));
+    audit::record(&runtime.audit, &event).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// End every session but the calling one.
+pub async fn revoke_other_sessions(
+    client: ClientInfo,
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    let (claims, user) = account_user(&headers, &*storage.read().await, &jwt_service, &runtime, &config).await?;
+
+    let ended = runtime.sessions.revoke_all(&user.id, claims.sid.as_deref()).await
+        .map_err(|e| state_error("sessions", e))?;
+
+    let mut event = audit::event("sessions_revoked", &user, &client);
+    event.metadata.insert("ended_sessions".to_string(), json!(ended));
+    audit::record(&runtime.audit, &event).await?;
+
+    Ok(Json(json!({ "success": true, "ended_sessions": ended })))
+}
+
+/// Clients the user has used, with their registered names.
+pub async fn consents(
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Value>>, StatusCode> {
+    let storage_guard = storage.read().await;
+    let (_, user) = account_user(&headers, &storage_guard, &jwt_service, &runtime, &config).await?;
+
+    let consents = runtime.consents.list(&user.id).await.map_err(|e| state_error("consents", e))?;
+    Ok(Json(consents.into_iter()
+        .map(|c| {
+            let name = storage_guard.get_client(&c.client_id).map(|client| client.name.clone());
+            let mut value = json!(c);
+            value["client_name"] = json!(name);
+            value
+        })
+        .collect()))
+}
+
+/// Withdraw a client's access: its refresh tokens from before now are
+/// refused and its opaque access tokens stop resolving.
+pub async fn revoke_consent(
+    client: ClientInfo,
+    Path(client_id): Path<String>,
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    headers: HeaderMap,
+) -> Result<StatusCode, StatusCode> {
+    let (_, user) = account_user(&headers, &*storage.read().await, &jwt_service, &runtime, &config).await?;
+
+    let now = OffsetDateTime::now_utc();
+    if !runtime.consents.revoke(&user.id, &client_id, now).await.map_err(|e| state_error("consents", e))? {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let revoked_tokens = runtime.tokens.write().await.revoke_user_client(&user.id, &client_id).await
+        .map_err(|e| state_error("tokens", e))?;
+
+    let mut event = audit::event("consent_revoked", &user, &client);
+    event.metadata.insert("client_id".to_string(), json!(client_id));
+    event.metadata.insert("revoked_tokens".to_string(), json!(revoked_tokens));
+    audit::record(&runtime.audit, &event).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Recent logins and failed attempts, newest first.
+pub async fn logins(
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    let (_, user) = account_user(&headers, &*storage.read().await, &jwt_service, &runtime, &config).await?;
+
+    let logins = runtime.logins.list(&user.id).await.map_err(|e| state_error("logins", e))?;
+    Ok(Json(json!(logins)))
+}
+
+/// The user of a login token from the login page; tokens a client holds on
+/// the user's behalf do not manage the account.
+async fn account_user(
+    headers: &HeaderMap,
+    storage: &FileStorage,
+    jwt_service: &JwtService,
+    runtime: &Runtime,
+    config: &Config,
+) -> Result<(Claims, User), StatusCode> {
+    let (claims, client_id, user) = bearer_user(headers, storage, jwt_service, runtime, config).await?;
+    if client_id.is_some() {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok((claims, user))
+}
+
+/// Registry claims users may set themselves, minus the ones their
+/// organization sets for every member.
+fn editable_claims(storage: &FileStorage, user: &User) -> HashMap<String, ClaimDefinition> {
+    let fixed = fixed_claims(storage, user);
+    storage.get_claims_registry().claims.iter()
+        .filter(|(key, definition)| {
+            definition.default_allowed && definition.admin_only != Some(true) && !fixed.contains_key(*key)
+        })
+        .map(|(key, definition)| (key.clone(), definition.clone()))
+        .collect()
+}
+
+fn fixed_claims(storage: &FileStorage, user: &User) -> HashMap<String, Value> {
+    storage.get_organization(&user.org)
+        .map(|org| org.registration.default_claims.clone())
+        .unwrap_or_default()
+}
+
+/// Why `user` cannot change their password here, if at all.
+fn password_refusal(user: &User, config: &Config) -> Option<&'static str> {
+    if config.ldap.contains_key(&user.org) {
+        Some("directory_account")
+    } else if user.password_hash.is_empty() {
+        Some("no_password")
+    } else {
+        None
+    }
+}
+
+/// Removing a factor needs a recent login, and must not leave the user
+/// short of a second factor the policy requires.
+fn factor_removal_refusal(
+    claims: &Claims,
+    remaining: &User,
+    storage: &FileStorage,
+    config: &Config,
+) -> Option<&'static str> {
+    let now = OffsetDateTime::now_utc().unix_timestamp() as u64;
+    if claims.auth_time.is_none_or(|t| now.saturating_sub(t) > REAUTHENTICATION_WINDOW) {
+        return Some("reauthentication_required");
+    }
+    if mfa_requirement(remaining, storage, config).0 == Some(MfaPurpose::Enroll) {
+        return Some("mfa_required");
+    }
+    None
+}
+
+fn refused(action: &str, user: &User, reason: &str) -> Json<Value> {
+    warn!(
+        service = "auth-service",
+        event = action,
+        user_id = %user.id,
+        success = false,
+        reason = reason
+    );
+    Json(json!({ "success": false, "error": reason }))
+}
+
+fn state_error(state: &str, e: anyhow::Error) -> StatusCode {
+    warn!(
+        service = "auth-service",
+        event = "account_update_failed",
+        state = state,
+        error = %format!("{:#}", e)
+    );
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::routes;
+    use crate::testing::*;
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_account_self_service() {
+        let dir = TempDir::new("account");
+        let data_dir = dir.path();
+        write_user(data_dir, json!({"claims": {"permissions": ["grades"]}})).await;
+        write_file(
+            &format!("{}/claims.json", data_dir),
+            json!({
+                "employee_id": {"type": "string", "items": null, "description": "", "default_allowed": true, "required": null, "sensitive": null, "admin_only": null},
+                "permissions": {"type": "array", "items": {"type": "string"}, "description": "", "default_allowed": false, "required": true, "sensitive": null, "admin_only": true}
+            })
+            .to_string(),
+        )
+        .await;
+        write_client(data_dir, "portal", &["https://portal.example.com/cb"], &["authorization_code", "refresh_token"], json!({"name": "Portal"})).await;
+
+        let mut config = Config::default();
+        config.oidc.signing_key = format!("{}/keys/oidc.pem", data_dir);
+        let (app, _, _) = test_app(data_dir, &config).await;
+        let credentials = credentials("anna@example.com");
+
+        let first = post_json(&app, "/api/auth/login", credentials.clone()).await;
+        let second = post_json(&app, "/api/auth/login", credentials.clone()).await;
+        let token = second["access_token"].as_str().unwrap();
+
+        let (status, profile) = request_as(&app, "GET", "/api/account", None, token).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(profile["email"], "anna@example.com");
+        assert_eq!(profile["password"]["can_change"], true);
+        assert!(profile["editable_claims"].get("employee_id").is_some());
+        assert!(profile["editable_claims"].get("permissions").is_none());
+
+        // Only claims the registry lets users set
+        let (_, updated) = request_as(&app, "PATCH", "/api/account/claims", Some(json!({"claims": {"employee_id": "E-17"}})), token).await;
+        assert_eq!(updated["success"], true);
+        assert_eq!(updated["claims"]["employee_id"], "E-17");
+        assert_eq!(updated["claims"]["permissions"], json!(["grades"]));
+        let (_, refused) = request_as(&app, "PATCH", "/api/account/claims", Some(json!({"claims": {"permissions": []}})), token).await;
+        assert_eq!(refused["success"], false);
+
+        // One session per login, the calling one marked
+        let (_, sessions) = request_as(&app, "GET", "/api/account/sessions", None, token).await;
+        let sessions = sessions.as_array().unwrap().clone();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions.iter().filter(|s| s["current"] == true).count(), 1);
+
+        // Tokens of the first login, used by a client
+        let first_token = first["access_token"].as_str().unwrap();
+        let (status, portal) = client_tokens(&app, "portal", "https://portal.example.com/cb", "openid", first_token).await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, consents) = request_as(&app, "GET", "/api/account/consents", None, token).await;
+        assert_eq!(consents[0]["client_id"], "portal");
+        assert_eq!(consents[0]["client_name"], "Portal");
+
+        // The session records the client and every refresh token issued in it
+        let (_, sessions) = request_as(&app, "GET", "/api/account/sessions", None, token).await;
+        let first_session = sessions.as_array().unwrap().iter().find(|s| s["current"] == false).unwrap().clone();
+        assert_eq!(first_session["client_id"], "portal");
+        assert_eq!(first_session["refresh_tokens"].as_array().unwrap().len(), 2);
+        assert!(first_session["last_seen_at"].is_string());
+
+        // Client tokens do not manage the account
+        let (status, _) = request_as(&app, "GET", "/api/account", None, portal["access_token"].as_str().unwrap()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // Withdrawing the consent refuses the client's refresh tokens
+        let (status, _) = request_as(&app, "DELETE", "/api/account/consents/portal", None, token).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let client_refresh = json!({"grant_type": "refresh_token", "client_id": "portal", "refresh_token": portal["refresh_token"], "scope": "openid"});
+        let (status, _) = post_form(&app, routes::TOKEN, client_refresh).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (_, consents) = request_as(&app, "GET", "/api/account/consents", None, token).await;
+        assert_eq!(consents, json!([]));
+
+        // Ending the other session ends its tokens
+        let (_, ended) = request_as(&app, "DELETE", "/api/account/sessions", None, token).await;
+        assert_eq!(ended["ended_sessions"], 1);
+        let (status, _) = request_as(&app, "GET", "/api/account", None, first["access_token"].as_str().unwrap()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // A wrong current password counts as failed login
+        let change = |current: &str| json!({"current_password": current, "new_password": "a much better passphrase"});
+        let (_, failed) = request_as(&app, "POST", "/api/account/password", Some(change("wrong")), token).await;
+        assert_eq!(failed["error"], "invalid_password");
+        let (_, logins) = request_as(&app, "GET", "/api/account/logins", None, token).await;
+        assert_eq!(logins[0]["success"], false);
+        assert_eq!(logins[0]["reason"], "invalid_password");
+        assert_eq!(logins[1]["success"], true);
+        assert_eq!(logins[1]["methods"], json!(["pwd"]));
+        assert_eq!(logins[1]["ip_address"], "127.0.0.1");
+
+        // Factors only go with a recent login; there is none to remove here
+        let (status, _) = request_as(&app, "DELETE", "/api/account/mfa/totp", None, token).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (_, changed) = request_as(&app, "POST", "/api/account/password", Some(change(PASSWORD)), token).await;
+        assert_eq!(changed["success"], true);
+        let (status, _) = request_as(&app, "GET", "/api/account", None, token).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let login = post_json(&app, "/api/auth/login", json!({"email": "anna@example.com", "password": "a much better passphrase"})).await;
+        assert_eq!(login["success"], true);
+    }
+}
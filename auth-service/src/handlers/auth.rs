@@ -1,27 +1,56 @@
 use axum::{
     extract::{ConnectInfo, State},
-    http::StatusCode,
+    http::{HeaderMap, StatusCode},
     response::Json,
 };
 use serde_json::{json, Value};
-use std::{net::SocketAddr, sync::Arc};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use time::OffsetDateTime;
 use tokio::sync::RwLock;
 use tracing::{info, warn};
 
 use crate::{
+    acr, audit,
     config::Config,
+    handlers::oauth::bearer_user,
     jwt::JwtService,
-    models::{LoginRequest, LoginResponse, UserStatus},
-    password,
+    ldap::{self, BindOutcome, DirectoryEntry},
+    mail::templates::MailTemplate,
+    models::{
+        AuditEvent, Claims, ForgotPasswordRequest, LoginRequest, LoginResponse, MfaPurpose,
+        ResetPasswordRequest, TokenUse, User,
+    },
+    password::{self, PasswordCheck},
+    runtime::Runtime,
+    sessions::{self, ClientInfo, LoginRecord, RefreshTokenLink},
     storage::FileStorage,
 };
 
-type AppState = (Arc<RwLock<FileStorage>>, Arc<JwtService>, Config);
+type AppState = (Arc<RwLock<FileStorage>>, Arc<JwtService>, Config, Arc<Runtime>);
 
 pub async fn login(
-    State((storage, jwt_service, config)): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    client: ClientInfo,
+    State((storage, jwt_service, config, runtime)): State<AppState>,
     Json(request): Json<LoginRequest>,
 ) -> Result<Json<LoginResponse>, StatusCode> {
+    if !runtime.login_throttle.allow(addr.ip(), &config.security.login_throttle, std::time::Instant::now()) {
+        warn!(
+            service = "auth-service",
+            event = "login",
+            ip = %addr,
+            success = false,
+            reason = "throttled"
+        );
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    // Members of directory-backed organizations log in with the directory password
+    let directory_org = directory_org(&*storage.read().await, &config, &request.email);
+    if let Some(org) = directory_org {
+        return directory_login(&client, &org, request, storage, &jwt_service, &config, &runtime).await;
+    }
+
     let storage_guard = storage.read().await;
 
     // Find user by email
@@ -35,15 +64,9 @@ pub async fn login(
                 success = false,
                 reason = "user_not_found"
             );
-            return Ok(Json(LoginResponse {
-                success: false,
-                access_token: None,
-                refresh_token: None,
-                expires_in: None,
-                requires_mfa: false,
-                mfa_session: None,
-                redirect_to: None,
-            }));
+            work_like_wrong_password(&request.password, &config, &runtime).await?;
+            record_refused_login(&runtime, &request.email, None, "user_not_found", &client).await?;
+            return Ok(Json(LoginResponse::failed()));
         }
     };
 
@@ -56,80 +79,616 @@ pub async fn login(
             success = false,
             reason = "user_inactive"
         );
-        return Ok(Json(LoginResponse {
-            success: false,
-            access_token: None,
-            refresh_token: None,
-            expires_in: None,
-            requires_mfa: false,
-            mfa_session: None,
-            redirect_to: None,
-        }));
+        work_like_wrong_password(&request.password, &config, &runtime).await?;
+        record_refused_login(&runtime, &request.email, Some(user), "user_inactive", &client).await?;
+        return Ok(Json(LoginResponse::failed()));
+    }
+
+    // A locked account fails like a wrong password, so the answer does not
+    // tell whether the account exists
+    let now = OffsetDateTime::now_utc();
+    if let Some(locked_until) = runtime.lockouts.locked_until(&user.id, now).await.map_err(lockout_error)? {
+        warn!(
+            service = "auth-service",
+            event = "login",
+            email = %request.email,
+            user_id = %user.id,
+            ip = %addr,
+            success = false,
+            reason = "account_locked",
+            locked_until = %locked_until
+        );
+        work_like_wrong_password(&request.password, &config, &runtime).await?;
+        record_refused_login(&runtime, &request.email, Some(user), "account_locked", &client).await?;
+        return Ok(Json(LoginResponse::failed()));
+    }
+
+    // Accounts created through an upstream provider have no password
+    if user.password_hash.is_empty() {
+        warn!(
+            service = "auth-service",
+            event = "login",
+            email = %request.email,
+            user_id = %user.id,
+            success = false,
+            reason = "no_password"
+        );
+        work_like_wrong_password(&request.password, &config, &runtime).await?;
+        record_refused_login(&runtime, &request.email, Some(user), "no_password", &client).await?;
+        return Ok(Json(LoginResponse::failed()));
     }
 
     // Verify password
-    let password_valid = match password::verify_password(&request.password, &user.password_hash) {
-        Ok(valid) => valid,
+    let check = password::check_password(&request.password, &user.password_hash, Some(&config.security.argon2));
+    let password_check = match check {
+        Ok(check) => check,
         Err(e) => {
             warn!(
                 service = "auth-service",
                 event = "password_verification_error",
+                user_id = %user.id,
                 error = %e,
             );
             return Err(StatusCode::INTERNAL_SERVER_ERROR);
         }
     };
 
-    if !password_valid {
+    if password_check == PasswordCheck::MatchOutdated {
+        upgrade_password_hash(storage.clone(), user, &request.password, &config);
+    }
+
+    if password_check == PasswordCheck::Mismatch {
         warn!(
             service = "auth-service",
             event = "login",
             email = %request.email,
+            ip = %addr,
             success = false,
             reason = "invalid_password"
         );
-        return Ok(Json(LoginResponse {
-            success: false,
-            access_token: None,
-            refresh_token: None,
-            expires_in: None,
-            requires_mfa: false,
-            mfa_session: None,
-            redirect_to: None,
-        }));
+
+        record_password_failure(user, &client, now, &config, &runtime).await?;
+        return Ok(Json(LoginResponse::failed()));
     }
 
-    // Check if MFA is required
-    if config.security.require_mfa && user.mfa_secret.is_some() {
-        // TODO: Implement MFA session handling
+    if let Some(reason) = login_refusal(user, &storage_guard) {
         warn!(
             service = "auth-service",
-            event = "mfa_required",
+            event = "login",
+            email = %request.email,
             user_id = %user.id,
+            success = false,
+            reason = reason
         );
-        return Ok(Json(LoginResponse {
-            success: true,
-            access_token: None,
-            refresh_token: None,
-            expires_in: None,
-            requires_mfa: true,
-            mfa_session: Some("mfa-session-placeholder".to_string()),
-            redirect_to: None,
-        }));
-    }
-
-    // Create JWT tokens
-    let claims_registry = crate::models::ClaimsRegistry {
-        claims: std::collections::HashMap::new(),
-    };
-    let access_token = match jwt_service.create_token(
-        user,
-        &claims_registry,
-        vec!["auth-service".to_string()],
-        &config.instance.issuer,
-        config.security.access_token_ttl,
-    ) {
-        Ok(token) => token,
+        record_refused_login(&runtime, &request.email, Some(user), reason, &client).await?;
+        return Ok(Json(LoginResponse::refused(reason)));
+    }
+
+    let amr = acr::add_method(&[], acr::AMR_PASSWORD);
+    let response = complete_first_factor(user, amr, &storage_guard, &jwt_service, &config, &runtime, &client).await?;
+    if response.requires_mfa || !response.success {
+        return Ok(Json(response));
+    }
+
+    info!(
+        service = "auth-service",
+        event = "login",
+        email = %request.email,
+        user_id = %user.id,
+        success = true
+    );
+
+    Ok(Json(response))
+}
+
+/// Pass a second factor again within an existing session, for clients that
+/// demand a stronger or more recent login than it had. Answers like a login
+/// waiting for its second factor; users without one are asked to enroll.
+pub async fn step_up(
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<LoginResponse>, StatusCode> {
+    let storage_guard = storage.read().await;
+    let (claims, client_id, user) = bearer_user(&headers, &storage_guard, &jwt_service, &runtime, &config).await?;
+
+    let (purpose, methods) = match mfa_requirement(&user, &storage_guard, &config) {
+        (Some(purpose), methods) => (purpose, methods),
+        (None, _) => (MfaPurpose::Enroll, vec!["totp".to_string(), "webauthn".to_string()]),
+    };
+
+    let mfa_session = jwt_service
+        .create_mfa_session(
+            &user.id,
+            purpose,
+            claims.amr.clone(),
+            &config.instance.issuer,
+            config.security.mfa_session_ttl,
+        )
+        .map_err(|e| {
+            warn!(
+                service = "auth-service",
+                event = "mfa_session_creation_failed",
+                error = %e,
+                user_id = %user.id
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    info!(
+        service = "auth-service",
+        event = "step_up_started",
+        user_id = %user.id,
+        client_id = ?client_id,
+        acr = ?claims.acr,
+        purpose = ?purpose
+    );
+
+    Ok(Json(LoginResponse::mfa_pending(mfa_session, purpose, methods)))
+}
+
+/// Spend on a login refused before its password is checked what a wrong
+/// password costs: a hash and a lockout update, so the answer takes as long
+/// and does not tell why.
+async fn work_like_wrong_password(password: &str, config: &Config, runtime: &Runtime) -> Result<(), StatusCode> {
+    password::dummy_verify(password, &config.security.argon2);
+    runtime.lockouts.record_unknown(&config.security.lockout, OffsetDateTime::now_utc()).await
+        .map_err(lockout_error)
+}
+
+pub(crate) fn lockout_error(e: anyhow::Error) -> StatusCode {
+    warn!(
+        service = "auth-service",
+        event = "lockout_state_failed",
+        error = %format!("{:#}", e)
+    );
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+/// Count a wrong password towards the lockout of `user` and show it in
+/// their login history.
+pub(crate) async fn record_password_failure(
+    user: &User,
+    client: &ClientInfo,
+    now: OffsetDateTime,
+    config: &Config,
+    runtime: &Runtime,
+) -> Result<(), StatusCode> {
+    record_login(runtime, user, LoginRecord::new(false, &[], Some("invalid_password"), client)).await?;
+    record_lockout_failure(user, client, now, config, runtime).await
+}
+
+/// Count a failed factor, password or second, towards the lockout of
+/// `user`, and audit the lock it may start.
+pub(crate) async fn record_lockout_failure(
+    user: &User,
+    client: &ClientInfo,
+    now: OffsetDateTime,
+    config: &Config,
+    runtime: &Runtime,
+) -> Result<(), StatusCode> {
+    let lockout = &config.security.lockout;
+    if let Some(locked_until) = runtime.lockouts.record_failure(&user.id, lockout, now).await.map_err(lockout_error)? {
+        let mut event = audit::event("account_locked", user, client);
+        event.metadata.insert("locked_until".to_string(), json!(locked_until.unix_timestamp()));
+        audit::record(&runtime.audit, &event).await?;
+    }
+    Ok(())
+}
+
+/// The organization whose directory checks the password of `email`, if not
+/// `password_hash`: the account's own, or for unknown addresses the one
+/// whose directory covers their domain.
+fn directory_org(storage: &FileStorage, config: &Config, email: &str) -> Option<String> {
+    let org = match storage.get_user_by_email(email) {
+        Some(user) => config.ldap.contains_key(&user.org).then_some(&user.org)?,
+        None => config.ldap.iter()
+            .find(|(org, directory)| directory.covers(email) && storage.get_organization(org).is_some())
+            .map(|(org, _)| org)?,
+    };
+    Some(org.clone())
+}
+
+/// `login` for members of an organization with a directory: the password is
+/// checked with an LDAP bind, and the account is created at the first login
+/// and updated from the directory entry at every one.
+async fn directory_login(
+    client: &ClientInfo,
+    org: &str,
+    request: LoginRequest,
+    storage: Arc<RwLock<FileStorage>>,
+    jwt_service: &JwtService,
+    config: &Config,
+    runtime: &Runtime,
+) -> Result<Json<LoginResponse>, StatusCode> {
+    let refuse = |reason: &str| {
+        warn!(
+            service = "auth-service",
+            event = "login",
+            email = %request.email,
+            ip = ?client.ip,
+            backend = "ldap",
+            success = false,
+            reason = reason
+        );
+        Ok(Json(LoginResponse::failed()))
+    };
+
+    let directory = &config.ldap[org];
+    let now = OffsetDateTime::now_utc();
+    let existing = storage.read().await.get_user_by_email(&request.email).cloned();
+    if let Some(user) = &existing {
+        if !user.is_active() {
+            return refuse("user_inactive");
+        }
+        if runtime.lockouts.locked_until(&user.id, now).await.map_err(lockout_error)?.is_some() {
+            return refuse("account_locked");
+        }
+    }
+
+    let entry = match ldap::authenticate(directory, &request.email, &request.password).await {
+        Ok(BindOutcome::Authenticated(entry)) => entry,
+        Ok(BindOutcome::InvalidCredentials) => {
+            if let Some(user) = &existing {
+                record_password_failure(user, client, now, config, runtime).await?;
+            }
+            return refuse("invalid_password");
+        }
+        Err(e) => {
+            warn!(
+                service = "auth-service",
+                event = "directory_unavailable",
+                org = %org,
+                error = %format!("{:#}", e)
+            );
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+    };
+
+    let created = existing.is_none();
+    let user = sync_directory_user(&mut *storage.write().await, org, existing, &request.email, &entry)
+        .await
+        .map_err(|e| {
+            warn!(
+                service = "auth-service",
+                event = "directory_user_sync_failed",
+                org = %org,
+                error = %format!("{:#}", e)
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    if created {
+        let mut event = audit::event("user_provisioned", &user, client);
+        event.metadata.insert("directory_dn".to_string(), json!(entry.dn));
+        audit::record(&runtime.audit, &event).await?;
+    }
+
+    let storage_guard = storage.read().await;
+    if let Some(reason) = login_refusal(&user, &storage_guard) {
+        return Ok(Json(LoginResponse::refused(reason)));
+    }
+
+    let amr = acr::add_method(&[], acr::AMR_PASSWORD);
+    let response = complete_first_factor(&user, amr, &storage_guard, jwt_service, config, runtime, client).await?;
+    if response.success && !response.requires_mfa {
+        info!(
+            service = "auth-service",
+            event = "login",
+            email = %request.email,
+            user_id = %user.id,
+            backend = "ldap",
+            success = true
+        );
+    }
+    Ok(Json(response))
+}
+
+/// The local account for a directory entry: created on the first login,
+/// otherwise updated where names or mapped claims changed. Directory values
+/// only fill claims the registry knows, as a list for array claims.
+async fn sync_directory_user(
+    storage: &mut FileStorage,
+    org: &str,
+    existing: Option<User>,
+    email: &str,
+    entry: &DirectoryEntry,
+) -> anyhow::Result<User> {
+    let registry = storage.get_claims_registry();
+    let claims: HashMap<String, Value> = entry.claims.iter()
+        .filter_map(|(name, values)| {
+            let definition = registry.claims.get(name)?;
+            let value = if definition.claim_type == "array" {
+                json!(values)
+            } else {
+                json!(values.first()?)
+            };
+            Some((name.clone(), value))
+        })
+        .collect();
+
+    let Some(user) = existing else {
+        let org_claims = storage.get_organization(org).map(|o| o.registration.default_claims.clone());
+        let mut user = User::new(
+            email.to_string(),
+            String::new(),
+            entry.given_name.clone().unwrap_or_default(),
+            entry.family_name.clone().unwrap_or_default(),
+            org.to_string(),
+        );
+        // The directory vouches for its own addresses
+        user.verified = true;
+        user.claims = org_claims.unwrap_or_default();
+        user.claims.extend(claims);
+        return storage.create_user(user).await?
+            .ok_or_else(|| anyhow::anyhow!("An account for {} was created meanwhile", email));
+    };
+
+    let first_name = entry.given_name.clone().unwrap_or_else(|| user.first_name.clone());
+    let last_name = entry.family_name.clone().unwrap_or_else(|| user.last_name.clone());
+    let unchanged = first_name == user.first_name
+        && last_name == user.last_name
+        && claims.iter().all(|(name, value)| user.claims.get(name) == Some(value));
+    if unchanged {
+        return Ok(user);
+    }
+
+    storage.modify_user(&user.id, |u| {
+        u.first_name = first_name;
+        u.last_name = last_name;
+        u.claims.extend(claims);
+        Ok(())
+    }).await
+}
+
+/// Replace the legacy or outdated hash of a password that was just verified
+/// with one at the configured cost. Runs in the background; the login does
+/// not wait for it.
+fn upgrade_password_hash(storage: Arc<RwLock<FileStorage>>, user: &User, password: &str, config: &Config) {
+    let (user_id, old_hash) = (user.id.clone(), user.password_hash.clone());
+    let (password, cost) = (password.to_string(), config.security.argon2.clone());
+
+    tokio::spawn(async move {
+        let result = async {
+            let new_hash = password::hash_password_with(&password, &cost)?;
+            storage.write().await.modify_user(&user_id, |u| {
+                if u.password_hash != old_hash {
+                    anyhow::bail!("Password changed since it was verified");
+                }
+                u.password_hash = new_hash;
+                Ok(())
+            }).await
+        }.await;
+
+        match result {
+            Ok(_) => info!(
+                service = "auth-service",
+                event = "password_rehashed",
+                user_id = %user_id
+            ),
+            Err(e) => warn!(
+                service = "auth-service",
+                event = "password_rehash_failed",
+                user_id = %user_id,
+                error = %format!("{:#}", e)
+            ),
+        }
+    });
+}
+
+/// Next step for `user` after the first factor, passed with `amr`: a session
+/// for the second factor if one is required, otherwise the tokens.
+pub(crate) async fn complete_first_factor(
+    user: &User,
+    amr: Vec<String>,
+    storage: &FileStorage,
+    jwt_service: &JwtService,
+    config: &Config,
+    runtime: &Runtime,
+    client: &ClientInfo,
+) -> Result<LoginResponse, StatusCode> {
+    let (mfa_purpose, mfa_methods) = mfa_requirement(user, storage, config);
+
+    let Some(purpose) = mfa_purpose else {
+        return issue_login_tokens(user, amr, storage, jwt_service, config, runtime, client).await;
+    };
+
+    let mfa_session = jwt_service
+        .create_mfa_session(
+            &user.id,
+            purpose,
+            amr,
+            &config.instance.issuer,
+            config.security.mfa_session_ttl,
+        )
+        .map_err(|e| {
+            warn!(
+                service = "auth-service",
+                event = "mfa_session_creation_failed",
+                error = %e,
+                user_id = %user.id
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    info!(
+        service = "auth-service",
+        event = "mfa_required",
+        user_id = %user.id,
+        purpose = ?purpose
+    );
+
+    Ok(LoginResponse::mfa_pending(mfa_session, purpose, mfa_methods))
+}
+
+/// Note a refresh token in the session it belongs to, if any.
+pub(crate) async fn link_refresh_token(
+    runtime: &Runtime,
+    user_id: &str,
+    claims: &Claims,
+    client_id: Option<&str>,
+) -> Result<(), StatusCode> {
+    let Some(sid) = &claims.sid else {
+        return Ok(());
+    };
+    runtime.sessions.link_refresh_token(user_id, sid, RefreshTokenLink::for_claims(claims, client_id)).await
+        .map_err(|e| {
+            warn!(
+                service = "auth-service",
+                event = "session_update_failed",
+                user_id = %user_id,
+                error = %format!("{:#}", e)
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(())
+}
+
+/// Audit a login attempt of `user` and add it to their login history. The
+/// history is for the user's information only, so failing to write it does
+/// not fail the login; failing to audit it does.
+pub(crate) async fn record_login(runtime: &Runtime, user: &User, record: LoginRecord) -> Result<(), StatusCode> {
+    let event_type = if record.success { "login_succeeded" } else { "login_failed" };
+    let mut event = AuditEvent::new(event_type.to_string(), Some(user.id.clone()), Some(user.org.clone()));
+    event.ip_address = record.ip_address.clone();
+    event.user_agent = record.user_agent.clone();
+    event.metadata.insert("methods".to_string(), json!(record.methods));
+    if let Some(reason) = &record.reason {
+        event.metadata.insert("reason".to_string(), json!(reason));
+    }
+    audit::record(&runtime.audit, &event).await?;
+
+    if let Err(e) = runtime.logins.record(&user.id, record).await {
+        warn!(
+            service = "auth-service",
+            event = "login_history_failed",
+            user_id = %user.id,
+            error = %format!("{:#}", e)
+        );
+    }
+    Ok(())
+}
+
+/// Audit a login refused before the password counted, `user` being the
+/// account behind `email` if there is one. These do not go into the
+/// user's login history.
+async fn record_refused_login(
+    runtime: &Runtime,
+    email: &str,
+    user: Option<&User>,
+    reason: &str,
+    client: &ClientInfo,
+) -> Result<(), StatusCode> {
+    let mut event = AuditEvent::new(
+        "login_failed".to_string(),
+        user.map(|u| u.id.clone()),
+        user.map(|u| u.org.clone()),
+    );
+    event.ip_address = client.ip.map(|ip| ip.to_string());
+    event.user_agent = client.user_agent.clone();
+    event.metadata.insert("email".to_string(), json!(email));
+    event.metadata.insert("reason".to_string(), json!(reason));
+    audit::record(&runtime.audit, &event).await
+}
+
+/// Why `user` may not log in even with the right credentials, if at all.
+pub(crate) fn login_refusal(user: &User, storage: &FileStorage) -> Option<&'static str> {
+    let org = storage.get_organization(&user.org);
+    if org.is_some_and(|o| o.require_verified_email) && !user.verified {
+        return Some("email_not_verified");
+    }
+    None
+}
+
+/// Which second factor step, if any, `user` has to pass after the password,
+/// and the methods it may be passed with. Enrolled users always need their
+/// second factor; with `require_mfa` the others have to enroll first. Where
+/// the security policy demands a passkey, only WebAuthn counts.
+pub(crate) fn mfa_requirement(
+    user: &User,
+    storage: &FileStorage,
+    config: &Config,
+) -> (Option<MfaPurpose>, Vec<String>) {
+    let has_passkey = !user.webauthn_credentials.is_empty();
+
+    if storage.get_security_policy().requires_passkey(user) {
+        let purpose = if has_passkey { MfaPurpose::Verify } else { MfaPurpose::Enroll };
+        return (Some(purpose), vec!["webauthn".to_string()]);
+    }
+
+    let mut methods = Vec::new();
+    if user.mfa_secret.is_some() {
+        methods.push("totp".to_string());
+    }
+    if has_passkey {
+        methods.push("webauthn".to_string());
+    }
+
+    if !methods.is_empty() {
+        (Some(MfaPurpose::Verify), methods)
+    } else if config.security.require_mfa {
+        (Some(MfaPurpose::Enroll), vec!["totp".to_string(), "webauthn".to_string()])
+    } else {
+        (None, methods)
+    }
+}
+
+/// Access and refresh token for a user who passed every required factor,
+/// with `amr` as the methods of this login. Both belong to a new session,
+/// which lasts as long as the refresh token unless the session policy of
+/// the user's organization ends it sooner. Refused with
+/// `session_limit_reached` if the user holds as many sessions as allowed.
+/// Only a complete login forgets the user's failed attempts.
+pub(crate) async fn issue_login_tokens(
+    user: &User,
+    amr: Vec<String>,
+    storage: &FileStorage,
+    jwt_service: &JwtService,
+    config: &Config,
+    runtime: &Runtime,
+    client: &ClientInfo,
+) -> Result<LoginResponse, StatusCode> {
+    runtime.lockouts.clear(&user.id).await.map_err(lockout_error)?;
+
+    let policy = sessions::policy_for(config, storage, &user.org, None);
+    let session = runtime.sessions.create(&user.id, &amr, client, config.security.refresh_token_ttl, &policy).await
+        .map_err(|e| {
+            warn!(
+                service = "auth-service",
+                event = "session_creation_failed",
+                error = %format!("{:#}", e),
+                user_id = %user.id
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let Some(session) = session else {
+        let reason = "session_limit_reached";
+        warn!(
+            service = "auth-service",
+            event = "login",
+            user_id = %user.id,
+            success = false,
+            reason = reason
+        );
+        record_login(runtime, user, LoginRecord::new(false, &amr, Some(reason), client)).await?;
+        return Ok(LoginResponse::refused(reason));
+    };
+    if let Err(status) = record_login(runtime, user, LoginRecord::new(true, &amr, None, client)).await {
+        // Tokens are only handed out for audited logins
+        let _ = runtime.sessions.revoke(&user.id, &session.id).await;
+        return Err(status);
+    }
+
+    let claims_registry = storage.get_claims_registry();
+    let auth_time = OffsetDateTime::now_utc().unix_timestamp() as u64;
+    // The login page's own tokens: no client is their authorized party
+    let create_token = |expires_in: u64, typ: TokenUse| {
+        let mut claims = jwt_service.build_claims(user, claims_registry, &config.instance.issuer, expires_in, typ, None);
+        claims.set_authentication(amr.clone(), auth_time);
+        claims.sid = Some(session.id.clone());
+        jwt_service.encode_claims(&claims).map(|token| (token, claims))
+    };
+
+    let access_token = match create_token(config.security.access_token_ttl, TokenUse::Access) {
+        Ok((token, _)) => token,
         Err(e) => {
             warn!(
                 service = "auth-service",
@@ -141,14 +700,8 @@ pub async fn login(
         }
     };
 
-    let refresh_token = match jwt_service.create_token(
-        user,
-        &claims_registry,
-        vec!["auth-service".to_string()],
-        &config.instance.issuer,
-        config.security.refresh_token_ttl,
-    ) {
-        Ok(token) => token,
+    let (refresh_token, refresh_claims) = match create_token(config.security.refresh_token_ttl, TokenUse::Refresh) {
+        Ok(created) => created,
         Err(e) => {
             warn!(
                 service = "auth-service",
@@ -159,16 +712,9 @@ pub async fn login(
             return Err(StatusCode::INTERNAL_SERVER_ERROR);
         }
     };
+    link_refresh_token(runtime, &user.id, &refresh_claims, None).await?;
 
-    info!(
-        service = "auth-service",
-        event = "login",
-        email = %request.email,
-        user_id = %user.id,
-        success = true
-    );
-
-    Ok(Json(LoginResponse {
+    Ok(LoginResponse {
         success: true,
         access_token: Some(access_token),
         refresh_token: Some(refresh_token),
@@ -176,20 +722,44 @@ pub async fn login(
         requires_mfa: false,
         mfa_session: None,
         redirect_to: Some(config.instance.admin_client_url.clone()),
-    }))
+        mfa_enrollment_required: false,
+        recovery_codes: None,
+        recovery_codes_remaining: None,
+        mfa_methods: Vec::new(),
+        error: None,
+    })
 }
 
+/// End the session of the presented login token, if any. The tokens are
+/// discarded client-side; without a valid token there is nothing to end.
 pub async fn logout(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    State(_): State<AppState>,
+    client: ClientInfo,
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    headers: HeaderMap,
     Json(_payload): Json<Value>,
 ) -> Result<Json<Value>, StatusCode> {
-    // In a stateless JWT system, logout is primarily client-side
-    // We just log the event and return success
+    let bearer = bearer_user(&headers, &*storage.read().await, &jwt_service, &runtime, &config).await;
+    if let Ok((Claims { sid: Some(sid), .. }, _, user)) = &bearer {
+        runtime.sessions.revoke(&user.id, sid).await.map_err(|e| {
+            warn!(
+                service = "auth-service",
+                event = "session_revoke_failed",
+                user_id = %user.id,
+                error = %format!("{:#}", e)
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        let mut event = audit::event("logout", user, &client);
+        event.metadata.insert("session_id".to_string(), json!(sid));
+        audit::record(&runtime.audit, &event).await?;
+    }
 
     info!(
         service = "auth-service",
         event = "logout",
+        ip = %addr,
+        user_id = ?bearer.ok().map(|(_, _, user)| user.id)
     );
 
     Ok(Json(json!({
@@ -198,36 +768,624 @@ pub async fn logout(
     })))
 }
 
+/// Mail a single-use reset link. Unknown and inactive addresses get the same
+/// answer as known ones, so the endpoint cannot be used to probe accounts.
 pub async fn forgot_password(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    State(_): State<AppState>,
-    Json(_payload): Json<Value>,
+    client: ClientInfo,
+    State((storage, _, config, runtime)): State<AppState>,
+    Json(request): Json<ForgotPasswordRequest>,
 ) -> Result<Json<Value>, StatusCode> {
-    // TODO: Implement password reset functionality
-    info!(
-        service = "auth-service",
-        event = "forgot_password",
-    );
+    if !config.features.allow_password_reset {
+        warn!(
+            service = "auth-service",
+            event = "forgot_password",
+            ip = %addr,
+            success = false,
+            reason = "password_reset_disabled"
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
 
-    Ok(Json(json!({
+    let accepted = json!({
         "success": true,
         "message": "If the email exists, a reset link has been sent"
-    })))
+    });
+
+    let user = match storage.read().await.get_user_by_email(&request.email).filter(|u| u.is_active()) {
+        Some(user) => user.clone(),
+        None => {
+            info!(
+                service = "auth-service",
+                event = "forgot_password",
+                ip = %addr,
+                email = %request.email,
+                reason = "user_not_found_or_inactive"
+            );
+            return Ok(Json(accepted));
+        }
+    };
+
+    // Their password lives in the directory
+    if config.ldap.contains_key(&user.org) {
+        info!(
+            service = "auth-service",
+            event = "forgot_password",
+            ip = %addr,
+            user_id = %user.id,
+            reason = "directory_account"
+        );
+        return Ok(Json(accepted));
+    }
+
+    // Issuing and mailing the link happens after the answer, so known and
+    // unknown addresses take the same time
+    tokio::spawn(async move {
+        if let Err(e) = send_password_reset(&user, &config, &runtime).await {
+            warn!(
+                service = "auth-service",
+                event = "password_reset_mail_failed",
+                user_id = %user.id,
+                error = %format!("{:#}", e)
+            );
+            return;
+        }
+
+        // Nobody waits for the answer; a failure is logged by record
+        let _ = audit::record(&runtime.audit, &audit::event("password_reset_requested", &user, &client)).await;
+
+        info!(
+            service = "auth-service",
+            event = "forgot_password",
+            ip = %addr,
+            user_id = %user.id
+        );
+    });
+
+    Ok(Json(accepted))
 }
 
+async fn send_password_reset(user: &User, config: &Config, runtime: &Runtime) -> anyhow::Result<()> {
+    let ttl = config.security.password_reset_ttl;
+    let token = runtime.resets.issue(&user.id, ttl, None).await?;
+
+    // The token travels in the fragment so it stays out of access logs
+    let params = HashMap::from([
+        ("name".to_string(), user.first_name.clone()),
+        (
+            "reset_url".to_string(),
+            format!("{}/#reset_token={}", config.instance.issuer.trim_end_matches('/'), token),
+        ),
+        ("expires_minutes".to_string(), (ttl / 60).to_string()),
+    ]);
+    runtime.outbox.enqueue(&user.email, MailTemplate::PasswordReset, None, params).await?;
+
+    Ok(())
+}
+
+/// Set a new password with a token from `forgot_password` or an admin
+/// reset. The token is used up, every session of the user ends.
 pub async fn reset_password(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    State(_): State<AppState>,
-    Json(_payload): Json<Value>,
+    client: ClientInfo,
+    State((storage, _, config, runtime)): State<AppState>,
+    Json(request): Json<ResetPasswordRequest>,
 ) -> Result<Json<Value>, StatusCode> {
-    // TODO: Implement password reset functionality
+    if !config.features.allow_password_reset {
+        warn!(
+            service = "auth-service",
+            event = "reset_password",
+            ip = %addr,
+            success = false,
+            reason = "password_reset_disabled"
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let invalid_token = || {
+        warn!(
+            service = "auth-service",
+            event = "reset_password",
+            ip = %addr,
+            success = false,
+            reason = "invalid_token"
+        );
+        Json(json!({
+            "success": false,
+            "message": "The reset link is invalid or has expired"
+        }))
+    };
+    let token_error = |e: anyhow::Error| {
+        warn!(
+            service = "auth-service",
+            event = "password_reset_token_failed",
+            error = %format!("{:#}", e)
+        );
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+
+    let now = OffsetDateTime::now_utc();
+    let Some(record) = runtime.resets.peek(&request.token, now).await.map_err(token_error)? else {
+        return Ok(invalid_token());
+    };
+
+    let user = match storage.read().await.get_user(&record.user_id).filter(|u| u.is_active()) {
+        Some(user) => user.clone(),
+        None => return Ok(invalid_token()),
+    };
+
+    // Check the password before the token is used up, so a rejected
+    // password does not cost the user their link
+    let violations = storage
+        .read()
+        .await
+        .password_violations(&user, &request.new_password)
+        .map_err(|e| {
+            warn!(
+                service = "auth-service",
+                event = "password_policy_failed",
+                error = %format!("{:#}", e)
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    if !violations.is_empty() {
+        info!(
+            service = "auth-service",
+            event = "reset_password",
+            ip = %addr,
+            user_id = %user.id,
+            success = false,
+            reason = "password_policy"
+        );
+        return Ok(Json(json!({
+            "success": false,
+            "message": violations.join(". "),
+            "errors": violations
+        })));
+    }
+
+    let password_hash = password::hash_password_with(&request.new_password, &config.security.argon2).map_err(|e| {
+        warn!(
+            service = "auth-service",
+            event = "password_hashing_failed",
+            error = %e
+        );
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    if runtime.resets.redeem(&request.token, now).await.map_err(token_error)?.is_none() {
+        return Ok(invalid_token());
+    }
+
+    let user = storage
+        .write()
+        .await
+        .modify_user(&user.id, |u| {
+            u.password_hash = password_hash;
+            u.password_changed_at = Some(now);
+            Ok(())
+        })
+        .await
+        .map_err(|e| {
+            warn!(
+                service = "auth-service",
+                event = "password_reset_persist_failed",
+                user_id = %user.id,
+                error = %format!("{:#}", e)
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let revoked_tokens = runtime.tokens.write().await.revoke_user(&user.id).await.map_err(|e| {
+        warn!(
+            service = "auth-service",
+            event = "password_reset_revoke_failed",
+            user_id = %user.id,
+            error = %format!("{:#}", e)
+        );
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let ended_sessions = runtime.sessions.revoke_all(&user.id, None).await.map_err(|e| {
+        warn!(
+            service = "auth-service",
+            event = "password_reset_revoke_failed",
+            user_id = %user.id,
+            error = %format!("{:#}", e)
+        );
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    // Whoever can reset the password may log in again right away
+    runtime.lockouts.clear(&user.id).await.map_err(|e| {
+        warn!(
+            service = "auth-service",
+            event = "lockout_state_failed",
+            user_id = %user.id,
+            error = %format!("{:#}", e)
+        );
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let params = HashMap::from([("name".to_string(), user.first_name.clone())]);
+    if let Err(e) = runtime.outbox.enqueue(&user.email, MailTemplate::PasswordChanged, None, params).await {
+        // The password is already changed; the notice is not worth failing over
+        warn!(
+            service = "auth-service",
+            event = "password_changed_mail_failed",
+            user_id = %user.id,
+            error = %format!("{:#}", e)
+        );
+    }
+
+    let mut event = audit::event("password_reset", &user, &client);
+    event.metadata.insert("revoked_tokens".to_string(), json!(revoked_tokens));
+    event.metadata.insert("ended_sessions".to_string(), json!(ended_sessions));
+    if let Some(admin_id) = &record.created_by {
+        event.metadata.insert("requested_by".to_string(), json!(admin_id));
+    }
+    audit::record(&runtime.audit, &event).await?;
+
     info!(
         service = "auth-service",
         event = "reset_password",
+        ip = %addr,
+        user_id = %user.id,
+        success = true
     );
 
     Ok(Json(json!({
         "success": true,
         "message": "Password reset successfully"
     })))
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{self, Config};
+    use crate::storage::FileStorage;
+    use crate::testing::*;
+    use crate::{acr, jwt, ldap, mfa, routes};
+    use axum::http::StatusCode;
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    #[tokio::test]
+    async fn test_password_reset_flow() {
+        let dir = TempDir::new("reset");
+        let data_dir = dir.path();
+        write_user(data_dir, json!({})).await;
+
+        let (app, _, runtime) = test_app(data_dir, &Config::default()).await;
+        let outbox_dir = format!("{}/mail/outbox", data_dir);
+        let outbox = || {
+            std::fs::read_dir(&outbox_dir)
+                .map(|d| d.filter(|e| e.as_ref().unwrap().path().extension().is_some_and(|ext| ext == "json")).count())
+                .unwrap_or(0)
+        };
+
+        let login = post_json(&app, "/api/auth/login", credentials("anna@example.com")).await;
+        let old_token = login["access_token"].as_str().unwrap().to_string();
+
+        // Unknown addresses get the same answer, but no mail
+        let unknown = post_json(&app, "/api/auth/forgot-password", json!({"email": "nobody@example.com"})).await;
+        let known = post_json(&app, "/api/auth/forgot-password", json!({"email": "anna@example.com"})).await;
+        assert_eq!(unknown, known);
+        eventually(|| outbox() == 1).await;
+        assert_eq!(outbox(), 1);
+
+        let mail_path = std::fs::read_dir(&outbox_dir).unwrap().next().unwrap().unwrap().path();
+        let mail = read_json(&mail_path.to_string_lossy()).await;
+        assert_eq!(mail["template"], "password_reset");
+        let token = mail["params"]["reset_url"].as_str().unwrap().split("#reset_token=").nth(1).unwrap().to_string();
+
+        // A rejected password does not use up the token
+        let weak = post_json(&app, "/api/auth/reset-password", json!({"token": token, "new_password": "short"})).await;
+        assert_eq!(weak["success"], false);
+
+        let reset = post_json(&app, "/api/auth/reset-password", json!({"token": token, "new_password": "a much better passphrase"})).await;
+        assert_eq!(reset["success"], true);
+        assert_eq!(outbox(), 2);
+
+        let reused = post_json(&app, "/api/auth/reset-password", json!({"token": token, "new_password": "yet another passphrase"})).await;
+        assert_eq!(reused["success"], false);
+
+        // Tokens and sessions from before the reset no longer work
+        let (status, _) = request_as(&app, "GET", routes::USERINFO, None, &old_token).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(runtime.sessions.list("user-1").await.unwrap().is_empty());
+
+        let old = post_json(&app, "/api/auth/login", credentials("anna@example.com")).await;
+        assert_eq!(old["success"], false);
+        let new = post_json(&app, "/api/auth/login", json!({"email": "anna@example.com", "password": "a much better passphrase"})).await;
+        assert_eq!(new["success"], true);
+    }
+
+    #[tokio::test]
+    async fn test_legacy_hash_upgraded_at_login() {
+        let dir = TempDir::new("rehash");
+        let data_dir = dir.path();
+        let user_path = write_user(data_dir, json!({"password_hash": bcrypt::hash(PASSWORD, 4).unwrap()})).await;
+
+        let mut config = Config::default();
+        config.security.argon2 = config::Argon2Config { memory_kib: 8192, iterations: 1, parallelism: 1 };
+        let (app, _, _) = test_app(data_dir, &config).await;
+
+        let login = post_json(&app, "/api/auth/login", credentials("anna@example.com")).await;
+        assert!(login["access_token"].is_string());
+
+        // The upgrade runs in the background
+        let mut upgraded = String::new();
+        for _ in 0..50 {
+            upgraded = read_json(&user_path).await["password_hash"].as_str().unwrap().to_string();
+            if upgraded.starts_with("$argon2id$") {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert!(upgraded.starts_with("$argon2id$v=19$m=8192,t=1,p=1$"), "{}", upgraded);
+
+        let login = post_json(&app, "/api/auth/login", credentials("anna@example.com")).await;
+        assert!(login["access_token"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_account_lockout_survives_reload_until_unlocked() {
+        let dir = TempDir::new("lockout");
+        let data_dir = dir.path();
+        write_user(data_dir, json!({})).await;
+
+        let mut config = Config::default();
+        config.security.lockout = config::LockoutConfig { max_failures: 3, base_duration: 600, max_duration: 3600 };
+        let (app, _, runtime) = test_app(data_dir, &config).await;
+        let reloaded = || async {
+            let storage = Arc::new(RwLock::new(FileStorage::load(data_dir).await.unwrap()));
+            app_with(storage, &config, runtime.clone()).await
+        };
+        let correct = credentials("anna@example.com");
+        let wrong = json!({"email": "anna@example.com", "password": "wrong horse battery"});
+        let unknown = json!({"email": "nobody@example.com", "password": "wrong horse battery"});
+
+        for _ in 0..3 {
+            assert_eq!(post_json(&app, "/api/auth/login", wrong.clone()).await["success"], false);
+        }
+
+        // Locked: the right password fails exactly like an unknown account
+        let locked = post_json(&app, "/api/auth/login", correct.clone()).await;
+        assert_eq!(locked, post_json(&app, "/api/auth/login", unknown).await);
+        assert!(locked["access_token"].is_null());
+
+        let app = reloaded().await;
+        assert_eq!(post_json(&app, "/api/auth/login", correct.clone()).await["success"], false);
+
+        // admin-service unlocks by removing the record
+        tokio::fs::remove_file(format!("{}/lockouts/user-1.json", data_dir)).await.unwrap();
+        assert!(post_json(&app, "/api/auth/login", correct).await["access_token"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_refused_logins_work_like_wrong_passwords() {
+        let dir = TempDir::new("timing");
+        let data_dir = dir.path();
+        write_user(data_dir, json!({})).await;
+        write_user(data_dir, json!({"id": "user-2", "email": "inactive@example.com", "status": "inactive"})).await;
+        write_user(data_dir, json!({"id": "user-3", "email": "locked@example.com"})).await;
+        write_user(data_dir, json!({"id": "user-4", "email": "federated@example.com", "password_hash": ""})).await;
+
+        let mut config = Config::default();
+        config.security.lockout.max_failures = 2;
+        let (app, _, _) = test_app(data_dir, &config).await;
+        let wrong_password = |email: &str| json!({"email": email, "password": "wrong horse battery"});
+        let unknown_failures = || async {
+            let path = format!("{}/lockouts/.unknown.json", data_dir);
+            match tokio::fs::try_exists(&path).await.unwrap() {
+                true => read_json(&path).await["failures"].as_u64().unwrap(),
+                false => 0,
+            }
+        };
+
+        // A wrong password counts for its own account
+        for _ in 0..2 {
+            post_json(&app, "/api/auth/login", wrong_password("locked@example.com")).await;
+        }
+        assert_eq!(unknown_failures().await, 0);
+
+        // Every login refused before the password check hashes the password
+        // and updates a lockout record just the same, and answers alike
+        let failed = post_json(&app, "/api/auth/login", wrong_password("anna@example.com")).await;
+        let refused = ["nobody@example.com", "inactive@example.com", "locked@example.com", "federated@example.com"];
+        for (count, email) in (1..).zip(refused) {
+            let answer = post_json(&app, "/api/auth/login", wrong_password(email)).await;
+            assert_eq!(answer, failed, "{}", email);
+            assert_eq!(unknown_failures().await, count, "{}", email);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_step_up_for_clients_demanding_mfa() {
+        let dir = TempDir::new("step-up");
+        let data_dir = dir.path();
+        write_user(data_dir, json!({})).await;
+        write_client(data_dir, "grades", &["https://grades.example.com/cb"], &["authorization_code", "refresh_token"], json!({"min_acr": "mfa", "max_auth_age": 900})).await;
+        write_client(data_dir, "portal", &["https://portal.example.com/cb"], &["authorization_code", "refresh_token"], json!({"allowed_scopes": ["openid", "grades:write"]})).await;
+
+        let mut config = Config::default();
+        config.oidc.signing_key = format!("{}/keys/oidc.pem", data_dir);
+        config.security.scope_requirements.insert(
+            "grades:write".to_string(),
+            acr::AuthRequirement { min_acr: Some("mfa".to_string()), max_auth_age: None },
+        );
+        let jwt = jwt::JwtService::new(&config.jwt_secret);
+        let (app, _, _) = test_app(data_dir, &config).await;
+        let refresh = |client_id: &str, refresh_token: &Value, scope: &str| {
+            json!({
+                "grant_type": "refresh_token",
+                "client_id": client_id,
+                "refresh_token": refresh_token,
+                "scope": scope
+            })
+        };
+
+        let login = post_json(&app, "/api/auth/login", credentials("anna@example.com")).await;
+        let claims = jwt.verify_token(login["access_token"].as_str().unwrap()).unwrap();
+        assert_eq!(claims.acr.as_deref(), Some("pwd"));
+        assert_eq!(claims.amr, ["pwd"]);
+        assert!(claims.auth_time.is_some());
+
+        // A password-only session is enough for plain scopes, not for the
+        // grade editor or the grades:write scope
+        let login_token = login["access_token"].as_str().unwrap();
+        let (status, portal) = client_tokens(&app, "portal", "https://portal.example.com/cb", "openid", login_token).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = post_form(&app, routes::TOKEN, refresh("portal", &portal["refresh_token"], "openid grades:write")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = client_tokens(&app, "grades", "https://grades.example.com/cb", "openid", login_token).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // Without a second factor, stepping up means enrolling one
+        let (status, step_up) = post_json_as(&app, "/api/auth/step-up", json!({}), login["access_token"].as_str()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(step_up["mfa_enrollment_required"], true);
+        let session = step_up["mfa_session"].clone();
+
+        let enrollment = post_json(&app, "/api/auth/mfa/enroll", json!({"mfa_session": session})).await;
+        let totp = mfa::totp(enrollment["secret"].as_str().unwrap(), "anna@example.com", &config.instance.name).unwrap();
+        let now = time::OffsetDateTime::now_utc().unix_timestamp() as u64;
+        let stepped_up = post_json(
+            &app,
+            "/api/auth/mfa/enroll/confirm",
+            json!({"mfa_session": session, "code": totp.generate(now)}),
+        )
+        .await;
+        let claims = jwt.verify_token(stepped_up["access_token"].as_str().unwrap()).unwrap();
+        assert_eq!(claims.acr.as_deref(), Some("mfa"));
+        assert_eq!(claims.amr, ["pwd", "otp", "mfa"]);
+
+        let stepped_up_token = stepped_up["access_token"].as_str().unwrap();
+        let (status, tokens) = client_tokens(&app, "grades", "https://grades.example.com/cb", "openid", stepped_up_token).await;
+        assert_eq!(status, StatusCode::OK);
+        let claims = jwt.verify_token(tokens["access_token"].as_str().unwrap()).unwrap();
+        assert_eq!(claims.acr.as_deref(), Some("mfa"));
+        assert_eq!(claims.azp.as_deref(), Some("grades"));
+
+        // The login behind the session keeps its strength across refreshes
+        let (status, refreshed) = post_form(&app, routes::TOKEN, refresh("grades", &tokens["refresh_token"], "openid")).await;
+        assert_eq!(status, StatusCode::OK);
+        let claims = jwt.verify_token(refreshed["access_token"].as_str().unwrap()).unwrap();
+        assert_eq!(claims.acr.as_deref(), Some("mfa"));
+        let (_, portal) = client_tokens(&app, "portal", "https://portal.example.com/cb", "openid", stepped_up_token).await;
+        let (status, _) = post_form(&app, routes::TOKEN, refresh("portal", &portal["refresh_token"], "grades:write")).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Enrolled users step up by verifying their factor
+        let (_, step_up) = post_json_as(&app, "/api/auth/step-up", json!({}), login["access_token"].as_str()).await;
+        assert_eq!(step_up["mfa_enrollment_required"], false);
+        assert_eq!(step_up["mfa_methods"], json!(["totp"]));
+    }
+
+    #[tokio::test]
+    async fn test_directory_login_creates_and_syncs_accounts() {
+        let dir = TempDir::new("ldap");
+        let data_dir = dir.path();
+        let org = |id: &str| json!({
+            "id": id,
+            "name": id,
+            "description": "",
+            "metadata": {},
+            "require_verified_email": true,
+            "created_at": "2024-01-01T00:00:00Z"
+        });
+        write_file(&format!("{}/orgs.json", data_dir), json!({"orgs": [org("staff"), org("branch")]}).to_string()).await;
+        write_file(
+            &format!("{}/claims.json", data_dir),
+            json!({
+                "groups": {"type": "array", "items": {"type": "string"}, "description": "", "default_allowed": true, "required": null, "sensitive": null, "admin_only": null},
+                "employee_id": {"type": "string", "items": null, "description": "", "default_allowed": true, "required": null, "sensitive": null, "admin_only": null}
+            })
+            .to_string(),
+        )
+        .await;
+        let bob_path = write_user(data_dir, json!({
+            "id": "user-bob",
+            "email": "bob@corp.example",
+            "password_hash": "",
+            "first_name": "Bob",
+            "last_name": "Old",
+            "org": "staff",
+            "claims": {"employee_id": "E-1"}
+        }))
+        .await;
+
+        let person = |uid: &str, given: &str, family: &str, groups: &[&str]| ldap::testing::StubEntry {
+            dn: format!("uid={},ou=people,dc=corp,dc=example", uid),
+            password: format!("{} directory secret", uid),
+            attributes: vec![
+                ("mail".to_string(), vec![format!("{}@corp.example", uid)]),
+                ("givenName".to_string(), vec![given.to_string()]),
+                ("sn".to_string(), vec![family.to_string()]),
+                ("memberOf".to_string(), groups.iter().map(|g| g.to_string()).collect()),
+                ("employeeNumber".to_string(), vec![format!("E-{}", uid.len())]),
+            ],
+        };
+        let stub = ldap::testing::StubDirectory::start(vec![
+            person("anna", "Anna", "Admin", &["staff", "it"]),
+            person("bob", "Bob", "New", &["staff"]),
+        ])
+        .await;
+
+        let directory = |url: &str, domain: &str| config::LdapDirectoryConfig {
+            url: url.to_string(),
+            starttls: false,
+            bind_dn: "uid={username},ou=people,dc=corp,dc=example".to_string(),
+            search_base: "dc=corp,dc=example".to_string(),
+            search_filter: "(mail={email})".to_string(),
+            email_domains: vec![domain.to_string()],
+            attributes: config::LdapAttributeMapping {
+                claims: [("groups", "memberOf"), ("employee_id", "employeeNumber")]
+                    .into_iter()
+                    .map(|(claim, attribute)| (claim.to_string(), attribute.to_string()))
+                    .collect(),
+                ..Default::default()
+            },
+            timeout: 2,
+        };
+        let mut config = Config::default();
+        config.ldap.insert("staff".to_string(), directory(&stub.url, "corp.example"));
+        // Nothing listens there
+        config.ldap.insert("branch".to_string(), directory("ldap://127.0.0.1:1", "branch.example"));
+        let (app, storage, _) = test_app(data_dir, &config).await;
+        let login = |email: &str, password: &str| {
+            post_json_as(&app, "/api/auth/login", json!({"email": email, "password": password}), None)
+        };
+
+        // First login with a directory address creates the account
+        let (status, anna) = login("anna@corp.example", "anna directory secret").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(anna["success"], true);
+        {
+            let storage = storage.read().await;
+            let user = storage.get_user_by_email("anna@corp.example").unwrap();
+            assert_eq!((user.org.as_str(), user.last_name.as_str(), user.verified), ("staff", "Admin", true));
+            assert_eq!(user.claims["groups"], json!(["staff", "it"]));
+            assert_eq!(user.claims["employee_id"], "E-4");
+            assert!(user.password_hash.is_empty());
+        }
+
+        let (_, wrong) = login("anna@corp.example", "guess").await;
+        assert_eq!(wrong["success"], false);
+        let (_, unknown) = login("carl@corp.example", "carl directory secret").await;
+        assert_eq!(unknown["success"], false);
+        assert!(storage.read().await.get_user_by_email("carl@corp.example").is_none());
+
+        // Existing accounts pick up changes in the directory
+        let (_, bob) = login("bob@corp.example", "bob directory secret").await;
+        assert_eq!(bob["success"], true);
+        let on_disk = read_json(&bob_path).await;
+        assert_eq!(on_disk["last_name"], "New");
+        assert_eq!(on_disk["claims"]["employee_id"], "E-3");
+
+        // An unreachable directory is an outage, not a wrong password
+        let (status, _) = login("dora@branch.example", "secret").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
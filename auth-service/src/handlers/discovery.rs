@@ -0,0 +1,178 @@
+use axum::{extract::State, http::StatusCode, response::Json};
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::{
+    acr,
+    config::Config,
+    handlers::oauth::{SUPPORTED_GRANT_TYPES, TOKEN_ENDPOINT_AUTH_METHODS},
+    jwt::JwtService,
+    oidc::ID_TOKEN_ALGORITHM,
+    routes,
+    runtime::Runtime,
+    storage::FileStorage,
+};
+
+type AppState = (Arc<RwLock<FileStorage>>, Arc<JwtService>, Config, Arc<Runtime>);
+
+/// Claims every token and userinfo response carries, independent of the registry.
+const STANDARD_CLAIMS: &[&str] = &[
+    "sub", "iss", "aud", "exp", "iat", "jti", "azp",
+    "email", "name", "given_name", "family_name", "org", "admin", "verified",
+    "auth_time", "acr", "amr",
+];
+
+/// OpenID Connect Discovery 1.0
+pub async fn openid_configuration(
+    State((storage, _, config, _)): State<AppState>,
+) -> Json<Value> {
+    let storage_guard = storage.read().await;
+    let mut metadata = server_metadata(&config, &storage_guard);
+
+    let subject_types_supported = if config.security.pairwise_salt.is_some() {
+        vec!["public", "pairwise"]
+    } else {
+        vec!["public"]
+    };
+
+    let claims_supported: BTreeSet<&str> = STANDARD_CLAIMS
+        .iter()
+        .copied()
+        .chain(storage_guard.get_claims_registry().claims.keys().map(|k| k.as_str()))
+        .collect();
+
+    metadata["userinfo_endpoint"] = json!(endpoint(&config, routes::USERINFO));
+    metadata["id_token_signing_alg_values_supported"] = json!([ID_TOKEN_ALGORITHM]);
+    metadata["subject_types_supported"] = json!(subject_types_supported);
+    metadata["claims_supported"] = json!(claims_supported);
+    metadata["acr_values_supported"] = json!(acr::ACR_VALUES);
+
+    Json(metadata)
+}
+
+/// RFC 8414 OAuth 2.0 Authorization Server Metadata
+pub async fn oauth_authorization_server(
+    State((storage, _, config, _)): State<AppState>,
+) -> Json<Value> {
+    let storage_guard = storage.read().await;
+    Json(server_metadata(&config, &storage_guard))
+}
+
+/// Metadata shared by both documents. Grant types and scopes come from the
+/// registered clients, so nothing is advertised that no client can use.
+fn server_metadata(config: &Config, storage: &FileStorage) -> Value {
+    let client_grant_types: BTreeSet<&str> = storage
+        .get_all_clients()
+        .flat_map(|c| c.grant_types.iter().map(|g| g.as_str()))
+        .collect();
+
+    let grant_types_supported: Vec<&str> = SUPPORTED_GRANT_TYPES
+        .iter()
+        .copied()
+        .filter(|g| client_grant_types.contains(g))
+        .collect();
+
+    let scopes_supported: BTreeSet<&str> = storage
+        .get_all_clients()
+        .flat_map(|c| c.allowed_scopes.iter().map(|s| s.as_str()))
+        .collect();
+
+    json!({
+        "issuer": config.instance.issuer,
+        "authorization_endpoint": endpoint(config, routes::AUTHORIZE),
+        "token_endpoint": endpoint(config, routes::TOKEN),
+        "introspection_endpoint": endpoint(config, routes::INTROSPECT),
+        "revocation_endpoint": endpoint(config, routes::REVOKE),
+        "jwks_uri": endpoint(config, routes::JWKS),
+        "scopes_supported": scopes_supported,
+        "response_types_supported": ["code"],
+        "response_modes_supported": ["query"],
+        "code_challenge_methods_supported": ["S256"],
+        "grant_types_supported": grant_types_supported,
+        "token_endpoint_auth_methods_supported": TOKEN_ENDPOINT_AUTH_METHODS,
+        "introspection_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post"],
+        "revocation_endpoint_auth_methods_supported": TOKEN_ENDPOINT_AUTH_METHODS,
+    })
+}
+
+/// Public keys ID tokens are signed with (RFC 7517).
+pub async fn jwks(State((_, _, config, runtime)): State<AppState>) -> Result<Json<Value>, StatusCode> {
+    let key = runtime.oidc.key(&config.oidc).await.map_err(|e| {
+        tracing::error!(
+            service = "auth-service",
+            event = "id_token_key_unavailable",
+            error = %format!("{:#}", e)
+        );
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(key.jwks()))
+}
+
+fn endpoint(config: &Config, path: &str) -> String {
+    format!("{}{}", config.instance.issuer.trim_end_matches('/'), path)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::routes;
+    use crate::testing::*;
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_advertised_endpoints_are_routed() {
+        let dir = TempDir::new("discovery");
+        let data_dir = dir.path();
+        write_client(data_dir, "webapp", &["https://app.example.com/cb"], &["authorization_code", "implicit"], json!({"allowed_scopes": ["openid", "profile"]})).await;
+
+        let mut config = Config::default();
+        config.oidc.signing_key = format!("{}/keys/oidc.pem", data_dir);
+        let (app, _, _) = test_app(data_dir, &config).await;
+
+        for document in [routes::OIDC_DISCOVERY, routes::OAUTH_METADATA] {
+            let response = get(&app, document).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let metadata = body_json(response).await;
+
+            for (key, value) in metadata.as_object().unwrap() {
+                if !(key.ends_with("_endpoint") || key.ends_with("_uri")) {
+                    continue;
+                }
+                let url = value.as_str().unwrap();
+                let path = url
+                    .strip_prefix(&config.instance.issuer)
+                    .unwrap_or_else(|| panic!("{} points outside the issuer: {}", key, url));
+
+                // POST-only routes answer GET with 405; only 404 means "not routed"
+                let status = get(&app, path).await.status();
+                assert_ne!(status, StatusCode::NOT_FOUND, "{} advertised but not routed: {}", key, path);
+            }
+
+            // Only grant types that are both implemented and enabled on a client
+            assert_eq!(metadata["grant_types_supported"], json!(["authorization_code"]));
+            assert_eq!(metadata["issuer"], config.instance.issuer);
+            assert_eq!(metadata["response_types_supported"], json!(["code"]));
+            assert_eq!(metadata["code_challenge_methods_supported"], json!(["S256"]));
+            assert_eq!(metadata["scopes_supported"], json!(["openid", "profile"]));
+            assert_eq!(metadata["jwks_uri"], format!("{}{}", config.instance.issuer, routes::JWKS));
+        }
+
+        // Fields OpenID Connect Discovery requires on top of RFC 8414
+        let metadata = body_json(get(&app, routes::OIDC_DISCOVERY).await).await;
+        for required in ["authorization_endpoint", "jwks_uri", "subject_types_supported", "id_token_signing_alg_values_supported"] {
+            assert!(!metadata[required].is_null(), "{} missing", required);
+        }
+        assert_eq!(metadata["id_token_signing_alg_values_supported"], json!(["ES256"]));
+        assert_eq!(metadata["subject_types_supported"], json!(["public"]));
+
+        // The key set carries the key the advertised algorithm needs
+        let response = get(&app, routes::JWKS).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let jwks = body_json(response).await;
+        assert_eq!(jwks["keys"][0]["kty"], "EC");
+        assert_eq!(jwks["keys"][0]["alg"], "ES256");
+    }
+}
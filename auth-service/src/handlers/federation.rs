@@ -0,0 +1,420 @@
+use axum::{
+    extract::{ConnectInfo, Path, Query, State},
+    http::StatusCode,
+    response::{Json, Redirect},
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{net::SocketAddr, sync::Arc};
+use time::OffsetDateTime;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::{
+    acr, audit,
+    config::{Config, UpstreamProviderConfig},
+    federation::UpstreamIdentity,
+    handlers::auth::{complete_first_factor, login_refusal},
+    jwt::JwtService,
+    models::{FederatedIdentity, LoginResponse, User},
+    routes,
+    runtime::Runtime,
+    sessions::ClientInfo,
+    storage::FileStorage,
+};
+
+type AppState = (Arc<RwLock<FileStorage>>, Arc<JwtService>, Config, Arc<Runtime>);
+
+#[derive(Debug, Deserialize)]
+pub struct StartQuery {
+    /// Query string of the login page, restored after the provider's login
+    #[serde(default)]
+    pub return_to: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CallbackQuery {
+    pub state: Option<String>,
+    pub code: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompleteRequest {
+    pub code: String,
+}
+
+/// How an upstream identity maps onto a local account.
+enum Resolution {
+    /// Logged in before with this identity
+    Known(User),
+    /// Existing account with the same verified address, linked just now
+    Linked(User),
+    /// Account created for this login
+    Provisioned(User),
+    Refused(&'static str),
+}
+
+/// Providers offered on the login page.
+pub async fn providers(State((_, _, config, _)): State<AppState>) -> Json<Value> {
+    let providers: Vec<Value> = config.federation.providers.iter()
+        .map(|p| json!({ "id": p.id, "name": p.name }))
+        .collect();
+    Json(json!({ "providers": providers }))
+}
+
+/// Send the user to the provider's login.
+pub async fn start(
+    State((_, _, config, runtime)): State<AppState>,
+    Path(provider_id): Path<String>,
+    Query(query): Query<StartQuery>,
+) -> Result<Redirect, StatusCode> {
+    let provider = find_provider(&config, &provider_id).ok_or(StatusCode::NOT_FOUND)?;
+
+    // Only ever used as the query of our own login page
+    let return_to = query.return_to.split('#').next().unwrap_or_default().to_string();
+    let url = runtime.federation
+        .authorization_url(provider, &callback_url(&config), return_to)
+        .await
+        .map_err(|e| {
+            warn!(
+                service = "auth-service",
+                event = "federation_start_failed",
+                provider = %provider.id,
+                error = %format!("{:#}", e)
+            );
+            StatusCode::BAD_GATEWAY
+        })?;
+
+    Ok(Redirect::to(&url))
+}
+
+/// Where the provider sends the user back to. Finishes the login and hands
+/// the result to the login page through a single-use code in the fragment.
+pub async fn callback(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    client: ClientInfo,
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    Query(query): Query<CallbackQuery>,
+) -> Result<Redirect, StatusCode> {
+    let Some(login) = query.state.as_deref().and_then(|state| runtime.federation.take_pending(state)) else {
+        return Ok(login_page("", "federation_error=invalid_state"));
+    };
+    let back = |fragment: String| login_page(&login.return_to, &fragment);
+    let refused = |provider: &str, reason: &'static str| {
+        warn!(
+            service = "auth-service",
+            event = "federated_login",
+            provider = %provider,
+            ip = %addr,
+            success = false,
+            reason = reason
+        );
+        back(format!("federation_error={}", reason))
+    };
+
+    let Some(provider) = find_provider(&config, &login.provider) else {
+        return Ok(refused(&login.provider, "unknown_provider"));
+    };
+    let Some(code) = query.code.as_deref().filter(|_| query.error.is_none()) else {
+        // Cancelled or denied at the provider
+        info!(
+            service = "auth-service",
+            event = "federated_login",
+            provider = %provider.id,
+            success = false,
+            reason = "upstream_error",
+            error = ?query.error
+        );
+        return Ok(back("federation_error=upstream_error".to_string()));
+    };
+
+    let identity = match runtime.federation.exchange(provider, &login, code, &callback_url(&config)).await {
+        Ok(identity) => identity,
+        Err(e) => {
+            warn!(
+                service = "auth-service",
+                event = "federation_exchange_failed",
+                provider = %provider.id,
+                error = %format!("{:#}", e)
+            );
+            return Ok(refused(&provider.id, "upstream_error"));
+        }
+    };
+
+    let resolution = {
+        let mut storage_guard = storage.write().await;
+        resolve_user(&mut storage_guard, provider, &identity).await.map_err(|e| {
+            warn!(
+                service = "auth-service",
+                event = "federated_user_update_failed",
+                provider = %provider.id,
+                error = %format!("{:#}", e)
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+    };
+
+    let user = match resolution {
+        Resolution::Known(user) => user,
+        Resolution::Linked(user) => {
+            record(&runtime, &user, "federated_identity_linked", provider, &identity, &client).await?;
+            user
+        }
+        Resolution::Provisioned(user) => {
+            record(&runtime, &user, "user_provisioned", provider, &identity, &client).await?;
+            user
+        }
+        Resolution::Refused(reason) => return Ok(refused(&provider.id, reason)),
+    };
+
+    let storage_guard = storage.read().await;
+    if !user.is_active() {
+        return Ok(refused(&provider.id, "user_inactive"));
+    }
+    if let Some(reason) = login_refusal(&user, &storage_guard) {
+        return Ok(refused(&provider.id, reason));
+    }
+
+    let amr = acr::add_method(&[], acr::AMR_FEDERATED);
+    let response = complete_first_factor(&user, amr, &storage_guard, &jwt_service, &config, &runtime, &client).await?;
+
+    if response.success && !response.requires_mfa {
+        record(&runtime, &user, "federated_login", provider, &identity, &client).await?;
+        info!(
+            service = "auth-service",
+            event = "federated_login",
+            provider = %provider.id,
+            user_id = %user.id,
+            success = true
+        );
+    }
+
+    let handoff = runtime.federation.hand_off(response);
+    Ok(back(format!("federation={}", handoff)))
+}
+
+/// Result of the federated login the login page got a code for.
+pub async fn complete(
+    State((_, _, _, runtime)): State<AppState>,
+    Json(request): Json<CompleteRequest>,
+) -> Json<LoginResponse> {
+    Json(runtime.federation.take_handoff(&request.code).unwrap_or_else(LoginResponse::failed))
+}
+
+/// The account `identity` logs in as. Addresses only link to an account if
+/// both sides have verified them; otherwise whoever controls an upstream
+/// account with a victim's address could take over the local one.
+async fn resolve_user(
+    storage: &mut FileStorage,
+    provider: &UpstreamProviderConfig,
+    identity: &UpstreamIdentity,
+) -> anyhow::Result<Resolution> {
+    if let Some(user) = storage.get_user_by_federated_identity(&provider.id, &identity.subject) {
+        return Ok(Resolution::Known(user.clone()));
+    }
+
+    let Some(email) = identity.email.as_deref() else {
+        return Ok(Resolution::Refused("email_missing"));
+    };
+    if !identity.email_verified && !provider.trust_email {
+        return Ok(Resolution::Refused("upstream_email_unverified"));
+    }
+    let link = FederatedIdentity {
+        provider: provider.id.clone(),
+        subject: identity.subject.clone(),
+        linked_at: OffsetDateTime::now_utc(),
+    };
+
+    if let Some(user) = storage.get_user_by_email(email) {
+        if !user.verified {
+            return Ok(Resolution::Refused("email_not_verified"));
+        }
+        if user.federated_identities.iter().any(|i| i.provider == provider.id) {
+            return Ok(Resolution::Refused("linked_to_other_identity"));
+        }
+        let user_id = user.id.clone();
+        let user = storage.modify_user(&user_id, |u| {
+            u.federated_identities.push(link);
+            Ok(())
+        }).await?;
+        return Ok(Resolution::Linked(user));
+    }
+
+    let Some(org) = provider.provision_org.as_deref().and_then(|org| storage.get_organization(org)) else {
+        return Ok(Resolution::Refused("not_provisioned"));
+    };
+
+    // Upstream claims only fill claims the registry knows
+    let mut claims = org.registration.default_claims.clone();
+    let registry = storage.get_claims_registry();
+    claims.extend(
+        identity.claims.iter()
+            .filter(|(name, _)| registry.claims.contains_key(*name))
+            .map(|(name, value)| (name.clone(), value.clone())),
+    );
+
+    let mut user = User::new(
+        email.to_string(),
+        String::new(),
+        identity.given_name.clone().unwrap_or_default(),
+        identity.family_name.clone().unwrap_or_default(),
+        org.id.clone(),
+    );
+    user.verified = true;
+    user.claims = claims;
+    user.federated_identities.push(link);
+
+    Ok(match storage.create_user(user).await? {
+        Some(user) => Resolution::Provisioned(user),
+        // Registered with that address in the meantime
+        None => Resolution::Refused("email_taken"),
+    })
+}
+
+fn find_provider<'a>(config: &'a Config, id: &str) -> Option<&'a UpstreamProviderConfig> {
+    config.federation.providers.iter().find(|p| p.id == id)
+}
+
+fn callback_url(config: &Config) -> String {
+    format!("{}{}", config.instance.issuer.trim_end_matches('/'), routes::FEDERATION_CALLBACK)
+}
+
+/// The login page with the query it was left with and `fragment` for its script.
+fn login_page(return_to: &str, fragment: &str) -> Redirect {
+    let query = if return_to.is_empty() { String::new() } else { format!("?{}", return_to) };
+    Redirect::to(&format!("/{}#{}", query, fragment))
+}
+
+async fn record(
+    runtime: &Runtime,
+    user: &User,
+    event_type: &str,
+    provider: &UpstreamProviderConfig,
+    identity: &UpstreamIdentity,
+    client: &ClientInfo,
+) -> Result<(), StatusCode> {
+    let mut event = audit::event(event_type, user, client);
+    event.metadata.insert("provider".to_string(), json!(provider.id));
+    event.metadata.insert("subject".to_string(), json!(identity.subject));
+    audit::record(&runtime.audit, &event).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::testing::*;
+    use crate::{federation, jwt, routes};
+    use axum::http::StatusCode;
+    use serde_json::{json, Value};
+
+    #[tokio::test]
+    async fn test_federated_login_through_upstream_provider() {
+        let dir = TempDir::new("federation");
+        let data_dir = dir.path();
+        write_file(
+            &format!("{}/orgs.json", data_dir),
+            json!({"orgs": [{
+                "id": "school",
+                "name": "School",
+                "description": "",
+                "metadata": {},
+                "registration": {"default_claims": {"roles": ["staff"]}},
+                "created_at": "2024-01-01T00:00:00Z"
+            }]})
+            .to_string(),
+        )
+        .await;
+        let mut anna_path = String::new();
+        for (id, email, verified) in [("user-anna", "anna@example.com", true), ("user-bob", "bob@example.com", true), ("user-carl", "carl@example.com", false)] {
+            let path = write_user(data_dir, json!({
+                "id": id,
+                "email": email,
+                "first_name": "Local",
+                "last_name": "User",
+                "verified": verified
+            }))
+            .await;
+            if id == "user-anna" {
+                anna_path = path;
+            }
+        }
+
+        let upstream = federation::testing::MockProvider::start().await;
+        let mut config = Config::default();
+        config.federation.providers.push(upstream.config("corp", Some("school")));
+        let jwt = jwt::JwtService::new(&config.jwt_secret);
+        let (app, storage, _) = test_app(data_dir, &config).await;
+
+        let providers = body_json(get(&app, "/api/auth/federation/providers").await).await;
+        assert_eq!(providers["providers"], json!([{"id": "corp", "name": "Mock"}]));
+
+        // Start at the provider, log in there and come back to the callback;
+        // the answer is where the login page continues
+        let location = |response: &axum::response::Response| {
+            response.headers()["location"].to_str().unwrap().to_string()
+        };
+        let login_at_provider = |claims: Value| {
+            let (app, upstream) = (&app, &upstream);
+            async move {
+                let start = get(app, "/api/auth/federation/corp/start?return_to=client_id%3Dportal").await;
+                assert_eq!(start.status(), StatusCode::SEE_OTHER);
+                let (state, code) = upstream.authorize(&location(&start), claims);
+                let callback = get(app, &format!("{}?state={}&code={}", routes::FEDERATION_CALLBACK, state, code)).await;
+                (state, location(&callback))
+            }
+        };
+        let complete = |target: String| {
+            let app = &app;
+            async move {
+                let code = target.split_once("#federation=").unwrap_or_else(|| panic!("no login in {}", target)).1.to_string();
+                post_json(app, "/api/auth/federation/complete", json!({"code": code})).await
+            }
+        };
+
+        // Unknown person, verified address: provisioned into the configured org
+        let new_person = json!({"sub": "u-1", "email": "neu@example.com", "email_verified": true, "given_name": "Nina", "family_name": "Neu"});
+        let (state, target) = login_at_provider(new_person.clone()).await;
+        assert!(target.starts_with("/?client_id=portal#federation="), "{}", target);
+        let login = complete(target.clone()).await;
+        assert_eq!(login["success"], true);
+        let claims = jwt.verify_token(login["access_token"].as_str().unwrap()).unwrap();
+        assert_eq!(claims.amr, ["fed"]);
+        {
+            let storage = storage.read().await;
+            let user = storage.get_user_by_email("neu@example.com").unwrap();
+            assert_eq!((user.org.as_str(), user.first_name.as_str(), user.verified), ("school", "Nina", true));
+            assert_eq!(user.claims["roles"], json!(["staff"]));
+            assert_eq!(user.federated_identities[0].subject, "u-1");
+        }
+
+        // Results and states are single use
+        assert_eq!(complete(target).await["success"], false);
+        let replayed = get(&app, &format!("{}?state={}&code=again", routes::FEDERATION_CALLBACK, state)).await;
+        assert_eq!(location(&replayed), "/#federation_error=invalid_state");
+
+        // Provisioned accounts have no password to log in with
+        let password_login = post_json(&app, "/api/auth/login", json!({"email": "neu@example.com", "password": ""})).await;
+        assert_eq!(password_login["success"], false);
+
+        // Known identity: the same account again, even with a new address upstream
+        let (_, target) = login_at_provider(json!({"sub": "u-1", "email": "renamed@example.com", "email_verified": true})).await;
+        let claims = jwt.verify_token(complete(target).await["access_token"].as_str().unwrap()).unwrap();
+        assert_eq!(claims.email, "neu@example.com");
+
+        // Existing verified account with the same verified address: linked
+        let (_, target) = login_at_provider(json!({"sub": "u-2", "email": "anna@example.com", "email_verified": true})).await;
+        let claims = jwt.verify_token(complete(target).await["access_token"].as_str().unwrap()).unwrap();
+        assert_eq!(claims.email, "anna@example.com");
+        assert_eq!(read_json(&anna_path).await["federated_identities"][0]["provider"], "corp");
+        let password_login = post_json(&app, "/api/auth/login", credentials("anna@example.com")).await;
+        assert_eq!(password_login["success"], true);
+
+        // No takeover through addresses either side has not verified
+        let (_, target) = login_at_provider(json!({"sub": "u-3", "email": "bob@example.com", "email_verified": false})).await;
+        assert_eq!(target, "/?client_id=portal#federation_error=upstream_email_unverified");
+        let (_, target) = login_at_provider(json!({"sub": "u-4", "email": "carl@example.com", "email_verified": true})).await;
+        assert_eq!(target, "/?client_id=portal#federation_error=email_not_verified");
+        assert!(storage.read().await.get_user_by_email("bob@example.com").unwrap().federated_identities.is_empty());
+    }
+}
@@ -6,9 +6,9 @@ use time::OffsetDateTime;
 
 use crate::{config::Config, storage::FileStorage};
 
-type AppState = (Arc<RwLock<FileStorage>>, Arc<crate::jwt::JwtService>, Config);
+type AppState = (Arc<RwLock<FileStorage>>, Arc<crate::jwt::JwtService>, Config, Arc<crate::runtime::Runtime>);
 
-pub async fn health(State((storage, _, _)): State<AppState>) -> Json<Value> {
+pub async fn health(State((storage, _, _, _)): State<AppState>) -> Json<Value> {
     let storage_guard = storage.read().await;
 
     Json(json!({
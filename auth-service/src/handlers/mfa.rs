@@ -0,0 +1,742 @@
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::Json,
+};
+use serde_json::json;
+use std::sync::Arc;
+use time::OffsetDateTime;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::{
+    acr, audit,
+    config::Config,
+    handlers::{
+        auth::{issue_login_tokens, lockout_error, record_lockout_failure, record_login},
+        oauth::bearer_user,
+    },
+    jwt::JwtService,
+    mfa,
+    models::{
+        LoginResponse, MfaConfirmRequest, MfaEnrollRequest, MfaEnrollResponse,
+        MfaPurpose, MfaRecoveryCodesRequest, MfaRecoveryCodesResponse, MfaSessionClaims,
+        MfaVerifyRequest, User,
+    },
+    runtime::Runtime,
+    sessions::{ClientInfo, LoginRecord},
+    storage::FileStorage,
+};
+
+type AppState = (Arc<RwLock<FileStorage>>, Arc<JwtService>, Config, Arc<Runtime>);
+
+/// Second step of a login: exchange the `mfa_session` and a TOTP code, or one
+/// of the user's recovery codes, for tokens.
+pub async fn verify(
+    client: ClientInfo,
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    Json(request): Json<MfaVerifyRequest>,
+) -> Result<Json<LoginResponse>, StatusCode> {
+    let session = verify_session(&jwt_service, &request.mfa_session, MfaPurpose::Verify)?;
+
+    let (user, passkey_required) = {
+        let storage_guard = storage.read().await;
+        let user = storage_guard
+            .get_user(&session.sub)
+            .filter(|u| u.is_active())
+            .ok_or(StatusCode::UNAUTHORIZED)?
+            .clone();
+        let passkey_required = storage_guard.get_security_policy().requires_passkey(&user);
+        (user, passkey_required)
+    };
+
+    if passkey_required {
+        warn!(
+            service = "auth-service",
+            event = "mfa_verify",
+            user_id = %user.id,
+            success = false,
+            reason = "passkey_required"
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    // The factor may have been reset by an admin since the password step
+    let secret = user.mfa_secret.as_deref().ok_or_else(|| {
+        warn!(
+            service = "auth-service",
+            event = "mfa_verify",
+            user_id = %user.id,
+            success = false,
+            reason = "not_enrolled"
+        );
+        StatusCode::UNAUTHORIZED
+    })?;
+
+    // Wrong codes count towards the same lockout as wrong passwords, so a
+    // new password login does not buy a new round of guesses
+    let now_utc = OffsetDateTime::now_utc();
+    if let Some(locked_until) = runtime.lockouts.locked_until(&user.id, now_utc).await.map_err(lockout_error)? {
+        warn!(
+            service = "auth-service",
+            event = "mfa_verify",
+            user_id = %user.id,
+            success = false,
+            reason = "account_locked",
+            locked_until = %locked_until
+        );
+        return Ok(Json(LoginResponse::failed()));
+    }
+
+    let mut mfa_store = runtime.mfa.write().await;
+    if !mfa_store.session_usable(&session.jti, config.security.mfa_max_attempts) {
+        warn!(
+            service = "auth-service",
+            event = "mfa_verify",
+            user_id = %user.id,
+            success = false,
+            reason = "session_exhausted"
+        );
+        return Ok(Json(LoginResponse::failed()));
+    }
+    // The attempt counts unless it succeeds; counted up front, parallel
+    // guesses cannot exceed the limit while a recovery code is checked
+    mfa_store.record_failure(&session.jti, session.exp);
+
+    let mut recovery_codes_remaining = None;
+    let reason = if mfa::is_totp_code(&request.code) {
+        let totp = build_totp(secret, &user, &config)?;
+        match mfa::matching_step(&totp, &request.code, now()) {
+            Some(step) if mfa_store.accept_step(&user.id, step) => None,
+            Some(_) => Some("code_replayed"),
+            None => Some("invalid_code"),
+        }
+    } else {
+        drop(mfa_store);
+        let remaining = use_recovery_code(&storage, &user, &request.code, &runtime, &client).await?;
+        mfa_store = runtime.mfa.write().await;
+        match remaining {
+            Some(remaining) => {
+                recovery_codes_remaining = Some(remaining);
+                None
+            }
+            None => Some("invalid_recovery_code"),
+        }
+    };
+
+    if let Some(reason) = reason {
+        warn!(
+            service = "auth-service",
+            event = "mfa_verify",
+            user_id = %user.id,
+            success = false,
+            reason = reason
+        );
+        let response = retry_response(&request.mfa_session, &mfa_store, &session, &config);
+        drop(mfa_store);
+        record_login(&runtime, &user, LoginRecord::new(false, &session.amr, Some(reason), &client)).await?;
+        record_lockout_failure(&user, &client, now_utc, &config, &runtime).await?;
+        return Ok(Json(response));
+    }
+
+    mfa_store.consume_session(&session.jti, session.exp);
+    drop(mfa_store);
+
+    let amr = acr::add_method(&session.amr, acr::AMR_OTP);
+    let storage_guard = storage.read().await;
+    let mut response = issue_login_tokens(&user, amr, &storage_guard, &jwt_service, &config, &runtime, &client).await?;
+    response.recovery_codes_remaining = recovery_codes_remaining;
+
+    info!(
+        service = "auth-service",
+        event = "mfa_verify",
+        user_id = %user.id,
+        success = response.success,
+        recovery_code = recovery_codes_remaining.is_some()
+    );
+
+    Ok(Json(response))
+}
+
+/// Replace the user's recovery codes. Needs a current TOTP code on top of the
+/// Bearer token; the old codes stop working immediately.
+pub async fn regenerate_recovery_codes(
+    client: ClientInfo,
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<MfaRecoveryCodesRequest>,
+) -> Result<Json<MfaRecoveryCodesResponse>, StatusCode> {
+    let (_, _, user) = bearer_user(&headers, &*storage.read().await, &jwt_service, &runtime, &config).await?;
+
+    let secret = user.mfa_secret.as_deref().ok_or_else(|| {
+        warn!(
+            service = "auth-service",
+            event = "mfa_recovery_codes_regenerated",
+            user_id = %user.id,
+            success = false,
+            reason = "not_enrolled"
+        );
+        StatusCode::BAD_REQUEST
+    })?;
+    let totp = build_totp(secret, &user, &config)?;
+
+    // Wrong codes count towards the lockout as at the login; a locked
+    // account fails like a wrong code
+    let now_utc = OffsetDateTime::now_utc();
+    let locked = runtime.lockouts.locked_until(&user.id, now_utc).await.map_err(lockout_error)?.is_some();
+    let accepted = !locked && {
+        let mut mfa_store = runtime.mfa.write().await;
+        mfa::matching_step(&totp, &request.code, now()).is_some_and(|step| mfa_store.accept_step(&user.id, step))
+    };
+    if !accepted {
+        warn!(
+            service = "auth-service",
+            event = "mfa_recovery_codes_regenerated",
+            user_id = %user.id,
+            success = false,
+            reason = if locked { "account_locked" } else { "invalid_code" }
+        );
+        if !locked {
+            record_lockout_failure(&user, &client, now_utc, &config, &runtime).await?;
+        }
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    // Hashed before the storage is locked for writing
+    let (codes, hashes) = new_recovery_codes(&user, &config)?;
+    storage
+        .write()
+        .await
+        .modify_user(&user.id, |u| {
+            u.mfa_recovery_codes = hashes;
+            Ok(())
+        })
+        .await
+        .map_err(|e| {
+            warn!(
+                service = "auth-service",
+                event = "mfa_recovery_codes_persist_failed",
+                user_id = %user.id,
+                error = %e
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let mut event = audit::event("mfa_recovery_codes_regenerated", &user, &client);
+    event.metadata.insert("remaining_codes".to_string(), json!(codes.len()));
+    audit::record(&runtime.audit, &event).await?;
+
+    Ok(Json(MfaRecoveryCodesResponse { recovery_codes: codes }))
+}
+
+/// Start TOTP enrollment. The secret only becomes active once a first code
+/// for it is confirmed.
+pub async fn enroll(
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<MfaEnrollRequest>,
+) -> Result<Json<MfaEnrollResponse>, StatusCode> {
+    let storage_guard = storage.read().await;
+    let (user, session) = enrolling_user(
+        &headers,
+        request.mfa_session.as_deref(),
+        &storage_guard,
+        &jwt_service,
+        &runtime,
+        &config,
+    )
+    .await?;
+    reject_totp_for_passkey_users(&user, session.as_ref(), &storage_guard)?;
+
+    if user.mfa_secret.is_some() {
+        warn!(
+            service = "auth-service",
+            event = "mfa_enrollment_started",
+            user_id = %user.id,
+            success = false,
+            reason = "already_enrolled"
+        );
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let secret = mfa::generate_secret();
+    let totp = build_totp(&secret, &user, &config)?;
+
+    let mut mfa_store = runtime.mfa.write().await;
+    if let Some(session) = &session {
+        if !mfa_store.session_usable(&session.jti, config.security.mfa_max_attempts) {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+    }
+    mfa_store.begin_enrollment(&user.id, &secret, config.security.mfa_session_ttl);
+
+    info!(
+        service = "auth-service",
+        event = "mfa_enrollment_started",
+        user_id = %user.id,
+        success = true
+    );
+
+    Ok(Json(MfaEnrollResponse {
+        secret,
+        provisioning_uri: totp.get_url(),
+        expires_in: config.security.mfa_session_ttl,
+    }))
+}
+
+/// Activate the pending secret with a first valid code. Enrollment during
+/// login (via `mfa_session`) completes that login.
+pub async fn confirm_enrollment(
+    client: ClientInfo,
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<MfaConfirmRequest>,
+) -> Result<Json<LoginResponse>, StatusCode> {
+    let (user, session) = {
+        let storage_guard = storage.read().await;
+        let (user, session) = enrolling_user(
+            &headers,
+            request.mfa_session.as_deref(),
+            &storage_guard,
+            &jwt_service,
+            &runtime,
+            &config,
+        )
+        .await?;
+        reject_totp_for_passkey_users(&user, session.as_ref(), &storage_guard)?;
+        (user, session)
+    };
+
+    if user.mfa_secret.is_some() {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let now_utc = OffsetDateTime::now_utc();
+    let locked = runtime.lockouts.locked_until(&user.id, now_utc).await.map_err(lockout_error)?.is_some();
+
+    let (secret, step) = {
+        let mfa_store = runtime.mfa.read().await;
+        if let Some(session) = &session {
+            if !mfa_store.session_usable(&session.jti, config.security.mfa_max_attempts) {
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+        }
+
+        let secret = mfa_store
+            .pending_secret(&user.id)
+            .map(str::to_string)
+            .ok_or_else(|| {
+                warn!(
+                    service = "auth-service",
+                    event = "mfa_enrollment_confirmed",
+                    user_id = %user.id,
+                    success = false,
+                    reason = "no_pending_enrollment"
+                );
+                StatusCode::BAD_REQUEST
+            })?;
+        let totp = build_totp(&secret, &user, &config)?;
+        (secret, mfa::matching_step(&totp, &request.code, now()).filter(|_| !locked))
+    };
+
+    // Wrong codes count against the login's session and towards the lockout,
+    // as in `verify`; a locked account fails like a wrong code
+    let Some(step) = step else {
+        warn!(
+            service = "auth-service",
+            event = "mfa_enrollment_confirmed",
+            user_id = %user.id,
+            success = false,
+            reason = if locked { "account_locked" } else { "invalid_code" }
+        );
+        if let Some(session) = &session {
+            runtime.mfa.write().await.record_failure(&session.jti, session.exp);
+        }
+        if !locked {
+            record_lockout_failure(&user, &client, now_utc, &config, &runtime).await?;
+        }
+        let mut response = LoginResponse::failed();
+        response.requires_mfa = true;
+        response.mfa_session = request.mfa_session.clone();
+        response.mfa_enrollment_required = session.is_some();
+        return Ok(Json(response));
+    };
+
+    // Hashed before the storage is locked for writing
+    let (recovery_codes, recovery_hashes) = new_recovery_codes(&user, &config)?;
+    let mut storage_guard = storage.write().await;
+    if storage_guard.get_user(&user.id).is_some_and(|u| u.mfa_secret.is_some()) {
+        return Err(StatusCode::CONFLICT);
+    }
+    let user = storage_guard
+        .modify_user(&user.id, |u| {
+            u.mfa_secret = Some(secret.clone());
+            u.mfa_recovery_codes = recovery_hashes;
+            Ok(())
+        })
+        .await
+        .map_err(|e| {
+            warn!(
+                service = "auth-service",
+                event = "mfa_enrollment_persist_failed",
+                user_id = %user.id,
+                error = %e
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let storage_guard = storage_guard.downgrade();
+
+    // Replay state of a previous secret must not block the new one
+    let mut mfa_store = runtime.mfa.write().await;
+    mfa_store.finish_enrollment(&user.id);
+    mfa_store.reset_steps(&user.id);
+    mfa_store.accept_step(&user.id, step);
+
+    info!(
+        service = "auth-service",
+        event = "mfa_enrollment_confirmed",
+        user_id = %user.id,
+        success = true
+    );
+
+    match session {
+        Some(session) => {
+            mfa_store.consume_session(&session.jti, session.exp);
+            drop(mfa_store);
+            let amr = acr::add_method(&session.amr, acr::AMR_OTP);
+            let mut response = issue_login_tokens(&user, amr, &storage_guard, &jwt_service, &config, &runtime, &client).await?;
+            response.recovery_codes = Some(recovery_codes);
+            Ok(Json(response))
+        }
+        None => Ok(Json(LoginResponse {
+            success: true,
+            recovery_codes: Some(recovery_codes),
+            ..LoginResponse::failed()
+        })),
+    }
+}
+
+/// Burn the recovery code `code` of `user`. Returns how many codes are left,
+/// or `None` if `code` matches none of them. The codes are checked against
+/// `user` as read before, so the storage is only locked to remove the match.
+async fn use_recovery_code(
+    storage: &RwLock<FileStorage>,
+    user: &User,
+    code: &str,
+    runtime: &Runtime,
+    client: &ClientInfo,
+) -> Result<Option<usize>, StatusCode> {
+    let index = mfa::find_recovery_code(&user.mfa_recovery_codes, code).map_err(|e| {
+        warn!(
+            service = "auth-service",
+            event = "mfa_recovery_code_check_failed",
+            user_id = %user.id,
+            error = %e
+        );
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let Some(index) = index else {
+        return Ok(None);
+    };
+
+    let used_hash = &user.mfa_recovery_codes[index];
+    let mut storage_guard = storage.write().await;
+    // Used by a parallel request, or replaced, since it was checked
+    if !storage_guard.get_user(&user.id).is_some_and(|u| u.mfa_recovery_codes.contains(used_hash)) {
+        return Ok(None);
+    }
+    let updated = storage_guard
+        .modify_user(&user.id, |u| {
+            u.mfa_recovery_codes.retain(|hash| hash != used_hash);
+            Ok(())
+        })
+        .await
+        .map_err(|e| {
+            warn!(
+                service = "auth-service",
+                event = "mfa_recovery_code_persist_failed",
+                user_id = %user.id,
+                error = %e
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    drop(storage_guard);
+
+    let remaining = updated.mfa_recovery_codes.len();
+    let mut event = audit::event("mfa_recovery_code_used", user, client);
+    event.metadata.insert("remaining_codes".to_string(), json!(remaining));
+    audit::record(&runtime.audit, &event).await?;
+
+    Ok(Some(remaining))
+}
+
+fn new_recovery_codes(user: &User, config: &Config) -> Result<(Vec<String>, Vec<String>), StatusCode> {
+    mfa::generate_recovery_codes(config.security.mfa_recovery_codes).map_err(|e| {
+        warn!(
+            service = "auth-service",
+            event = "mfa_recovery_codes_generation_failed",
+            user_id = %user.id,
+            error = %e
+        );
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// Enrolling TOTP during login would not get a passkey-only user any
+/// further; it is still allowed from an authenticated session.
+fn reject_totp_for_passkey_users(
+    user: &User,
+    session: Option<&MfaSessionClaims>,
+    storage: &FileStorage,
+) -> Result<(), StatusCode> {
+    if session.is_some() && storage.get_security_policy().requires_passkey(user) {
+        warn!(
+            service = "auth-service",
+            event = "mfa_enrollment_started",
+            user_id = %user.id,
+            success = false,
+            reason = "passkey_required"
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(())
+}
+
+/// The user enrolling: holder of an enrollment `mfa_session` if one is given,
+/// otherwise of the Bearer access token.
+pub(crate) async fn enrolling_user(
+    headers: &HeaderMap,
+    mfa_session: Option<&str>,
+    storage: &FileStorage,
+    jwt_service: &JwtService,
+    runtime: &Runtime,
+    config: &Config,
+) -> Result<(User, Option<MfaSessionClaims>), StatusCode> {
+    match mfa_session {
+        Some(token) => {
+            let session = verify_session(jwt_service, token, MfaPurpose::Enroll)?;
+            let user = storage
+                .get_user(&session.sub)
+                .filter(|u| u.is_active())
+                .ok_or(StatusCode::UNAUTHORIZED)?
+                .clone();
+            Ok((user, Some(session)))
+        }
+        None => {
+            let (_, _, user) = bearer_user(headers, storage, jwt_service, runtime, config).await?;
+            Ok((user, None))
+        }
+    }
+}
+
+pub(crate) fn verify_session(
+    jwt_service: &JwtService,
+    token: &str,
+    purpose: MfaPurpose,
+) -> Result<MfaSessionClaims, StatusCode> {
+    let session = jwt_service.verify_mfa_session(token).map_err(|e| {
+        warn!(
+            service = "auth-service",
+            event = "mfa_session_invalid",
+            error = %e
+        );
+        StatusCode::UNAUTHORIZED
+    })?;
+
+    if session.purpose != purpose {
+        warn!(
+            service = "auth-service",
+            event = "mfa_session_invalid",
+            reason = "wrong_purpose",
+            purpose = ?session.purpose
+        );
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(session)
+}
+
+fn build_totp(secret: &str, user: &User, config: &Config) -> Result<totp_rs::TOTP, StatusCode> {
+    mfa::totp(secret, &user.email, &config.instance.name).map_err(|e| {
+        warn!(
+            service = "auth-service",
+            event = "totp_setup_failed",
+            user_id = %user.id,
+            error = %e
+        );
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// Wrong code: hand the session back for another try unless it is used up.
+pub(crate) fn retry_response(
+    token: &str,
+    mfa_store: &mfa::MfaStore,
+    session: &MfaSessionClaims,
+    config: &Config,
+) -> LoginResponse {
+    if mfa_store.session_usable(&session.jti, config.security.mfa_max_attempts) {
+        LoginResponse {
+            requires_mfa: true,
+            mfa_session: Some(token.to_string()),
+            ..LoginResponse::failed()
+        }
+    } else {
+        LoginResponse::failed()
+    }
+}
+
+fn now() -> u64 {
+    OffsetDateTime::now_utc().unix_timestamp() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, LockoutConfig};
+    use crate::mfa;
+    use crate::testing::*;
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_totp_enrollment_and_login() {
+        let dir = TempDir::new("mfa");
+        let data_dir = dir.path();
+        let user_path = write_user(data_dir, json!({})).await;
+
+        let mut config = Config::default();
+        config.security.require_mfa = true;
+        config.security.mfa_recovery_codes = 2;
+        config.security.mfa_max_attempts = 2;
+        config.security.lockout = LockoutConfig { max_failures: 3, base_duration: 600, max_duration: 3600 };
+        let (app, _, _) = test_app(data_dir, &config).await;
+        let credentials = credentials("anna@example.com");
+
+        // Mandatory MFA without a factor: enroll during login
+        let login = post_json(&app, "/api/auth/login", credentials.clone()).await;
+        assert_eq!(login["requires_mfa"], true);
+        assert_eq!(login["mfa_enrollment_required"], true);
+        assert!(login["access_token"].is_null());
+        let session = login["mfa_session"].clone();
+
+        let enrollment = post_json(&app, "/api/auth/mfa/enroll", json!({"mfa_session": session})).await;
+        assert!(enrollment["provisioning_uri"].as_str().unwrap().starts_with("otpauth://totp/"));
+        let totp = mfa::totp(enrollment["secret"].as_str().unwrap(), "anna@example.com", &config.instance.name).unwrap();
+        let now = time::OffsetDateTime::now_utc().unix_timestamp() as u64;
+
+        let confirmed = post_json(
+            &app,
+            "/api/auth/mfa/enroll/confirm",
+            json!({"mfa_session": session, "code": totp.generate(now)}),
+        )
+        .await;
+        assert_eq!(confirmed["success"], true);
+        assert!(confirmed["access_token"].is_string());
+        let recovery_codes = confirmed["recovery_codes"].as_array().unwrap().clone();
+        assert_eq!(recovery_codes.len(), 2);
+        assert_eq!(read_json(&user_path).await["mfa_secret"], enrollment["secret"]);
+
+        // Enrolled: the password step alone yields no tokens
+        let login = post_json(&app, "/api/auth/login", credentials.clone()).await;
+        assert_eq!(login["requires_mfa"], true);
+        assert_eq!(login["mfa_enrollment_required"], false);
+        let session = login["mfa_session"].clone();
+
+        // The code used for enrollment cannot be replayed
+        let replay = post_json(
+            &app,
+            "/api/auth/mfa/verify",
+            json!({"mfa_session": session, "code": totp.generate(now)}),
+        )
+        .await;
+        assert_eq!(replay["success"], false);
+        assert_eq!(replay["mfa_session"], session);
+
+        let verified = post_json(
+            &app,
+            "/api/auth/mfa/verify",
+            json!({"mfa_session": session, "code": totp.generate(now + 30)}),
+        )
+        .await;
+        assert_eq!(verified["success"], true);
+        assert!(verified["access_token"].is_string());
+
+        // The session is spent once the second factor succeeded
+        let again = post_json(
+            &app,
+            "/api/auth/mfa/verify",
+            json!({"mfa_session": session, "code": totp.generate(now + 60)}),
+        )
+        .await;
+        assert_eq!(again["success"], false);
+        assert!(again["mfa_session"].is_null());
+
+        // A recovery code works once in place of the TOTP code
+        let session = post_json(&app, "/api/auth/login", credentials.clone()).await["mfa_session"].clone();
+        let recovered = post_json(
+            &app,
+            "/api/auth/mfa/verify",
+            json!({"mfa_session": session, "code": recovery_codes[0]}),
+        )
+        .await;
+        assert_eq!(recovered["success"], true);
+        assert_eq!(recovered["recovery_codes_remaining"], 1);
+        assert_eq!(read_json(&user_path).await["mfa_recovery_codes"].as_array().unwrap().len(), 1);
+
+        // Wrong codes count towards the lockout; a new password login does
+        // not start the count over
+        for attempts in [2, 1] {
+            let session = post_json(&app, "/api/auth/login", credentials.clone()).await["mfa_session"].clone();
+            assert!(session.is_string());
+            for _ in 0..attempts {
+                let wrong = post_json(
+                    &app,
+                    "/api/auth/mfa/verify",
+                    json!({"mfa_session": session, "code": "wrong-recovery-code"}),
+                )
+                .await;
+                assert_eq!(wrong["success"], false);
+            }
+        }
+        let locked = post_json(&app, "/api/auth/login", credentials).await;
+        assert_eq!(locked["success"], false);
+        assert!(locked["mfa_session"].is_null());
+        assert!(tokio::fs::metadata(format!("{}/lockouts/user-1.json", data_dir)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_wrong_codes_outside_login_lock_the_account() {
+        let dir = TempDir::new("mfa-lockout");
+        let data_dir = dir.path();
+        write_user(data_dir, json!({})).await;
+
+        let mut config = Config::default();
+        config.security.lockout = LockoutConfig { max_failures: 3, base_duration: 600, max_duration: 3600 };
+        let (app, _, _) = test_app(data_dir, &config).await;
+        let login = post_json(&app, "/api/auth/login", credentials("anna@example.com")).await;
+        let token = login["access_token"].as_str().unwrap();
+
+        let (_, enrollment) = post_json_as(&app, "/api/auth/mfa/enroll", json!({}), Some(token)).await;
+        let totp = mfa::totp(enrollment["secret"].as_str().unwrap(), "anna@example.com", &config.instance.name).unwrap();
+        let now = time::OffsetDateTime::now_utc().unix_timestamp() as u64;
+        let wrong = totp.generate(now - 3600);
+
+        // Confirming the enrollment and minting recovery codes both count
+        // wrong codes towards the lockout, the third one locks
+        let (_, confirmed) = post_json_as(&app, "/api/auth/mfa/enroll/confirm", json!({"code": wrong}), Some(token)).await;
+        assert_eq!(confirmed["success"], false);
+        let (_, confirmed) = post_json_as(&app, "/api/auth/mfa/enroll/confirm", json!({"code": totp.generate(now)}), Some(token)).await;
+        assert_eq!(confirmed["success"], true);
+        for _ in 0..2 {
+            let (status, _) = post_json_as(&app, "/api/auth/mfa/recovery-codes", json!({"code": wrong}), Some(token)).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+
+        // Locked, even the right code is refused
+        let (status, _) = post_json_as(&app, "/api/auth/mfa/recovery-codes", json!({"code": totp.generate(now + 30)}), Some(token)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let locked = post_json(&app, "/api/auth/login", credentials("anna@example.com")).await;
+        assert_eq!(locked["success"], false);
+        assert!(locked["mfa_session"].is_null());
+    }
+}
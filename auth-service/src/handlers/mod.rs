@@ -1,3 +1,10 @@
 pub mod auth;
+pub mod registration;
+pub mod mfa;
+pub mod webauthn;
+pub mod federation;
+pub mod saml;
+pub mod account;
 pub mod oauth;
-pub mod health;
\ No newline at end of file
+pub mod discovery;
+pub mod health;
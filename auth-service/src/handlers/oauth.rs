@@ -1,28 +1,45 @@
 use axum::{
     extract::{Query, State},
-    http::StatusCode,
-    response::Json,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Redirect, Response},
+    Form,
 };
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::Deserialize;
 use serde_json::{json, Value};
 use std::sync::Arc;
+use time::OffsetDateTime;
 use tokio::sync::RwLock;
 
 use crate::{
+    audit,
     config::Config,
-    jwt::JwtService,
-    models::{OAuth2AuthorizeRequest, OAuth2TokenRequest, OAuth2TokenResponse, UserInfo},
+    handlers::auth::link_refresh_token,
+    jwt::{ClientGrant, JwtService},
+    models::{
+        AccessTokenFormat, Claims, Client, ClientType, OAuth2AuthorizeRequest, OAuth2TokenRequest,
+        OAuth2TokenResponse, TokenIntrospectionRequest, TokenRevocationRequest, TokenUse, User, UserInfo,
+    },
+    oidc::{IdTokenClaims, PendingAuthorization},
+    password,
+    runtime::Runtime,
+    sessions::{self, ClientInfo},
     storage::FileStorage,
+    subject,
+    tokens::is_opaque_token,
 };
 
-type AppState = (Arc<RwLock<FileStorage>>, Arc<JwtService>, Config);
+type AppState = (Arc<RwLock<FileStorage>>, Arc<JwtService>, Config, Arc<Runtime>);
 
+/// Check an authorization request (RFC 6749 §4.1.1, PKCE as in RFC 7636)
+/// against the client's registration and send the user to the login page,
+/// which answers it once there is a session. Requests that cannot be sent
+/// back to a registered redirect URI end here with `400`; all other errors
+/// go back to the client.
 pub async fn authorize(
     Query(params): Query<OAuth2AuthorizeRequest>,
-    State((_storage, _, _config)): State<AppState>,
-) -> Result<Json<Value>, StatusCode> {
-    // TODO: Implement full OAuth2 authorization flow
-    // For now, return a placeholder response
-
+    State((storage, _, _, runtime)): State<AppState>,
+) -> Result<Redirect, StatusCode> {
     tracing::info!(
         service = "auth-service",
         event = "oauth2_authorize",
@@ -30,18 +47,142 @@ pub async fn authorize(
         response_type = %params.response_type
     );
 
-    Ok(Json(json!({
-        "status": "redirect",
-        "location": "/login.html",
-        "client_id": params.client_id,
-        "redirect_uri": params.redirect_uri,
-        "state": params.state
-    })))
+    let storage_guard = storage.read().await;
+    let client = match storage_guard.get_client(&params.client_id) {
+        Some(client) if client.redirect_uris.contains(&params.redirect_uri) => client,
+        _ => {
+            tracing::warn!(
+                service = "auth-service",
+                event = "oauth2_authorize_rejected",
+                reason = "unknown_client_or_redirect_uri",
+                client_id = %params.client_id
+            );
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    };
+
+    let error = if params.response_type != "code" {
+        Some("unsupported_response_type")
+    } else if !client.grant_types.iter().any(|g| g == "authorization_code") {
+        Some("unauthorized_client")
+    } else if params.code_challenge.is_some() && params.code_challenge_method.as_deref() != Some("S256") {
+        // Only S256; "plain" would hand the verifier over with the request
+        Some("invalid_request")
+    } else if params.code_challenge.is_none() && client.require_pkce {
+        Some("invalid_request")
+    } else {
+        None
+    };
+    if let Some(error) = error {
+        tracing::warn!(
+            service = "auth-service",
+            event = "oauth2_authorize_rejected",
+            reason = error,
+            client_id = %client.client_id
+        );
+        let mut response = vec![("error", error)];
+        if let Some(state) = &params.state {
+            response.push(("state", state));
+        }
+        return Ok(Redirect::to(&with_query(&params.redirect_uri, &response)?));
+    }
+
+    let scope = params.scope.as_deref()
+        .unwrap_or("openid profile email")
+        .split_whitespace()
+        .filter(|s| client.allowed_scopes.iter().any(|allowed| allowed == s))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let handle = runtime.oidc.remember(PendingAuthorization::new(
+        client.client_id.clone(),
+        params.redirect_uri,
+        scope.clone(),
+        params.state,
+        params.nonce,
+        params.code_challenge,
+    ));
+
+    // client_id and scope only inform the page; the handle is what counts
+    Ok(Redirect::to(&login_page(&[("authorize", &handle), ("client_id", &client.client_id), ("scope", &scope)])))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompleteAuthorizationRequest {
+    /// Handle of the authorization request from the login page's `?authorize=`
+    pub request: String,
+}
+
+/// Answer a pending authorization request with a code for the user of the
+/// login page's session. `401` asks the page to log the user in first.
+pub async fn complete_authorization(
+    client_info: ClientInfo,
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<CompleteAuthorizationRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let storage_guard = storage.read().await;
+    let (claims, client_id, user) = bearer_user(&headers, &storage_guard, &jwt_service, &runtime, &config).await?;
+
+    // Only the login page's own session, not tokens held by some client
+    if client_id.is_some() {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    let pending = runtime.oidc.take(&request.request).ok_or(StatusCode::NOT_FOUND)?;
+
+    let mut response = Vec::new();
+    let code = runtime.oidc.issue_code(pending.clone(), &user.id, claims);
+    response.push(("code", code.as_str()));
+    if let Some(state) = &pending.state {
+        response.push(("state", state));
+    }
+    let redirect_uri = with_query(&pending.redirect_uri, &response)?;
+
+    let mut event = audit::event("authorization_code_issued", &user, &client_info);
+    event.metadata.insert("client_id".to_string(), json!(pending.client_id));
+    event.metadata.insert("scope".to_string(), json!(pending.scope));
+    audit::record(&runtime.audit, &event).await?;
+
+    tracing::info!(
+        service = "auth-service",
+        event = "authorization_code_issued",
+        client_id = %pending.client_id,
+        user_id = %user.id
+    );
+
+    Ok(Json(json!({ "redirect_uri": redirect_uri })))
+}
+
+/// The client's redirect URI with `params` added to its query.
+fn with_query(redirect_uri: &str, params: &[(&str, &str)]) -> Result<String, StatusCode> {
+    let mut url = reqwest::Url::parse(redirect_uri).map_err(|_| StatusCode::BAD_REQUEST)?;
+    url.query_pairs_mut().extend_pairs(params);
+    Ok(url.to_string())
 }
 
+/// The login page with `params` in its query.
+fn login_page(params: &[(&str, &str)]) -> String {
+    let query: String = params
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, urlencoding::encode(value)))
+        .collect::<Vec<_>>()
+        .join("&");
+    format!("/?{}", query)
+}
+
+/// Grant types the token endpoint implements. Discovery advertises the
+/// subset that at least one registered client is allowed to use.
+pub const SUPPORTED_GRANT_TYPES: &[&str] = &["authorization_code", "refresh_token"];
+
+/// Client authentication methods accepted by `authenticate_client`.
+pub const TOKEN_ENDPOINT_AUTH_METHODS: &[&str] = &["client_secret_basic", "client_secret_post", "none"];
+
+/// RFC 6749 token endpoint. Parameters come form-encoded, as §4.1.3 and §6
+/// prescribe.
 pub async fn token(
-    State((storage, jwt_service, config)): State<AppState>,
-    Json(request): Json<OAuth2TokenRequest>,
+    client_info: ClientInfo,
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    headers: HeaderMap,
+    Form(request): Form<OAuth2TokenRequest>,
 ) -> Result<Json<OAuth2TokenResponse>, StatusCode> {
     tracing::info!(
         service = "auth-service",
@@ -50,129 +191,1098 @@ pub async fn token(
         client_id = %request.client_id
     );
 
-    // For development/testing, we'll create a token for the admin user
-    // In production, this should validate the authorization code properly
     let storage_guard = storage.read().await;
 
-    // Get admin user for token creation
-    let user = match storage_guard.get_user_by_email("admin@example.com") {
-        Some(user) => user,
-        None => {
+    let client = authenticate_client(
+        &storage_guard,
+        &headers,
+        Some(&request.client_id),
+        request.client_secret.as_deref(),
+    )?;
+
+    if client.client_id != request.client_id {
+        tracing::warn!(
+            service = "auth-service",
+            event = "oauth2_token_error",
+            reason = "client_id_mismatch",
+            client_id = %request.client_id
+        );
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    if !SUPPORTED_GRANT_TYPES.contains(&request.grant_type.as_str())
+        || !client.grant_types.contains(&request.grant_type)
+    {
+        tracing::warn!(
+            service = "auth-service",
+            event = "oauth2_token_error",
+            reason = "unsupported_grant_type",
+            grant_type = %request.grant_type,
+            client_id = %client.client_id
+        );
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    // Both grants carry the login of the login page's session: the code in
+    // its grant, the refresh token in its claims
+    let (user, login, authorization) = if request.grant_type == "refresh_token" {
+        let refresh_token = request.refresh_token.as_deref().ok_or(StatusCode::BAD_REQUEST)?;
+        let refresh_claims = if is_opaque_token(refresh_token) {
+            runtime.tokens.read().await.resolve(refresh_token).map(|record| record.claims.clone())
+        } else {
+            jwt_service.verify_token(refresh_token).ok()
+        };
+        let Some(refresh_claims) = refresh_claims else {
             tracing::warn!(
                 service = "auth-service",
                 event = "oauth2_token_error",
-                reason = "admin_user_not_found"
+                reason = "invalid_refresh_token",
+                client_id = %client.client_id
             );
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            return Err(StatusCode::BAD_REQUEST);
+        };
+
+        // RFC 6749 §6: only a refresh token, and only by the client it was
+        // issued to; the login page's own tokens are no client's
+        if !refresh_claims.is_refresh_token() || refresh_claims.azp.as_deref() != Some(client.client_id.as_str()) {
+            tracing::warn!(
+                service = "auth-service",
+                event = "oauth2_token_error",
+                reason = "refresh_token_not_issued_to_client",
+                client_id = %client.client_id,
+                azp = ?refresh_claims.azp
+            );
+            return Err(StatusCode::BAD_REQUEST);
+        }
+
+        // Impersonation tokens run out with their record, never into fresh tokens
+        if refresh_claims.act.is_some() {
+            tracing::warn!(
+                service = "auth-service",
+                event = "oauth2_token_error",
+                reason = "impersonation_token"
+            );
+            return Err(StatusCode::BAD_REQUEST);
         }
+
+        // The token carries the client's view of `sub`
+        let user = match token_user(&storage_guard, &refresh_claims, Some(&client.client_id), &config)? {
+            Some(user) => user,
+            None => {
+                tracing::warn!(
+                    service = "auth-service",
+                    event = "oauth2_token_error",
+                    reason = "refresh_user_unavailable"
+                );
+                return Err(StatusCode::BAD_REQUEST);
+            }
+        };
+
+        // Revoked by the client, ended by the user in their account, or
+        // consent withdrawn for this client
+        let reason = if runtime.tokens.read().await.is_refresh_token_revoked(&refresh_claims.jti) {
+            Some("refresh_token_revoked")
+        } else if !session_open(&storage_guard, &config, &runtime, user, &refresh_claims, Some(&client.client_id)).await? {
+            Some("session_ended")
+        } else if !runtime.consents
+            .allows_refresh(&user.id, &client.client_id, refresh_claims.iat)
+            .await
+            .map_err(consent_error)?
+        {
+            Some("consent_revoked")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            tracing::warn!(
+                service = "auth-service",
+                event = "oauth2_token_error",
+                reason = reason,
+                client_id = %client.client_id,
+                user_id = %user.id
+            );
+            return Err(StatusCode::BAD_REQUEST);
+        }
+
+        (user, refresh_claims, None)
+    } else {
+        // Single use, for the client and redirect URI it was issued to, and
+        // only with the verifier of the request's PKCE challenge
+        let grant = request.code.as_deref()
+            .and_then(|code| runtime.oidc.redeem(code))
+            .filter(|grant| {
+                grant.request.client_id == client.client_id
+                    && request.redirect_uri.as_deref() == Some(grant.request.redirect_uri.as_str())
+                    && grant.verifies(request.code_verifier.as_deref())
+            });
+        let Some(grant) = grant else {
+            tracing::warn!(
+                service = "auth-service",
+                event = "oauth2_token_error",
+                reason = "invalid_grant",
+                client_id = %client.client_id
+            );
+            return Err(StatusCode::BAD_REQUEST);
+        };
+
+        let user = match storage_guard
+            .get_user(&grant.user_id)
+            .filter(|u| u.is_active() && !u.token_revoked_by_password_change(grant.login.iat))
+        {
+            Some(user) => user,
+            None => {
+                tracing::warn!(
+                    service = "auth-service",
+                    event = "oauth2_token_error",
+                    reason = "code_user_unavailable"
+                );
+                return Err(StatusCode::BAD_REQUEST);
+            }
+        };
+
+        (user, grant.login, Some(grant.request))
     };
 
-    // Create JWT tokens using same logic as login endpoint
-    let claims_registry = crate::models::ClaimsRegistry {
-        claims: std::collections::HashMap::new(),
+    let claims_registry = storage_guard.get_claims_registry();
+    let scope = match (&authorization, request.scope.as_deref()) {
+        (Some(authorization), _) => authorization.scope.clone(),
+        (None, Some(requested)) => requested
+            .split_whitespace()
+            .filter(|s| client.allowed_scopes.iter().any(|allowed| allowed == s))
+            .collect::<Vec<_>>()
+            .join(" "),
+        (None, None) => "openid profile email".to_string(),
     };
 
-    let access_token = match jwt_service.create_token(
+    let requirement = scope
+        .split_whitespace()
+        .filter_map(|s| config.security.scope_requirements.get(s))
+        .fold(client.auth_requirement(), |required, by_scope| required.and(by_scope));
+    let now = OffsetDateTime::now_utc().unix_timestamp() as u64;
+    let (acr, auth_time) = (login.acr.as_deref(), login.auth_time);
+    if !requirement.is_met(acr, auth_time, now) {
+        // The client sends the user through /api/auth/step-up and authorizes
+        // again on the stepped-up session
+        tracing::warn!(
+            service = "auth-service",
+            event = "oauth2_token_error",
+            reason = "insufficient_user_authentication",
+            client_id = %client.client_id,
+            user_id = %user.id,
+            acr = ?acr,
+            min_acr = ?requirement.min_acr,
+            max_auth_age = ?requirement.max_auth_age
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let subject = match subject::subject_for(&user.id, client, config.security.pairwise_salt.as_deref()) {
+        Ok(subject) => subject,
+        Err(e) => {
+            tracing::error!(
+                service = "auth-service",
+                event = "oauth2_subject_failed",
+                client_id = %client.client_id,
+                error = %e
+            );
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let mut claims = jwt_service.build_claims(
         user,
-        &claims_registry,
-        vec!["auth-service".to_string()],
+        claims_registry,
         &config.instance.issuer,
         config.security.access_token_ttl,
-    ) {
+        TokenUse::Access,
+        Some(ClientGrant { client_id: &client.client_id, scope: &scope }),
+    );
+    claims.sub = subject.clone();
+    claims.copy_authentication(&login);
+
+    let id_token = if scope.split_whitespace().any(|s| s == "openid") {
+        let id_claims = IdTokenClaims {
+            iss: config.instance.issuer.clone(),
+            sub: claims.sub.clone(),
+            aud: client.client_id.clone(),
+            exp: claims.exp,
+            iat: claims.iat,
+            auth_time: claims.auth_time,
+            nonce: authorization.and_then(|a| a.nonce),
+            acr: claims.acr.clone(),
+            amr: claims.amr.clone(),
+            azp: client.client_id.clone(),
+        };
+        let signed = match runtime.oidc.key(&config.oidc).await {
+            Ok(key) => key.sign(&id_claims),
+            Err(e) => Err(e),
+        };
+        match signed {
+            Ok(token) => Some(token),
+            Err(e) => {
+                tracing::error!(
+                    service = "auth-service",
+                    event = "oauth2_id_token_creation_failed",
+                    error = %format!("{:#}", e)
+                );
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+    } else {
+        None
+    };
+
+    let access_token = match client.access_token_format {
+        AccessTokenFormat::Jwt => jwt_service.encode_claims(&claims),
+        AccessTokenFormat::Opaque => {
+            runtime.tokens.write().await
+                .issue(claims, &user.id, &client.client_id, &scope)
+                .await
+        }
+    };
+
+    let access_token = match access_token {
         Ok(token) => token,
         Err(e) => {
             tracing::warn!(
                 service = "auth-service",
-                event = "oauth2_jwt_creation_failed",
+                event = "oauth2_access_token_creation_failed",
+                format = ?client.access_token_format,
                 error = %e
             );
             return Err(StatusCode::INTERNAL_SERVER_ERROR);
         }
     };
 
-    let refresh_token = match jwt_service.create_token(
+    let mut refresh_claims = jwt_service.build_claims(
         user,
-        &claims_registry,
-        vec!["auth-service".to_string()],
+        claims_registry,
         &config.instance.issuer,
         config.security.refresh_token_ttl,
-    ) {
+        TokenUse::Refresh,
+        Some(ClientGrant { client_id: &client.client_id, scope: &scope }),
+    );
+    refresh_claims.sub = subject;
+    refresh_claims.copy_authentication(&login);
+
+    // Clients that never see JWT contents get a handle for the refresh token too
+    let refresh_token = match client.access_token_format {
+        AccessTokenFormat::Jwt => jwt_service.encode_claims(&refresh_claims),
+        AccessTokenFormat::Opaque => {
+            runtime.tokens.write().await
+                .issue(refresh_claims.clone(), &user.id, &client.client_id, &scope)
+                .await
+        }
+    };
+    let refresh_token = match refresh_token {
         Ok(token) => token,
         Err(e) => {
             tracing::warn!(
                 service = "auth-service",
                 event = "oauth2_refresh_token_creation_failed",
+                format = ?client.access_token_format,
                 error = %e
             );
             return Err(StatusCode::INTERNAL_SERVER_ERROR);
         }
     };
 
+    link_refresh_token(&runtime, &user.id, &refresh_claims, Some(&client.client_id)).await?;
+
+    let used_at = OffsetDateTime::now_utc();
+    runtime.consents.record_use(&user.id, &client.client_id, &scope, used_at).await
+        .map_err(consent_error)?;
+
+    let mut event = audit::event("token_issued", user, &client_info);
+    event.metadata.insert("client_id".to_string(), json!(client.client_id));
+    event.metadata.insert("grant_type".to_string(), json!(request.grant_type));
+    event.metadata.insert("scope".to_string(), json!(scope));
+    event.metadata.insert("access_token_format".to_string(), json!(client.access_token_format));
+    audit::record(&runtime.audit, &event).await?;
+
     Ok(Json(OAuth2TokenResponse {
         access_token,
         token_type: "Bearer".to_string(),
         expires_in: config.security.access_token_ttl,
         refresh_token: Some(refresh_token),
-        scope: "openid profile email".to_string(),
+        scope,
+        id_token,
     }))
 }
 
+/// RFC 7662 token introspection. Only confidential clients may introspect.
+pub async fn introspect(
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    headers: HeaderMap,
+    Form(request): Form<TokenIntrospectionRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let storage_guard = storage.read().await;
+    let client = authenticate_client(
+        &storage_guard,
+        &headers,
+        request.client_id.as_deref(),
+        request.client_secret.as_deref(),
+    )?;
+
+    if !matches!(client.client_type, ClientType::Confidential) {
+        tracing::warn!(
+            service = "auth-service",
+            event = "oauth2_introspect_denied",
+            client_id = %client.client_id,
+            reason = "public_client"
+        );
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let response = if is_opaque_token(&request.token) {
+        let tokens = runtime.tokens.read().await;
+        match tokens.resolve(&request.token) {
+            Some(record) => active_token_response(&record.claims, Some(&record.client_id), Some(&record.scope)),
+            None => json!({ "active": false }),
+        }
+    } else {
+        let claims = jwt_service.verify_token(&request.token).ok();
+        let user = match &claims {
+            Some(claims) => token_user(&storage_guard, claims, claims.azp.as_deref(), &config)?,
+            None => None,
+        };
+        match (claims, user) {
+            (Some(claims), Some(user))
+                if impersonation_open(&storage_guard, &claims).await?
+                    && session_open(&storage_guard, &config, &runtime, user, &claims, claims.azp.as_deref()).await? =>
+            {
+                active_token_response(&claims, None, None)
+            }
+            _ => json!({ "active": false }),
+        }
+    };
+
+    tracing::info!(
+        service = "auth-service",
+        event = "oauth2_introspect",
+        client_id = %client.client_id,
+        token_type_hint = ?request.token_type_hint,
+        active = response["active"].as_bool().unwrap_or(false)
+    );
+
+    Ok(Json(response))
+}
+
+/// RFC 7009 token revocation. Opaque tokens stop resolving at once, and a
+/// refresh token takes the access tokens of its grant along; self-contained
+/// JWT access tokens cannot be revoked and simply run out. A token issued to
+/// another client is refused with `unauthorized_client`.
+pub async fn revoke(
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    headers: HeaderMap,
+    Form(request): Form<TokenRevocationRequest>,
+) -> Result<Response, StatusCode> {
+    let storage_guard = storage.read().await;
+    let client = authenticate_client(
+        &storage_guard,
+        &headers,
+        request.client_id.as_deref(),
+        request.client_secret.as_deref(),
+    )?;
+
+    let revoke_failed = |e: anyhow::Error| {
+        tracing::error!(
+            service = "auth-service",
+            event = "oauth2_revoke_failed",
+            client_id = %client.client_id,
+            error = %e
+        );
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+
+    // The client the token was issued to and the user it stands for
+    let token = if is_opaque_token(&request.token) {
+        let tokens = runtime.tokens.read().await;
+        tokens.resolve(&request.token).map(|record| (Some(record.client_id.clone()), Some(record.user_id.clone()), record.claims.clone()))
+    } else {
+        jwt_service.verify_token(&request.token).ok().map(|claims| (claims.azp.clone(), None, claims))
+    };
+    let Some((issued_to, user_id, claims)) = token else {
+        // RFC 7009: invalid or unknown tokens are not an error
+        tracing::info!(
+            service = "auth-service",
+            event = "oauth2_revoke",
+            client_id = %client.client_id,
+            token_type_hint = ?request.token_type_hint,
+            revoked = false
+        );
+        return Ok(StatusCode::OK.into_response());
+    };
+
+    if issued_to.as_deref() != Some(client.client_id.as_str()) {
+        tracing::warn!(
+            service = "auth-service",
+            event = "oauth2_revoke_denied",
+            client_id = %client.client_id,
+            issued_to = ?issued_to,
+            reason = "unauthorized_client"
+        );
+        let error = Json(json!({ "error": "unauthorized_client" }));
+        return Ok((StatusCode::BAD_REQUEST, error).into_response());
+    }
+
+    // JWTs carry the client's view of `sub`; map it back to the user
+    let user_id = match user_id {
+        Some(user_id) => Some(user_id),
+        None => subject::find_user_by_subject(&storage_guard, client, &claims.sub, config.security.pairwise_salt.as_deref())
+            .map_err(revoke_failed)?
+            .map(|user| user.id.clone()),
+    };
+
+    let mut tokens = runtime.tokens.write().await;
+    let revoked = if is_opaque_token(&request.token) {
+        tokens.revoke(&request.token, &client.client_id).await.map_err(revoke_failed)?
+    } else if claims.is_refresh_token() {
+        tokens.revoke_refresh_token(&claims.jti, &client.client_id, claims.exp).await.map_err(revoke_failed)?
+    } else {
+        false
+    };
+    // RFC 7009 §2.1: the access tokens of the same grant go with a refresh token
+    if let Some(user_id) = user_id.filter(|_| claims.is_refresh_token()) {
+        tokens.revoke_user_client(&user_id, &client.client_id).await.map_err(revoke_failed)?;
+    }
+
+    tracing::info!(
+        service = "auth-service",
+        event = "oauth2_revoke",
+        client_id = %client.client_id,
+        token_type_hint = ?request.token_type_hint,
+        revoked = revoked
+    );
+
+    Ok(StatusCode::OK.into_response())
+}
+
+fn active_token_response(claims: &Claims, client_id: Option<&str>, scope: Option<&str>) -> Value {
+    let mut response = serde_json::to_value(claims).unwrap_or_else(|_| json!({}));
+    response["active"] = json!(true);
+    response["token_type"] = json!("Bearer");
+    if !claims.email.is_empty() {
+        response["username"] = json!(claims.email);
+    }
+    if let Some(client_id) = client_id {
+        response["client_id"] = json!(client_id);
+    }
+    if let Some(scope) = scope {
+        response["scope"] = json!(scope);
+    }
+    response
+}
+
+/// Identify the calling client via HTTP Basic (client_secret_basic) or form
+/// parameters (client_secret_post). Confidential clients must present a valid
+/// secret; public clients are identified by client_id alone.
+fn authenticate_client<'a>(
+    storage: &'a FileStorage,
+    headers: &HeaderMap,
+    form_client_id: Option<&str>,
+    form_client_secret: Option<&str>,
+) -> Result<&'a Client, StatusCode> {
+    let basic = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Basic "))
+        .and_then(|encoded| BASE64.decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .and_then(|pair| pair.split_once(':').map(|(id, secret)| (id.to_string(), secret.to_string())));
+
+    let (client_id, client_secret) = match &basic {
+        Some((id, secret)) => (Some(id.as_str()), Some(secret.as_str())),
+        None => (form_client_id, form_client_secret),
+    };
+
+    let client_id = client_id.ok_or_else(|| {
+        tracing::warn!(
+            service = "auth-service",
+            event = "client_auth_failed",
+            reason = "missing_client_id"
+        );
+        StatusCode::UNAUTHORIZED
+    })?;
+
+    let client = storage.get_client(client_id).ok_or_else(|| {
+        tracing::warn!(
+            service = "auth-service",
+            event = "client_auth_failed",
+            client_id = %client_id,
+            reason = "unknown_client"
+        );
+        StatusCode::UNAUTHORIZED
+    })?;
+
+    if matches!(client.client_type, ClientType::Confidential) {
+        let (secret, secret_hash) = match (client_secret, client.client_secret_hash.as_deref()) {
+            (Some(secret), Some(hash)) => (secret, hash),
+            _ => {
+                tracing::warn!(
+                    service = "auth-service",
+                    event = "client_auth_failed",
+                    client_id = %client_id,
+                    reason = "missing_secret"
+                );
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+        };
+
+        match password::verify_password(secret, secret_hash) {
+            Ok(true) => {}
+            Ok(false) => {
+                tracing::warn!(
+                    service = "auth-service",
+                    event = "client_auth_failed",
+                    client_id = %client_id,
+                    reason = "invalid_secret"
+                );
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+            Err(e) => {
+                tracing::error!(
+                    service = "auth-service",
+                    event = "client_auth_failed",
+                    client_id = %client_id,
+                    reason = "unusable_secret_hash",
+                    error = %e
+                );
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+        }
+    }
+
+    Ok(client)
+}
+
 pub async fn userinfo(
-    State((_storage, _jwt_service, _config)): State<AppState>,
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    headers: HeaderMap,
 ) -> Result<Json<UserInfo>, StatusCode> {
-    // TODO: Extract and validate Bearer token from Authorization header
-    // For now, return a placeholder response
+    let storage_guard = storage.read().await;
+    let (claims, client_id, user) =
+        resolve_bearer(&headers, &storage_guard, &jwt_service, &runtime, &config).await?;
 
     tracing::info!(
         service = "auth-service",
-        event = "oauth2_userinfo"
+        event = "oauth2_userinfo",
+        client_id = ?client_id,
+        actor = ?claims.act.as_ref().map(|act| &act.sub)
     );
 
+    // Only what the client was granted: email and profile by their scopes
+    let (email, profile) = (claims.grants("email"), claims.grants("profile"));
     Ok(Json(UserInfo {
-        sub: "placeholder-user-id".to_string(),
-        email: "placeholder@example.com".to_string(),
-        name: "Placeholder User".to_string(),
-        given_name: "Placeholder".to_string(),
-        family_name: "User".to_string(),
-        org: "placeholder-org".to_string(),
-        verified: true,
-        claims: std::collections::HashMap::new(),
+        sub: claims.sub.clone(),
+        email: email.then(|| user.email.clone()),
+        verified: email.then_some(user.verified),
+        name: profile.then(|| user.full_name()),
+        given_name: profile.then(|| user.first_name.clone()),
+        family_name: profile.then(|| user.last_name.clone()),
+        org: user.org.clone(),
+        act: claims.act,
+        claims: claims.user_claims,
     }))
 }
 
-pub async fn discovery(
-    State((_, _, config)): State<AppState>,
-) -> Result<Json<Value>, StatusCode> {
-    Ok(Json(json!({
-        "issuer": config.instance.issuer,
-        "authorization_endpoint": format!("{}/oauth2/authorize", config.instance.issuer),
-        "token_endpoint": format!("{}/oauth2/token", config.instance.issuer),
-        "userinfo_endpoint": format!("{}/oauth2/userinfo", config.instance.issuer),
-        "jwks_uri": format!("{}/oauth2/jwks", config.instance.issuer),
-        "scopes_supported": [
-            "openid",
-            "profile",
-            "email"
-        ],
-        "response_types_supported": [
-            "code"
-        ],
-        "grant_types_supported": [
-            "authorization_code",
-            "refresh_token"
-        ],
-        "subject_types_supported": [
-            "public"
-        ],
-        "id_token_signing_alg_values_supported": [
-            "HS256"
-        ],
-        "token_endpoint_auth_methods_supported": [
-            "client_secret_post",
-            "client_secret_basic"
-        ],
-        "code_challenge_methods_supported": [
-            "S256"
-        ]
-    })))
-}
\ No newline at end of file
+/// Like `resolve_bearer`, but only for the user's own tokens: acting as
+/// someone else does not reach their credentials or logins elsewhere.
+pub(crate) async fn bearer_user(
+    headers: &HeaderMap,
+    storage: &FileStorage,
+    jwt_service: &JwtService,
+    runtime: &Runtime,
+    config: &Config,
+) -> Result<(Claims, Option<String>, User), StatusCode> {
+    let (claims, client_id, user) = resolve_bearer(headers, storage, jwt_service, runtime, config).await?;
+    if let Some(act) = &claims.act {
+        tracing::warn!(
+            service = "auth-service",
+            event = "impersonation_refused",
+            user_id = %user.id,
+            actor = %act.sub
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok((claims, client_id, user))
+}
+
+/// Resolve the Bearer token in `headers` (opaque or JWT) to its claims, the
+/// client it was issued to and the active local user behind it.
+pub(crate) async fn resolve_bearer(
+    headers: &HeaderMap,
+    storage: &FileStorage,
+    jwt_service: &JwtService,
+    runtime: &Runtime,
+    config: &Config,
+) -> Result<(Claims, Option<String>, User), StatusCode> {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    let (claims, client_id) = if is_opaque_token(token) {
+        let tokens = runtime.tokens.read().await;
+        let record = tokens.resolve(token).ok_or(StatusCode::UNAUTHORIZED)?;
+        (record.claims.clone(), Some(record.client_id.clone()))
+    } else {
+        let claims = jwt_service.verify_token(token).map_err(|_| StatusCode::UNAUTHORIZED)?;
+        let client_id = claims.azp.clone();
+        (claims, client_id)
+    };
+    // Refresh tokens only ever go to the token endpoint
+    if claims.is_refresh_token() {
+        tracing::warn!(
+            service = "auth-service",
+            event = "bearer_refused",
+            reason = "refresh_token",
+            client_id = ?client_id
+        );
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let user = token_user(storage, &claims, client_id.as_deref(), config)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    if !impersonation_open(storage, &claims).await?
+        || !session_open(storage, config, runtime, user, &claims, client_id.as_deref()).await?
+    {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok((claims, client_id, user.clone()))
+}
+
+/// Tokens of a login are good only while its session lasts; tokens from
+/// before sessions, and impersonation tokens, have none and pass. A session
+/// idle or older than the policy for `client_id` allows is ended here.
+pub(crate) async fn session_open(
+    storage: &FileStorage,
+    config: &Config,
+    runtime: &Runtime,
+    user: &User,
+    claims: &Claims,
+    client_id: Option<&str>,
+) -> Result<bool, StatusCode> {
+    let Some(sid) = &claims.sid else {
+        return Ok(true);
+    };
+    let session_error = |e: anyhow::Error| {
+        tracing::error!(
+            service = "auth-service",
+            event = "session_lookup_failed",
+            user_id = %user.id,
+            error = %format!("{:#}", e)
+        );
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    let Some(session) = runtime.sessions.get(&user.id, sid).await.map_err(session_error)? else {
+        return Ok(false);
+    };
+
+    let now = OffsetDateTime::now_utc();
+    let policy = sessions::policy_for(config, storage, &user.org, client_id.or(session.client_id.as_deref()));
+    if let Some(reason) = session.ended_by(&policy, now) {
+        runtime.sessions.revoke(&user.id, sid).await.map_err(session_error)?;
+        tracing::info!(
+            service = "auth-service",
+            event = "session_expired",
+            session_id = %sid,
+            user_id = %user.id,
+            reason = reason
+        );
+        return Ok(false);
+    }
+
+    let session = runtime.sessions.touch(&session, now).await.map_err(session_error)?;
+    Ok(session.is_some())
+}
+
+fn consent_error(e: anyhow::Error) -> StatusCode {
+    tracing::error!(
+        service = "auth-service",
+        event = "consent_state_failed",
+        error = %format!("{:#}", e)
+    );
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+/// Tokens with `act` are good only while admin-service's record of the
+/// impersonation is open; all others pass.
+pub(crate) async fn impersonation_open(storage: &FileStorage, claims: &Claims) -> Result<bool, StatusCode> {
+    if claims.act.is_none() {
+        return Ok(true);
+    }
+    let impersonation = storage.get_impersonation(&claims.jti).await.map_err(|e| {
+        tracing::error!(
+            service = "auth-service",
+            event = "impersonation_lookup_failed",
+            jti = %claims.jti,
+            error = %e
+        );
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(impersonation.is_some_and(|i| i.covers(claims, OffsetDateTime::now_utc())))
+}
+
+/// The active local user a token with `claims` was issued for, or `None` if
+/// there is none or the token predates their last password change.
+pub(crate) fn token_user<'a>(
+    storage: &'a FileStorage,
+    claims: &Claims,
+    client_id: Option<&str>,
+    config: &Config,
+) -> Result<Option<&'a User>, StatusCode> {
+    // Tokens from the token endpoint carry the client's view of `sub`;
+    // first-party login tokens (no azp) carry the user id.
+    let user = match client_id.and_then(|id| storage.get_client(id)) {
+        Some(client) => subject::find_user_by_subject(
+            storage,
+            client,
+            &claims.sub,
+            config.security.pairwise_salt.as_deref(),
+        )
+        .map_err(|e| {
+            tracing::error!(
+                service = "auth-service",
+                event = "bearer_subject_failed",
+                client_id = %client.client_id,
+                error = %e
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?,
+        None => storage.get_user(&claims.sub),
+    };
+
+    Ok(user.filter(|u| u.is_active() && !u.token_revoked_by_password_change(claims.iat)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::testing::*;
+    use crate::{jwt, models, routes};
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_revoked_refresh_token_is_refused() {
+        let dir = TempDir::new("revoke");
+        let data_dir = dir.path();
+        write_user(data_dir, json!({})).await;
+        write_client(data_dir, "portal", &["https://portal.example.com/cb"], &["authorization_code", "refresh_token"], json!({"access_token_format": "opaque"})).await;
+        write_client(data_dir, "grades", &["https://grades.example.com/cb"], &["authorization_code", "refresh_token"], json!({})).await;
+
+        let mut config = Config::default();
+        config.oidc.signing_key = format!("{}/keys/oidc.pem", data_dir);
+        let (app, _, _) = test_app(data_dir, &config).await;
+        let refresh = |client_id: &str, refresh_token: &Value| {
+            json!({"grant_type": "refresh_token", "client_id": client_id, "refresh_token": refresh_token})
+        };
+        let revoke = |client_id: &str, token: &str| {
+            Request::post(routes::REVOKE)
+                .header("content-type", "application/x-www-form-urlencoded")
+                .body(Body::from(format!("token={}&client_id={}", token, client_id)))
+                .unwrap()
+        };
+
+        let login = post_json(&app, "/api/auth/login", credentials("anna@example.com")).await;
+        let login_token = login["access_token"].as_str().unwrap();
+        let (status, tokens) = client_tokens(&app, "portal", "https://portal.example.com/cb", "openid", login_token).await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, others) = client_tokens(&app, "grades", "https://grades.example.com/cb", "openid", login_token).await;
+        let access_token = tokens["access_token"].as_str().unwrap();
+        let (status, _) = request_as(&app, "GET", routes::USERINFO, None, access_token).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Opaque clients get opaque refresh handles, bound to them alone
+        let refresh_token = tokens["refresh_token"].as_str().unwrap();
+        assert!(refresh_token.starts_with("rt_"), "{}", refresh_token);
+        let (status, _) = post_form(&app, routes::TOKEN, refresh("grades", &tokens["refresh_token"])).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = post_form(&app, routes::TOKEN, refresh("portal", &others["refresh_token"])).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // Neither the login page's tokens nor access tokens refresh, and
+        // refresh tokens are no bearer tokens
+        let (status, _) = post_form(&app, routes::TOKEN, refresh("portal", &login["refresh_token"])).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = post_form(&app, routes::TOKEN, refresh("grades", &others["access_token"])).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = request_as(&app, "GET", routes::USERINFO, None, others["refresh_token"].as_str().unwrap()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // A client only revokes its own tokens
+        let response = app.clone().oneshot(revoke("grades", refresh_token)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_json(response).await["error"], "unauthorized_client");
+
+        let response = app.clone().oneshot(revoke("portal", refresh_token)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The refresh token is refused from now on, and the opaque access
+        // tokens of the grant went with it; other clients' tokens still work
+        let (status, _) = post_form(&app, routes::TOKEN, refresh("portal", &tokens["refresh_token"])).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = request_as(&app, "GET", routes::USERINFO, None, access_token).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = post_form(&app, routes::TOKEN, refresh("grades", &others["refresh_token"])).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Unknown tokens are no error
+        let response = app.clone().oneshot(revoke("portal", "not-a-token")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_authorization_code_flow() {
+        let dir = TempDir::new("code");
+        let data_dir = dir.path();
+        write_user(data_dir, json!({})).await;
+        write_client(data_dir, "webapp", &["https://app.example.com/cb"], &["authorization_code", "refresh_token"], json!({"allowed_scopes": ["openid", "profile"]})).await;
+
+        let mut config = Config::default();
+        config.oidc.signing_key = format!("{}/keys/oidc.pem", data_dir);
+        let (app, _, _) = test_app(data_dir, &config).await;
+
+        let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+        let challenge = base64::Engine::encode(
+            &base64::engine::general_purpose::URL_SAFE_NO_PAD,
+            sha2::Digest::finalize(<sha2::Sha256 as sha2::Digest>::new_with_prefix(verifier)),
+        );
+        let authorize = |extra: &str| {
+            format!(
+                "{}?response_type=code&client_id=webapp&redirect_uri=https%3A%2F%2Fapp.example.com%2Fcb&scope=openid%20profile%20email&state=xyz&nonce=n-0S6{}",
+                routes::AUTHORIZE,
+                extra
+            )
+        };
+        let location = |response: &axum::response::Response| {
+            response.headers()[axum::http::header::LOCATION].to_str().unwrap().to_string()
+        };
+        // A parameter of an absolute URL or of one relative to the login page
+        let query = |url: &str, name: &str| {
+            reqwest::Url::parse(&config.instance.issuer)
+                .and_then(|issuer| issuer.join(url))
+                .unwrap()
+                .query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.to_string())
+        };
+
+        // Unregistered redirect URIs are never redirected to
+        let response = get(&app, &authorize("").replace("app.example.com", "evil.example.com")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // The client requires PKCE; without it the error goes back to the client
+        let response = get(&app, &authorize("")).await;
+        assert!(response.status().is_redirection());
+        let error = location(&response);
+        assert!(error.starts_with("https://app.example.com/cb?"), "{}", error);
+        assert_eq!(query(&error, "error").as_deref(), Some("invalid_request"));
+        assert_eq!(query(&error, "state").as_deref(), Some("xyz"));
+
+        let with_pkce = format!("&code_challenge={}&code_challenge_method=S256", challenge);
+        let response = get(&app, &authorize(&with_pkce)).await;
+        assert!(response.status().is_redirection());
+        let login_page = location(&response);
+        let request = query(&login_page, "authorize").unwrap();
+        // Scopes the client may not have are dropped
+        assert_eq!(query(&login_page, "scope").as_deref(), Some("openid profile"));
+
+        // The login page answers the request with the session it has
+        let complete = |request: &str| json!({"request": request});
+        let (status, _) = post_json_as(&app, "/api/oauth2/authorize/complete", complete(&request), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let login = post_json(&app, "/api/auth/login", credentials("anna@example.com")).await;
+        let (status, answer) = post_json_as(&app, "/api/oauth2/authorize/complete", complete(&request), login["access_token"].as_str()).await;
+        assert_eq!(status, StatusCode::OK);
+        let redirect = answer["redirect_uri"].as_str().unwrap();
+        assert!(redirect.starts_with("https://app.example.com/cb?"));
+        assert_eq!(query(redirect, "state").as_deref(), Some("xyz"));
+        let code = query(redirect, "code").unwrap();
+        let (status, _) = post_json_as(&app, "/api/oauth2/authorize/complete", complete(&request), login["access_token"].as_str()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let exchange = |code: &str, verifier: &str| {
+            json!({
+                "grant_type": "authorization_code",
+                "client_id": "webapp",
+                "code": code,
+                "redirect_uri": "https://app.example.com/cb",
+                "code_verifier": verifier
+            })
+        };
+        // Token requests are form-encoded; JSON is no OAuth client's
+        let (status, _) = post_json_as(&app, routes::TOKEN, exchange(&code, verifier), None).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let (status, tokens) = post_form(&app, routes::TOKEN, exchange(&code, verifier)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(tokens["scope"], "openid profile");
+
+        // The ID token is signed with the published key and names the login
+        let jwks = body_json(get(&app, routes::JWKS).await).await;
+        let key = &jwks["keys"][0];
+        let decoding_key = jsonwebtoken::DecodingKey::from_ec_components(key["x"].as_str().unwrap(), key["y"].as_str().unwrap()).unwrap();
+        let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::ES256);
+        validation.set_audience(&["webapp"]);
+        validation.set_issuer(&[&config.instance.issuer]);
+        let id_token = jsonwebtoken::decode::<Value>(tokens["id_token"].as_str().unwrap(), &decoding_key, &validation).unwrap();
+        assert_eq!(id_token.header.kid.as_deref(), key["kid"].as_str());
+        assert_eq!(id_token.claims["sub"], "user-1");
+        assert_eq!(id_token.claims["nonce"], "n-0S6");
+        assert_eq!(id_token.claims["acr"], "pwd");
+        assert!(id_token.claims["auth_time"].is_u64());
+
+        // Codes are single use
+        let (status, _) = post_form(&app, routes::TOKEN, exchange(&code, verifier)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // A code is bound to the verifier of its challenge
+        let response = get(&app, &authorize(&with_pkce)).await;
+        let request = query(&location(&response), "authorize").unwrap();
+        let (_, answer) = post_json_as(&app, "/api/oauth2/authorize/complete", complete(&request), login["access_token"].as_str()).await;
+        let code = query(answer["redirect_uri"].as_str().unwrap(), "code").unwrap();
+        let (status, _) = post_form(&app, routes::TOKEN, exchange(&code, "another-verifier-another-verifier-1234567")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // The refresh token continues the login and keeps issuing ID tokens
+        let refresh = json!({"grant_type": "refresh_token", "client_id": "webapp", "refresh_token": tokens["refresh_token"]});
+        let (status, refreshed) = post_form(&app, routes::TOKEN, refresh).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(refreshed["id_token"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_pairwise_client_sees_granted_claims() {
+        let dir = TempDir::new("pairwise");
+        let data_dir = dir.path();
+        write_user(data_dir, json!({})).await;
+        write_client(data_dir, "partner", &["https://partner.example.com/cb"], &["authorization_code", "refresh_token"], json!({"allowed_scopes": ["openid", "email", "profile"], "subject_type": "pairwise"})).await;
+
+        let mut config = Config::default();
+        config.oidc.signing_key = format!("{}/keys/oidc.pem", data_dir);
+        config.security.pairwise_salt = Some("pepper".to_string());
+        let jwt = jwt::JwtService::new(&config.jwt_secret);
+        let (app, _, _) = test_app(data_dir, &config).await;
+        let refresh = |refresh_token: &Value| {
+            json!({"grant_type": "refresh_token", "client_id": "partner", "refresh_token": refresh_token, "scope": "openid email"})
+        };
+
+        let login = post_json(&app, "/api/auth/login", credentials("anna@example.com")).await;
+        let (status, tokens) = client_tokens(&app, "partner", "https://partner.example.com/cb", "openid email", login["access_token"].as_str().unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Every token names the user by the client's subject alone
+        let access = jwt.verify_token(tokens["access_token"].as_str().unwrap()).unwrap();
+        let refresh_claims = jwt.verify_token(tokens["refresh_token"].as_str().unwrap()).unwrap();
+        assert_ne!(access.sub, "user-1");
+        assert_eq!(refresh_claims.sub, access.sub);
+
+        // Email was granted, the profile was not
+        assert_eq!(access.email, "anna@example.com");
+        assert_eq!(access.name, "");
+        let (status, userinfo) = request_as(&app, "GET", routes::USERINFO, None, tokens["access_token"].as_str().unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(userinfo["sub"], json!(access.sub));
+        assert_eq!(userinfo["email"], "anna@example.com");
+        assert!(userinfo.get("name").is_none());
+        assert!(userinfo.get("given_name").is_none());
+
+        // The refresh grant finds the user behind the subject
+        let (status, refreshed) = post_form(&app, routes::TOKEN, refresh(&tokens["refresh_token"])).await;
+        assert_eq!(status, StatusCode::OK);
+        let claims = jwt.verify_token(refreshed["access_token"].as_str().unwrap()).unwrap();
+        assert_eq!(claims.sub, access.sub);
+
+        // Revoking the refresh token revokes the user's grant to the client
+        let response = app
+            .clone()
+            .oneshot(
+                Request::post(routes::REVOKE)
+                    .header("content-type", "application/x-www-form-urlencoded")
+                    .body(Body::from(format!("token={}&client_id=partner", refreshed["refresh_token"].as_str().unwrap())))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let (status, _) = post_form(&app, routes::TOKEN, refresh(&refreshed["refresh_token"])).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_impersonation_token_valid_while_open() {
+        let dir = TempDir::new("impersonation");
+        let data_dir = dir.path();
+        write_user(data_dir, json!({
+            "id": "user-tom",
+            "email": "tom@example.com",
+            "first_name": "Tom",
+            "last_name": "Teacher"
+        }))
+        .await;
+
+        let config = Config::default();
+        let (app, storage, _) = test_app(data_dir, &config).await;
+
+        // The token admin-service hands out, and its record
+        let jwt = jwt::JwtService::new(&config.jwt_secret);
+        let mut claims = {
+            let storage_guard = storage.read().await;
+            let user = storage_guard.get_user("user-tom").unwrap();
+            jwt.build_claims(user, storage_guard.get_claims_registry(), &config.instance.issuer, 1800, models::TokenUse::Access, None)
+        };
+        claims.act = Some(models::Actor { sub: "user-anna".to_string(), email: "anna@example.com".to_string() });
+        let token = jwt.encode_claims(&claims).unwrap();
+        let now = time::OffsetDateTime::now_utc();
+        let mut impersonation = models::Impersonation {
+            id: claims.jti.clone(),
+            admin_id: "user-anna".to_string(),
+            admin_email: "anna@example.com".to_string(),
+            user_id: "user-tom".to_string(),
+            org: "default".to_string(),
+            reason: "ticket 4711".to_string(),
+            started_at: now,
+            expires_at: now + time::Duration::minutes(30),
+            ended_at: None,
+        };
+        let record_path = format!("{}/impersonations/{}.json", data_dir, impersonation.id);
+        write_file(&record_path, serde_json::to_string(&impersonation).unwrap()).await;
+        let userinfo = |token: String| {
+            let app = app.clone();
+            async move { request_as(&app, "GET", routes::USERINFO, None, &token).await }
+        };
+
+        let (status, info) = userinfo(token.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(info["sub"], "user-tom");
+        assert_eq!(info["act"]["sub"], "user-anna");
+
+        // Not for the user's own credentials or for fresh tokens
+        let (status, _) = post_json_as(&app, "/api/auth/step-up", json!({}), Some(&token)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // A token naming another admin than the record is refused
+        let mut forged = claims.clone();
+        forged.act = Some(models::Actor { sub: "user-mallory".to_string(), email: "mallory@example.com".to_string() });
+        let (status, _) = userinfo(jwt.encode_claims(&forged).unwrap()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // Ending the impersonation in admin-service takes effect at once
+        impersonation.ended_at = Some(time::OffsetDateTime::now_utc());
+        tokio::fs::write(&record_path, serde_json::to_string(&impersonation).unwrap()).await.unwrap();
+        let (status, _) = userinfo(token.clone()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // Without a record there is no impersonation at all
+        tokio::fs::remove_file(&record_path).await.unwrap();
+        let (status, _) = userinfo(token).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,428 @@
+use axum::{
+    extract::{ConnectInfo, State},
+    http::StatusCode,
+    response::Json,
+};
+use serde_json::{json, Value};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use time::OffsetDateTime;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::{
+    audit,
+    config::Config,
+    jwt::JwtService,
+    mail::templates::MailTemplate,
+    models::{RegisterRequest, User, UserStatus, VerifyEmailRequest},
+    password,
+    runtime::Runtime,
+    sessions::ClientInfo,
+    storage::FileStorage,
+    tokens::hash_token,
+};
+
+type AppState = (Arc<RwLock<FileStorage>>, Arc<JwtService>, Config, Arc<Runtime>);
+
+/// Organizations open for self-registration, for the registration form.
+pub async fn organizations(
+    State((storage, _, config, _)): State<AppState>,
+) -> Result<Json<Value>, StatusCode> {
+    if !config.features.allow_registration {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let storage_guard = storage.read().await;
+    let mut orgs: Vec<Value> = storage_guard
+        .get_all_organizations()
+        .filter(|org| org.registration.enabled)
+        .map(|org| {
+            json!({
+                "id": org.id,
+                "name": org.name,
+                "invite_code_required": org.registration.invite_code.is_some(),
+                "allowed_email_domains": org.registration.allowed_email_domains,
+            })
+        })
+        .collect();
+    orgs.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+
+    Ok(Json(json!({ "organizations": orgs })))
+}
+
+/// Create an unverified account and mail a confirmation link. An address
+/// that already has an account gets the same answer as a new one.
+pub async fn register(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    client: ClientInfo,
+    State((storage, _, config, runtime)): State<AppState>,
+    Json(request): Json<RegisterRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    if !config.features.allow_registration {
+        warn!(
+            service = "auth-service",
+            event = "register",
+            ip = %addr,
+            success = false,
+            reason = "registration_disabled"
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let rejected = |reason: &str, errors: Vec<String>| {
+        info!(
+            service = "auth-service",
+            event = "register",
+            ip = %addr,
+            org = %request.org,
+            success = false,
+            reason = reason
+        );
+        Json(json!({
+            "success": false,
+            "message": errors.join(". "),
+            "errors": errors
+        }))
+    };
+
+    let email = request.email.trim().to_string();
+    let now = OffsetDateTime::now_utc();
+    let mut user = User {
+        id: format!("user-{}", uuid::Uuid::new_v4().simple()),
+        email: email.clone(),
+        password_hash: String::new(),
+        first_name: request.first_name.trim().to_string(),
+        last_name: request.last_name.trim().to_string(),
+        status: UserStatus::Active,
+        verified: false,
+        authenticated: None,
+        admin: Vec::new(),
+        org: request.org.clone(),
+        claims: HashMap::new(),
+        mfa_secret: None,
+        mfa_recovery_codes: Vec::new(),
+        webauthn_credentials: Vec::new(),
+        federated_identities: Vec::new(),
+        password_changed_at: None,
+        created_at: now,
+        updated_at: now,
+    };
+
+    {
+        let storage_guard = storage.read().await;
+        let Some(org) = storage_guard.get_organization(&request.org).filter(|o| o.registration.enabled) else {
+            return Ok(rejected(
+                "org_closed",
+                vec!["Registration is not open for this organization".to_string()],
+            ));
+        };
+        let policy = &org.registration;
+
+        if let Some(expected) = &policy.invite_code {
+            let presented = request.invite_code.as_deref().unwrap_or_default();
+            if hash_token(presented.trim()) != hash_token(expected) {
+                return Ok(rejected("invalid_invite_code", vec!["The invite code is not valid".to_string()]));
+            }
+        }
+
+        let mut errors = Vec::new();
+        if !email.contains('@') {
+            errors.push("Email address is invalid".to_string());
+        } else if !policy.allows_email(&email) {
+            errors.push("Email addresses of this domain cannot register here".to_string());
+        }
+        if user.first_name.is_empty() || user.last_name.is_empty() {
+            errors.push("First and last name are required".to_string());
+        }
+        errors.extend(
+            storage_guard
+                .get_claims_registry()
+                .registration_violations(&request.claims, &policy.default_claims),
+        );
+        errors.extend(storage_guard.password_violations(&user, &request.password).map_err(|e| {
+            warn!(
+                service = "auth-service",
+                event = "password_policy_failed",
+                error = %format!("{:#}", e)
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?);
+        if !errors.is_empty() {
+            return Ok(rejected("validation_failed", errors));
+        }
+
+        user.claims = policy.default_claims.clone();
+        user.claims.extend(request.claims.clone());
+    }
+
+    let accepted = Json(json!({
+        "success": true,
+        "message": "Please confirm your email address with the link we sent you"
+    }));
+
+    // Hashed before the duplicate check so both paths take the same time
+    user.password_hash = password::hash_password_with(&request.password, &config.security.argon2).map_err(|e| {
+        warn!(
+            service = "auth-service",
+            event = "password_hashing_failed",
+            error = %e
+        );
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let created = storage.write().await.create_user(user).await.map_err(|e| {
+        warn!(
+            service = "auth-service",
+            event = "user_registration_persist_failed",
+            error = %format!("{:#}", e)
+        );
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let Some(user) = created else {
+        info!(
+            service = "auth-service",
+            event = "register",
+            ip = %addr,
+            org = %request.org,
+            success = false,
+            reason = "email_taken"
+        );
+        return Ok(accepted);
+    };
+
+    // Mailing the link happens after the answer, so new and taken addresses
+    // take the same time; failures are logged by send_verification
+    tokio::spawn(async move {
+        if send_verification(&user, &config, &runtime).await.is_err() {
+            return;
+        }
+
+        // Nobody waits for the answer; a failure is logged by record
+        let _ = audit::record(&runtime.audit, &audit::event("user_registered", &user, &client)).await;
+
+        info!(
+            service = "auth-service",
+            event = "register",
+            ip = %addr,
+            user_id = %user.id,
+            org = %user.org,
+            success = true
+        );
+    });
+
+    Ok(accepted)
+}
+
+/// Mark the address behind a verification token as confirmed.
+pub async fn verify_email(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    client: ClientInfo,
+    State((storage, _, _, runtime)): State<AppState>,
+    Json(request): Json<VerifyEmailRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let record = runtime.verifications
+        .redeem(&request.token, OffsetDateTime::now_utc())
+        .await
+        .map_err(|e| {
+            warn!(
+                service = "auth-service",
+                event = "email_verification_token_failed",
+                error = %format!("{:#}", e)
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let Some(record) = record else {
+        warn!(
+            service = "auth-service",
+            event = "verify_email",
+            ip = %addr,
+            success = false,
+            reason = "invalid_token"
+        );
+        return Ok(Json(json!({
+            "success": false,
+            "message": "The confirmation link is invalid or has expired"
+        })));
+    };
+
+    let user = storage
+        .write()
+        .await
+        .modify_user(&record.user_id, |u| {
+            u.verified = true;
+            Ok(())
+        })
+        .await
+        .map_err(|e| {
+            warn!(
+                service = "auth-service",
+                event = "email_verification_persist_failed",
+                user_id = %record.user_id,
+                error = %format!("{:#}", e)
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    audit::record(&runtime.audit, &audit::event("email_verified", &user, &client)).await?;
+
+    info!(
+        service = "auth-service",
+        event = "verify_email",
+        ip = %addr,
+        user_id = %user.id,
+        success = true
+    );
+
+    Ok(Json(json!({
+        "success": true,
+        "message": "Email address confirmed"
+    })))
+}
+
+async fn send_verification(user: &User, config: &Config, runtime: &Runtime) -> Result<(), StatusCode> {
+    let ttl = config.security.email_verification_ttl;
+    let token = runtime.verifications.issue(&user.id, ttl, None).await.map_err(|e| {
+        warn!(
+            service = "auth-service",
+            event = "email_verification_token_failed",
+            user_id = %user.id,
+            error = %format!("{:#}", e)
+        );
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let params = HashMap::from([
+        ("name".to_string(), user.first_name.clone()),
+        (
+            "verify_url".to_string(),
+            format!("{}/#verify_token={}", config.instance.issuer.trim_end_matches('/'), token),
+        ),
+        ("expires_hours".to_string(), (ttl / 3600).to_string()),
+    ]);
+    runtime.outbox
+        .enqueue(&user.email, MailTemplate::EmailVerification, None, params)
+        .await
+        .map_err(|e| {
+            warn!(
+                service = "auth-service",
+                event = "email_verification_mail_failed",
+                user_id = %user.id,
+                error = %format!("{:#}", e)
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::testing::*;
+    use serde_json::{json, Value};
+
+    #[tokio::test]
+    async fn test_self_registration_with_email_verification() {
+        let dir = TempDir::new("register");
+        let data_dir = dir.path();
+        write_file(
+            &format!("{}/orgs.json", data_dir),
+            json!({"orgs": [{
+                "id": "school",
+                "name": "School",
+                "description": "",
+                "metadata": {},
+                "require_verified_email": true,
+                "registration": {
+                    "enabled": true,
+                    "allowed_email_domains": ["example.com"],
+                    "invite_code": "welcome",
+                    "default_claims": {"roles": ["guardian"]}
+                },
+                "created_at": "2024-01-01T00:00:00Z"
+            }]})
+            .to_string(),
+        )
+        .await;
+        write_file(
+            &format!("{}/claims.json", data_dir),
+            json!({
+                "roles": {"type": "array", "items": {"type": "string", "enum": ["staff", "guardian"]}, "description": "", "default_allowed": true, "required": true, "sensitive": null, "admin_only": null},
+                "employee_id": {"type": "string", "items": null, "description": "", "default_allowed": true, "required": false, "sensitive": null, "admin_only": null},
+                "permissions": {"type": "array", "items": {"type": "string"}, "description": "", "default_allowed": false, "required": null, "sensitive": null, "admin_only": true}
+            })
+            .to_string(),
+        )
+        .await;
+
+        let mut config = Config::default();
+        config.features.allow_registration = true;
+        let (app, _, _) = test_app(data_dir, &config).await;
+        let outbox_dir = format!("{}/mail/outbox", data_dir);
+        let outbox = || {
+            std::fs::read_dir(&outbox_dir)
+                .map(|d| d.filter(|e| e.as_ref().unwrap().path().extension().is_some_and(|ext| ext == "json")).count())
+                .unwrap_or(0)
+        };
+
+        let registration = |overrides: Value| {
+            let mut body = json!({
+                "org": "school",
+                "invite_code": "welcome",
+                "first_name": "Anna",
+                "last_name": "Test",
+                "email": "anna@example.com",
+                "password": PASSWORD,
+                "claims": {"employee_id": "E-17"}
+            });
+            body.as_object_mut().unwrap().extend(overrides.as_object().unwrap().clone());
+            body
+        };
+
+        for overrides in [
+            json!({"invite_code": "guess"}),
+            json!({"email": "anna@elsewhere.org"}),
+            json!({"claims": {"permissions": ["all"]}}),
+            json!({"claims": {"roles": ["staff"]}}),
+            json!({"password": "short"}),
+        ] {
+            let rejected = post_json(&app, "/api/auth/register", registration(overrides.clone())).await;
+            assert_eq!(rejected["success"], false, "{}", overrides);
+        }
+        assert_eq!(outbox(), 0);
+
+        let registered = post_json(&app, "/api/auth/register", registration(json!({}))).await;
+        assert_eq!(registered["success"], true);
+        // A second attempt looks the same but creates and sends nothing
+        let again = post_json(&app, "/api/auth/register", registration(json!({}))).await;
+        assert_eq!(again, registered);
+        eventually(|| outbox() == 1).await;
+        assert_eq!(outbox(), 1);
+
+        let login = post_json(&app, "/api/auth/login", credentials("anna@example.com")).await;
+        assert_eq!(login["success"], false);
+        assert_eq!(login["error"], "email_not_verified");
+
+        let mail_path = std::fs::read_dir(&outbox_dir).unwrap().next().unwrap().unwrap().path();
+        let mail = read_json(&mail_path.to_string_lossy()).await;
+        assert_eq!(mail["template"], "email_verification");
+        let token = mail["params"]["verify_url"].as_str().unwrap().split("#verify_token=").nth(1).unwrap().to_string();
+
+        let verified = post_json(&app, "/api/auth/verify-email", json!({"token": token})).await;
+        assert_eq!(verified["success"], true);
+        let reused = post_json(&app, "/api/auth/verify-email", json!({"token": token})).await;
+        assert_eq!(reused["success"], false);
+
+        let login = post_json(&app, "/api/auth/login", credentials("anna@example.com")).await;
+        assert_eq!(login["success"], true);
+
+        let user_file = std::fs::read_dir(format!("{}/users/school", data_dir)).unwrap()
+            .map(|e| e.unwrap().path())
+            .find(|p| p.extension().is_some_and(|e| e == "json"))
+            .unwrap();
+        let on_disk = read_json(&user_file.to_string_lossy()).await;
+        assert_eq!(on_disk["verified"], true);
+        assert_eq!(on_disk["claims"], json!({"roles": ["guardian"], "employee_id": "E-17"}));
+    }
+}
@@ -0,0 +1,268 @@
+use axum::{
+    extract::{Form, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Redirect, Response},
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use time::OffsetDateTime;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::{
+    audit,
+    config::Config,
+    handlers::oauth::bearer_user,
+    jwt::JwtService,
+    runtime::Runtime,
+    saml::{self, Authentication},
+    sessions::ClientInfo,
+    storage::FileStorage,
+};
+
+type AppState = (Arc<RwLock<FileStorage>>, Arc<JwtService>, Config, Arc<Runtime>);
+
+/// Parameters of both SSO bindings; names as in the SAML bindings spec.
+#[derive(Debug, Deserialize)]
+pub struct SsoParams {
+    #[serde(rename = "SAMLRequest")]
+    pub saml_request: String,
+    #[serde(rename = "RelayState")]
+    pub relay_state: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompleteRequest {
+    /// Handle of the AuthnRequest from the login page's `?saml=`
+    pub request: String,
+}
+
+pub async fn metadata(State((_, _, config, runtime)): State<AppState>) -> Result<Response, StatusCode> {
+    let key = runtime.saml.key(&config.saml).await.map_err(key_error)?;
+    Ok(([(header::CONTENT_TYPE, "application/samlmetadata+xml")], saml::metadata(&config, key)).into_response())
+}
+
+/// HTTP-Redirect binding: deflated request in the query.
+pub async fn sso_redirect(
+    State((storage, _, _, runtime)): State<AppState>,
+    Query(params): Query<SsoParams>,
+) -> Result<Redirect, StatusCode> {
+    accept_request(&storage, &runtime, params, true).await
+}
+
+/// HTTP-POST binding: request in a form field.
+pub async fn sso_post(
+    State((storage, _, _, runtime)): State<AppState>,
+    Form(params): Form<SsoParams>,
+) -> Result<Redirect, StatusCode> {
+    accept_request(&storage, &runtime, params, false).await
+}
+
+/// Check the AuthnRequest against the registration of its issuer and send
+/// the user to the login page, which answers it once there is a session.
+/// Requests need not be signed: the response only ever goes to an ACS URL
+/// registered for the provider.
+async fn accept_request(
+    storage: &RwLock<FileStorage>,
+    runtime: &Runtime,
+    params: SsoParams,
+    deflated: bool,
+) -> Result<Redirect, StatusCode> {
+    let rejected = |reason: &str, detail: String| {
+        warn!(
+            service = "auth-service",
+            event = "saml_request_rejected",
+            reason = reason,
+            detail = %detail
+        );
+        StatusCode::BAD_REQUEST
+    };
+
+    let request = saml::decode_request(&params.saml_request, deflated)
+        .map_err(|e| rejected("malformed_request", format!("{:#}", e)))?;
+
+    let storage_guard = storage.read().await;
+    let service_provider = storage_guard.get_service_provider(&request.issuer)
+        .ok_or_else(|| rejected("unknown_service_provider", request.issuer.clone()))?;
+
+    let acs_url = match &request.acs_url {
+        Some(url) if service_provider.acs_urls.contains(url) => url.clone(),
+        Some(url) => return Err(rejected("acs_url_not_registered", url.clone())),
+        None => service_provider.acs_urls.first()
+            .cloned()
+            .ok_or_else(|| rejected("acs_url_not_registered", request.issuer.clone()))?,
+    };
+    if !saml::accepts_name_id_format(service_provider, request.name_id_format.as_deref()) {
+        return Err(rejected("name_id_format_unsupported", request.name_id_format.clone().unwrap_or_default()));
+    }
+
+    let handle = runtime.saml.remember(&request, service_provider, acs_url, params.relay_state);
+
+    info!(
+        service = "auth-service",
+        event = "saml_request_accepted",
+        service_provider = %service_provider.entity_id,
+        request_id = %request.id,
+        force_authn = request.force_authn
+    );
+
+    Ok(Redirect::to(&format!("/?saml={}", handle)))
+}
+
+/// Answer a pending AuthnRequest for the user of the login page's session.
+/// `401` asks the page to log the user in (again) first.
+pub async fn complete(
+    client: ClientInfo,
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<CompleteRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let pending = runtime.saml.get(&request.request).ok_or(StatusCode::NOT_FOUND)?;
+
+    let storage_guard = storage.read().await;
+    let (claims, client_id, user) = bearer_user(&headers, &storage_guard, &jwt_service, &runtime, &config).await?;
+
+    // Only the login page's own session, not tokens held by some client
+    if client_id.is_some() {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    let auth_time = claims.auth_time
+        .and_then(|t| OffsetDateTime::from_unix_timestamp(t as i64).ok())
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    if pending.force_authn && auth_time < pending.received_at.replace_nanosecond(0).unwrap_or(pending.received_at) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let service_provider = storage_guard.get_service_provider(&pending.service_provider)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let pending = runtime.saml.take(&request.request).ok_or(StatusCode::NOT_FOUND)?;
+
+    let key = runtime.saml.key(&config.saml).await.map_err(key_error)?;
+    let authentication = Authentication {
+        acr: claims.acr.clone().unwrap_or_else(|| crate::acr::acr_for(&claims.amr).to_string()),
+        auth_time,
+    };
+    let saml_response = saml::response(&config, key, service_provider, &pending, &user, &authentication)
+        .map_err(|e| {
+            warn!(
+                service = "auth-service",
+                event = "saml_response_failed",
+                service_provider = %service_provider.entity_id,
+                error = %format!("{:#}", e)
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let mut event = audit::event("saml_assertion_issued", &user, &client);
+    event.metadata.insert("service_provider".to_string(), json!(service_provider.entity_id));
+    event.metadata.insert("acr".to_string(), json!(authentication.acr));
+    audit::record(&runtime.audit, &event).await?;
+
+    info!(
+        service = "auth-service",
+        event = "saml_assertion_issued",
+        service_provider = %service_provider.entity_id,
+        user_id = %user.id
+    );
+
+    Ok(Json(json!({
+        "acs_url": pending.acs_url,
+        "saml_response": saml_response,
+        "relay_state": pending.relay_state,
+    })))
+}
+
+fn key_error(e: anyhow::Error) -> StatusCode {
+    warn!(
+        service = "auth-service",
+        event = "saml_key_unavailable",
+        error = %format!("{:#}", e)
+    );
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::testing::*;
+    use crate::{routes, saml};
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_saml_single_sign_on() {
+        let dir = TempDir::new("saml");
+        let data_dir = dir.path();
+        write_user(data_dir, json!({
+            "id": "user-anna",
+            "last_name": "Admin",
+            "claims": {"roles": ["teacher", "staff"]}
+        }))
+        .await;
+        write_file(
+            &format!("{}/service_providers/wiki.json", data_dir),
+            json!({
+                "id": "wiki",
+                "entity_id": "https://wiki.example.com/saml",
+                "name": "Wiki",
+                "acs_urls": ["https://wiki.example.com/saml/acs"],
+                "attributes": [
+                    {"name": "urn:oid:0.9.2342.19200300.100.1.3", "friendly_name": "mail", "source": "email"},
+                    {"name": "roles", "source": "roles"}
+                ],
+                "created_at": "2024-01-01T00:00:00Z"
+            })
+            .to_string(),
+        )
+        .await;
+
+        let mut config = Config::default();
+        config.saml.certificate = format!("{}/saml/cert.pem", data_dir);
+        config.saml.private_key = format!("{}/saml/key.pem", data_dir);
+        let (app, _, runtime) = test_app(data_dir, &config).await;
+
+        let metadata = get(&app, routes::SAML_METADATA).await;
+        assert_eq!(metadata.status(), StatusCode::OK);
+        assert_eq!(metadata.headers()["content-type"], "application/samlmetadata+xml");
+
+        let encode = |value: String| value.replace('+', "%2B").replace('/', "%2F").replace('=', "%3D");
+        let sso = |issuer: &str, acs_url: Option<&str>| {
+            let request = saml::testing::redirect_request(issuer, "_req-1", acs_url);
+            format!("{}?SAMLRequest={}&RelayState=page-7", routes::SAML_SSO, encode(request))
+        };
+
+        // Only registered providers, and only their registered endpoints
+        assert_eq!(get(&app, &sso("https://evil.example.com", None)).await.status(), StatusCode::BAD_REQUEST);
+        let foreign_acs = sso("https://wiki.example.com/saml", Some("https://evil.example.com/acs"));
+        assert_eq!(get(&app, &foreign_acs).await.status(), StatusCode::BAD_REQUEST);
+
+        let accepted = get(&app, &sso("https://wiki.example.com/saml", Some("https://wiki.example.com/saml/acs"))).await;
+        assert_eq!(accepted.status(), StatusCode::SEE_OTHER);
+        let location = accepted.headers()["location"].to_str().unwrap().to_string();
+        let handle = location.strip_prefix("/?saml=").unwrap_or_else(|| panic!("{}", location));
+        let complete = json!({"request": handle});
+
+        // The login page answers once there is a session
+        let (status, _) = post_json_as(&app, "/api/saml/sso/complete", complete.clone(), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let login = post_json(&app, "/api/auth/login", credentials("anna@example.com")).await;
+        let token = login["access_token"].as_str().unwrap();
+        let (status, answer) = post_json_as(&app, "/api/saml/sso/complete", complete.clone(), Some(token)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(answer["acs_url"], "https://wiki.example.com/saml/acs");
+        assert_eq!(answer["relay_state"], "page-7");
+
+        let key = runtime.saml.key(&config.saml).await.unwrap();
+        let assertion = saml::testing::verify_response(answer["saml_response"].as_str().unwrap(), &saml::testing::public_key(key));
+        assert_eq!(assertion.name_id, "user-anna");
+        assert_eq!(assertion.audience, "https://wiki.example.com/saml");
+        assert_eq!(assertion.in_response_to, "_req-1");
+        assert_eq!(assertion.attributes["urn:oid:0.9.2342.19200300.100.1.3"], ["anna@example.com"]);
+        assert_eq!(assertion.attributes["roles"], ["teacher", "staff"]);
+
+        // Each request is answered once
+        let (status, _) = post_json_as(&app, "/api/saml/sso/complete", complete, Some(token)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,488 @@
+use anyhow::anyhow;
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::Json,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use time::OffsetDateTime;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::{
+    acr, audit,
+    config::Config,
+    handlers::{
+        auth::{issue_login_tokens, login_refusal},
+        mfa::{enrolling_user, retry_response, verify_session},
+    },
+    jwt::JwtService,
+    models::{
+        LoginResponse, MfaPurpose, User, WebAuthnAuthenticateFinishRequest,
+        WebAuthnAuthenticateStartRequest, WebAuthnRegisterFinishRequest, WebAuthnRegisterStartRequest,
+    },
+    runtime::Runtime,
+    sessions::ClientInfo,
+    storage::FileStorage,
+    webauthn::{self, Ceremony, CollectedClientData},
+};
+
+type AppState = (Arc<RwLock<FileStorage>>, Arc<JwtService>, Config, Arc<Runtime>);
+
+/// Options for `navigator.credentials.create()` to add a passkey.
+pub async fn register_start(
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<WebAuthnRegisterStartRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let storage_guard = storage.read().await;
+    let (user, session) = enrolling_user(
+        &headers,
+        request.mfa_session.as_deref(),
+        &storage_guard,
+        &jwt_service,
+        &runtime,
+        &config,
+    )
+    .await?;
+
+    if let Some(session) = &session {
+        if !runtime.mfa.read().await.session_usable(&session.jti, config.security.mfa_max_attempts) {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    let nickname = request.nickname.trim();
+    if nickname.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let challenge = runtime.webauthn.write().await.issue(
+        Ceremony::Registration {
+            user_id: user.id.clone(),
+            nickname: nickname.to_string(),
+        },
+        config.webauthn.challenge_ttl,
+    );
+
+    info!(
+        service = "auth-service",
+        event = "webauthn_registration_started",
+        user_id = %user.id
+    );
+
+    Ok(Json(webauthn::registration_options(
+        &config.webauthn,
+        &config.instance.name,
+        &user,
+        &challenge,
+    )))
+}
+
+/// Store the new passkey. Registration during login (via `mfa_session`)
+/// completes that login.
+pub async fn register_finish(
+    client: ClientInfo,
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<WebAuthnRegisterFinishRequest>,
+) -> Result<Json<LoginResponse>, StatusCode> {
+    let mut storage_guard = storage.write().await;
+    let (user, session) = enrolling_user(
+        &headers,
+        request.mfa_session.as_deref(),
+        &storage_guard,
+        &jwt_service,
+        &runtime,
+        &config,
+    )
+    .await?;
+
+    let client_data = parse_client_data(&request.credential.response.client_data_json)?;
+    let nickname = match runtime.webauthn.write().await.take(client_data.challenge()) {
+        Some(Ceremony::Registration { user_id, nickname }) if user_id == user.id => nickname,
+        _ => {
+            warn!(
+                service = "auth-service",
+                event = "webauthn_registration",
+                user_id = %user.id,
+                success = false,
+                reason = "unknown_challenge"
+            );
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    };
+
+    let credential = webauthn::verify_registration(&config.webauthn, &client_data, &request.credential, &nickname)
+        .map_err(|e| {
+            warn!(
+                service = "auth-service",
+                event = "webauthn_registration",
+                user_id = %user.id,
+                success = false,
+                error = %e
+            );
+            StatusCode::BAD_REQUEST
+        })?;
+
+    // A credential id identifies its owner at passwordless login
+    if find_credential_owner(&storage_guard, &credential.credential_id).is_some() {
+        warn!(
+            service = "auth-service",
+            event = "webauthn_registration",
+            user_id = %user.id,
+            success = false,
+            reason = "credential_exists"
+        );
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let mut mfa_store = runtime.mfa.write().await;
+    if let Some(session) = &session {
+        if !mfa_store.session_usable(&session.jti, config.security.mfa_max_attempts) {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    let credential_id = credential.credential_id.clone();
+    let user = storage_guard
+        .modify_user(&user.id, |u| {
+            if u.webauthn_credentials.iter().any(|c| c.credential_id == credential.credential_id) {
+                return Err(anyhow!("Credential already registered for user {}", u.id));
+            }
+            u.webauthn_credentials.push(credential);
+            Ok(())
+        })
+        .await
+        .map_err(|e| {
+            warn!(
+                service = "auth-service",
+                event = "webauthn_credential_persist_failed",
+                user_id = %user.id,
+                error = %e
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let mut event = audit::event("webauthn_credential_registered", &user, &client);
+    event.metadata.insert("credential_id".to_string(), json!(credential_id));
+    event.metadata.insert("nickname".to_string(), json!(nickname));
+    audit::record(&runtime.audit, &event).await?;
+
+    match session {
+        Some(session) => {
+            mfa_store.consume_session(&session.jti, session.exp);
+            drop(mfa_store);
+            let amr = acr::add_method(&session.amr, acr::AMR_HARDWARE_KEY);
+            Ok(Json(issue_login_tokens(&user, amr, &storage_guard, &jwt_service, &config, &runtime, &client).await?))
+        }
+        None => Ok(Json(LoginResponse {
+            success: true,
+            ..LoginResponse::failed()
+        })),
+    }
+}
+
+/// Options for `navigator.credentials.get()`: the user's own credentials
+/// after the password step, discoverable credentials for passwordless login.
+pub async fn authenticate_start(
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    Json(request): Json<WebAuthnAuthenticateStartRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let storage_guard = storage.read().await;
+
+    let user = match request.mfa_session.as_deref() {
+        Some(token) => {
+            let session = verify_session(&jwt_service, token, MfaPurpose::Verify)?;
+            if !runtime.mfa.read().await.session_usable(&session.jti, config.security.mfa_max_attempts) {
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+            let user = storage_guard
+                .get_user(&session.sub)
+                .filter(|u| u.is_active())
+                .ok_or(StatusCode::UNAUTHORIZED)?;
+            if user.webauthn_credentials.is_empty() {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            Some(user)
+        }
+        None => None,
+    };
+
+    let challenge = runtime.webauthn.write().await.issue(
+        Ceremony::Authentication {
+            user_id: user.map(|u| u.id.clone()),
+        },
+        config.webauthn.challenge_ttl,
+    );
+
+    Ok(Json(webauthn::authentication_options(&config.webauthn, user, &challenge)))
+}
+
+/// Check an assertion and hand out tokens, either as the second factor of a
+/// password login or as a complete passwordless login.
+pub async fn authenticate_finish(
+    client: ClientInfo,
+    State((storage, jwt_service, config, runtime)): State<AppState>,
+    Json(request): Json<WebAuthnAuthenticateFinishRequest>,
+) -> Result<Json<LoginResponse>, StatusCode> {
+    let mut storage_guard = storage.write().await;
+    let credential = &request.credential;
+
+    let session = request
+        .mfa_session
+        .as_deref()
+        .map(|token| verify_session(&jwt_service, token, MfaPurpose::Verify))
+        .transpose()?;
+
+    let client_data = parse_client_data(&credential.response.client_data_json)?;
+    let expected_user = match runtime.webauthn.write().await.take(client_data.challenge()) {
+        Some(Ceremony::Authentication { user_id }) if user_id == session.as_ref().map(|s| s.sub.clone()) => user_id,
+        _ => {
+            warn!(
+                service = "auth-service",
+                event = "webauthn_login",
+                success = false,
+                reason = "unknown_challenge"
+            );
+            return Ok(Json(LoginResponse::failed()));
+        }
+    };
+
+    let user = match expected_user {
+        Some(user_id) => storage_guard.get_user(&user_id),
+        None => passwordless_user(&storage_guard, credential.response.user_handle.as_deref(), &credential.id),
+    }
+    .filter(|u| u.is_active())
+    .cloned();
+    let Some(user) = user else {
+        warn!(
+            service = "auth-service",
+            event = "webauthn_login",
+            credential_id = %credential.id,
+            success = false,
+            reason = "unknown_credential"
+        );
+        return Ok(Json(LoginResponse::failed()));
+    };
+
+    let mut mfa_store = runtime.mfa.write().await;
+    if let Some(session) = &session {
+        if !mfa_store.session_usable(&session.jti, config.security.mfa_max_attempts) {
+            return Ok(Json(LoginResponse::failed()));
+        }
+    }
+
+    let verified = user
+        .webauthn_credentials
+        .iter()
+        .find(|c| c.credential_id == credential.id)
+        .ok_or_else(|| anyhow!("Credential {} does not belong to user {}", credential.id, user.id))
+        .and_then(|stored| {
+            webauthn::verify_assertion(&config.webauthn, &client_data, credential, stored, session.is_none())
+        });
+
+    let sign_count = match verified {
+        Ok(sign_count) => sign_count,
+        Err(e) => {
+            warn!(
+                service = "auth-service",
+                event = "webauthn_login",
+                user_id = %user.id,
+                success = false,
+                error = %e
+            );
+            return Ok(Json(match (&session, request.mfa_session.as_deref()) {
+                (Some(session), Some(token)) => {
+                    mfa_store.record_failure(&session.jti, session.exp);
+                    retry_response(token, &mfa_store, session, &config)
+                }
+                _ => LoginResponse::failed(),
+            }));
+        }
+    };
+
+    if let Some(session) = &session {
+        mfa_store.consume_session(&session.jti, session.exp);
+    }
+    drop(mfa_store);
+
+    let user = storage_guard
+        .modify_user(&user.id, |u| {
+            let stored = u
+                .webauthn_credentials
+                .iter_mut()
+                .find(|c| c.credential_id == credential.id)
+                .ok_or_else(|| anyhow!("Credential {} was removed from user {}", credential.id, u.id))?;
+            stored.sign_count = sign_count;
+            stored.last_used_at = Some(OffsetDateTime::now_utc());
+            Ok(())
+        })
+        .await
+        .map_err(|e| {
+            warn!(
+                service = "auth-service",
+                event = "webauthn_credential_persist_failed",
+                user_id = %user.id,
+                error = %e
+            );
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    // After the password step this was checked already
+    if session.is_none() {
+        if let Some(reason) = login_refusal(&user, &storage_guard) {
+            warn!(
+                service = "auth-service",
+                event = "webauthn_login",
+                user_id = %user.id,
+                success = false,
+                reason = reason
+            );
+            return Ok(Json(LoginResponse::refused(reason)));
+        }
+    }
+
+    // Passwordless logins require user verification by the authenticator
+    let amr = match &session {
+        Some(session) => acr::add_method(&session.amr, acr::AMR_HARDWARE_KEY),
+        None => acr::add_method(&[acr::AMR_HARDWARE_KEY.to_string()], acr::AMR_USER_VERIFICATION),
+    };
+    let response = issue_login_tokens(&user, amr, &storage_guard, &jwt_service, &config, &runtime, &client).await?;
+
+    info!(
+        service = "auth-service",
+        event = "webauthn_login",
+        user_id = %user.id,
+        credential_id = %credential.id,
+        passwordless = session.is_none(),
+        success = response.success
+    );
+
+    Ok(Json(response))
+}
+
+fn parse_client_data(client_data_json: &str) -> Result<CollectedClientData, StatusCode> {
+    CollectedClientData::parse(client_data_json).map_err(|e| {
+        warn!(
+            service = "auth-service",
+            event = "webauthn_client_data_invalid",
+            error = %e
+        );
+        StatusCode::BAD_REQUEST
+    })
+}
+
+/// Owner of a discoverable credential: the user handle names the user, and
+/// the credential must be one of theirs. Authenticators that omit the
+/// handle are matched by credential id alone.
+fn passwordless_user<'a>(
+    storage: &'a FileStorage,
+    user_handle: Option<&str>,
+    credential_id: &str,
+) -> Option<&'a User> {
+    let owner = find_credential_owner(storage, credential_id)?;
+    match user_handle {
+        Some(handle) => {
+            let handle = URL_SAFE_NO_PAD.decode(handle.trim_end_matches('=')).ok()?;
+            (handle == owner.id.as_bytes()).then_some(owner)
+        }
+        None => Some(owner),
+    }
+}
+
+fn find_credential_owner<'a>(storage: &'a FileStorage, credential_id: &str) -> Option<&'a User> {
+    storage
+        .get_all_users()
+        .find(|u| u.webauthn_credentials.iter().any(|c| c.credential_id == credential_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::testing::*;
+    use crate::webauthn::testing::SoftAuthenticator;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_passkey_required_for_admins() {
+        let dir = TempDir::new("passkey");
+        let data_dir = dir.path();
+        let user_path = write_user(data_dir, json!({
+            "id": "user-admin",
+            "email": "root@example.com",
+            "first_name": "Root",
+            "last_name": "Admin",
+            "admin": ["all"]
+        }))
+        .await;
+        write_file(&format!("{}/security_policy.json", data_dir), r#"{"require_passkey_for_admins": true}"#).await;
+
+        let config = Config::default();
+        let (app, _, _) = test_app(data_dir, &config).await;
+        let credentials = credentials("root@example.com");
+        let origin = &config.webauthn.origin;
+        let mut authenticator = SoftAuthenticator::new();
+
+        // Policy applies even though require_mfa is off: register during login
+        let login = post_json(&app, "/api/auth/login", credentials.clone()).await;
+        assert_eq!(login["mfa_enrollment_required"], true);
+        assert_eq!(login["mfa_methods"], json!(["webauthn"]));
+        let session = login["mfa_session"].clone();
+
+        let options = post_json(
+            &app,
+            "/api/auth/webauthn/register/start",
+            json!({"mfa_session": session, "nickname": "Laptop"}),
+        )
+        .await;
+        let registered = post_json(
+            &app,
+            "/api/auth/webauthn/register/finish",
+            json!({"mfa_session": session, "credential": authenticator.register(&options, origin)}),
+        )
+        .await;
+        assert!(registered["access_token"].is_string());
+
+        // Passkey as second factor
+        let session = post_json(&app, "/api/auth/login", credentials).await["mfa_session"].clone();
+        let options = post_json(
+            &app,
+            "/api/auth/webauthn/authenticate/start",
+            json!({"mfa_session": session}),
+        )
+        .await;
+        assert_eq!(options["publicKey"]["allowCredentials"][0]["id"], authenticator.id());
+        let verified = post_json(
+            &app,
+            "/api/auth/webauthn/authenticate/finish",
+            json!({"mfa_session": session, "credential": authenticator.assert(&options, origin, "user-admin")}),
+        )
+        .await;
+        assert!(verified["access_token"].is_string());
+
+        // Passwordless login, and a replayed assertion is refused
+        let options = post_json(&app, "/api/auth/webauthn/authenticate/start", json!({})).await;
+        let assertion = authenticator.assert(&options, origin, "user-admin");
+        let passwordless = post_json(
+            &app,
+            "/api/auth/webauthn/authenticate/finish",
+            json!({"credential": assertion}),
+        )
+        .await;
+        assert!(passwordless["access_token"].is_string());
+        let replayed = post_json(
+            &app,
+            "/api/auth/webauthn/authenticate/finish",
+            json!({"credential": assertion}),
+        )
+        .await;
+        assert_eq!(replayed["success"], false);
+
+        let on_disk = read_json(&user_path).await;
+        assert_eq!(on_disk["webauthn_credentials"][0]["nickname"], "Laptop");
+        assert_eq!(on_disk["webauthn_credentials"][0]["sign_count"], 2);
+    }
+}
@@ -5,7 +5,20 @@ use std::collections::HashMap;
 use time::OffsetDateTime;
 use uuid::Uuid;
 
-use crate::models::{Claims, User, ClaimsRegistry};
+use crate::models::{Claims, User, ClaimsRegistry, MfaPurpose, MfaSessionClaims, TokenUse};
+
+/// Audience of MFA session tokens; never accepted where access tokens are.
+pub const MFA_SESSION_AUDIENCE: &str = "mfa-session";
+
+/// Audience of access and refresh tokens
+pub const AUDIENCE: &str = "auth-service";
+
+/// The client a token is issued to and the scope granted to it
+#[derive(Debug, Clone, Copy)]
+pub struct ClientGrant<'a> {
+    pub client_id: &'a str,
+    pub scope: &'a str,
+}
 
 #[derive(Clone)]
 pub struct JwtService {
@@ -23,21 +36,27 @@ impl JwtService {
         }
     }
 
-    pub fn create_token(
+    /// Assemble the claim set for `user` without signing it. Opaque tokens
+    /// store this set server-side instead of handing it to the client.
+    /// `grant` names the client and its scope, which decides on the email
+    /// and name claims; `None` for the login page's own tokens, which no
+    /// client can redeem.
+    pub fn build_claims(
         &self,
         user: &User,
         claims_registry: &ClaimsRegistry,
-        audience: Vec<String>,
         issuer: &str,
         expires_in: u64,
-    ) -> Result<String> {
+        typ: TokenUse,
+        grant: Option<ClientGrant>,
+    ) -> Claims {
         let now = OffsetDateTime::now_utc().unix_timestamp() as u64;
         let exp = now + expires_in;
 
         // Filter claims based on registry and allowance
         let allowed_claims = self.filter_allowed_claims(user, claims_registry);
 
-        let claims = Claims {
+        let mut claims = Claims {
             sub: user.id.clone(),
             email: user.email.clone(),
             name: user.full_name(),
@@ -45,15 +64,32 @@ impl JwtService {
             admin: user.admin.clone(),
             user_claims: allowed_claims,
             iss: issuer.to_string(),
-            aud: audience,
+            aud: vec![AUDIENCE.to_string()],
             exp,
             iat: now,
             jti: Uuid::new_v4().to_string(),
+            azp: grant.map(|g| g.client_id.to_string()),
+            auth_time: None,
+            acr: None,
+            amr: Vec::new(),
+            act: None,
+            sid: None,
+            typ: Some(typ),
+            scope: grant.map(|g| g.scope.to_string()),
         };
+        if !claims.grants("email") {
+            claims.email.clear();
+        }
+        if !claims.grants("profile") {
+            claims.name.clear();
+        }
+        claims
+    }
 
+    pub fn encode_claims(&self, claims: &Claims) -> Result<String> {
         let header = Header::new(self.algorithm);
 
-        encode(&header, &claims, &self.encoding_key)
+        encode(&header, claims, &self.encoding_key)
             .context("Failed to encode JWT")
     }
 
@@ -67,20 +103,39 @@ impl JwtService {
         Ok(token_data.claims)
     }
 
-    pub fn refresh_token(
+    pub fn create_mfa_session(
         &self,
-        refresh_token: &str,
-        user: &User,
-        claims_registry: &ClaimsRegistry,
-        audience: Vec<String>,
+        user_id: &str,
+        purpose: MfaPurpose,
+        amr: Vec<String>,
         issuer: &str,
         expires_in: u64,
     ) -> Result<String> {
-        // Verify the refresh token first
-        let _claims = self.verify_token(refresh_token)?;
+        let now = OffsetDateTime::now_utc().unix_timestamp() as u64;
+        let claims = MfaSessionClaims {
+            sub: user_id.to_string(),
+            purpose,
+            iss: issuer.to_string(),
+            aud: vec![MFA_SESSION_AUDIENCE.to_string()],
+            exp: now + expires_in,
+            iat: now,
+            jti: Uuid::new_v4().to_string(),
+            amr,
+        };
+
+        encode(&Header::new(self.algorithm), &claims, &self.encoding_key)
+            .context("Failed to encode MFA session")
+    }
+
+    pub fn verify_mfa_session(&self, token: &str) -> Result<MfaSessionClaims> {
+        let mut validation = Validation::new(self.algorithm);
+        validation.set_audience(&[MFA_SESSION_AUDIENCE]);
+        validation.leeway = 0;
 
-        // Create a new access token
-        self.create_token(user, claims_registry, audience, issuer, expires_in)
+        let token_data = decode::<MfaSessionClaims>(token, &self.decoding_key, &validation)
+            .context("Failed to decode MFA session")?;
+
+        Ok(token_data.claims)
     }
 
     fn filter_allowed_claims(&self, user: &User, registry: &ClaimsRegistry) -> HashMap<String, serde_json::Value> {
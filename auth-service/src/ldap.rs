@@ -0,0 +1,312 @@
+use anyhow::{anyhow, Context, Result};
+use ldap3::{dn_escape, ldap_escape, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use std::{collections::HashMap, time::Duration};
+
+use crate::config::LdapDirectoryConfig;
+
+/// Result code for a wrong DN or password (RFC 4511)
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// The directory entry of a user whose bind succeeded.
+#[derive(Debug, Clone)]
+pub struct DirectoryEntry {
+    pub dn: String,
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+    /// Values of the mapped attributes present, by local claim name
+    pub claims: HashMap<String, Vec<String>>,
+}
+
+#[derive(Debug)]
+pub enum BindOutcome {
+    Authenticated(DirectoryEntry),
+    InvalidCredentials,
+}
+
+/// Check `password` with a bind as the user behind `email`, then read their
+/// entry with that same connection.
+pub async fn authenticate(directory: &LdapDirectoryConfig, email: &str, password: &str) -> Result<BindOutcome> {
+    // Servers accept a bind without password as anonymous
+    if password.is_empty() {
+        return Ok(BindOutcome::InvalidCredentials);
+    }
+
+    let username = email.split('@').next().unwrap_or_default();
+    let bind_dn = directory.bind_dn
+        .replace("{username}", &dn_escape(username))
+        .replace("{email}", &dn_escape(email));
+    let filter = directory.search_filter
+        .replace("{username}", &ldap_escape(username))
+        .replace("{email}", &ldap_escape(email));
+    let timeout = Duration::from_secs(directory.timeout);
+
+    let settings = LdapConnSettings::new()
+        .set_conn_timeout(timeout)
+        .set_starttls(directory.starttls);
+    let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &directory.url)
+        .await
+        .with_context(|| format!("Failed to connect to {}", directory.url))?;
+    ldap3::drive!(conn);
+
+    let bind = ldap.with_timeout(timeout).simple_bind(&bind_dn, password).await.context("LDAP bind failed")?;
+    if bind.rc == INVALID_CREDENTIALS {
+        let _ = ldap.unbind().await;
+        return Ok(BindOutcome::InvalidCredentials);
+    }
+    bind.success().context("LDAP bind failed")?;
+
+    let mapping = &directory.attributes;
+    let mut attributes = vec![mapping.given_name.as_str(), mapping.family_name.as_str()];
+    attributes.extend(mapping.claims.values().map(String::as_str));
+    let (entries, _) = ldap
+        .with_timeout(timeout)
+        .search(&directory.search_base, Scope::Subtree, &filter, attributes)
+        .await
+        .and_then(|result| result.success())
+        .context("LDAP search failed")?;
+    let _ = ldap.unbind().await;
+
+    let entry = entries.into_iter().next()
+        .map(SearchEntry::construct)
+        .ok_or_else(|| anyhow!("No entry matches {} below {}", filter, directory.search_base))?;
+
+    // Attribute names are case-insensitive
+    let values = |name: &str| {
+        entry.attrs.iter()
+            .find(|(attribute, _)| attribute.eq_ignore_ascii_case(name))
+            .map(|(_, values)| values.clone())
+            .filter(|values| !values.is_empty())
+    };
+    let first = |name: &str| values(name).and_then(|v| v.into_iter().next());
+
+    Ok(BindOutcome::Authenticated(DirectoryEntry {
+        given_name: first(&mapping.given_name),
+        family_name: first(&mapping.family_name),
+        claims: mapping.claims.iter()
+            .filter_map(|(claim, attribute)| Some((claim.clone(), values(attribute)?)))
+            .collect(),
+        dn: entry.dn,
+    }))
+}
+
+/// In-process directory for tests: answers simple binds, equality searches
+/// and unbinds for a fixed set of entries.
+#[cfg(test)]
+pub mod testing {
+    use bytes::BytesMut;
+    use ldap3::asn1::{
+        parse_tag, write, ASNTag, Enumerated, Integer, OctetString, Sequence, Set, StructureTag, Tag, TagClass, PL,
+    };
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    pub struct StubEntry {
+        pub dn: String,
+        pub password: String,
+        pub attributes: Vec<(String, Vec<String>)>,
+    }
+
+    pub struct StubDirectory {
+        pub url: String,
+        binds: Arc<AtomicUsize>,
+    }
+
+    impl StubDirectory {
+        pub async fn start(entries: Vec<StubEntry>) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ldap://{}", listener.local_addr().unwrap());
+            let (entries, binds) = (Arc::new(entries), Arc::new(AtomicUsize::new(0)));
+
+            let counter = binds.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(serve(stream, entries.clone(), counter.clone()));
+                }
+            });
+            Self { url, binds }
+        }
+
+        /// Bind requests that reached the server
+        pub fn binds(&self) -> usize {
+            self.binds.load(Ordering::SeqCst)
+        }
+    }
+
+    async fn serve(mut stream: TcpStream, entries: Arc<Vec<StubEntry>>, binds: Arc<AtomicUsize>) {
+        let mut buffer = Vec::new();
+        let mut bound = false;
+        loop {
+            let (consumed, message) = match parse_tag(&buffer) {
+                Ok((rest, message)) => (buffer.len() - rest.len(), message),
+                Err(_) => {
+                    let mut chunk = [0u8; 4096];
+                    match stream.read(&mut chunk).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+                    }
+                    continue;
+                }
+            };
+            buffer.drain(..consumed);
+
+            let mut parts = message.expect_constructed().unwrap().into_iter();
+            let id = integer(parts.next().unwrap());
+            let operation = parts.next().unwrap();
+            let mut responses = Vec::new();
+            match operation.id {
+                // BindRequest: version, name, simple password
+                0 => {
+                    binds.fetch_add(1, Ordering::SeqCst);
+                    let fields = operation.expect_constructed().unwrap();
+                    let (dn, password) = (string(fields[1].clone()), string(fields[2].clone()));
+                    bound = entries.iter().any(|e| e.dn.eq_ignore_ascii_case(&dn) && e.password == password);
+                    responses.push(result(1, if bound { 0 } else { 49 }));
+                }
+                // UnbindRequest
+                2 => return,
+                // SearchRequest: base, scope, deref, limits, typesOnly, filter, attributes
+                3 => {
+                    let fields = operation.expect_constructed().unwrap();
+                    let base = string(fields[0].clone()).to_lowercase();
+                    if bound {
+                        let filter = equality(fields[6].clone());
+                        for entry in entries.iter().filter(|e| e.dn.to_lowercase().ends_with(&base)) {
+                            let matches = filter.as_ref().is_some_and(|(name, value)| {
+                                entry.attributes.iter().any(|(attribute, values)| {
+                                    attribute.eq_ignore_ascii_case(name) && values.iter().any(|v| v.eq_ignore_ascii_case(value))
+                                })
+                            });
+                            if matches {
+                                responses.push(search_entry(entry));
+                            }
+                        }
+                    }
+                    responses.push(result(5, if bound { 0 } else { 50 }));
+                }
+                _ => return,
+            }
+
+            for response in responses {
+                let envelope = Tag::Sequence(Sequence {
+                    inner: vec![Tag::Integer(Integer { inner: id, ..Default::default() }), response],
+                    ..Default::default()
+                });
+                let mut out = BytesMut::new();
+                write::encode_into(&mut out, envelope.into_structure()).unwrap();
+                if stream.write_all(&out).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn result(operation: u64, code: i64) -> Tag {
+        Tag::Sequence(Sequence {
+            class: TagClass::Application,
+            id: operation,
+            inner: vec![
+                Tag::Enumerated(Enumerated { inner: code, ..Default::default() }),
+                Tag::OctetString(OctetString::default()),
+                Tag::OctetString(OctetString::default()),
+            ],
+        })
+    }
+
+    fn search_entry(entry: &StubEntry) -> Tag {
+        let attributes = entry.attributes.iter().map(|(name, values)| {
+            Tag::Sequence(Sequence {
+                inner: vec![
+                    octets(name),
+                    Tag::Set(Set { inner: values.iter().map(|v| octets(v)).collect(), ..Default::default() }),
+                ],
+                ..Default::default()
+            })
+        });
+        Tag::Sequence(Sequence {
+            class: TagClass::Application,
+            id: 4,
+            inner: vec![octets(&entry.dn), Tag::Sequence(Sequence { inner: attributes.collect(), ..Default::default() })],
+        })
+    }
+
+    /// Attribute and value of an `equalityMatch` filter
+    fn equality(filter: StructureTag) -> Option<(String, String)> {
+        if filter.class != TagClass::Context || filter.id != 3 {
+            return None;
+        }
+        let fields = filter.expect_constructed()?;
+        Some((string(fields[0].clone()), string(fields[1].clone())))
+    }
+
+    fn octets(value: &str) -> Tag {
+        Tag::OctetString(OctetString { inner: value.as_bytes().to_vec(), ..Default::default() })
+    }
+
+    fn string(tag: StructureTag) -> String {
+        String::from_utf8(tag.expect_primitive().unwrap_or_default()).unwrap()
+    }
+
+    fn integer(tag: StructureTag) -> i64 {
+        match tag.payload {
+            PL::P(bytes) => bytes.iter().fold(0, |n, b| (n << 8) | *b as i64),
+            PL::C(_) => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{StubDirectory, StubEntry};
+    use super::*;
+    use crate::config::LdapAttributeMapping;
+
+    #[tokio::test]
+    async fn test_bind_and_entry_lookup() {
+        let stub = StubDirectory::start(vec![StubEntry {
+            dn: "uid=anna,ou=people,dc=example,dc=org".to_string(),
+            password: "directory secret".to_string(),
+            attributes: vec![
+                ("mail".to_string(), vec!["anna@example.org".to_string()]),
+                ("givenName".to_string(), vec!["Anna".to_string()]),
+                ("memberOf".to_string(), vec!["staff".to_string(), "it".to_string()]),
+            ],
+        }])
+        .await;
+        let mut attributes = LdapAttributeMapping::default();
+        attributes.claims.insert("groups".to_string(), "memberof".to_string());
+        let directory = LdapDirectoryConfig {
+            url: stub.url.clone(),
+            starttls: false,
+            bind_dn: "uid={username},ou=people,dc=example,dc=org".to_string(),
+            search_base: "dc=example,dc=org".to_string(),
+            search_filter: "(mail={email})".to_string(),
+            email_domains: Vec::new(),
+            attributes,
+            timeout: 5,
+        };
+
+        let outcome = authenticate(&directory, "anna@example.org", "directory secret").await.unwrap();
+        let BindOutcome::Authenticated(entry) = outcome else { panic!("bind failed") };
+        assert_eq!(entry.dn, "uid=anna,ou=people,dc=example,dc=org");
+        assert_eq!(entry.given_name.as_deref(), Some("Anna"));
+        assert_eq!(entry.family_name, None);
+        assert_eq!(entry.claims["groups"], ["staff", "it"]);
+
+        let wrong = authenticate(&directory, "anna@example.org", "guess").await.unwrap();
+        assert!(matches!(wrong, BindOutcome::InvalidCredentials));
+
+        // Never sent: the server would take it as an anonymous bind
+        let binds = stub.binds();
+        let empty = authenticate(&directory, "anna@example.org", "").await.unwrap();
+        assert!(matches!(empty, BindOutcome::InvalidCredentials));
+        assert_eq!(stub.binds(), binds);
+
+        // Placeholders are escaped, so input cannot change the DN or filter
+        let injected = authenticate(&directory, "anna,ou=people@example.org", "directory secret").await.unwrap();
+        assert!(matches!(injected, BindOutcome::InvalidCredentials));
+    }
+}
@@ -0,0 +1,223 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+use time::{Duration, OffsetDateTime};
+
+use crate::config::{LockoutConfig, LoginThrottleConfig};
+
+/// Record that failed logins for unknown addresses go to; no account has
+/// this id.
+const UNKNOWN_ACCOUNT: &str = ".unknown";
+
+/// Consecutive failed logins of one account, in `lockouts/{user_id}.json`.
+/// admin-service unlocks an account by deleting the file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoginFailures {
+    pub user_id: String,
+    pub failures: u32,
+    #[serde(with = "time::serde::iso8601")]
+    pub last_failure_at: OffsetDateTime,
+    #[serde(default, with = "time::serde::iso8601::option")]
+    pub locked_until: Option<OffsetDateTime>,
+}
+
+/// Per-account lockout state on disk, so it outlives reloads and restarts
+/// and can be lifted from admin-service.
+#[derive(Debug)]
+pub struct LockoutStore {
+    dir: PathBuf,
+    /// Serializes read-modify-write of the records within this process
+    update: tokio::sync::Mutex<()>,
+}
+
+impl LockoutStore {
+    pub fn new(data_dir: &str) -> Self {
+        Self {
+            dir: Path::new(data_dir).join("lockouts"),
+            update: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Until when `user_id` is locked, if it is at `now`.
+    pub async fn locked_until(&self, user_id: &str, now: OffsetDateTime) -> Result<Option<OffsetDateTime>> {
+        Ok(self.load(user_id).await?.and_then(|r| r.locked_until).filter(|until| *until > now))
+    }
+
+    /// Count a failed login. Returns the end of the lock if this failure
+    /// locks the account. Every failure past `max_failures` doubles the
+    /// lock, up to `max_duration`; a streak is forgotten after
+    /// `max_duration` without failures.
+    pub async fn record_failure(
+        &self,
+        user_id: &str,
+        config: &LockoutConfig,
+        now: OffsetDateTime,
+    ) -> Result<Option<OffsetDateTime>> {
+        let _guard = self.update.lock().await;
+
+        let forget_before = now - Duration::seconds(config.max_duration as i64);
+        let failures = match self.load(user_id).await? {
+            Some(record) if record.last_failure_at > forget_before => record.failures.saturating_add(1),
+            _ => 1,
+        };
+
+        let locked_until = (failures >= config.max_failures).then(|| {
+            let doublings = (failures - config.max_failures).min(32);
+            let seconds = config.base_duration.saturating_mul(1 << doublings).min(config.max_duration);
+            now + Duration::seconds(seconds as i64)
+        });
+
+        let record = LoginFailures {
+            user_id: user_id.to_string(),
+            failures,
+            last_failure_at: now,
+            locked_until,
+        };
+        tokio::fs::create_dir_all(&self.dir).await
+            .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+        let path = self.path_for(user_id);
+        let temp_path = path.with_extension("json.tmp");
+        tokio::fs::write(&temp_path, serde_json::to_string_pretty(&record)?).await
+            .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+        tokio::fs::rename(&temp_path, &path).await
+            .with_context(|| format!("Failed to rename {}", temp_path.display()))?;
+
+        Ok(locked_until)
+    }
+
+    /// The lookup and update a wrong password costs, for a login refused
+    /// before its password is checked, so its answer takes as long.
+    pub async fn record_unknown(&self, config: &LockoutConfig, now: OffsetDateTime) -> Result<()> {
+        self.locked_until(UNKNOWN_ACCOUNT, now).await?;
+        self.record_failure(UNKNOWN_ACCOUNT, config, now).await?;
+        Ok(())
+    }
+
+    /// Forget the failures of `user_id` after a successful login.
+    pub async fn clear(&self, user_id: &str) -> Result<()> {
+        let _guard = self.update.lock().await;
+        match tokio::fs::remove_file(self.path_for(user_id)).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("Failed to remove lockout of {}", user_id)),
+        }
+    }
+
+    async fn load(&self, user_id: &str) -> Result<Option<LoginFailures>> {
+        let path = self.path_for(user_id);
+        match tokio::fs::read_to_string(&path).await {
+            Ok(content) => Ok(Some(
+                serde_json::from_str(&content).with_context(|| format!("Failed to parse {}", path.display()))?,
+            )),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    fn path_for(&self, user_id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", user_id))
+    }
+}
+
+/// Token bucket per client address for login attempts, kept in memory.
+#[derive(Debug, Default)]
+pub struct LoginThrottle {
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Buckets kept before full ones are dropped
+const MAX_TRACKED_ADDRESSES: usize = 10_000;
+
+impl LoginThrottle {
+    /// Take a token for `ip`; `false` if its bucket is empty.
+    pub fn allow(&self, ip: IpAddr, config: &LoginThrottleConfig, now: Instant) -> bool {
+        let burst = config.burst as f64;
+        let refill = |bucket: &Bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            (bucket.tokens + elapsed * config.per_minute as f64 / 60.0).min(burst)
+        };
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if buckets.len() >= MAX_TRACKED_ADDRESSES {
+            buckets.retain(|_, bucket| refill(bucket) < burst);
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket { tokens: burst, updated: now });
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lockout_grows_and_clears() {
+        let data_dir = std::env::temp_dir()
+            .join(format!("um-oic-lockout-{}", uuid::Uuid::new_v4().simple()))
+            .to_string_lossy()
+            .to_string();
+        let store = LockoutStore::new(&data_dir);
+        let config = LockoutConfig { max_failures: 3, base_duration: 60, max_duration: 200 };
+        let now = OffsetDateTime::now_utc();
+
+        assert!(store.record_failure("user-1", &config, now).await.unwrap().is_none());
+        assert!(store.record_failure("user-1", &config, now).await.unwrap().is_none());
+        assert_eq!(store.record_failure("user-1", &config, now).await.unwrap(), Some(now + Duration::seconds(60)));
+        assert_eq!(store.locked_until("user-1", now).await.unwrap(), Some(now + Duration::seconds(60)));
+        assert!(store.locked_until("user-1", now + Duration::seconds(60)).await.unwrap().is_none());
+
+        let later = now + Duration::seconds(61);
+        assert_eq!(store.record_failure("user-1", &config, later).await.unwrap(), Some(later + Duration::seconds(120)));
+        assert_eq!(store.record_failure("user-1", &config, later).await.unwrap(), Some(later + Duration::seconds(200)));
+
+        // A new streak long after the last failure starts from scratch
+        let much_later = later + Duration::seconds(201);
+        assert!(store.record_failure("user-1", &config, much_later).await.unwrap().is_none());
+
+        // Logins with unknown addresses lock no account
+        for _ in 0..5 {
+            store.record_unknown(&config, much_later).await.unwrap();
+        }
+        assert!(store.locked_until("user-1", much_later).await.unwrap().is_none());
+
+        store.clear("user-1").await.unwrap();
+        store.clear("user-1").await.unwrap();
+        assert!(store.locked_until("user-1", much_later).await.unwrap().is_none());
+
+        tokio::fs::remove_dir_all(&data_dir).await.unwrap();
+    }
+
+    #[test]
+    fn test_throttle_refills_per_address() {
+        let throttle = LoginThrottle::default();
+        let config = LoginThrottleConfig { burst: 2, per_minute: 6 };
+        let (a, b): (IpAddr, IpAddr) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        let start = Instant::now();
+
+        assert!(throttle.allow(a, &config, start));
+        assert!(throttle.allow(a, &config, start));
+        assert!(!throttle.allow(a, &config, start));
+        assert!(throttle.allow(b, &config, start));
+
+        // One token every ten seconds
+        assert!(!throttle.allow(a, &config, start + std::time::Duration::from_secs(9)));
+        assert!(throttle.allow(a, &config, start + std::time::Duration::from_secs(10)));
+    }
+}
@@ -0,0 +1,231 @@
+pub mod outbox;
+pub mod templates;
+pub mod transport;
+
+use anyhow::{Context, Result};
+use lettre::message::Mailbox;
+use std::time::Duration;
+use time::OffsetDateTime;
+use tracing::{error, info, warn};
+
+use crate::config::{InstanceConfig, MailConfig};
+
+pub use outbox::Outbox;
+pub use templates::Locale;
+use transport::Transport;
+
+/// Renders queued mail and hands it to the configured transport.
+pub struct Mailer {
+    config: MailConfig,
+    instance: InstanceConfig,
+    from: Mailbox,
+    transport: Transport,
+}
+
+impl Mailer {
+    pub fn new(config: &MailConfig, instance: &InstanceConfig) -> Result<Self> {
+        // A broken template should stop startup, not the first reset mail
+        for template in templates::MailTemplate::ALL {
+            let params = template
+                .required_params()
+                .iter()
+                .map(|name| (name.to_string(), String::new()))
+                .collect();
+            for locale in [Locale::De, Locale::En] {
+                templates::render(template, locale, &params, instance)
+                    .with_context(|| format!("Mail template {:?} ({:?}) does not render", template, locale))?;
+            }
+        }
+
+        let from = config
+            .from
+            .parse::<Mailbox>()
+            .with_context(|| format!("Invalid mail sender address: {}", config.from))?;
+
+        Ok(Self {
+            transport: Transport::from_config(config)?,
+            config: config.clone(),
+            instance: instance.clone(),
+            from,
+        })
+    }
+
+    /// One pass over the outbox: try every message that is due at `now`.
+    /// Returns how many were delivered.
+    pub async fn deliver_due(&self, outbox: &Outbox, now: OffsetDateTime) -> Result<usize> {
+        let mut delivered = 0;
+
+        for mail in outbox.due(now).await? {
+            let locale = mail.locale.unwrap_or(self.config.default_locale);
+            let result = match templates::render(mail.template, locale, &mail.params, &self.instance) {
+                Ok(rendered) => self.transport.send(&self.from, &mail.to, &rendered).await,
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Ok(()) => {
+                    outbox.mark_sent(&mail).await?;
+                    delivered += 1;
+                    info!(
+                        service = "auth-service",
+                        event = "mail_sent",
+                        mail_id = %mail.id,
+                        template = ?mail.template,
+                        attempts = mail.attempts + 1
+                    );
+                }
+                Err(e) => {
+                    let (id, template, attempts) = (mail.id.clone(), mail.template, mail.attempts + 1);
+                    let gave_up = outbox
+                        .mark_failed(mail, &format!("{:#}", e), self.config.max_attempts, self.config.retry_delay, now)
+                        .await?;
+                    if gave_up {
+                        error!(
+                            service = "auth-service",
+                            event = "mail_failed",
+                            mail_id = %id,
+                            template = ?template,
+                            attempts = attempts,
+                            error = %format!("{:#}", e)
+                        );
+                    } else {
+                        warn!(
+                            service = "auth-service",
+                            event = "mail_retry_scheduled",
+                            mail_id = %id,
+                            template = ?template,
+                            attempts = attempts,
+                            error = %format!("{:#}", e)
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(delivered)
+    }
+}
+
+/// Poll the outbox every `poll_interval` seconds for the lifetime of the
+/// process. Messages queued by admin-service are picked up the same way.
+/// Messages given up on are dropped after `failed_retention_days`.
+pub fn spawn_delivery(mailer: Mailer, outbox: Outbox) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(mailer.config.poll_interval));
+        loop {
+            interval.tick().await;
+            let now = OffsetDateTime::now_utc();
+            if let Err(e) = mailer.deliver_due(&outbox, now).await {
+                error!(
+                    service = "auth-service",
+                    event = "mail_outbox_error",
+                    error = %format!("{:#}", e)
+                );
+            }
+            match outbox.prune_failed(now, mailer.config.failed_retention_days).await {
+                Ok(0) => {}
+                Ok(removed) => info!(
+                    service = "auth-service",
+                    event = "mail_failed_pruned",
+                    removed = removed
+                ),
+                Err(e) => error!(
+                    service = "auth-service",
+                    event = "mail_outbox_error",
+                    error = %format!("{:#}", e)
+                ),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, MailTransportConfig};
+    use crate::mail::templates::MailTemplate;
+    use std::collections::HashMap;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn test_dir(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("um-oic-{}-{}", name, uuid::Uuid::new_v4().simple()))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_outbox_delivers_to_maildir() {
+        let data_dir = test_dir("mail");
+        let mut config = Config::default();
+        config.mail.transport = MailTransportConfig::Maildir { path: format!("{}/maildir", data_dir) };
+        let mailer = Mailer::new(&config.mail, &config.instance).unwrap();
+        let outbox = Outbox::new(&data_dir);
+
+        let params = HashMap::from([("name".to_string(), "Anna".to_string())]);
+        outbox
+            .enqueue("anna@example.com", MailTemplate::PasswordChanged, Some(Locale::En), params)
+            .await
+            .unwrap();
+
+        let now = OffsetDateTime::now_utc();
+        assert_eq!(mailer.deliver_due(&outbox, now).await.unwrap(), 1);
+        assert!(outbox.due(now).await.unwrap().is_empty());
+
+        let mut delivered = std::fs::read_dir(format!("{}/maildir/new", data_dir)).unwrap();
+        let message = std::fs::read_to_string(delivered.next().unwrap().unwrap().path()).unwrap();
+        assert!(message.contains("To: anna@example.com"));
+        assert!(message.contains("Content-Type: text/html"));
+
+        tokio::fs::remove_dir_all(&data_dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_delivery_backs_off_then_gives_up() {
+        let data_dir = test_dir("mail-fail");
+        std::fs::create_dir_all(&data_dir).unwrap();
+        // A regular file where the maildir should be: every attempt fails
+        std::fs::write(format!("{}/maildir", data_dir), "").unwrap();
+
+        let mut config = Config::default();
+        config.mail.transport = MailTransportConfig::Maildir { path: format!("{}/maildir", data_dir) };
+        config.mail.max_attempts = 2;
+        config.mail.retry_delay = 60;
+        let mailer = Mailer::new(&config.mail, &config.instance).unwrap();
+        let outbox = Outbox::new(&data_dir);
+
+        let params = HashMap::from([
+            ("name".to_string(), "Anna".to_string()),
+            ("reset_url".to_string(), "https://auth.example.com/#reset_token=secret".to_string()),
+            ("expires_minutes".to_string(), "60".to_string()),
+        ]);
+        outbox.enqueue("anna@example.com", MailTemplate::PasswordReset, None, params).await.unwrap();
+        let queued = std::fs::read_dir(format!("{}/mail/outbox", data_dir)).unwrap().next().unwrap().unwrap();
+        assert_eq!(queued.metadata().unwrap().permissions().mode() & 0o777, 0o600);
+
+        let now = OffsetDateTime::now_utc();
+        assert_eq!(mailer.deliver_due(&outbox, now).await.unwrap(), 0);
+        // Not due again before the back-off elapsed
+        assert!(outbox.due(now + time::Duration::seconds(59)).await.unwrap().is_empty());
+
+        let later = now + time::Duration::seconds(60);
+        let retry = outbox.due(later).await.unwrap();
+        assert_eq!(retry[0].attempts, 1);
+        assert!(retry[0].last_error.is_some());
+
+        assert_eq!(mailer.deliver_due(&outbox, later).await.unwrap(), 0);
+        assert!(outbox.due(later + time::Duration::days(1)).await.unwrap().is_empty());
+        let failed: Vec<_> = std::fs::read_dir(format!("{}/mail/failed", data_dir)).unwrap().collect();
+        assert_eq!(failed.len(), 1);
+
+        // Kept for inspection without the token, and only for a while
+        let kept = std::fs::read_to_string(failed[0].as_ref().unwrap().path()).unwrap();
+        assert!(!kept.contains("secret"), "{}", kept);
+        assert!(kept.contains("Anna"));
+        assert_eq!(outbox.prune_failed(later, 1).await.unwrap(), 0);
+        assert_eq!(outbox.prune_failed(later + time::Duration::days(2), 1).await.unwrap(), 1);
+        assert_eq!(std::fs::read_dir(format!("{}/mail/failed", data_dir)).unwrap().count(), 0);
+
+        tokio::fs::remove_dir_all(&data_dir).await.unwrap();
+    }
+}
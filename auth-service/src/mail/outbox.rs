@@ -0,0 +1,218 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use time::{Duration, OffsetDateTime};
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
+
+use crate::mail::templates::{Locale, MailTemplate};
+
+/// Stands in for a redacted parameter
+const REDACTED: &str = "[redacted]";
+
+/// A queued message. It is stored unrendered, so that any service can queue
+/// mail by writing one of these into the outbox directory; auth-service
+/// renders and delivers it. Links with tokens are only on disk until the
+/// message is sent or given up on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboundMail {
+    pub id: String,
+    pub to: String,
+    pub template: MailTemplate,
+    /// `None` means the configured default locale
+    #[serde(default)]
+    pub locale: Option<Locale>,
+    pub params: HashMap<String, String>,
+    #[serde(with = "time::serde::iso8601")]
+    pub created_at: OffsetDateTime,
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default, with = "time::serde::iso8601::option")]
+    pub next_attempt_at: Option<OffsetDateTime>,
+    #[serde(default)]
+    pub last_error: Option<String>,
+}
+
+impl OutboundMail {
+    fn redact_secrets(&mut self) {
+        for name in self.template.secret_params() {
+            if let Some(value) = self.params.get_mut(*name) {
+                *value = REDACTED.to_string();
+            }
+        }
+    }
+}
+
+/// File-backed queue in `{data_dir}/mail/outbox`, one JSON file per message.
+/// Messages that ran out of attempts move to `{data_dir}/mail/failed`, with
+/// their secret parameters redacted, until `prune_failed` removes them.
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    outbox_dir: PathBuf,
+    failed_dir: PathBuf,
+}
+
+impl Outbox {
+    pub fn new(data_dir: &str) -> Self {
+        Self {
+            outbox_dir: Path::new(data_dir).join("mail/outbox"),
+            failed_dir: Path::new(data_dir).join("mail/failed"),
+        }
+    }
+
+    pub async fn enqueue(
+        &self,
+        to: &str,
+        template: MailTemplate,
+        locale: Option<Locale>,
+        params: HashMap<String, String>,
+    ) -> Result<String> {
+        let now = OffsetDateTime::now_utc();
+        let mail = OutboundMail {
+            id: format!("{}-{}", now.unix_timestamp(), uuid::Uuid::new_v4().simple()),
+            to: to.to_string(),
+            template,
+            locale,
+            params,
+            created_at: now,
+            attempts: 0,
+            next_attempt_at: None,
+            last_error: None,
+        };
+
+        tokio::fs::create_dir_all(&self.outbox_dir).await
+            .with_context(|| format!("Failed to create outbox {}", self.outbox_dir.display()))?;
+        write_atomic(&self.path_for(&mail.id), &mail).await?;
+
+        Ok(mail.id)
+    }
+
+    /// Messages whose next attempt is due at `now`, oldest first. A file
+    /// that does not parse is moved aside instead of being retried forever.
+    pub async fn due(&self, now: OffsetDateTime) -> Result<Vec<OutboundMail>> {
+        let mut entries = match tokio::fs::read_dir(&self.outbox_dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read outbox {}", self.outbox_dir.display())),
+        };
+
+        let mut due = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+
+            let content = tokio::fs::read_to_string(&path).await
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            match serde_json::from_str::<OutboundMail>(&content) {
+                Ok(mail) if mail.next_attempt_at.is_none_or(|at| at <= now) => due.push(mail),
+                Ok(_) => {}
+                Err(e) => {
+                    warn!(
+                        service = "auth-service",
+                        event = "mail_outbox_corrupt",
+                        path = %path.display(),
+                        error = %e
+                    );
+                    self.move_to_failed(&path).await?;
+                }
+            }
+        }
+
+        due.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(due)
+    }
+
+    pub async fn mark_sent(&self, mail: &OutboundMail) -> Result<()> {
+        tokio::fs::remove_file(self.path_for(&mail.id)).await
+            .with_context(|| format!("Failed to remove sent mail {}", mail.id))
+    }
+
+    /// Record a failed attempt. Returns true if the message was given up on.
+    /// Retries back off exponentially from `retry_delay` seconds.
+    pub async fn mark_failed(
+        &self,
+        mut mail: OutboundMail,
+        error: &str,
+        max_attempts: u32,
+        retry_delay: u64,
+        now: OffsetDateTime,
+    ) -> Result<bool> {
+        mail.attempts += 1;
+        mail.last_error = Some(error.to_string());
+
+        if mail.attempts >= max_attempts {
+            mail.redact_secrets();
+            tokio::fs::create_dir_all(&self.failed_dir).await
+                .with_context(|| format!("Failed to create {}", self.failed_dir.display()))?;
+            write_atomic(&self.failed_dir.join(format!("{}.json", mail.id)), &mail).await?;
+            tokio::fs::remove_file(self.path_for(&mail.id)).await
+                .with_context(|| format!("Failed to remove failed mail {}", mail.id))?;
+            return Ok(true);
+        }
+
+        let backoff = retry_delay.saturating_mul(1u64 << (mail.attempts - 1).min(16));
+        mail.next_attempt_at = Some(now + Duration::seconds(backoff as i64));
+        write_atomic(&self.path_for(&mail.id), &mail).await?;
+        Ok(false)
+    }
+
+    /// Remove messages that have been in `mail/failed` for more than
+    /// `retention_days` at `now`. Returns how many were removed.
+    pub async fn prune_failed(&self, now: OffsetDateTime, retention_days: u64) -> Result<usize> {
+        let mut entries = match tokio::fs::read_dir(&self.failed_dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", self.failed_dir.display())),
+        };
+
+        let cutoff = SystemTime::from(now - Duration::days(retention_days as i64));
+        let mut removed = 0;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let modified = entry.metadata().await
+                .and_then(|m| m.modified())
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            if modified < cutoff {
+                tokio::fs::remove_file(&path).await
+                    .with_context(|| format!("Failed to remove {}", path.display()))?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn path_for(&self, id: &str) -> PathBuf {
+        self.outbox_dir.join(format!("{}.json", id))
+    }
+
+    async fn move_to_failed(&self, path: &Path) -> Result<()> {
+        tokio::fs::create_dir_all(&self.failed_dir).await
+            .with_context(|| format!("Failed to create {}", self.failed_dir.display()))?;
+        let target = self.failed_dir.join(path.file_name().unwrap_or_default());
+        tokio::fs::rename(path, &target).await
+            .with_context(|| format!("Failed to move {} to {}", path.display(), target.display()))
+    }
+}
+
+/// Write `mail` readable by the service user only; it may carry a token.
+async fn write_atomic(path: &Path, mail: &OutboundMail) -> Result<()> {
+    let temp_path = path.with_extension("json.tmp");
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temp_path)
+        .await
+        .with_context(|| format!("Failed to create {}", temp_path.display()))?;
+    file.write_all(serde_json::to_string_pretty(mail)?.as_bytes()).await
+        .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+    file.flush().await
+        .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+    tokio::fs::rename(&temp_path, path).await
+        .with_context(|| format!("Failed to rename {}", temp_path.display()))
+}
@@ -0,0 +1,213 @@
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::config::InstanceConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Locale {
+    De,
+    En,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MailTemplate {
+    PasswordReset,
+    EmailVerification,
+    PasswordChanged,
+}
+
+impl MailTemplate {
+    pub const ALL: [MailTemplate; 3] = [
+        MailTemplate::PasswordReset,
+        MailTemplate::EmailVerification,
+        MailTemplate::PasswordChanged,
+    ];
+
+    /// Parameters the caller has to supply; `instance_name` is always set.
+    pub fn required_params(self) -> &'static [&'static str] {
+        match self {
+            MailTemplate::PasswordReset => &["name", "reset_url", "expires_minutes"],
+            MailTemplate::EmailVerification => &["name", "verify_url", "expires_hours"],
+            MailTemplate::PasswordChanged => &["name"],
+        }
+    }
+
+    /// Parameters that carry a one-time token. They are redacted from
+    /// messages given up on, which are kept for inspection.
+    pub fn secret_params(self) -> &'static [&'static str] {
+        match self {
+            MailTemplate::PasswordReset => &["reset_url"],
+            MailTemplate::EmailVerification => &["verify_url"],
+            MailTemplate::PasswordChanged => &[],
+        }
+    }
+
+    fn source(self, locale: Locale) -> &'static str {
+        match (self, locale) {
+            (MailTemplate::PasswordReset, Locale::De) => include_str!("../../templates/mail/de/password_reset.txt"),
+            (MailTemplate::PasswordReset, Locale::En) => include_str!("../../templates/mail/en/password_reset.txt"),
+            (MailTemplate::EmailVerification, Locale::De) => include_str!("../../templates/mail/de/email_verification.txt"),
+            (MailTemplate::EmailVerification, Locale::En) => include_str!("../../templates/mail/en/email_verification.txt"),
+            (MailTemplate::PasswordChanged, Locale::De) => include_str!("../../templates/mail/de/password_changed.txt"),
+            (MailTemplate::PasswordChanged, Locale::En) => include_str!("../../templates/mail/en/password_changed.txt"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RenderedMail {
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+/// Render `template`: the first line of the source is `Subject: ...`, the
+/// rest after a blank line is the plain-text body. The HTML part is derived
+/// from the text and carries the instance branding.
+pub fn render(
+    template: MailTemplate,
+    locale: Locale,
+    params: &HashMap<String, String>,
+    instance: &InstanceConfig,
+) -> Result<RenderedMail> {
+    for required in template.required_params() {
+        if !params.contains_key(*required) {
+            bail!("Mail template {:?} needs parameter '{}'", template, required);
+        }
+    }
+
+    let mut values: HashMap<&str, &str> = params.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    values.insert("instance_name", &instance.name);
+
+    let source = template.source(locale);
+    let (subject_line, body) = source
+        .split_once("\n\n")
+        .ok_or_else(|| anyhow!("Mail template {:?} has no body", template))?;
+    let subject = subject_line
+        .strip_prefix("Subject: ")
+        .ok_or_else(|| anyhow!("Mail template {:?} does not start with a subject", template))?;
+
+    let subject = substitute(subject, &values)?;
+    let text = substitute(body, &values)?;
+    let html = html_layout(&text, &subject, instance);
+
+    Ok(RenderedMail { subject, text, html })
+}
+
+/// Replace `{{name}}` placeholders. An unknown placeholder is an error rather
+/// than an empty string in a mail to a user.
+fn substitute(source: &str, values: &HashMap<&str, &str>) -> Result<String> {
+    let mut output = String::with_capacity(source.len());
+    let mut rest = source;
+
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find("}}")
+            .ok_or_else(|| anyhow!("Unterminated placeholder in mail template"))?;
+        let name = rest[start + 2..start + end].trim();
+        let value = values
+            .get(name)
+            .ok_or_else(|| anyhow!("Unknown placeholder '{}' in mail template", name))?;
+        output.push_str(value);
+        rest = &rest[start + end + 2..];
+    }
+
+    output.push_str(rest);
+    Ok(output)
+}
+
+fn html_layout(text: &str, subject: &str, instance: &InstanceConfig) -> String {
+    let logo_url = if instance.logo_url.starts_with('/') {
+        format!("{}{}", instance.issuer.trim_end_matches('/'), instance.logo_url)
+    } else {
+        instance.logo_url.clone()
+    };
+
+    // Paragraphs that are a bare link become a button
+    let paragraphs: String = text
+        .trim()
+        .split("\n\n")
+        .map(|paragraph| {
+            let paragraph = paragraph.trim();
+            if paragraph.starts_with("https://") || paragraph.starts_with("http://") {
+                format!(
+                    "<p><a href=\"{url}\" style=\"display:inline-block;padding:10px 20px;background:{color};color:#ffffff;text-decoration:none;border-radius:4px\">{url}</a></p>\n",
+                    url = escape_html(paragraph),
+                    color = escape_html(&instance.primary_color)
+                )
+            } else {
+                format!("<p>{}</p>\n", escape_html(paragraph).replace('\n', "<br>"))
+            }
+        })
+        .collect();
+
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{title}</title></head>\n\
+         <body style=\"font-family:sans-serif;color:#222222\">\n\
+         <div style=\"border-top:4px solid {color};max-width:600px;margin:0 auto;padding:20px\">\n\
+         <img src=\"{logo}\" alt=\"{name}\" style=\"max-height:48px\">\n{paragraphs}</div>\n</body>\n</html>\n",
+        title = escape_html(subject),
+        color = escape_html(&instance.primary_color),
+        logo = escape_html(&logo_url),
+        name = escape_html(&instance.name),
+        paragraphs = paragraphs
+    )
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_all_templates_render_in_both_locales() {
+        let instance = Config::default().instance;
+        let params: HashMap<String, String> = [
+            ("name", "Anna <Test>"),
+            ("reset_url", "https://auth.example.com/reset?token=abc&x=1"),
+            ("verify_url", "https://auth.example.com/verify?token=abc"),
+            ("expires_minutes", "30"),
+            ("expires_hours", "48"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+        for template in MailTemplate::ALL {
+            for locale in [Locale::De, Locale::En] {
+                let mail = render(template, locale, &params, &instance).unwrap();
+                assert!(mail.subject.contains(&instance.name));
+                assert!(mail.text.contains("Anna <Test>"));
+                assert!(!mail.text.contains("{{"));
+                assert!(mail.html.contains("Anna &lt;Test&gt;"));
+                assert!(mail.html.contains(&instance.primary_color));
+            }
+        }
+
+        let reset = render(MailTemplate::PasswordReset, Locale::En, &params, &instance).unwrap();
+        assert!(reset.html.contains("href=\"https://auth.example.com/reset?token=abc&amp;x=1\""));
+    }
+
+    #[test]
+    fn test_missing_parameter_is_an_error() {
+        let instance = Config::default().instance;
+        let params = HashMap::from([("name".to_string(), "Anna".to_string())]);
+
+        assert!(render(MailTemplate::PasswordReset, Locale::De, &params, &instance).is_err());
+        assert!(render(MailTemplate::PasswordChanged, Locale::De, &params, &instance).is_ok());
+        assert!(substitute("Hello {{ unknown }}", &HashMap::new()).is_err());
+    }
+}
@@ -0,0 +1,80 @@
+use anyhow::{Context, Result};
+use lettre::{
+    message::{Mailbox, MultiPart},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use std::path::PathBuf;
+use time::OffsetDateTime;
+
+use crate::config::{MailConfig, MailTransportConfig, SmtpSecurity};
+use crate::mail::templates::RenderedMail;
+
+/// Where rendered mail goes, selected by `[mail.transport] kind`.
+pub enum Transport {
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+    /// Writes each message into a Maildir (`tmp/` -> `new/`), for development
+    /// and tests.
+    Maildir(PathBuf),
+}
+
+impl Transport {
+    pub fn from_config(config: &MailConfig) -> Result<Self> {
+        match &config.transport {
+            MailTransportConfig::Smtp { host, port, security, username, password } => {
+                let builder = match security {
+                    SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(host),
+                    SmtpSecurity::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host),
+                    SmtpSecurity::None => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)),
+                }
+                .with_context(|| format!("Invalid SMTP relay {}", host))?;
+
+                let builder = match (username, password) {
+                    (Some(username), Some(password)) => {
+                        builder.credentials(Credentials::new(username.clone(), password.clone()))
+                    }
+                    (None, None) => builder,
+                    _ => anyhow::bail!("SMTP username and password must be set together"),
+                };
+
+                Ok(Transport::Smtp(builder.port(*port).build()))
+            }
+            MailTransportConfig::Maildir { path } => Ok(Transport::Maildir(PathBuf::from(path))),
+        }
+    }
+
+    pub async fn send(&self, from: &Mailbox, to: &str, mail: &RenderedMail) -> Result<()> {
+        let message = Message::builder()
+            .from(from.clone())
+            .to(to.parse::<Mailbox>().with_context(|| format!("Invalid recipient {}", to))?)
+            .subject(&mail.subject)
+            .multipart(MultiPart::alternative_plain_html(mail.text.clone(), mail.html.clone()))
+            .context("Failed to build message")?;
+
+        match self {
+            Transport::Smtp(smtp) => {
+                smtp.send(message).await.context("SMTP delivery failed")?;
+            }
+            Transport::Maildir(path) => {
+                let name = format!(
+                    "{}.{}.auth-service",
+                    OffsetDateTime::now_utc().unix_timestamp(),
+                    uuid::Uuid::new_v4().simple()
+                );
+                let tmp_dir = path.join("tmp");
+                let new_dir = path.join("new");
+                tokio::fs::create_dir_all(&tmp_dir).await
+                    .with_context(|| format!("Failed to create maildir {}", tmp_dir.display()))?;
+                tokio::fs::create_dir_all(&new_dir).await
+                    .with_context(|| format!("Failed to create maildir {}", new_dir.display()))?;
+
+                tokio::fs::write(tmp_dir.join(&name), message.formatted()).await
+                    .context("Failed to write message to maildir")?;
+                tokio::fs::rename(tmp_dir.join(&name), new_dir.join(&name)).await
+                    .context("Failed to move message into maildir")?;
+            }
+        }
+
+        Ok(())
+    }
+}
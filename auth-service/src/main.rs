@@ -1,7 +1,7 @@
 use anyhow::{Context, Result};
 use axum::{
     extract::connect_info::ConnectInfo,
-    routing::{get, post},
+    routing::{delete, get, patch, post},
     Router,
 };
 use axum_server::tls_rustls::RustlsConfig;
@@ -20,9 +20,29 @@ mod logging;
 mod jwt;
 mod password;
 mod tls;
+mod tokens;
+mod mfa;
+mod webauthn;
+mod mail;
+mod action_tokens;
+mod lockout;
+mod audit;
+mod runtime;
+mod subject;
+mod routes;
+mod acr;
+mod federation;
+mod ldap;
+mod saml;
+mod oidc;
+mod sessions;
+mod consents;
+#[cfg(test)]
+mod testing;
 
 use config::Config;
 use storage::FileStorage;
+use runtime::Runtime;
 use tls::TlsManager;
 
 #[derive(Parser)]
@@ -100,6 +120,12 @@ async fn main() -> Result<()> {
             .context("Failed to load data storage")?
     ));
 
+    // Load server-side state (opaque tokens, ...)
+    let runtime = Arc::new(
+        Runtime::load(&args.data_dir, &config).await
+            .context("Failed to load runtime state")?
+    );
+
     info!(
         service = "auth-service",
         event = "startup",
@@ -109,6 +135,11 @@ async fn main() -> Result<()> {
         version = env!("CARGO_PKG_VERSION")
     );
 
+    // Deliver queued mail in the background
+    let mailer = mail::Mailer::new(&config.mail, &config.instance)
+        .context("Failed to set up mail delivery")?;
+    mail::spawn_delivery(mailer, runtime.outbox.clone());
+
     // Setup SIGHUP handler for data reload
     setup_reload_handler(storage.clone(), args.data_dir.clone());
 
@@ -116,7 +147,7 @@ async fn main() -> Result<()> {
     setup_shutdown_handler(args.pid_file.clone());
 
     // Create application router
-    let app = create_app(storage, config).await?;
+    let app = create_app(storage, config, runtime).await?;
 
     info!(
         service = "auth-service",
@@ -138,7 +169,8 @@ async fn main() -> Result<()> {
 
 async fn create_app(
     storage: Arc<RwLock<FileStorage>>,
-    config: Config
+    config: Config,
+    runtime: Arc<Runtime>,
 ) -> Result<Router> {
     let jwt_service = Arc::new(jwt::JwtService::new(&config.jwt_secret));
 
@@ -149,14 +181,50 @@ async fn create_app(
         // Authentication API
         .route("/api/auth/login", post(handlers::auth::login))
         .route("/api/auth/logout", post(handlers::auth::logout))
+        .route("/api/auth/step-up", post(handlers::auth::step_up))
+        .route("/api/auth/register", post(handlers::registration::register))
+        .route("/api/auth/register/organizations", get(handlers::registration::organizations))
+        .route("/api/auth/verify-email", post(handlers::registration::verify_email))
         .route("/api/auth/forgot-password", post(handlers::auth::forgot_password))
         .route("/api/auth/reset-password", post(handlers::auth::reset_password))
+        .route("/api/auth/mfa/verify", post(handlers::mfa::verify))
+        .route("/api/auth/mfa/enroll", post(handlers::mfa::enroll))
+        .route("/api/auth/mfa/enroll/confirm", post(handlers::mfa::confirm_enrollment))
+        .route("/api/auth/mfa/recovery-codes", post(handlers::mfa::regenerate_recovery_codes))
+        .route("/api/auth/webauthn/register/start", post(handlers::webauthn::register_start))
+        .route("/api/auth/webauthn/register/finish", post(handlers::webauthn::register_finish))
+        .route("/api/auth/webauthn/authenticate/start", post(handlers::webauthn::authenticate_start))
+        .route("/api/auth/webauthn/authenticate/finish", post(handlers::webauthn::authenticate_finish))
+        .route("/api/auth/federation/providers", get(handlers::federation::providers))
+        .route("/api/auth/federation/:provider/start", get(handlers::federation::start))
+        .route(routes::FEDERATION_CALLBACK, get(handlers::federation::callback))
+        .route("/api/auth/federation/complete", post(handlers::federation::complete))
+        .route("/api/saml/sso/complete", post(handlers::saml::complete))
+        .route("/api/oauth2/authorize/complete", post(handlers::oauth::complete_authorization))
+        .route("/api/account", get(handlers::account::profile))
+        .route("/api/account/password", post(handlers::account::change_password))
+        .route("/api/account/claims", patch(handlers::account::update_claims))
+        .route("/api/account/mfa/totp", delete(handlers::account::remove_totp))
+        .route("/api/account/passkeys/:credential_id", delete(handlers::account::remove_passkey))
+        .route("/api/account/sessions", get(handlers::account::sessions).delete(handlers::account::revoke_other_sessions))
+        .route("/api/account/sessions/:id", delete(handlers::account::revoke_session))
+        .route("/api/account/consents", get(handlers::account::consents))
+        .route("/api/account/consents/:client_id", delete(handlers::account::revoke_consent))
+        .route("/api/account/logins", get(handlers::account::logins))
 
         // OAuth2/OIDC endpoints
-        .route("/oauth2/authorize", get(handlers::oauth::authorize))
-        .route("/oauth2/token", post(handlers::oauth::token))
-        .route("/oauth2/userinfo", get(handlers::oauth::userinfo))
-        .route("/.well-known/openid-configuration", get(handlers::oauth::discovery))
+        .route(routes::AUTHORIZE, get(handlers::oauth::authorize))
+        .route(routes::TOKEN, post(handlers::oauth::token))
+        .route(routes::USERINFO, get(handlers::oauth::userinfo))
+        .route(routes::INTROSPECT, post(handlers::oauth::introspect))
+        .route(routes::REVOKE, post(handlers::oauth::revoke))
+        .route(routes::JWKS, get(handlers::discovery::jwks))
+        .route(routes::OIDC_DISCOVERY, get(handlers::discovery::openid_configuration))
+        .route(routes::OAUTH_METADATA, get(handlers::discovery::oauth_authorization_server))
+
+        // SAML 2.0 identity provider
+        .route(routes::SAML_METADATA, get(handlers::saml::metadata))
+        .route(routes::SAML_SSO, get(handlers::saml::sso_redirect).post(handlers::saml::sso_post))
 
         // Health check
         .route("/health", get(handlers::health::health))
@@ -165,7 +233,7 @@ async fn create_app(
         .layer(axum::middleware::from_fn(middleware::security::security_headers))
 
         // Shared state
-        .with_state((storage, jwt_service, config));
+        .with_state((storage, jwt_service, config, runtime));
 
     Ok(app)
 }
@@ -311,4 +379,4 @@ fn setup_shutdown_handler(pid_file: String) {
 
         std::process::exit(0);
     });
-}
\ No newline at end of file
+}
@@ -0,0 +1,231 @@
+use anyhow::{anyhow, Result};
+use rand::{Rng, RngCore};
+use std::collections::HashMap;
+use time::OffsetDateTime;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::password;
+
+const DIGITS: usize = 6;
+const STEP: u64 = 30;
+/// Steps accepted either side of the current one, for clock drift
+const SKEW: u64 = 1;
+const SECRET_BYTES: usize = 20;
+/// No 0/o, 1/l/i: recovery codes get typed off paper
+const RECOVERY_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+const RECOVERY_GROUP_LEN: usize = 5;
+
+/// Fresh random TOTP secret, base32 encoded as stored in `User.mfa_secret`.
+pub fn generate_secret() -> String {
+    let mut bytes = vec![0u8; SECRET_BYTES];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    Secret::Raw(bytes).to_encoded().to_string()
+}
+
+pub fn totp(secret: &str, account: &str, issuer: &str) -> Result<TOTP> {
+    let bytes = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|e| anyhow!("Invalid TOTP secret: {}", e))?;
+
+    TOTP::new(
+        Algorithm::SHA1,
+        DIGITS,
+        SKEW as u8,
+        STEP,
+        bytes,
+        Some(issuer.to_string()),
+        account.to_string(),
+    )
+    .map_err(|e| anyhow!("Invalid TOTP parameters: {}", e))
+}
+
+/// The time step `code` belongs to at unix time `now`, if it is valid.
+pub fn matching_step(totp: &TOTP, code: &str, now: u64) -> Option<u64> {
+    let current = now / STEP;
+    (current.saturating_sub(SKEW)..=current + SKEW)
+        .find(|step| password::constant_time_eq(totp.generate(step * STEP).as_bytes(), code.trim().as_bytes()))
+}
+
+/// TOTP codes are six digits; anything else is taken as a recovery code.
+pub fn is_totp_code(code: &str) -> bool {
+    let code = code.trim();
+    code.len() == DIGITS && code.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// `count` fresh recovery codes (`xxxxx-xxxxx`) and their Argon2 hashes.
+pub fn generate_recovery_codes(count: usize) -> Result<(Vec<String>, Vec<String>)> {
+    let mut rng = rand::rngs::OsRng;
+    let mut codes = Vec::with_capacity(count);
+    let mut hashes = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        let chars: String = (0..2 * RECOVERY_GROUP_LEN)
+            .map(|_| RECOVERY_ALPHABET[rng.gen_range(0..RECOVERY_ALPHABET.len())] as char)
+            .collect();
+        hashes.push(password::hash_password(&chars)?);
+        codes.push(format!("{}-{}", &chars[..RECOVERY_GROUP_LEN], &chars[RECOVERY_GROUP_LEN..]));
+    }
+
+    Ok((codes, hashes))
+}
+
+/// Index of the hash in `hashes` that `code` matches. Case, spaces and the
+/// dash are ignored.
+pub fn find_recovery_code(hashes: &[String], code: &str) -> Result<Option<usize>> {
+    let normalized: String = code
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .flat_map(char::to_lowercase)
+        .collect();
+
+    for (index, hash) in hashes.iter().enumerate() {
+        if password::verify_password(&normalized, hash)? {
+            return Ok(Some(index));
+        }
+    }
+
+    Ok(None)
+}
+
+fn now() -> u64 {
+    OffsetDateTime::now_utc().unix_timestamp() as u64
+}
+
+#[derive(Debug)]
+struct PendingEnrollment {
+    secret: String,
+    expires_at: u64,
+}
+
+#[derive(Debug, Default)]
+struct SessionState {
+    failures: u32,
+    consumed: bool,
+    exp: u64,
+}
+
+/// In-memory MFA bookkeeping: last accepted time step per user (replay
+/// protection), unconfirmed enrollments and per-session attempt counters.
+/// Everything here expires within a few minutes, so it is not persisted.
+#[derive(Debug, Default)]
+pub struct MfaStore {
+    last_steps: HashMap<String, u64>, // user_id -> last accepted step
+    pending: HashMap<String, PendingEnrollment>, // user_id -> enrollment
+    sessions: HashMap<String, SessionState>, // mfa_session jti -> state
+}
+
+impl MfaStore {
+    pub fn begin_enrollment(&mut self, user_id: &str, secret: &str, ttl: u64) {
+        let now = now();
+        self.pending.retain(|_, p| p.expires_at > now);
+        self.pending.insert(user_id.to_string(), PendingEnrollment {
+            secret: secret.to_string(),
+            expires_at: now + ttl,
+        });
+    }
+
+    pub fn pending_secret(&self, user_id: &str) -> Option<&str> {
+        self.pending
+            .get(user_id)
+            .filter(|p| p.expires_at > now())
+            .map(|p| p.secret.as_str())
+    }
+
+    pub fn finish_enrollment(&mut self, user_id: &str) {
+        self.pending.remove(user_id);
+    }
+
+    /// Record `step` as used by `user_id`. Returns false when that step, or a
+    /// later one, was already accepted, i.e. the code is a replay.
+    pub fn accept_step(&mut self, user_id: &str, step: u64) -> bool {
+        match self.last_steps.get(user_id) {
+            Some(&last) if step <= last => false,
+            _ => {
+                self.last_steps.insert(user_id.to_string(), step);
+                true
+            }
+        }
+    }
+
+    /// Forget replay state, e.g. after the user's secret was replaced.
+    pub fn reset_steps(&mut self, user_id: &str) {
+        self.last_steps.remove(user_id);
+    }
+
+    /// Whether the session `jti` may still be used for a code attempt.
+    pub fn session_usable(&self, jti: &str, max_attempts: u32) -> bool {
+        !self.sessions
+            .get(jti)
+            .is_some_and(|s| s.consumed || s.failures >= max_attempts)
+    }
+
+    pub fn record_failure(&mut self, jti: &str, exp: u64) {
+        self.session_mut(jti, exp).failures += 1;
+    }
+
+    /// Burn the session after a successful second factor.
+    pub fn consume_session(&mut self, jti: &str, exp: u64) {
+        self.session_mut(jti, exp).consumed = true;
+    }
+
+    fn session_mut(&mut self, jti: &str, exp: u64) -> &mut SessionState {
+        let now = now();
+        self.sessions.retain(|_, s| s.exp > now);
+        self.sessions.entry(jti.to_string()).or_insert_with(|| SessionState {
+            exp,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code_accepted_once_within_skew() {
+        let secret = generate_secret();
+        let totp = totp(&secret, "user@example.com", "Test").unwrap();
+        let now = 1_700_000_000;
+
+        let previous = totp.generate(now - STEP);
+        let step = matching_step(&totp, &previous, now).unwrap();
+        assert_eq!(step, now / STEP - 1);
+        assert!(matching_step(&totp, &totp.generate(now - 3 * STEP), now).is_none());
+        assert!(matching_step(&totp, "000000x", now).is_none());
+
+        let mut store = MfaStore::default();
+        assert!(store.accept_step("user-1", step));
+        assert!(!store.accept_step("user-1", step));
+        // An older step is a replay as well
+        assert!(!store.accept_step("user-1", step - 1));
+        assert!(store.accept_step("user-1", step + 1));
+    }
+
+    #[test]
+    fn test_recovery_codes_match_normalized_input() {
+        let (codes, hashes) = generate_recovery_codes(2).unwrap();
+        assert_eq!(codes[0].len(), 11);
+        assert!(!is_totp_code(&codes[0]));
+        assert!(is_totp_code(" 123456 "));
+
+        let typed = format!(" {} ", codes[1].to_uppercase().replace('-', " "));
+        assert_eq!(find_recovery_code(&hashes, &typed).unwrap(), Some(1));
+        assert_eq!(find_recovery_code(&hashes, "aaaaa-aaaaa").unwrap(), None);
+    }
+
+    #[test]
+    fn test_session_burned_after_attempts_or_success() {
+        let mut store = MfaStore::default();
+        let exp = now() + 60;
+
+        assert!(store.session_usable("jti-1", 2));
+        store.record_failure("jti-1", exp);
+        assert!(store.session_usable("jti-1", 2));
+        store.record_failure("jti-1", exp);
+        assert!(!store.session_usable("jti-1", 2));
+
+        store.consume_session("jti-2", exp);
+        assert!(!store.session_usable("jti-2", 2));
+    }
+}
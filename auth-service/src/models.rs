@@ -3,6 +3,12 @@ use std::collections::HashMap;
 use time::OffsetDateTime;
 use uuid::Uuid;
 
+pub use audit_log::AuditEvent;
+pub use password_policy::PasswordPolicy;
+
+use crate::acr::{acr_for, AuthRequirement};
+use crate::webauthn::{AssertionCredential, RegistrationCredential};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub id: String,
@@ -17,6 +23,16 @@ pub struct User {
     pub org: String, // Primary organization
     pub claims: HashMap<String, serde_json::Value>, // Registry-validated claims
     pub mfa_secret: Option<String>,
+    #[serde(default)]
+    pub mfa_recovery_codes: Vec<String>, // Argon2 hashes of unused recovery codes
+    #[serde(default)]
+    pub webauthn_credentials: Vec<WebAuthnCredential>,
+    /// Accounts at upstream identity providers that log in as this user
+    #[serde(default)]
+    pub federated_identities: Vec<FederatedIdentity>,
+    /// Tokens issued before this instant are no longer accepted
+    #[serde(default, with = "time::serde::iso8601::option")]
+    pub password_changed_at: Option<OffsetDateTime>,
     #[serde(with = "time::serde::iso8601")]
     pub created_at: OffsetDateTime,
     #[serde(with = "time::serde::iso8601")]
@@ -31,16 +47,154 @@ pub enum UserStatus {
     Suspended,
 }
 
+/// Link between a user and their `sub` at an upstream provider.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Group {
+pub struct FederatedIdentity {
+    pub provider: String,
+    pub subject: String,
+    #[serde(with = "time::serde::iso8601")]
+    pub linked_at: OffsetDateTime,
+}
+
+/// A registered WebAuthn credential (passkey or security key).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebAuthnCredential {
+    pub credential_id: String, // base64url
+    pub public_key: String, // base64url, uncompressed P-256 point
+    pub algorithm: i64, // COSE algorithm, -7 = ES256
+    pub sign_count: u32,
+    #[serde(default)]
+    pub transports: Vec<String>,
+    pub nickname: String,
+    #[serde(with = "time::serde::iso8601")]
+    pub created_at: OffsetDateTime,
+    #[serde(default, with = "time::serde::iso8601::option")]
+    pub last_used_at: Option<OffsetDateTime>,
+}
+
+/// Instance-wide security policy, edited in admin-service
+/// (`security_policy.json`).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SecurityPolicy {
+    /// Users holding any admin scope must use a passkey as second factor
+    #[serde(default)]
+    pub require_passkey_for_admins: bool,
+    /// Rules for new passwords; organizations may override them
+    #[serde(default)]
+    pub password: PasswordPolicy,
+}
+
+impl SecurityPolicy {
+    pub fn requires_passkey(&self, user: &User) -> bool {
+        self.require_passkey_for_admins && user.is_admin()
+    }
+}
+
+/// How long sessions last and how many a user may hold. Fields left out
+/// are not limited; in org and client overrides they keep the value of
+/// the level below.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionPolicy {
+    /// Seconds without a refresh or any other use of the session's tokens
+    /// at auth-service, after which the session ends
+    pub idle_timeout: Option<u64>,
+    /// Seconds after the login after which the session ends however busy
+    pub max_lifetime: Option<u64>,
+    /// Sessions a user may hold at once
+    pub max_concurrent: Option<usize>,
+    /// What a login beyond `max_concurrent` does; evicts by default
+    pub on_limit: Option<SessionLimitAction>,
+}
+
+impl SessionPolicy {
+    /// This policy with the fields `over` sets replaced.
+    pub fn overridden_by(&self, over: Option<&SessionPolicy>) -> SessionPolicy {
+        let Some(over) = over else {
+            return self.clone();
+        };
+        SessionPolicy {
+            idle_timeout: over.idle_timeout.or(self.idle_timeout),
+            max_lifetime: over.max_lifetime.or(self.max_lifetime),
+            max_concurrent: over.max_concurrent.or(self.max_concurrent),
+            on_limit: over.on_limit.or(self.on_limit),
+        }
+    }
+
+    /// Why a setting of the policy is unusable, if one is.
+    pub fn problem(&self) -> Option<&'static str> {
+        if self.idle_timeout == Some(0) || self.max_lifetime == Some(0) {
+            return Some("session timeouts must be at least one second");
+        }
+        if self.max_concurrent == Some(0) {
+            return Some("max_concurrent must allow at least one session");
+        }
+        None
+    }
+}
+
+/// What happens to a login of a user already holding `max_concurrent`
+/// sessions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionLimitAction {
+    /// The user's oldest session ends
+    #[default]
+    EvictOldest,
+    /// The login is refused with `session_limit_reached`
+    Refuse,
+}
+
+/// An organization from `orgs.json`, maintained in admin-service.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Organization {
     pub id: String,
     pub name: String,
     pub description: String,
     pub metadata: HashMap<String, serde_json::Value>,
+    /// Members may not log in before confirming their email address
+    #[serde(default)]
+    pub require_verified_email: bool,
+    #[serde(default)]
+    pub registration: RegistrationPolicy,
+    /// Replaces the instance-wide password policy for members
+    #[serde(default)]
+    pub password_policy: Option<PasswordPolicy>,
+    /// Session limits of members, field by field over `[security.sessions]`
+    #[serde(default)]
+    pub session_policy: Option<SessionPolicy>,
     #[serde(with = "time::serde::iso8601")]
     pub created_at: OffsetDateTime,
 }
 
+/// Who may create an account in an organization through `/api/auth/register`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RegistrationPolicy {
+    /// Self-registration into this organization is possible at all
+    #[serde(default)]
+    pub enabled: bool,
+    /// Email domains accepted for new accounts; empty accepts any domain
+    #[serde(default)]
+    pub allowed_email_domains: Vec<String>,
+    /// If set, new accounts have to present this code
+    #[serde(default)]
+    pub invite_code: Option<String>,
+    /// Claims every new account gets, e.g. `{"roles": ["guardian"]}`.
+    /// Registrants cannot set these themselves.
+    #[serde(default)]
+    pub default_claims: HashMap<String, serde_json::Value>,
+}
+
+impl RegistrationPolicy {
+    pub fn allows_email(&self, email: &str) -> bool {
+        let domain = email.rsplit_once('@').map(|(_, domain)| domain.to_lowercase());
+        self.allowed_email_domains.is_empty()
+            || domain.is_some_and(|domain| {
+                self.allowed_email_domains.iter().any(|allowed| allowed.to_lowercase() == domain)
+            })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Role {
     pub id: String,
@@ -59,10 +213,32 @@ pub struct Client {
     pub allowed_scopes: Vec<String>,
     pub require_pkce: bool,
     pub grant_types: Vec<String>,
+    #[serde(default)]
+    pub access_token_format: AccessTokenFormat,
+    #[serde(default)]
+    pub subject_type: SubjectType,
+    pub sector_identifier_uri: Option<String>,
+    /// Weakest login (`acr`) the client accepts tokens for
+    pub min_acr: Option<String>,
+    /// Seconds after which the client needs a fresh login
+    pub max_auth_age: Option<u64>,
+    /// Session limits for sessions used by this client, field by field
+    /// over those of the user's organization
+    #[serde(default)]
+    pub session_policy: Option<SessionPolicy>,
     #[serde(with = "time::serde::iso8601")]
     pub created_at: OffsetDateTime,
 }
 
+impl Client {
+    pub fn auth_requirement(&self) -> AuthRequirement {
+        AuthRequirement {
+            min_acr: self.min_acr.clone(),
+            max_auth_age: self.max_auth_age,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ClientType {
@@ -70,19 +246,68 @@ pub enum ClientType {
     Confidential,
 }
 
+/// How access tokens for a client are represented. `Jwt` tokens are
+/// self-contained; `Opaque` tokens are random references resolved through
+/// introspection and revocable immediately.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessTokenFormat {
+    #[default]
+    Jwt,
+    Opaque,
+}
+
+/// Which `sub` a client sees. `Public` is the global user id; `Pairwise`
+/// derives a per-sector identifier so clients cannot correlate users.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SubjectType {
+    #[default]
+    Public,
+    Pairwise,
+}
+
+/// A SAML 2.0 service provider that receives signed assertions.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct AuditEvent {
+pub struct ServiceProvider {
+    /// Short name, used as file name and in admin URLs
     pub id: String,
-    pub user_id: Option<String>,
-    pub org: Option<String>,
-    pub event_type: String,
-    pub ip_address: Option<String>,
-    pub user_agent: Option<String>,
-    pub metadata: HashMap<String, serde_json::Value>,
+    /// The provider's entityID, the Issuer of its AuthnRequests
+    pub entity_id: String,
+    pub name: String,
+    /// AssertionConsumerService URLs (HTTP-POST binding); requests that
+    /// name none are answered at the first
+    pub acs_urls: Vec<String>,
+    #[serde(default)]
+    pub name_id_format: NameIdFormat,
+    /// Attributes released in assertions
+    #[serde(default)]
+    pub attributes: Vec<AttributeRelease>,
     #[serde(with = "time::serde::iso8601")]
     pub created_at: OffsetDateTime,
 }
 
+/// What identifies the user in the assertion's `NameID`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NameIdFormat {
+    /// The user id, stable across address changes
+    #[default]
+    Persistent,
+    Email,
+}
+
+/// One attribute of an assertion, filled from a user field (`id`, `email`,
+/// `given_name`, `family_name`, `name`, `org`) or a registry claim.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttributeRelease {
+    /// SAML attribute name, e.g. `urn:oid:0.9.2342.19200300.100.1.3`
+    pub name: String,
+    #[serde(default)]
+    pub friendly_name: Option<String>,
+    pub source: String,
+}
+
 // Claims Registry
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClaimsRegistry {
@@ -90,6 +315,73 @@ pub struct ClaimsRegistry {
     pub claims: HashMap<String, ClaimDefinition>,
 }
 
+impl ClaimsRegistry {
+    /// Problems with the claims a registrant supplied. `fixed` are the
+    /// organization's default claims, which count towards required claims
+    /// but may not be supplied again.
+    pub fn registration_violations(
+        &self,
+        supplied: &HashMap<String, serde_json::Value>,
+        fixed: &HashMap<String, serde_json::Value>,
+    ) -> Vec<String> {
+        let mut violations = Vec::new();
+
+        for (key, value) in supplied {
+            match self.claims.get(key) {
+                None => violations.push(format!("Unknown claim '{}'", key)),
+                Some(_) if fixed.contains_key(key) => {
+                    violations.push(format!("Claim '{}' is set by the organization", key))
+                }
+                Some(definition) if definition.admin_only == Some(true) || !definition.default_allowed => {
+                    violations.push(format!("Claim '{}' can only be set by an administrator", key))
+                }
+                Some(definition) => {
+                    if !claim_value_matches(&definition.claim_type, definition.items.as_ref(), value) {
+                        violations.push(format!("Claim '{}' must be of type {}", key, definition.claim_type));
+                    }
+                }
+            }
+        }
+
+        let mut required: Vec<&String> = self
+            .claims
+            .iter()
+            .filter(|(key, definition)| {
+                definition.required == Some(true) && !supplied.contains_key(*key) && !fixed.contains_key(*key)
+            })
+            .map(|(key, _)| key)
+            .collect();
+        required.sort();
+        for key in required {
+            violations.push(format!("Claim '{}' is required", key));
+        }
+
+        violations
+    }
+}
+
+/// Whether `value` fits a registry type: `string`, `number`, `boolean` or
+/// `array` (whose `items` is again `{type, enum}`), optionally with an `enum`.
+fn claim_value_matches(claim_type: &str, items: Option<&serde_json::Value>, value: &serde_json::Value) -> bool {
+    match (claim_type, value) {
+        ("array", serde_json::Value::Array(elements)) => {
+            let item_type = items.and_then(|i| i.get("type")).and_then(|t| t.as_str());
+            elements.iter().all(|element| match item_type {
+                Some(item_type) => claim_value_matches(item_type, items, element),
+                None => true,
+            })
+        }
+        ("string", serde_json::Value::String(_)) | ("number", serde_json::Value::Number(_)) | ("boolean", serde_json::Value::Bool(_)) => {
+            // `items` of an array carries the enum for its elements
+            match items.and_then(|i| i.get("enum")).and_then(|e| e.as_array()) {
+                Some(allowed) => allowed.contains(value),
+                None => true,
+            }
+        }
+        _ => false,
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClaimDefinition {
     #[serde(rename = "type")]
@@ -105,9 +397,11 @@ pub struct ClaimDefinition {
 // JWT Claims (simplified)
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
-    pub sub: String, // user_id
-    pub email: String,
-    pub name: String,
+    pub sub: String, // user_id, or the client's pairwise subject
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub email: String, // empty unless the email scope was granted
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub name: String, // empty unless the profile scope was granted
     pub org: String, // Primary organization
     pub admin: Vec<String>, // Admin scopes
     #[serde(flatten)]
@@ -117,6 +411,118 @@ pub struct Claims {
     pub exp: u64, // expiration
     pub iat: u64, // issued at
     pub jti: String, // JWT ID
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub azp: Option<String>, // authorized party (client_id)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth_time: Option<u64>, // last interactive authentication
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acr: Option<String>, // authentication context class
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub amr: Vec<String>, // authentication methods
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<Actor>, // admin acting as the user (impersonation)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>, // login session
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub typ: Option<TokenUse>, // access or refresh; absent in impersonation tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>, // granted to the client; absent in first-party tokens
+}
+
+/// What a token is good for, as its `typ` claim. A refresh token is never
+/// accepted as a bearer token, nor an access token at the token endpoint.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenUse {
+    Access,
+    Refresh,
+}
+
+/// The admin behind an impersonation token, as the `act` claim (RFC 8693)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Actor {
+    pub sub: String,
+    pub email: String,
+}
+
+/// An impersonation admin-service started, in `impersonations/{jti}.json`.
+/// Its token is good only while the record is open.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Impersonation {
+    pub id: String,
+    pub admin_id: String,
+    pub admin_email: String,
+    pub user_id: String,
+    pub org: String,
+    pub reason: String,
+    #[serde(with = "time::serde::iso8601")]
+    pub started_at: OffsetDateTime,
+    #[serde(with = "time::serde::iso8601")]
+    pub expires_at: OffsetDateTime,
+    #[serde(default, with = "time::serde::iso8601::option")]
+    pub ended_at: Option<OffsetDateTime>,
+}
+
+impl Impersonation {
+    /// Whether the record still vouches for a token with `claims`
+    pub fn covers(&self, claims: &Claims, now: OffsetDateTime) -> bool {
+        self.ended_at.is_none()
+            && self.expires_at > now
+            && self.user_id == claims.sub
+            && claims.act.as_ref().is_some_and(|act| act.sub == self.admin_id)
+    }
+}
+
+impl Claims {
+    pub fn is_refresh_token(&self) -> bool {
+        self.typ == Some(TokenUse::Refresh)
+    }
+
+    /// Whether the token was granted `scope`. First-party tokens carry no
+    /// scope and stand for everything the user may see of themselves.
+    pub fn grants(&self, scope: &str) -> bool {
+        self.scope.as_deref().is_none_or(|granted| granted.split_whitespace().any(|s| s == scope))
+    }
+
+    /// Record the login the token stems from: its methods and when it happened.
+    pub fn set_authentication(&mut self, amr: Vec<String>, auth_time: u64) {
+        self.acr = Some(acr_for(&amr).to_string());
+        self.amr = amr;
+        self.auth_time = Some(auth_time);
+    }
+
+    /// Carry over the login of another token of the same session.
+    pub fn copy_authentication(&mut self, from: &Claims) {
+        self.sid = from.sid.clone();
+        self.auth_time = from.auth_time;
+        self.acr = from.acr.clone();
+        self.amr = from.amr.clone();
+    }
+}
+
+// Short-lived token bridging the password step and the second factor.
+// Its audience keeps it from being accepted as an access token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MfaSessionClaims {
+    pub sub: String, // user_id
+    pub purpose: MfaPurpose,
+    pub iss: String,
+    pub aud: Vec<String>,
+    pub exp: u64,
+    pub iat: u64,
+    pub jti: String,
+    /// Methods passed so far, which the second factor adds to
+    #[serde(default)]
+    pub amr: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MfaPurpose {
+    /// User has a TOTP secret and must present a code
+    Verify,
+    /// MFA is mandatory but the user has not enrolled yet
+    Enroll,
 }
 
 // API Request/Response types
@@ -126,6 +532,46 @@ pub struct LoginRequest {
     pub password: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct RegisterRequest {
+    pub email: String,
+    pub password: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub org: String,
+    pub invite_code: Option<String>,
+    #[serde(default)]
+    pub claims: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyEmailRequest {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+/// Claims a user edits on their own account; `null` removes a claim.
+#[derive(Debug, Deserialize)]
+pub struct UpdateAccountClaimsRequest {
+    pub claims: HashMap<String, serde_json::Value>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct LoginResponse {
     pub success: bool,
@@ -135,6 +581,125 @@ pub struct LoginResponse {
     pub requires_mfa: bool,
     pub mfa_session: Option<String>,
     pub redirect_to: Option<String>,
+    pub mfa_enrollment_required: bool,
+    /// Plaintext recovery codes, only in the response that created them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recovery_codes: Option<Vec<String>>,
+    /// Set when a recovery code was used to log in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recovery_codes_remaining: Option<usize>,
+    /// Second factors the pending `mfa_session` may be answered with:
+    /// `totp`, `webauthn`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mfa_methods: Vec<String>,
+    /// Why a login with correct credentials was still refused
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl LoginResponse {
+    /// Rejected credentials; deliberately says nothing about why.
+    pub fn failed() -> Self {
+        Self {
+            success: false,
+            access_token: None,
+            refresh_token: None,
+            expires_in: None,
+            requires_mfa: false,
+            mfa_session: None,
+            redirect_to: None,
+            mfa_enrollment_required: false,
+            recovery_codes: None,
+            recovery_codes_remaining: None,
+            mfa_methods: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Correct credentials, but the account may not log in yet.
+    pub fn refused(error: &str) -> Self {
+        Self {
+            error: Some(error.to_string()),
+            ..Self::failed()
+        }
+    }
+
+    /// Password accepted, second factor (or its enrollment) still outstanding.
+    pub fn mfa_pending(mfa_session: String, purpose: MfaPurpose, methods: Vec<String>) -> Self {
+        Self {
+            success: true,
+            requires_mfa: true,
+            mfa_session: Some(mfa_session),
+            mfa_enrollment_required: purpose == MfaPurpose::Enroll,
+            mfa_methods: methods,
+            ..Self::failed()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MfaVerifyRequest {
+    pub mfa_session: String,
+    pub code: String,
+}
+
+/// Enrollment is authorized either by a Bearer access token or, when MFA is
+/// mandatory and the user has no factor yet, by an enrollment `mfa_session`.
+#[derive(Debug, Default, Deserialize)]
+pub struct MfaEnrollRequest {
+    pub mfa_session: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MfaEnrollResponse {
+    pub secret: String,
+    /// otpauth:// URI, also the payload to render as QR code
+    pub provisioning_uri: String,
+    pub expires_in: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MfaRecoveryCodesRequest {
+    /// Current TOTP code; a Bearer token alone may not mint new codes
+    pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MfaRecoveryCodesResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MfaConfirmRequest {
+    pub code: String,
+    pub mfa_session: Option<String>,
+}
+
+/// Passkey registration, authorized like TOTP enrollment by a Bearer token
+/// or an enrollment `mfa_session`.
+#[derive(Debug, Deserialize)]
+pub struct WebAuthnRegisterStartRequest {
+    pub mfa_session: Option<String>,
+    pub nickname: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebAuthnRegisterFinishRequest {
+    pub mfa_session: Option<String>,
+    pub credential: RegistrationCredential,
+}
+
+/// With a verification `mfa_session` the passkey is the second factor;
+/// without one it is a passwordless login.
+#[derive(Debug, Default, Deserialize)]
+pub struct WebAuthnAuthenticateStartRequest {
+    pub mfa_session: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebAuthnAuthenticateFinishRequest {
+    pub mfa_session: Option<String>,
+    pub credential: AssertionCredential,
 }
 
 #[derive(Debug, Deserialize)]
@@ -144,6 +709,7 @@ pub struct OAuth2AuthorizeRequest {
     pub redirect_uri: String,
     pub scope: Option<String>,
     pub state: Option<String>,
+    pub nonce: Option<String>,
     pub code_challenge: Option<String>,
     pub code_challenge_method: Option<String>,
 }
@@ -157,6 +723,7 @@ pub struct OAuth2TokenRequest {
     pub client_secret: Option<String>,
     pub code_verifier: Option<String>,
     pub refresh_token: Option<String>,
+    pub scope: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -166,17 +733,44 @@ pub struct OAuth2TokenResponse {
     pub expires_in: u64,
     pub refresh_token: Option<String>,
     pub scope: String,
+    /// With the `openid` scope
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+// RFC 7662 / RFC 7009 requests (form-encoded)
+#[derive(Debug, Deserialize)]
+pub struct TokenIntrospectionRequest {
+    pub token: String,
+    pub token_type_hint: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenRevocationRequest {
+    pub token: String,
+    pub token_type_hint: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct UserInfo {
     pub sub: String,
-    pub email: String,
-    pub name: String,
-    pub given_name: String,
-    pub family_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verified: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub given_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub family_name: Option<String>,
     pub org: String,
-    pub verified: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub act: Option<Actor>,
     #[serde(flatten)]
     pub claims: HashMap<String, serde_json::Value>,
 }
@@ -197,6 +791,10 @@ impl User {
             org,
             claims: HashMap::new(),
             mfa_secret: None,
+            mfa_recovery_codes: Vec::new(),
+            webauthn_credentials: Vec::new(),
+            federated_identities: Vec::new(),
+            password_changed_at: None,
             created_at: now,
             updated_at: now,
         }
@@ -210,6 +808,14 @@ impl User {
         self.admin.contains(&"all".to_string()) || !self.admin.is_empty()
     }
 
+    /// Whether a token issued at `iat` predates the last password change.
+    /// `iat` only has whole seconds, so tokens from the second of the change
+    /// itself count as older.
+    pub fn token_revoked_by_password_change(&self, iat: u64) -> bool {
+        self.password_changed_at
+            .is_some_and(|changed| (iat as i64) <= changed.unix_timestamp())
+    }
+
     pub fn is_admin_for_org(&self, org: &str) -> bool {
         self.admin.contains(&"all".to_string()) || self.admin.contains(&org.to_string())
     }
@@ -225,18 +831,3 @@ impl User {
             .unwrap_or_default()
     }
 }
-
-impl AuditEvent {
-    pub fn new(event_type: String, user_id: Option<String>, org: Option<String>) -> Self {
-        Self {
-            id: format!("evt-{}", Uuid::new_v4().simple()),
-            user_id,
-            org,
-            event_type,
-            ip_address: None,
-            user_agent: None,
-            metadata: HashMap::new(),
-            created_at: OffsetDateTime::now_utc(),
-        }
-    }
-}
\ No newline at end of file
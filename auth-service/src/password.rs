@@ -1,9 +1,32 @@
-use anyhow::{Result, anyhow};
+use anyhow::{Result, anyhow, bail};
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
-    Argon2,
+    Algorithm, Argon2, Params, Version,
 };
+use base64::{engine::general_purpose::{STANDARD, STANDARD_NO_PAD}, Engine as _};
+use sha2::Sha256;
 
+use crate::config::Argon2Config;
+
+/// Outcome of checking a password against a stored hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordCheck {
+    Mismatch,
+    Match,
+    /// Correct, but the hash is a legacy scheme or uses other Argon2
+    /// parameters than configured and should be replaced
+    MatchOutdated,
+}
+
+impl Argon2Config {
+    pub fn params(&self) -> Result<Params> {
+        Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+            .map_err(|e| anyhow!("Invalid Argon2 parameters: {}", e))
+    }
+}
+
+/// Argon2id hash with the library's default cost, for secrets other than
+/// user passwords (recovery codes, client secrets).
 pub fn hash_password(password: &str) -> Result<String> {
     let salt = SaltString::generate(&mut OsRng);
     let argon2 = Argon2::default();
@@ -16,16 +39,148 @@ pub fn hash_password(password: &str) -> Result<String> {
     Ok(password_hash)
 }
 
+/// Argon2id hash with the configured cost.
+pub fn hash_password_with(password: &str, cost: &Argon2Config) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, cost.params()?);
+
+    let password_hash = argon2
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("Password hashing failed: {}", e))?
+        .to_string();
+
+    Ok(password_hash)
+}
+
+/// Spend what verifying `password` against a current hash would cost, for
+/// logins that fail before there is a hash to check (unknown, inactive or
+/// locked accounts), so the response time does not tell them apart.
+pub fn dummy_verify(password: &str, cost: &Argon2Config) {
+    let _ = hash_password_with(password, cost);
+}
+
 pub fn verify_password(password: &str, hash: &str) -> Result<bool> {
+    Ok(check_password(password, hash, None)? != PasswordCheck::Mismatch)
+}
+
+/// Verify `password` against a hash in any supported scheme: Argon2 PHC
+/// strings, bcrypt (`$2a$`, `$2b$`, `$2y$`), PBKDF2-SHA256 (PHC, passlib
+/// and Django notation) and SHA-crypt (`$5$`, `$6$`). Argon2 hashes count as
+/// outdated if they differ from `cost`; without it only legacy schemes do.
+pub fn check_password(password: &str, hash: &str, cost: Option<&Argon2Config>) -> Result<PasswordCheck> {
+    let (matches, current) = if hash.starts_with("$argon2") {
+        verify_argon2(password, hash, cost)?
+    } else if ["$2a$", "$2b$", "$2y$"].iter().any(|prefix| hash.starts_with(prefix)) {
+        let matches = bcrypt::verify(password, hash).map_err(|e| anyhow!("Invalid bcrypt hash: {}", e))?;
+        (matches, false)
+    } else if hash.starts_with("$pbkdf2-sha256$") || hash.starts_with("pbkdf2_sha256$") {
+        (verify_pbkdf2_sha256(password, hash)?, false)
+    } else if hash.starts_with("$5$") || hash.starts_with("$6$") {
+        (verify_sha_crypt(password, hash)?, false)
+    } else {
+        bail!("Unsupported password hash scheme");
+    };
+
+    Ok(match (matches, current) {
+        (false, _) => PasswordCheck::Mismatch,
+        (true, true) => PasswordCheck::Match,
+        (true, false) => PasswordCheck::MatchOutdated,
+    })
+}
+
+fn verify_argon2(password: &str, hash: &str, cost: Option<&Argon2Config>) -> Result<(bool, bool)> {
     let parsed_hash = PasswordHash::new(hash)
         .map_err(|e| anyhow!("Invalid password hash: {}", e))?;
-    let argon2 = Argon2::default();
 
-    match argon2.verify_password(password.as_bytes(), &parsed_hash) {
-        Ok(()) => Ok(true),
-        Err(argon2::password_hash::Error::Password) => Ok(false),
-        Err(e) => Err(anyhow!("Password verification failed: {}", e)),
+    let matches = match Argon2::default().verify_password(password.as_bytes(), &parsed_hash) {
+        Ok(()) => true,
+        Err(argon2::password_hash::Error::Password) => false,
+        Err(e) => return Err(anyhow!("Password verification failed: {}", e)),
+    };
+
+    let current = match cost {
+        None => true,
+        Some(cost) => {
+            let params = Params::try_from(&parsed_hash)
+                .map_err(|e| anyhow!("Invalid Argon2 parameters in hash: {}", e))?;
+            parsed_hash.algorithm == Algorithm::Argon2id.ident()
+                && parsed_hash.version == Some(Version::V0x13.into())
+                && params.m_cost() == cost.memory_kib
+                && params.t_cost() == cost.iterations
+                && params.p_cost() == cost.parallelism
+        }
+    };
+
+    Ok((matches, current))
+}
+
+/// Most iterations an imported PBKDF2 or SHA-crypt hash may ask for. Each
+/// login pays for them, so a planted hash must not tie up a worker; current
+/// Django and passlib defaults stay well below.
+const MAX_LEGACY_ROUNDS: u32 = 2_000_000;
+
+/// `$pbkdf2-sha256$i=N,l=32$<b64 salt>$<b64 hash>` (PHC),
+/// `$pbkdf2-sha256$N$<ab64 salt>$<ab64 hash>` (passlib) or
+/// `pbkdf2_sha256$N$<salt>$<base64 hash>` (Django).
+fn verify_pbkdf2_sha256(password: &str, hash: &str) -> Result<bool> {
+    let invalid = || anyhow!("Invalid PBKDF2 hash");
+    let fields: Vec<&str> = hash.trim_start_matches('$').split('$').collect();
+    let [_, rounds, salt, expected] = fields[..] else {
+        return Err(invalid());
+    };
+
+    let (rounds, salt, expected) = if hash.starts_with("pbkdf2_sha256$") {
+        (rounds.parse::<u32>()?, salt.as_bytes().to_vec(), STANDARD.decode(expected)?)
+    } else if let Some(phc) = rounds.strip_prefix("i=") {
+        let rounds = phc.split(',').next().ok_or_else(invalid)?;
+        (rounds.parse::<u32>()?, STANDARD_NO_PAD.decode(salt)?, STANDARD_NO_PAD.decode(expected)?)
+    } else {
+        // passlib's adapted base64 uses '.' for '+'
+        let ab64 = |value: &str| STANDARD_NO_PAD.decode(value.replace('.', "+"));
+        (rounds.parse::<u32>()?, ab64(salt)?, ab64(expected)?)
+    };
+    if rounds == 0 || expected.is_empty() {
+        return Err(invalid());
+    }
+    if rounds > MAX_LEGACY_ROUNDS {
+        bail!("PBKDF2 hash asks for {} rounds, more than the {} accepted", rounds, MAX_LEGACY_ROUNDS);
+    }
+
+    let mut derived = vec![0u8; expected.len()];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, rounds, &mut derived);
+    Ok(constant_time_eq(&derived, &expected))
+}
+
+/// SHA-crypt as specified by Ulrich Drepper, used by glibc's `crypt(3)`.
+fn verify_sha_crypt(password: &str, hash: &str) -> Result<bool> {
+    let invalid = || anyhow!("Invalid SHA-crypt hash");
+    let (id, rest) = hash[1..].split_once('$').ok_or_else(invalid)?;
+
+    let (rounds, rest) = match rest.strip_prefix("rounds=") {
+        Some(rest) => {
+            let (rounds, rest) = rest.split_once('$').ok_or_else(invalid)?;
+            (rounds.parse::<u32>()?, rest)
+        }
+        None => (sha_crypt::ROUNDS_DEFAULT as u32, rest),
+    };
+    if rounds > MAX_LEGACY_ROUNDS {
+        bail!("SHA-crypt hash asks for {} rounds, more than the {} accepted", rounds, MAX_LEGACY_ROUNDS);
     }
+    let (salt, expected) = rest.rsplit_once('$').ok_or_else(invalid)?;
+
+    let encoded = match id {
+        "5" => sha_crypt::Sha256Params::new(rounds as usize)
+            .and_then(|params| sha_crypt::sha256_crypt_b64(password.as_bytes(), salt.as_bytes(), &params)),
+        "6" => sha_crypt::Sha512Params::new(rounds as usize)
+            .and_then(|params| sha_crypt::sha512_crypt_b64(password.as_bytes(), salt.as_bytes(), &params)),
+        _ => return Err(invalid()),
+    };
+    let encoded = encoded.map_err(|e| anyhow!("Invalid SHA-crypt hash: {:?}", e))?;
+    Ok(constant_time_eq(encoded.as_bytes(), expected.as_bytes()))
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
 }
 
 #[cfg(test)]
@@ -51,4 +206,43 @@ mod tests {
         assert!(verify_password("password123", &new_hash).unwrap());
         println!("password123 works with new hash");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_legacy_schemes_verify_and_need_rehash() {
+        let cost = Argon2Config { memory_kib: 8192, iterations: 1, parallelism: 1 };
+        let password = "Hello world!";
+        let hashes = [
+            bcrypt::hash(password, 4).unwrap(),
+            "$pbkdf2-sha256$i=1000,l=32$c2FsdHNhbHRzYWx0$d+o5+l8s+LGaQzxcovmk87gkRWX2/MUVH6/6GVFuZFA".to_string(),
+            "$pbkdf2-sha256$1000$c2FsdHNhbHRzYWx0$d.o5.l8s.LGaQzxcovmk87gkRWX2/MUVH6/6GVFuZFA".to_string(),
+            "pbkdf2_sha256$1000$saltsaltsalt$d+o5+l8s+LGaQzxcovmk87gkRWX2/MUVH6/6GVFuZFA=".to_string(),
+            "$5$saltstring$5B8vYYiY.CVt1RlTTf8KbXBH3hsxY/GNooZaBBGWEc5".to_string(),
+            "$6$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8iKwSMHWjl/O817G3uBnIFNjnQJuesI68u4OTLiBFdcbYEdFCoEOfaS35inz1".to_string(),
+            "$6$rounds=10000$saltstringsaltst$OW1/O6BYHV6BcXZu8QVeXbDWra3Oeqh0sbHbbMCVNSnCM/UrjmM0Dp8vOuZeHBy/YTBmSK6H9qs/y3RnOaw5v.".to_string(),
+            hash_password(password).unwrap(),
+        ];
+
+        for hash in &hashes {
+            assert_eq!(check_password(password, hash, Some(&cost)).unwrap(), PasswordCheck::MatchOutdated, "{}", hash);
+            assert_eq!(check_password("Hello world?", hash, Some(&cost)).unwrap(), PasswordCheck::Mismatch, "{}", hash);
+        }
+
+        let upgraded = hash_password_with(password, &cost).unwrap();
+        assert_eq!(check_password(password, &upgraded, Some(&cost)).unwrap(), PasswordCheck::Match);
+        assert!(check_password(password, "$1$md5crypt$unsupported", Some(&cost)).is_err());
+    }
+
+    #[test]
+    fn test_legacy_rounds_are_bounded() {
+        // Refused before any work is done, whatever the password
+        let hashes = [
+            "$pbkdf2-sha256$i=4000000000,l=32$c2FsdHNhbHRzYWx0$d+o5+l8s+LGaQzxcovmk87gkRWX2/MUVH6/6GVFuZFA",
+            "pbkdf2_sha256$2000001$saltsaltsalt$d+o5+l8s+LGaQzxcovmk87gkRWX2/MUVH6/6GVFuZFA=",
+            "$6$rounds=999999999$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8iKwSMHWjl/O817G3uBnIFNjnQJuesI68u4OTLiBFdcbYEdFCoEOfaS35inz1",
+        ];
+        for hash in hashes {
+            let error = check_password("Hello world!", hash, None).unwrap_err();
+            assert!(error.to_string().contains("rounds"), "{}: {:#}", hash, error);
+        }
+    }
+}
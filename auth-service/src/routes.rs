@@ -0,0 +1,17 @@
+// Protocol endpoint paths, shared by the router and the discovery documents
+// so that metadata can only point at routes that exist.
+pub const AUTHORIZE: &str = "/oauth2/authorize";
+pub const TOKEN: &str = "/oauth2/token";
+pub const USERINFO: &str = "/oauth2/userinfo";
+pub const INTROSPECT: &str = "/oauth2/introspect";
+pub const REVOKE: &str = "/oauth2/revoke";
+pub const JWKS: &str = "/oauth2/jwks";
+pub const OIDC_DISCOVERY: &str = "/.well-known/openid-configuration";
+pub const OAUTH_METADATA: &str = "/.well-known/oauth-authorization-server";
+
+/// SAML 2.0 identity provider
+pub const SAML_METADATA: &str = "/saml/metadata";
+pub const SAML_SSO: &str = "/saml/sso";
+
+/// Redirect URI registered at upstream identity providers
+pub const FEDERATION_CALLBACK: &str = "/api/auth/federation/callback";
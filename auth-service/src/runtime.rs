@@ -0,0 +1,61 @@
+use anyhow::{Context, Result};
+use tokio::sync::RwLock;
+
+use crate::action_tokens::ActionTokenStore;
+use crate::audit::{self, AuditLog};
+use crate::config::Config;
+use crate::consents::ConsentStore;
+use crate::federation::FederationState;
+use crate::lockout::{LockoutStore, LoginThrottle};
+use crate::mail::Outbox;
+use crate::saml::SamlState;
+use crate::sessions::{LoginHistory, SessionStore};
+use crate::mfa::MfaStore;
+use crate::oidc::OidcState;
+use crate::tokens::TokenStore;
+use crate::webauthn::ChallengeStore;
+
+/// State owned by auth-service itself, as opposed to the shared data that
+/// `FileStorage` mirrors. It is not replaced on SIGHUP reload.
+pub struct Runtime {
+    pub tokens: RwLock<TokenStore>,
+    pub mfa: RwLock<MfaStore>,
+    pub webauthn: RwLock<ChallengeStore>,
+    pub outbox: Outbox,
+    pub resets: ActionTokenStore,
+    pub verifications: ActionTokenStore,
+    pub lockouts: LockoutStore,
+    pub login_throttle: LoginThrottle,
+    pub federation: FederationState,
+    pub saml: SamlState,
+    pub oidc: OidcState,
+    pub sessions: SessionStore,
+    pub logins: LoginHistory,
+    pub consents: ConsentStore,
+    pub audit: AuditLog,
+}
+
+impl Runtime {
+    pub async fn load(data_dir: &str, config: &Config) -> Result<Self> {
+        let tokens = TokenStore::load(data_dir).await
+            .context("Failed to load token store")?;
+
+        Ok(Self {
+            tokens: RwLock::new(tokens),
+            mfa: RwLock::new(MfaStore::default()),
+            webauthn: RwLock::new(ChallengeStore::default()),
+            outbox: Outbox::new(data_dir),
+            resets: ActionTokenStore::password_resets(data_dir),
+            verifications: ActionTokenStore::email_verifications(data_dir),
+            lockouts: LockoutStore::new(data_dir),
+            login_throttle: LoginThrottle::default(),
+            federation: FederationState::new()?,
+            saml: SamlState::new(),
+            oidc: OidcState::new(),
+            sessions: SessionStore::new(data_dir),
+            logins: LoginHistory::new(data_dir),
+            consents: ConsentStore::new(data_dir),
+            audit: audit::open(data_dir, &config.audit),
+        })
+    }
+}
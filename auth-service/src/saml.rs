@@ -0,0 +1,780 @@
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD}, Engine};
+use flate2::read::DeflateDecoder;
+use rand::RngCore;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, RsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING, RSA_PKCS1_SHA256};
+use rustls::pki_types::PrivateKeyDer;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    io::Read,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::sync::OnceCell;
+use tracing::warn;
+
+use crate::{
+    config::{Config, SamlConfig},
+    models::{NameIdFormat, ServiceProvider, User},
+    routes,
+};
+
+const NS_PROTOCOL: &str = "urn:oasis:names:tc:SAML:2.0:protocol";
+const NS_ASSERTION: &str = "urn:oasis:names:tc:SAML:2.0:assertion";
+const NS_METADATA: &str = "urn:oasis:names:tc:SAML:2.0:metadata";
+const NS_DSIG: &str = "http://www.w3.org/2000/09/xmldsig#";
+const EXCLUSIVE_C14N: &str = "http://www.w3.org/2001/10/xml-exc-c14n#";
+const ENVELOPED_SIGNATURE: &str = "http://www.w3.org/2000/09/xmldsig#enveloped-signature";
+const DIGEST_SHA256: &str = "http://www.w3.org/2001/04/xmlenc#sha256";
+const SIGNATURE_RSA_SHA256: &str = "http://www.w3.org/2001/04/xmldsig-more#rsa-sha256";
+const SIGNATURE_ECDSA_SHA256: &str = "http://www.w3.org/2001/04/xmldsig-more#ecdsa-sha256";
+
+pub const BINDING_POST: &str = "urn:oasis:names:tc:SAML:2.0:bindings:HTTP-POST";
+pub const BINDING_REDIRECT: &str = "urn:oasis:names:tc:SAML:2.0:bindings:HTTP-Redirect";
+const NAME_ID_PERSISTENT: &str = "urn:oasis:names:tc:SAML:2.0:nameid-format:persistent";
+const NAME_ID_EMAIL: &str = "urn:oasis:names:tc:SAML:1.1:nameid-format:emailAddress";
+const NAME_ID_UNSPECIFIED: &str = "urn:oasis:names:tc:SAML:1.1:nameid-format:unspecified";
+const STATUS_SUCCESS: &str = "urn:oasis:names:tc:SAML:2.0:status:Success";
+const CONFIRMATION_BEARER: &str = "urn:oasis:names:tc:SAML:2.0:cm:bearer";
+const ATTRIBUTE_NAME_URI: &str = "urn:oasis:names:tc:SAML:2.0:attrname-format:uri";
+const ATTRIBUTE_NAME_BASIC: &str = "urn:oasis:names:tc:SAML:2.0:attrname-format:basic";
+const CONTEXT_PASSWORD: &str = "urn:oasis:names:tc:SAML:2.0:ac:classes:PasswordProtectedTransport";
+const CONTEXT_MFA: &str = "https://refeds.org/profile/mfa";
+
+/// Time the user has to log in before an AuthnRequest is dropped
+const PENDING_REQUEST_TTL: Duration = Duration::from_secs(600);
+/// Decoded requests larger than this are refused rather than inflated further
+const MAX_REQUEST_SIZE: u64 = 64 * 1024;
+
+/// The identity provider's signing key and the AuthnRequests waiting for
+/// the user to log in. Requests are kept in memory only.
+pub struct SamlState {
+    key: OnceCell<SigningKey>,
+    pending: Mutex<HashMap<String, PendingRequest>>,
+}
+
+/// An AuthnRequest accepted from a registered service provider.
+#[derive(Debug, Clone)]
+pub struct PendingRequest {
+    /// entityID of the service provider
+    pub service_provider: String,
+    /// ID of the AuthnRequest, answered in `InResponseTo`
+    pub request_id: String,
+    pub acs_url: String,
+    pub relay_state: Option<String>,
+    /// The provider wants a login that happened after its request
+    pub force_authn: bool,
+    pub received_at: OffsetDateTime,
+    expires_at: Instant,
+}
+
+/// The parts of an AuthnRequest the identity provider acts on.
+#[derive(Debug)]
+pub struct AuthnRequest {
+    pub id: String,
+    pub issuer: String,
+    pub acs_url: Option<String>,
+    pub force_authn: bool,
+    pub name_id_format: Option<String>,
+}
+
+/// The login an assertion vouches for.
+pub struct Authentication {
+    /// Context class (`acr`) of the login
+    pub acr: String,
+    pub auth_time: OffsetDateTime,
+}
+
+impl SamlState {
+    pub fn new() -> Self {
+        Self {
+            key: OnceCell::new(),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The signing key, read (or created) on first use.
+    pub async fn key(&self, config: &SamlConfig) -> Result<&SigningKey> {
+        self.key.get_or_try_init(|| SigningKey::load_or_create(config)).await
+    }
+
+    /// Keep `request` until the login page completes it; returns its handle.
+    pub fn remember(&self, request: &AuthnRequest, service_provider: &ServiceProvider, acs_url: String, relay_state: Option<String>) -> String {
+        let handle = random_handle();
+        let now = Instant::now();
+        let mut pending = lock(&self.pending);
+        pending.retain(|_, r| r.expires_at > now);
+        pending.insert(handle.clone(), PendingRequest {
+            service_provider: service_provider.entity_id.clone(),
+            request_id: request.id.clone(),
+            acs_url,
+            relay_state,
+            force_authn: request.force_authn,
+            received_at: OffsetDateTime::now_utc(),
+            expires_at: now + PENDING_REQUEST_TTL,
+        });
+        handle
+    }
+
+    pub fn get(&self, handle: &str) -> Option<PendingRequest> {
+        lock(&self.pending).get(handle).filter(|r| r.expires_at > Instant::now()).cloned()
+    }
+
+    /// Remove the request once it has been answered.
+    pub fn take(&self, handle: &str) -> Option<PendingRequest> {
+        lock(&self.pending).remove(handle).filter(|r| r.expires_at > Instant::now())
+    }
+}
+
+impl Default for SamlState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// EntityID of this identity provider.
+pub fn entity_id(config: &Config) -> String {
+    config.saml.entity_id.clone().unwrap_or_else(|| endpoint(config, routes::SAML_METADATA))
+}
+
+fn endpoint(config: &Config, path: &str) -> String {
+    format!("{}{}", config.instance.issuer.trim_end_matches('/'), path)
+}
+
+/// Decode the `SAMLRequest` parameter: deflated for the HTTP-Redirect
+/// binding, plain for HTTP-POST.
+pub fn decode_request(saml_request: &str, deflated: bool) -> Result<AuthnRequest> {
+    let compact: String = saml_request.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+    let bytes = STANDARD.decode(compact).context("SAMLRequest is not base64")?;
+
+    let mut xml = String::new();
+    let read = if deflated {
+        DeflateDecoder::new(&bytes[..]).take(MAX_REQUEST_SIZE + 1).read_to_string(&mut xml)
+    } else {
+        (&bytes[..]).take(MAX_REQUEST_SIZE + 1).read_to_string(&mut xml)
+    };
+    read.context("SAMLRequest cannot be decoded")?;
+    if xml.len() as u64 > MAX_REQUEST_SIZE {
+        bail!("SAMLRequest is larger than {} bytes", MAX_REQUEST_SIZE);
+    }
+
+    parse_authn_request(&xml)
+}
+
+fn parse_authn_request(xml: &str) -> Result<AuthnRequest> {
+    // roxmltree rejects DTDs, so there are no external entities to resolve
+    let document = roxmltree::Document::parse(xml).context("SAMLRequest is not well-formed XML")?;
+    let root = document.root_element();
+    if !root.has_tag_name((NS_PROTOCOL, "AuthnRequest")) {
+        bail!("Expected an AuthnRequest, got {}", root.tag_name().name());
+    }
+    if root.attribute("Version") != Some("2.0") {
+        bail!("Unsupported SAML version {:?}", root.attribute("Version"));
+    }
+    if let Some(binding) = root.attribute("ProtocolBinding").filter(|b| *b != BINDING_POST) {
+        bail!("Unsupported response binding {}", binding);
+    }
+
+    let id = root.attribute("ID").ok_or_else(|| anyhow!("AuthnRequest without ID"))?;
+    let issuer = root.children()
+        .find(|n| n.has_tag_name((NS_ASSERTION, "Issuer")))
+        .and_then(|n| n.text())
+        .map(str::trim)
+        .ok_or_else(|| anyhow!("AuthnRequest without Issuer"))?;
+    let name_id_format = root.children()
+        .find(|n| n.has_tag_name((NS_PROTOCOL, "NameIDPolicy")))
+        .and_then(|n| n.attribute("Format"));
+
+    Ok(AuthnRequest {
+        id: id.to_string(),
+        issuer: issuer.to_string(),
+        acs_url: root.attribute("AssertionConsumerServiceURL").map(str::to_string),
+        force_authn: matches!(root.attribute("ForceAuthn"), Some("true" | "1")),
+        name_id_format: name_id_format.map(str::to_string),
+    })
+}
+
+/// Whether a request's `NameIDPolicy` can be met for `service_provider`.
+pub fn accepts_name_id_format(service_provider: &ServiceProvider, requested: Option<&str>) -> bool {
+    match requested {
+        None | Some(NAME_ID_UNSPECIFIED) => true,
+        Some(format) => format == name_id_format(service_provider.name_id_format),
+    }
+}
+
+fn name_id_format(format: NameIdFormat) -> &'static str {
+    match format {
+        NameIdFormat::Persistent => NAME_ID_PERSISTENT,
+        NameIdFormat::Email => NAME_ID_EMAIL,
+    }
+}
+
+/// The identity provider's metadata document.
+pub fn metadata(config: &Config, key: &SigningKey) -> String {
+    let sso = endpoint(config, routes::SAML_SSO);
+    let mut descriptor = Element::new("md:IDPSSODescriptor")
+        .attribute("WantAuthnRequestsSigned", "false")
+        .attribute("protocolSupportEnumeration", NS_PROTOCOL)
+        .child(
+            Element::new("md:KeyDescriptor").attribute("use", "signing").child(key.key_info()),
+        );
+    for format in [NAME_ID_PERSISTENT, NAME_ID_EMAIL] {
+        descriptor = descriptor.child(Element::new("md:NameIDFormat").text(format));
+    }
+    for binding in [BINDING_REDIRECT, BINDING_POST] {
+        descriptor = descriptor.child(
+            Element::new("md:SingleSignOnService").attribute("Binding", binding).attribute("Location", &sso),
+        );
+    }
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    Element::new("md:EntityDescriptor")
+        .attribute("xmlns:md", NS_METADATA)
+        .attribute("xmlns:ds", NS_DSIG)
+        .attribute("entityID", &entity_id(config))
+        .child(descriptor)
+        .write(&mut xml);
+    xml
+}
+
+/// A `samlp:Response` to `request` with an assertion about `user`, signed
+/// with `key`, base64-encoded for the HTTP-POST binding.
+pub fn response(
+    config: &Config,
+    key: &SigningKey,
+    service_provider: &ServiceProvider,
+    request: &PendingRequest,
+    user: &User,
+    authentication: &Authentication,
+) -> Result<String> {
+    let issuer = entity_id(config);
+    let now = OffsetDateTime::now_utc();
+    let not_on_or_after = now + time::Duration::seconds(config.saml.assertion_lifetime as i64);
+    let assertion_id = format!("_{}", random_id());
+
+    let name_id = match service_provider.name_id_format {
+        NameIdFormat::Persistent => user.id.clone(),
+        NameIdFormat::Email => user.email.clone(),
+    };
+    let context_class = match authentication.acr.as_str() {
+        "mfa" | "phr" => CONTEXT_MFA,
+        _ => CONTEXT_PASSWORD,
+    };
+
+    let mut assertion = Element::new("saml:Assertion")
+        .attribute("xmlns:saml", NS_ASSERTION)
+        .attribute("ID", &assertion_id)
+        .attribute("IssueInstant", &timestamp(now))
+        .attribute("Version", "2.0")
+        .child(Element::new("saml:Issuer").text(&issuer))
+        .child(
+            Element::new("saml:Subject")
+                .child(
+                    Element::new("saml:NameID")
+                        .attribute("Format", name_id_format(service_provider.name_id_format))
+                        .text(&name_id),
+                )
+                .child(
+                    Element::new("saml:SubjectConfirmation").attribute("Method", CONFIRMATION_BEARER).child(
+                        Element::new("saml:SubjectConfirmationData")
+                            .attribute("InResponseTo", &request.request_id)
+                            .attribute("NotOnOrAfter", &timestamp(not_on_or_after))
+                            .attribute("Recipient", &request.acs_url),
+                    ),
+                ),
+        )
+        .child(
+            Element::new("saml:Conditions")
+                .attribute("NotBefore", &timestamp(now - time::Duration::seconds(30)))
+                .attribute("NotOnOrAfter", &timestamp(not_on_or_after))
+                .child(
+                    Element::new("saml:AudienceRestriction")
+                        .child(Element::new("saml:Audience").text(&service_provider.entity_id)),
+                ),
+        )
+        .child(
+            Element::new("saml:AuthnStatement")
+                .attribute("AuthnInstant", &timestamp(authentication.auth_time))
+                .attribute("SessionIndex", &assertion_id)
+                .child(
+                    Element::new("saml:AuthnContext")
+                        .child(Element::new("saml:AuthnContextClassRef").text(context_class)),
+                ),
+        );
+
+    let attributes = released_attributes(service_provider, user);
+    if !attributes.is_empty() {
+        assertion = assertion.child(attributes.into_iter().fold(
+            Element::new("saml:AttributeStatement"),
+            |statement, attribute| statement.child(attribute),
+        ));
+    }
+
+    // Enveloped signature right after the Issuer, as the schema wants it
+    let signature = key.sign_element(&assertion, &assertion_id)?;
+    assertion.children.insert(1, Node::Element(signature));
+
+    let mut xml = String::new();
+    Element::new("samlp:Response")
+        .attribute("xmlns:samlp", NS_PROTOCOL)
+        .attribute("xmlns:saml", NS_ASSERTION)
+        .attribute("Destination", &request.acs_url)
+        .attribute("ID", &format!("_{}", random_id()))
+        .attribute("InResponseTo", &request.request_id)
+        .attribute("IssueInstant", &timestamp(now))
+        .attribute("Version", "2.0")
+        .child(Element::new("saml:Issuer").text(&issuer))
+        .child(
+            Element::new("samlp:Status")
+                .child(Element::new("samlp:StatusCode").attribute("Value", STATUS_SUCCESS)),
+        )
+        .child(assertion)
+        .write(&mut xml);
+
+    Ok(STANDARD.encode(xml))
+}
+
+/// `saml:Attribute` elements for what `service_provider` gets to see of
+/// `user`; sources without a value are left out.
+fn released_attributes(service_provider: &ServiceProvider, user: &User) -> Vec<Element> {
+    service_provider.attributes.iter().filter_map(|release| {
+        let values = match release.source.as_str() {
+            "id" => vec![user.id.clone()],
+            "email" => vec![user.email.clone()],
+            "given_name" => vec![user.first_name.clone()],
+            "family_name" => vec![user.last_name.clone()],
+            "name" => vec![format!("{} {}", user.first_name, user.last_name).trim().to_string()],
+            "org" => vec![user.org.clone()],
+            claim => match user.claims.get(claim)? {
+                serde_json::Value::Array(items) => items.iter().map(claim_value).collect(),
+                value => vec![claim_value(value)],
+            },
+        };
+        let values: Vec<String> = values.into_iter().filter(|v| !v.is_empty()).collect();
+        if values.is_empty() {
+            return None;
+        }
+
+        let name_format = if release.name.contains(':') { ATTRIBUTE_NAME_URI } else { ATTRIBUTE_NAME_BASIC };
+        let mut attribute = Element::new("saml:Attribute")
+            .attribute("Name", &release.name)
+            .attribute("NameFormat", name_format);
+        if let Some(friendly_name) = &release.friendly_name {
+            attribute = attribute.attribute("FriendlyName", friendly_name);
+        }
+        Some(values.iter().fold(attribute, |attribute, value| {
+            attribute.child(Element::new("saml:AttributeValue").text(value))
+        }))
+    })
+    .collect()
+}
+
+fn claim_value(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// The certificate and private key assertions are signed with.
+pub struct SigningKey {
+    signer: Signer,
+    /// DER of the certificate published in metadata
+    certificate: Vec<u8>,
+}
+
+enum Signer {
+    Rsa(RsaKeyPair),
+    Ecdsa(EcdsaKeyPair),
+}
+
+impl SigningKey {
+    async fn load_or_create(config: &SamlConfig) -> Result<Self> {
+        if !Path::new(&config.certificate).exists() && !Path::new(&config.private_key).exists() {
+            generate(config).await?;
+        }
+        let certificate = tokio::fs::read(&config.certificate).await
+            .with_context(|| format!("Failed to read SAML certificate {}", config.certificate))?;
+        let private_key = tokio::fs::read(&config.private_key).await
+            .with_context(|| format!("Failed to read SAML private key {}", config.private_key))?;
+        Self::from_pem(&certificate, &private_key)
+    }
+
+    fn from_pem(certificate: &[u8], private_key: &[u8]) -> Result<Self> {
+        let certificate = rustls_pemfile::certs(&mut &certificate[..])
+            .next()
+            .ok_or_else(|| anyhow!("No certificate in PEM file"))?
+            .context("Invalid certificate PEM")?;
+        let private_key = rustls_pemfile::private_key(&mut &private_key[..])
+            .context("Invalid private key PEM")?
+            .ok_or_else(|| anyhow!("No private key in PEM file"))?;
+
+        let rng = SystemRandom::new();
+        let signer = match &private_key {
+            PrivateKeyDer::Pkcs8(der) => match RsaKeyPair::from_pkcs8(der.secret_pkcs8_der()) {
+                Ok(key) => Signer::Rsa(key),
+                Err(_) => EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, der.secret_pkcs8_der(), &rng)
+                    .map(Signer::Ecdsa)
+                    .map_err(|e| anyhow!("Private key is neither RSA nor P-256: {}", e))?,
+            },
+            PrivateKeyDer::Pkcs1(der) => RsaKeyPair::from_der(der.secret_pkcs1_der())
+                .map(Signer::Rsa)
+                .map_err(|e| anyhow!("Invalid RSA private key: {}", e))?,
+            _ => bail!("Unsupported private key format; use PKCS#8 or PKCS#1"),
+        };
+
+        Ok(Self { signer, certificate: certificate.to_vec() })
+    }
+
+    fn signature_method(&self) -> &'static str {
+        match self.signer {
+            Signer::Rsa(_) => SIGNATURE_RSA_SHA256,
+            Signer::Ecdsa(_) => SIGNATURE_ECDSA_SHA256,
+        }
+    }
+
+    fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let rng = SystemRandom::new();
+        match &self.signer {
+            Signer::Rsa(key) => {
+                let mut signature = vec![0; key.public().modulus_len()];
+                key.sign(&RSA_PKCS1_SHA256, &rng, data, &mut signature)
+                    .map_err(|_| anyhow!("RSA signing failed"))?;
+                Ok(signature)
+            }
+            Signer::Ecdsa(key) => Ok(key.sign(&rng, data).map_err(|_| anyhow!("ECDSA signing failed"))?.as_ref().to_vec()),
+        }
+    }
+
+    fn key_info(&self) -> Element {
+        Element::new("ds:KeyInfo").child(
+            Element::new("ds:X509Data")
+                .child(Element::new("ds:X509Certificate").text(&STANDARD.encode(&self.certificate))),
+        )
+    }
+
+    /// Enveloped XML signature over `element`, referenced by its `ID`. Both
+    /// the element and `SignedInfo` are written in exclusive canonical form,
+    /// so the bytes signed are the bytes a verifier canonicalizes to.
+    fn sign_element(&self, element: &Element, id: &str) -> Result<Element> {
+        let mut canonical = String::new();
+        element.write(&mut canonical);
+        let digest = STANDARD.encode(Sha256::digest(canonical.as_bytes()));
+
+        let signed_info = Element::new("ds:SignedInfo")
+            .attribute("xmlns:ds", NS_DSIG)
+            .child(Element::new("ds:CanonicalizationMethod").attribute("Algorithm", EXCLUSIVE_C14N))
+            .child(Element::new("ds:SignatureMethod").attribute("Algorithm", self.signature_method()))
+            .child(
+                Element::new("ds:Reference")
+                    .attribute("URI", &format!("#{}", id))
+                    .child(
+                        Element::new("ds:Transforms")
+                            .child(Element::new("ds:Transform").attribute("Algorithm", ENVELOPED_SIGNATURE))
+                            .child(Element::new("ds:Transform").attribute("Algorithm", EXCLUSIVE_C14N)),
+                    )
+                    .child(Element::new("ds:DigestMethod").attribute("Algorithm", DIGEST_SHA256))
+                    .child(Element::new("ds:DigestValue").text(&digest)),
+            );
+        let mut canonical_signed_info = String::new();
+        signed_info.write(&mut canonical_signed_info);
+        let signature = self.sign(canonical_signed_info.as_bytes())?;
+
+        Ok(Element::new("ds:Signature")
+            .attribute("xmlns:ds", NS_DSIG)
+            .child(signed_info)
+            .child(Element::new("ds:SignatureValue").text(&STANDARD.encode(signature)))
+            .child(self.key_info()))
+    }
+}
+
+/// Self-signed P-256 pair for a start without configured key material.
+async fn generate(config: &SamlConfig) -> Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::io::Write;
+
+    let mut params = rcgen::CertificateParams::new(Vec::new());
+    params.distinguished_name.push(rcgen::DnType::CommonName, "auth-service SAML signing");
+    let certificate = rcgen::Certificate::from_params(params)
+        .context("Failed to generate SAML signing certificate")?;
+
+    for path in [&config.certificate, &config.private_key] {
+        if let Some(parent) = Path::new(path).parent() {
+            tokio::fs::create_dir_all(parent).await
+                .with_context(|| format!("Failed to create directory for {}", path))?;
+        }
+    }
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&config.private_key)
+        .and_then(|mut file| file.write_all(certificate.serialize_private_key_pem().as_bytes()))
+        .with_context(|| format!("Failed to write {}", config.private_key))?;
+    tokio::fs::write(&config.certificate, certificate.serialize_pem()?)
+        .await
+        .with_context(|| format!("Failed to write {}", config.certificate))?;
+
+    warn!(
+        service = "auth-service",
+        event = "saml_certificate_generated",
+        certificate = %config.certificate,
+        "Generated a self-signed SAML signing certificate; service providers must trust it from the metadata"
+    );
+    Ok(())
+}
+
+/// An XML element written in exclusive canonical form: namespace
+/// declarations first, then attributes sorted by name, no whitespace
+/// between elements and explicit end tags. Only unprefixed attributes
+/// besides `xmlns:*` are used, which keeps that order simple.
+struct Element {
+    name: &'static str,
+    attributes: Vec<(&'static str, String)>,
+    children: Vec<Node>,
+}
+
+enum Node {
+    Element(Element),
+    Text(String),
+}
+
+impl Element {
+    fn new(name: &'static str) -> Self {
+        Self { name, attributes: Vec::new(), children: Vec::new() }
+    }
+
+    fn attribute(mut self, name: &'static str, value: &str) -> Self {
+        self.attributes.push((name, value.to_string()));
+        self
+    }
+
+    fn child(mut self, child: Element) -> Self {
+        self.children.push(Node::Element(child));
+        self
+    }
+
+    fn text(mut self, text: &str) -> Self {
+        self.children.push(Node::Text(text.to_string()));
+        self
+    }
+
+    fn write(&self, out: &mut String) {
+        let mut attributes: Vec<&(&str, String)> = self.attributes.iter().collect();
+        attributes.sort_by_key(|(name, _)| (!name.starts_with("xmlns"), *name));
+
+        out.push('<');
+        out.push_str(self.name);
+        for (name, value) in attributes {
+            out.push(' ');
+            out.push_str(name);
+            out.push_str("=\"");
+            for c in value.chars() {
+                match c {
+                    '&' => out.push_str("&amp;"),
+                    '<' => out.push_str("&lt;"),
+                    '"' => out.push_str("&quot;"),
+                    '\t' => out.push_str("&#x9;"),
+                    '\n' => out.push_str("&#xA;"),
+                    '\r' => out.push_str("&#xD;"),
+                    c => out.push(c),
+                }
+            }
+            out.push('"');
+        }
+        out.push('>');
+        for child in &self.children {
+            match child {
+                Node::Element(element) => element.write(out),
+                Node::Text(text) => {
+                    for c in text.chars() {
+                        match c {
+                            '&' => out.push_str("&amp;"),
+                            '<' => out.push_str("&lt;"),
+                            '>' => out.push_str("&gt;"),
+                            '\r' => out.push_str("&#xD;"),
+                            c => out.push(c),
+                        }
+                    }
+                }
+            }
+        }
+        out.push_str("</");
+        out.push_str(self.name);
+        out.push('>');
+    }
+}
+
+fn timestamp(instant: OffsetDateTime) -> String {
+    instant.replace_nanosecond(0).unwrap_or(instant).format(&Rfc3339).unwrap_or_default()
+}
+
+/// Random identifier; the caller prefixes `_` where an XML ID needs to
+/// start with a letter.
+fn random_id() -> String {
+    let mut bytes = [0u8; 20];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn random_handle() -> String {
+    let mut bytes = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Checks a response the way a service provider would: the assertion's
+/// digest after removing the enveloped signature, and the signature over
+/// `SignedInfo` with the key from the embedded certificate.
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+
+    pub struct VerifiedAssertion {
+        pub name_id: String,
+        pub audience: String,
+        pub in_response_to: String,
+        pub attributes: HashMap<String, Vec<String>>,
+    }
+
+    pub fn verify_response(encoded: &str, public_key: &[u8]) -> VerifiedAssertion {
+        let xml = String::from_utf8(STANDARD.decode(encoded).unwrap()).unwrap();
+
+        // Our output is canonical already; the enveloped transform cuts the
+        // Signature element out and the assertion stays self-contained
+        let start = xml.find("<saml:Assertion").unwrap();
+        let end = xml.find("</saml:Assertion>").unwrap() + "</saml:Assertion>".len();
+        let assertion = &xml[start..end];
+        let signature_start = assertion.find("<ds:Signature ").unwrap();
+        let signature_end = assertion.find("</ds:Signature>").unwrap() + "</ds:Signature>".len();
+        let unsigned = format!("{}{}", &assertion[..signature_start], &assertion[signature_end..]);
+
+        let document = roxmltree::Document::parse(&xml).unwrap();
+        let find = |name: &str| document.descendants().find(|n| n.tag_name().name() == name).unwrap();
+        let digest = find("DigestValue").text().unwrap();
+        assert_eq!(digest, STANDARD.encode(Sha256::digest(unsigned.as_bytes())), "digest mismatch");
+
+        let signed_info = &assertion[assertion.find("<ds:SignedInfo").unwrap()
+            ..assertion.find("</ds:SignedInfo>").unwrap() + "</ds:SignedInfo>".len()];
+        let signature = STANDARD.decode(find("SignatureValue").text().unwrap()).unwrap();
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, public_key)
+            .verify(signed_info.as_bytes(), &signature)
+            .expect("signature does not verify");
+
+        let mut attributes = HashMap::new();
+        for attribute in document.descendants().filter(|n| n.tag_name().name() == "Attribute") {
+            attributes.insert(
+                attribute.attribute("Name").unwrap().to_string(),
+                attribute.children().filter_map(|v| v.text()).map(str::to_string).collect(),
+            );
+        }
+        VerifiedAssertion {
+            name_id: find("NameID").text().unwrap().to_string(),
+            audience: find("Audience").text().unwrap().to_string(),
+            in_response_to: find("SubjectConfirmationData").attribute("InResponseTo").unwrap().to_string(),
+            attributes,
+        }
+    }
+
+    /// Public key of an ECDSA signing key, to verify its signatures with
+    pub fn public_key(key: &SigningKey) -> Vec<u8> {
+        use ring::signature::KeyPair;
+        match &key.signer {
+            Signer::Ecdsa(key) => key.public_key().as_ref().to_vec(),
+            Signer::Rsa(_) => panic!("test keys are P-256"),
+        }
+    }
+
+    /// `SAMLRequest` value for the HTTP-Redirect binding
+    pub fn redirect_request(issuer: &str, id: &str, acs_url: Option<&str>) -> String {
+        use flate2::{write::DeflateEncoder, Compression};
+        use std::io::Write;
+
+        let acs = acs_url.map(|url| format!(" AssertionConsumerServiceURL=\"{}\"", url)).unwrap_or_default();
+        let xml = format!(
+            "<samlp:AuthnRequest xmlns:samlp=\"{}\" xmlns:saml=\"{}\" ID=\"{}\" Version=\"2.0\" \
+             IssueInstant=\"2026-01-01T00:00:00Z\"{}><saml:Issuer>{}</saml:Issuer></samlp:AuthnRequest>",
+            NS_PROTOCOL, NS_ASSERTION, id, acs, issuer
+        );
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(xml.as_bytes()).unwrap();
+        STANDARD.encode(encoder.finish().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{redirect_request, verify_response};
+    use super::*;
+    use crate::models::AttributeRelease;
+
+    #[tokio::test]
+    async fn test_signed_response_verifies() {
+        let dir = std::env::temp_dir().join(format!("saml-test-{}", random_id()));
+        let mut config = Config::default();
+        config.saml.certificate = dir.join("cert.pem").to_string_lossy().to_string();
+        config.saml.private_key = dir.join("key.pem").to_string_lossy().to_string();
+
+        let state = SamlState::new();
+        let key = state.key(&config.saml).await.unwrap();
+        assert!(metadata(&config, key).contains(&STANDARD.encode(&key.certificate)));
+
+        let sp = ServiceProvider {
+            id: "wiki".to_string(),
+            entity_id: "https://wiki.example.org/sp".to_string(),
+            name: "Wiki".to_string(),
+            acs_urls: vec!["https://wiki.example.org/acs".to_string()],
+            name_id_format: NameIdFormat::Email,
+            attributes: vec![
+                AttributeRelease { name: "mail".to_string(), friendly_name: None, source: "email".to_string() },
+                AttributeRelease { name: "urn:groups".to_string(), friendly_name: None, source: "groups".to_string() },
+                AttributeRelease { name: "missing".to_string(), friendly_name: None, source: "nothing".to_string() },
+            ],
+            created_at: OffsetDateTime::now_utc(),
+        };
+        let mut user = User::new("anna@example.org".to_string(), String::new(), "Anna & Co".to_string(), "<B>".to_string(), "staff".to_string());
+        user.claims.insert("groups".to_string(), serde_json::json!(["a", "b"]));
+
+        let request = decode_request(&redirect_request(&sp.entity_id, "_req1", None), true).unwrap();
+        assert_eq!(request.issuer, sp.entity_id);
+        let handle = state.remember(&request, &sp, sp.acs_urls[0].clone(), None);
+        let pending = state.take(&handle).unwrap();
+        assert!(state.take(&handle).is_none());
+
+        let authentication = Authentication { acr: "mfa".to_string(), auth_time: OffsetDateTime::now_utc() };
+        let encoded = response(&config, key, &sp, &pending, &user, &authentication).unwrap();
+        let assertion = verify_response(&encoded, &testing::public_key(key));
+        assert_eq!(assertion.name_id, "anna@example.org");
+        assert_eq!(assertion.audience, sp.entity_id);
+        assert_eq!(assertion.in_response_to, "_req1");
+        assert_eq!(assertion.attributes["urn:groups"], ["a", "b"]);
+        assert!(!assertion.attributes.contains_key("missing"));
+
+        // The key is reused, not generated again
+        let reloaded = SigningKey::load_or_create(&config.saml).await.unwrap();
+        assert_eq!(reloaded.certificate, key.certificate);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_rejects_foreign_documents() {
+        let post = |xml: &str| decode_request(&STANDARD.encode(xml), false);
+        assert!(post("<LogoutRequest xmlns=\"urn:oasis:names:tc:SAML:2.0:protocol\"/>").is_err());
+        assert!(post("<!DOCTYPE x [<!ENTITY e SYSTEM \"file:///etc/passwd\">]><x>&e;</x>").is_err());
+        let binding = format!(
+            "<AuthnRequest xmlns=\"{}\" ID=\"_a\" Version=\"2.0\" ProtocolBinding=\"{}\">\
+             <Issuer xmlns=\"{}\">sp</Issuer></AuthnRequest>",
+            NS_PROTOCOL, "urn:oasis:names:tc:SAML:2.0:bindings:HTTP-Artifact", NS_ASSERTION
+        );
+        assert!(post(&binding).is_err());
+    }
+}
@@ -0,0 +1,521 @@
+use anyhow::{Context, Result};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header, request::Parts},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use time::{Duration, OffsetDateTime};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::models::{Claims, SessionLimitAction, SessionPolicy};
+use crate::storage::{lock_exclusive, FileStorage};
+
+/// Entries kept in a user's login history
+const LOGIN_HISTORY_LENGTH: usize = 20;
+
+/// How stale `last_seen_at` may get before a use of the session rewrites it
+const LAST_SEEN_RESOLUTION: Duration = Duration::minutes(1);
+
+/// Where a request comes from, as far as it tells.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let connect_info = ConnectInfo::<SocketAddr>::from_request_parts(parts, state).await.ok();
+        Ok(Self {
+            ip: connect_info.map(|info| info.0.ip()),
+            user_agent: parts.headers
+                .get(header::USER_AGENT)
+                .and_then(|v| v.to_str().ok())
+                .map(|ua| ua.chars().take(256).collect()),
+        })
+    }
+}
+
+/// A login, from the first factor until its refresh token runs out. Tokens
+/// of the login name it in `sid` and are refused once it is gone.
+///
+/// admin-service reads and ends sessions in the same files, so the format
+/// is shared with its copy of this struct.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    pub user_id: String,
+    /// Client the session's tokens were last issued to, if any
+    #[serde(default)]
+    pub client_id: Option<String>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub amr: Vec<String>,
+    #[serde(with = "time::serde::iso8601")]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::iso8601")]
+    pub last_seen_at: OffsetDateTime,
+    #[serde(with = "time::serde::iso8601")]
+    pub expires_at: OffsetDateTime,
+    /// Refresh tokens issued within the session that have not run out
+    #[serde(default)]
+    pub refresh_tokens: Vec<RefreshTokenLink>,
+}
+
+impl Session {
+    pub fn is_active(&self, now: OffsetDateTime) -> bool {
+        self.expires_at > now
+    }
+
+    /// Why `policy` ends the session at `now`, if it does. Idle time counts
+    /// from `last_seen_at`, so it is up to a minute longer than configured.
+    pub fn ended_by(&self, policy: &SessionPolicy, now: OffsetDateTime) -> Option<&'static str> {
+        let exceeds = |since: OffsetDateTime, limit: Option<u64>| {
+            limit.is_some_and(|limit| now - since > Duration::seconds(limit as i64))
+        };
+        if exceeds(self.created_at, policy.max_lifetime) {
+            Some("max_lifetime")
+        } else if exceeds(self.last_seen_at, policy.idle_timeout) {
+            Some("idle_timeout")
+        } else {
+            None
+        }
+    }
+}
+
+/// Session limits for a member of `org` using `client_id`: the client's
+/// overrides over the organization's over `[security.sessions]`.
+pub fn policy_for(config: &Config, storage: &FileStorage, org: &str, client_id: Option<&str>) -> SessionPolicy {
+    let org = storage.get_organization(org).and_then(|o| o.session_policy.as_ref());
+    let client = client_id
+        .and_then(|id| storage.get_client(id))
+        .and_then(|c| c.session_policy.as_ref());
+    config.security.sessions.overridden_by(org).overridden_by(client)
+}
+
+/// A refresh token of a session, by its `jti`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshTokenLink {
+    pub jti: String,
+    pub client_id: Option<String>,
+    #[serde(with = "time::serde::iso8601")]
+    pub issued_at: OffsetDateTime,
+    #[serde(with = "time::serde::iso8601")]
+    pub expires_at: OffsetDateTime,
+}
+
+impl RefreshTokenLink {
+    pub fn for_claims(claims: &Claims, client_id: Option<&str>) -> Self {
+        let at = |t: u64| OffsetDateTime::from_unix_timestamp(t as i64).unwrap_or(OffsetDateTime::UNIX_EPOCH);
+        Self {
+            jti: claims.jti.clone(),
+            client_id: client_id.map(str::to_string),
+            issued_at: at(claims.iat),
+            expires_at: at(claims.exp),
+        }
+    }
+}
+
+/// One login attempt, as the user sees it in their history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoginRecord {
+    #[serde(with = "time::serde::iso8601")]
+    pub at: OffsetDateTime,
+    pub success: bool,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    pub reason: Option<String>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl LoginRecord {
+    pub fn new(success: bool, methods: &[String], reason: Option<&str>, client: &ClientInfo) -> Self {
+        Self {
+            at: OffsetDateTime::now_utc(),
+            success,
+            methods: methods.to_vec(),
+            reason: reason.map(str::to_string),
+            ip_address: client.ip.map(|ip| ip.to_string()),
+            user_agent: client.user_agent.clone(),
+        }
+    }
+}
+
+/// Sessions per user in `sessions/{user_id}.json`. Ended sessions are
+/// dropped at the next write; a token whose session is gone is refused.
+#[derive(Debug)]
+pub struct SessionStore {
+    dir: PathBuf,
+    /// Serializes read-modify-write of the files within this process;
+    /// `SessionsLock` does across processes
+    update: tokio::sync::Mutex<()>,
+}
+
+impl SessionStore {
+    pub fn new(data_dir: &str) -> Self {
+        Self {
+            dir: Path::new(data_dir).join("sessions"),
+            update: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Open a session for a login of `user_id` with `amr`, lasting `ttl`
+    /// seconds or the policy's `max_lifetime` if shorter. Sessions the
+    /// policy ended are dropped; beyond `max_concurrent` the oldest are
+    /// evicted, or with `refuse` no session is opened and `None` returned.
+    pub async fn create(
+        &self,
+        user_id: &str,
+        amr: &[String],
+        client: &ClientInfo,
+        ttl: u64,
+        policy: &SessionPolicy,
+    ) -> Result<Option<Session>> {
+        let _guard = self.update.lock().await;
+        let _lock = SessionsLock::acquire(&self.dir, user_id).await?;
+        let now = OffsetDateTime::now_utc();
+        let ttl = policy.max_lifetime.map_or(ttl, |lifetime| ttl.min(lifetime));
+        let session = Session {
+            id: Uuid::new_v4().to_string(),
+            user_id: user_id.to_string(),
+            client_id: None,
+            ip_address: client.ip.map(|ip| ip.to_string()),
+            user_agent: client.user_agent.clone(),
+            amr: amr.to_vec(),
+            created_at: now,
+            last_seen_at: now,
+            expires_at: now + Duration::seconds(ttl as i64),
+            refresh_tokens: Vec::new(),
+        };
+
+        let path = self.path_for(user_id);
+        let mut sessions: Vec<Session> = load_list(&path).await?;
+        sessions.retain(|s| s.is_active(now) && s.ended_by(policy, now).is_none());
+
+        if let Some(max) = policy.max_concurrent.filter(|max| sessions.len() >= *max) {
+            if policy.on_limit.unwrap_or_default() == SessionLimitAction::Refuse {
+                return Ok(None);
+            }
+            sessions.sort_by_key(|s| s.created_at);
+            // Room for the new one, even under a limit of 0 that got past validation
+            let excess = (sessions.len() + 1).saturating_sub(max.max(1));
+            sessions.drain(..excess);
+        }
+
+        sessions.push(session.clone());
+        save_list(&path, &sessions).await?;
+
+        Ok(Some(session))
+    }
+
+    /// The session `id` of `user_id`, if it is still active.
+    pub async fn get(&self, user_id: &str, id: &str) -> Result<Option<Session>> {
+        let now = OffsetDateTime::now_utc();
+        let sessions: Vec<Session> = load_list(&self.path_for(user_id)).await?;
+        Ok(sessions.into_iter().find(|s| s.id == id && s.is_active(now)))
+    }
+
+    /// Note that `session` is in use. `last_seen_at` is only rewritten once
+    /// it is a minute old; `None` if the session ended meanwhile.
+    pub async fn touch(&self, session: &Session, now: OffsetDateTime) -> Result<Option<Session>> {
+        if now - session.last_seen_at < LAST_SEEN_RESOLUTION {
+            return Ok(Some(session.clone()));
+        }
+        self.update(&session.user_id, &session.id, |s| s.last_seen_at = now).await
+    }
+
+    /// Record a refresh token issued within session `id`; links of tokens
+    /// that ran out are dropped.
+    pub async fn link_refresh_token(&self, user_id: &str, id: &str, link: RefreshTokenLink) -> Result<Option<Session>> {
+        self.update(user_id, id, |s| {
+            s.refresh_tokens.retain(|l| l.expires_at > link.issued_at);
+            if link.client_id.is_some() {
+                s.client_id = link.client_id.clone();
+            }
+            s.last_seen_at = link.issued_at;
+            s.refresh_tokens.push(link);
+        })
+        .await
+    }
+
+    /// Active sessions of `user_id`, newest first.
+    pub async fn list(&self, user_id: &str) -> Result<Vec<Session>> {
+        let now = OffsetDateTime::now_utc();
+        let mut sessions: Vec<Session> = load_list(&self.path_for(user_id)).await?;
+        sessions.retain(|s| s.is_active(now));
+        sessions.sort_by_key(|s| std::cmp::Reverse(s.created_at));
+        Ok(sessions)
+    }
+
+    /// End the session `id` of `user_id`; `false` if it was not active.
+    pub async fn revoke(&self, user_id: &str, id: &str) -> Result<bool> {
+        Ok(self.remove(user_id, |s| s.id == id).await? > 0)
+    }
+
+    /// End every session of `user_id` but `keep`. Returns how many ended.
+    pub async fn revoke_all(&self, user_id: &str, keep: Option<&str>) -> Result<usize> {
+        self.remove(user_id, |s| Some(s.id.as_str()) != keep).await
+    }
+
+    async fn update(&self, user_id: &str, id: &str, modify: impl FnOnce(&mut Session)) -> Result<Option<Session>> {
+        let _guard = self.update.lock().await;
+        let _lock = SessionsLock::acquire(&self.dir, user_id).await?;
+        let now = OffsetDateTime::now_utc();
+        let path = self.path_for(user_id);
+        let mut sessions: Vec<Session> = load_list(&path).await?;
+        sessions.retain(|s| s.is_active(now));
+
+        let Some(session) = sessions.iter_mut().find(|s| s.id == id) else {
+            return Ok(None);
+        };
+        modify(session);
+        let session = session.clone();
+        save_list(&path, &sessions).await?;
+        Ok(Some(session))
+    }
+
+    async fn remove(&self, user_id: &str, ends: impl Fn(&Session) -> bool) -> Result<usize> {
+        let _guard = self.update.lock().await;
+        let _lock = SessionsLock::acquire(&self.dir, user_id).await?;
+        let now = OffsetDateTime::now_utc();
+        let path = self.path_for(user_id);
+        let mut sessions: Vec<Session> = load_list(&path).await?;
+        sessions.retain(|s| s.is_active(now));
+
+        let before = sessions.len();
+        sessions.retain(|s| !ends(s));
+        let ended = before - sessions.len();
+        if ended > 0 {
+            save_list(&path, &sessions).await?;
+        }
+        Ok(ended)
+    }
+
+    fn path_for(&self, user_id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", user_id))
+    }
+}
+
+/// Exclusive advisory lock on `sessions/.{user_id}.lock`, over the sessions
+/// of one user. admin-service takes the same lock to end sessions, so
+/// neither undoes the other's changes. Released when dropped.
+struct SessionsLock {
+    _file: std::fs::File,
+}
+
+impl SessionsLock {
+    async fn acquire(dir: &Path, user_id: &str) -> Result<Self> {
+        Ok(Self { _file: lock_exclusive(dir.join(format!(".{}.lock", user_id))).await? })
+    }
+}
+
+/// The last logins and failed attempts per user, in `logins/{user_id}.json`.
+#[derive(Debug)]
+pub struct LoginHistory {
+    dir: PathBuf,
+    update: tokio::sync::Mutex<()>,
+}
+
+impl LoginHistory {
+    pub fn new(data_dir: &str) -> Self {
+        Self {
+            dir: Path::new(data_dir).join("logins"),
+            update: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub async fn record(&self, user_id: &str, record: LoginRecord) -> Result<()> {
+        let _guard = self.update.lock().await;
+        let path = self.dir.join(format!("{}.json", user_id));
+        let mut records: Vec<LoginRecord> = load_list(&path).await?;
+        records.push(record);
+        let excess = records.len().saturating_sub(LOGIN_HISTORY_LENGTH);
+        records.drain(..excess);
+        save_list(&path, &records).await
+    }
+
+    /// The history of `user_id`, newest first.
+    pub async fn list(&self, user_id: &str) -> Result<Vec<LoginRecord>> {
+        let mut records: Vec<LoginRecord> = load_list(&self.dir.join(format!("{}.json", user_id))).await?;
+        records.reverse();
+        Ok(records)
+    }
+}
+
+/// A JSON array file; a missing file is an empty list.
+pub(crate) async fn load_list<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(content) => serde_json::from_str(&content).with_context(|| format!("Failed to parse {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+pub(crate) async fn save_list<T: Serialize>(path: &Path, items: &[T]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let temp_path = path.with_extension("json.tmp");
+    tokio::fs::write(&temp_path, serde_json::to_string_pretty(items)?).await
+        .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+    tokio::fs::rename(&temp_path, path).await
+        .with_context(|| format!("Failed to rename {}", temp_path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientInfo, SessionStore};
+    use crate::config::Config;
+    use crate::models::SessionPolicy;
+    use crate::routes;
+    use crate::storage::FileStorage;
+    use crate::testing::*;
+    use axum::http::StatusCode;
+    use serde_json::{json, Value};
+    use std::os::unix::io::AsRawFd;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    #[tokio::test]
+    async fn test_session_lock_wait_does_not_block_the_runtime() {
+        let dir = TempDir::new("session-lock");
+        let store = Arc::new(SessionStore::new(dir.path()));
+        let client = ClientInfo { ip: None, user_agent: None };
+
+        // admin-service holding the user's lock
+        std::fs::create_dir_all(format!("{}/sessions", dir.path())).unwrap();
+        let held = std::fs::File::create(format!("{}/sessions/.user-1.lock", dir.path())).unwrap();
+        assert_eq!(unsafe { libc::flock(held.as_raw_fd(), libc::LOCK_EX) }, 0);
+
+        let waiting = tokio::spawn({
+            let store = store.clone();
+            async move { store.create("user-1", &[], &client, 60, &SessionPolicy::default()).await }
+        });
+        // The single test thread still runs other work while the session waits
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert!(!waiting.is_finished());
+        assert!(store.list("user-2").await.unwrap().is_empty());
+
+        drop(held);
+        assert!(waiting.await.unwrap().unwrap().is_some());
+        assert_eq!(store.list("user-1").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_zero_session_limit() {
+        let dir = TempDir::new("session-zero");
+        let store = SessionStore::new(dir.path());
+        let client = ClientInfo { ip: None, user_agent: None };
+
+        // Never loaded from files, but a limit of 0 still keeps the new session
+        let policy = SessionPolicy { max_concurrent: Some(0), ..SessionPolicy::default() };
+        for _ in 0..2 {
+            assert!(store.create("user-1", &[], &client, 60, &policy).await.unwrap().is_some());
+        }
+        assert_eq!(store.list("user-1").await.unwrap().len(), 1);
+
+        write_file(
+            &format!("{}/orgs.json", dir.path()),
+            json!({"orgs": [{
+                "id": "school",
+                "name": "School",
+                "description": "",
+                "metadata": {},
+                "session_policy": {"max_concurrent": 0},
+                "created_at": "2024-01-01T00:00:00Z"
+            }]})
+            .to_string(),
+        )
+        .await;
+        let error = FileStorage::load(dir.path()).await.unwrap_err();
+        assert!(error.to_string().contains("max_concurrent"), "{:#}", error);
+    }
+
+    #[tokio::test]
+    async fn test_session_policy() {
+        let dir = TempDir::new("session-policy");
+        let data_dir = dir.path();
+        write_user(data_dir, json!({"org": "school"})).await;
+        write_client(data_dir, "portal", &["https://portal.example.com/cb"], &["authorization_code", "refresh_token"], json!({"session_policy": {"idle_timeout": 7200}})).await;
+
+        let mut config = Config::default();
+        config.oidc.signing_key = format!("{}/keys/oidc.pem", data_dir);
+        config.security.sessions = SessionPolicy {
+            idle_timeout: Some(600),
+            max_concurrent: Some(2),
+            ..Default::default()
+        };
+        let (app, _, runtime) = test_app(data_dir, &config).await;
+        let reloaded = || async {
+            let storage = Arc::new(RwLock::new(FileStorage::load(data_dir).await.unwrap()));
+            app_with(storage, &config, runtime.clone()).await
+        };
+        let credentials = credentials("anna@example.com");
+
+        // A third login evicts the oldest session
+        let first = post_json(&app, "/api/auth/login", credentials.clone()).await;
+        let second = post_json(&app, "/api/auth/login", credentials.clone()).await;
+        let third = post_json(&app, "/api/auth/login", credentials.clone()).await;
+        let bearer = |login: &Value| login["access_token"].as_str().unwrap().to_string();
+        let (status, _) = request_as(&app, "GET", "/api/account", None, &bearer(&first)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, sessions) = request_as(&app, "GET", "/api/account/sessions", None, &bearer(&third)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(sessions.as_array().unwrap().len(), 2);
+        let (_, portal) = client_tokens(&app, "portal", "https://portal.example.com/cb", "openid", &bearer(&second)).await;
+
+        // The organization refuses logins beyond the limit instead
+        write_file(
+            &format!("{}/orgs.json", data_dir),
+            json!({"orgs": [{
+                "id": "school",
+                "name": "School",
+                "description": "",
+                "metadata": {},
+                "session_policy": {"on_limit": "refuse"},
+                "created_at": "2024-01-01T00:00:00Z"
+            }]})
+            .to_string(),
+        )
+        .await;
+        let app = reloaded().await;
+        let refused = post_json(&app, "/api/auth/login", credentials.clone()).await;
+        assert_eq!(refused["success"], false);
+        assert_eq!(refused["error"], "session_limit_reached");
+        assert!(refused["access_token"].is_null());
+        let (_, logins) = request_as(&app, "GET", "/api/account/logins", None, &bearer(&third)).await;
+        assert_eq!(logins[0]["reason"], "session_limit_reached");
+
+        // Both sessions idle for an hour
+        let sessions_file = format!("{}/sessions/user-1.json", data_dir);
+        let mut stored = read_json(&sessions_file).await;
+        let an_hour_ago = (time::OffsetDateTime::now_utc() - time::Duration::hours(1))
+            .format(&time::format_description::well_known::Rfc3339)
+            .unwrap();
+        for session in stored.as_array_mut().unwrap() {
+            session["last_seen_at"] = json!(an_hour_ago);
+        }
+        write_file(&sessions_file, stored.to_string()).await;
+
+        // The portal allows two idle hours, the instance ten minutes
+        let refresh = json!({"grant_type": "refresh_token", "client_id": "portal", "refresh_token": portal["refresh_token"], "scope": "openid"});
+        let (status, _) = post_form(&app, routes::TOKEN, refresh).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = request_as(&app, "GET", "/api/account", None, &bearer(&third)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        // The idle session is gone, so the limit admits a new login
+        let again = post_json(&app, "/api/auth/login", credentials).await;
+        assert_eq!(again["success"], true);
+    }
+}
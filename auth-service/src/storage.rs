@@ -1,10 +1,13 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::Path;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
 use tracing::{info, warn, error};
 
-use crate::models::{User, Group, Role, Client, ClaimsRegistry};
+use crate::models::{User, Organization, Role, Client, ServiceProvider, Impersonation, ClaimsRegistry, SecurityPolicy, PasswordPolicy};
+use crate::subject::PairwiseIndex;
 
 #[derive(Debug, Clone)]
 pub struct FileStorage {
@@ -12,9 +15,14 @@ pub struct FileStorage {
     users: HashMap<String, User>,
     roles: HashMap<String, Role>,
     clients: HashMap<String, Client>,
+    service_providers: HashMap<String, ServiceProvider>, // entity_id -> SAML service provider
+    claims_registry: ClaimsRegistry,
+    security_policy: SecurityPolicy,
+    orgs: HashMap<String, Organization>,
 
     // Computed indices for O(1) lookups
     email_index: HashMap<String, String>, // email -> user_id
+    pairwise_index: PairwiseIndex,
 
     data_dir: String,
 }
@@ -27,7 +35,7 @@ struct UsersFile {
 
 #[derive(Debug, Serialize, Deserialize)]
 struct OrgsFile {
-    orgs: Vec<Group>,
+    orgs: Vec<Organization>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -56,6 +64,29 @@ impl FileStorage {
         // Load all JSON files
         let users_result = load_users_file(data_dir).await;
         let clients_result = load_clients_file(data_dir).await;
+        let service_providers = load_service_providers(data_dir).await;
+        let claims_result = load_claims_registry_file(data_dir).await;
+
+        // A broken policy must not silently relax requirements
+        let security_policy = load_security_policy_file(data_dir).await
+            .context("Failed to load security policy")?;
+        // Same for organizations: they decide who may register and log in
+        let orgs = load_orgs_file(data_dir).await
+            .context("Failed to load organizations")?;
+
+        // A policy asking for the breached-password check cannot run without the corpus
+        let checks_breached = security_policy.password.check_breached
+            || orgs.iter().any(|o| o.password_policy.as_ref().is_some_and(|p| p.check_breached));
+        let corpus = Path::new(data_dir).join(password_policy::BREACHED_CORPUS_FILE);
+        if checks_breached && !corpus.is_file() {
+            return Err(anyhow!(
+                "Password policy requires the breached-password corpus, but {} does not exist",
+                corpus.display()
+            ));
+        }
+        if let Some((org, problem)) = orgs.iter().find_map(|o| Some((&o.id, o.session_policy.as_ref()?.problem()?))) {
+            return Err(anyhow!("Invalid session policy of organization {}: {}", org, problem));
+        }
 
         // Handle user data (can be corrupt, use fallback)
         let users = match users_result {
@@ -83,6 +114,23 @@ impl FileStorage {
                 fallback
             }
         };
+        let client_problem = clients.iter().find_map(|c| Some((&c.client_id, c.session_policy.as_ref()?.problem()?)));
+        if let Some((client, problem)) = client_problem {
+            return Err(anyhow!("Invalid session policy of client {}: {}", client, problem));
+        }
+
+        // Handle claims registry (tokens carry no registry claims without it)
+        let claims_registry = match claims_result {
+            LoadResult::Success(registry) => registry,
+            LoadResult::CorruptData { error, fallback } => {
+                warn!(
+                    event = "claims_registry_corrupt",
+                    error = error,
+                    fallback_count = fallback.claims.len()
+                );
+                fallback
+            }
+        };
 
         // Convert to HashMaps
         let users_map: HashMap<String, User> = users
@@ -107,14 +155,22 @@ impl FileStorage {
         info!(
             event = "storage_loaded",
             users_count = users_map.len(),
-            clients_count = clients_map.len()
+            clients_count = clients_map.len(),
+            service_providers_count = service_providers.len(),
+            claims_count = claims_registry.claims.len(),
+            orgs_count = orgs.len()
         );
 
         Ok(Self {
             users: users_map,
             roles: HashMap::new(),
             clients: clients_map,
+            service_providers,
+            claims_registry,
+            security_policy,
+            orgs: orgs.into_iter().map(|o| (o.id.clone(), o)).collect(),
             email_index,
+            pairwise_index: PairwiseIndex::default(),
             data_dir: data_dir.to_string(),
         })
     }
@@ -125,14 +181,95 @@ impl FileStorage {
         self.users.get(user_id)
     }
 
+    /// The user an upstream provider's `subject` is linked to.
+    pub fn get_user_by_federated_identity(&self, provider: &str, subject: &str) -> Option<&User> {
+        self.users.values().find(|u| {
+            u.federated_identities.iter().any(|i| i.provider == provider && i.subject == subject)
+        })
+    }
+
     pub fn get_user(&self, user_id: &str) -> Option<&User> {
         self.users.get(user_id)
     }
 
+    pub fn pairwise_index(&self) -> &PairwiseIndex {
+        &self.pairwise_index
+    }
+
     pub fn get_all_users(&self) -> impl Iterator<Item = &User> {
         self.users.values()
     }
 
+    /// Write a new user file. Returns `None` without writing if the email is
+    /// taken, also by a user admin-service created since our last reload.
+    pub async fn create_user(&mut self, user: User) -> Result<Option<User>> {
+        let _lock = UsersLock::acquire(&self.data_dir).await?;
+
+        // CorruptData only means there is no user file at all yet
+        let on_disk = match load_users_file(&self.data_dir).await {
+            LoadResult::Success(users) => users,
+            LoadResult::CorruptData { fallback, .. } => fallback,
+        };
+        if self.email_index.contains_key(&user.email) || on_disk.iter().any(|u| u.email == user.email) {
+            return Ok(None);
+        }
+
+        let org_dir = format!("{}/users/{}", self.data_dir, user.org);
+        tokio::fs::create_dir_all(&org_dir)
+            .await
+            .context("Failed to create org directory")?;
+
+        let user_path = format!("{}/{}.json", org_dir, user.id);
+        let temp_path = format!("{}.tmp", user_path);
+        tokio::fs::write(&temp_path, serde_json::to_string_pretty(&user)?)
+            .await
+            .context("Failed to write user temp file")?;
+        tokio::fs::rename(temp_path, &user_path)
+            .await
+            .context("Failed to rename user file")?;
+
+        self.email_index.insert(user.email.clone(), user.id.clone());
+        self.users.insert(user.id.clone(), user.clone());
+
+        Ok(Some(user))
+    }
+
+    /// Apply `modify` to the on-disk record of `user_id` and persist it.
+    ///
+    /// admin-service owns the user files, so the record is re-read under the
+    /// shared users lock instead of writing back our possibly stale copy.
+    pub async fn modify_user<F>(&mut self, user_id: &str, modify: F) -> Result<User>
+    where
+        F: FnOnce(&mut User) -> Result<()>,
+    {
+        let org = self.users.get(user_id)
+            .map(|u| u.org.clone())
+            .ok_or_else(|| anyhow!("User not found: {}", user_id))?;
+        let user_path = format!("{}/users/{}/{}.json", self.data_dir, org, user_id);
+
+        let _lock = UsersLock::acquire(&self.data_dir).await?;
+
+        let mut user: User = load_json_file(&user_path).await?;
+        modify(&mut user)?;
+        user.updated_at = OffsetDateTime::now_utc();
+
+        let temp_path = format!("{}.tmp", user_path);
+        tokio::fs::write(&temp_path, serde_json::to_string_pretty(&user)?)
+            .await
+            .context("Failed to write user temp file")?;
+        tokio::fs::rename(temp_path, &user_path)
+            .await
+            .context("Failed to rename user file")?;
+
+        if let Some(old) = self.users.get(user_id) {
+            self.email_index.remove(&old.email);
+        }
+        self.email_index.insert(user.email.clone(), user.id.clone());
+        self.users.insert(user.id.clone(), user.clone());
+
+        Ok(user)
+    }
+
 
     // Role operations
     pub fn get_role(&self, role_id: &str) -> Option<&Role> {
@@ -152,6 +289,60 @@ impl FileStorage {
         self.clients.values()
     }
 
+    /// The SAML service provider with this entityID
+    pub fn get_service_provider(&self, entity_id: &str) -> Option<&ServiceProvider> {
+        self.service_providers.get(entity_id)
+    }
+
+    /// The impersonation record behind a token's `jti`. Read on every use, so
+    /// that ending an impersonation in admin-service takes effect at once.
+    pub async fn get_impersonation(&self, id: &str) -> Result<Option<Impersonation>> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Ok(None);
+        }
+        let path = format!("{}/impersonations/{}.json", self.data_dir, id);
+        match tokio::fs::read_to_string(&path).await {
+            Ok(content) => Ok(Some(serde_json::from_str(&content).context("Failed to parse impersonation record")?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context("Failed to read impersonation record"),
+        }
+    }
+
+    // Claims registry
+    pub fn get_claims_registry(&self) -> &ClaimsRegistry {
+        &self.claims_registry
+    }
+
+    pub fn get_organization(&self, org_id: &str) -> Option<&Organization> {
+        self.orgs.get(org_id)
+    }
+
+    pub fn get_all_organizations(&self) -> impl Iterator<Item = &Organization> {
+        self.orgs.values()
+    }
+
+    pub fn get_security_policy(&self) -> &SecurityPolicy {
+        &self.security_policy
+    }
+
+    /// The password policy for members of `org_id`.
+    pub fn password_policy(&self, org_id: &str) -> &PasswordPolicy {
+        self.orgs
+            .get(org_id)
+            .and_then(|org| org.password_policy.as_ref())
+            .unwrap_or(&self.security_policy.password)
+    }
+
+    /// Reasons `password` may not be set for `user`; empty if it is acceptable.
+    pub fn password_violations(&self, user: &User, password: &str) -> Result<Vec<String>> {
+        password_policy::violations(
+            self.password_policy(&user.org),
+            password,
+            &[&user.email, &user.first_name, &user.last_name],
+            &self.data_dir,
+        )
+    }
+
     // Statistics
     pub fn users_count(&self) -> usize {
         self.users.len()
@@ -183,6 +374,45 @@ impl FileStorage {
     }
 }
 
+/// Exclusive advisory lock on `{data_dir}/users/.lock`. admin-service takes
+/// the same lock, so read-modify-write cycles on user files never interleave.
+/// Released when dropped.
+pub struct UsersLock {
+    _file: std::fs::File,
+}
+
+impl UsersLock {
+    pub async fn acquire(data_dir: &str) -> Result<Self> {
+        let lock_path = Path::new(data_dir).join("users").join(".lock");
+        Ok(Self { _file: lock_exclusive(lock_path).await? })
+    }
+}
+
+/// Open the lock file at `path` and wait for an exclusive `flock` on it.
+/// The wait happens on a blocking thread, so other requests go on meanwhile.
+pub(crate) async fn lock_exclusive(path: PathBuf) -> Result<std::fs::File> {
+    tokio::task::spawn_blocking(move || {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open lock {}", path.display()))?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Failed to lock {}", path.display()));
+        }
+
+        Ok(file)
+    })
+    .await
+    .context("Lock task panicked")?
+}
+
 async fn load_users_file(data_dir: &str) -> LoadResult<Vec<User>> {
     let users_dir = format!("{}/users", data_dir);
     let mut all_users = Vec::new();
@@ -237,9 +467,85 @@ async fn load_roles_file(data_dir: &str) -> LoadResult<Vec<Role>> {
     }
 }
 
-async fn load_clients_file(_data_dir: &str) -> LoadResult<Vec<Client>> {
-    // clients.json was removed - return empty list
-    LoadResult::Success(Vec::new())
+async fn load_clients_file(data_dir: &str) -> LoadResult<Vec<Client>> {
+    let mut clients: HashMap<String, Client> = match load_json_file::<ClientsFile>(&format!("{}/clients.json", data_dir)).await {
+        Ok(clients_file) => clients_file.clients
+            .into_iter()
+            .map(|c| (c.client_id.clone(), c))
+            .collect(),
+        Err(e) => {
+            return LoadResult::CorruptData {
+                error: e.to_string(),
+                fallback: Vec::new(),
+            }
+        }
+    };
+
+    // admin-service persists created/updated clients as clients/<client_id>.json
+    let clients_dir = format!("{}/clients", data_dir);
+    if let Ok(mut entries) = tokio::fs::read_dir(&clients_dir).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                match load_json_file::<Client>(&path.to_string_lossy()).await {
+                    Ok(client) => {
+                        clients.insert(client.client_id.clone(), client);
+                    }
+                    Err(e) => warn!("Failed to load client file {:?}: {}", path, e),
+                }
+            }
+        }
+    }
+
+    LoadResult::Success(clients.into_values().collect())
+}
+
+/// admin-service keeps SAML service providers as service_providers/<id>.json
+async fn load_service_providers(data_dir: &str) -> HashMap<String, ServiceProvider> {
+    let mut service_providers = HashMap::new();
+    let Ok(mut entries) = tokio::fs::read_dir(format!("{}/service_providers", data_dir)).await else {
+        return service_providers;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            match load_json_file::<ServiceProvider>(&path.to_string_lossy()).await {
+                Ok(sp) => {
+                    service_providers.insert(sp.entity_id.clone(), sp);
+                }
+                Err(e) => warn!("Failed to load service provider file {:?}: {}", path, e),
+            }
+        }
+    }
+    service_providers
+}
+
+async fn load_claims_registry_file(data_dir: &str) -> LoadResult<ClaimsRegistry> {
+    match load_json_file::<ClaimsRegistry>(&format!("{}/claims.json", data_dir)).await {
+        Ok(registry) => LoadResult::Success(registry),
+        Err(e) => LoadResult::CorruptData {
+            error: e.to_string(),
+            fallback: ClaimsRegistry { claims: HashMap::new() },
+        },
+    }
+}
+
+async fn load_security_policy_file(data_dir: &str) -> Result<SecurityPolicy> {
+    let path = format!("{}/security_policy.json", data_dir);
+    if !Path::new(&path).exists() {
+        info!(event = "security_policy_absent", path = %path);
+        return Ok(SecurityPolicy::default());
+    }
+    load_json_file(&path).await
+}
+
+async fn load_orgs_file(data_dir: &str) -> Result<Vec<Organization>> {
+    let path = format!("{}/orgs.json", data_dir);
+    if !Path::new(&path).exists() {
+        info!(event = "orgs_absent", path = %path);
+        return Ok(Vec::new());
+    }
+    Ok(load_json_file::<OrgsFile>(&path).await?.orgs)
 }
 
 async fn load_json_file<T: for<'de> Deserialize<'de>>(path: &str) -> Result<T> {
@@ -0,0 +1,227 @@
+use anyhow::{anyhow, Context, Result};
+use axum::http::Uri;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use crate::models::{Client, SubjectType, User};
+use crate::storage::FileStorage;
+
+/// The `sub` value `client` sees for `user_id` (OIDC Core §8).
+pub fn subject_for(user_id: &str, client: &Client, pairwise_salt: Option<&str>) -> Result<String> {
+    match client.subject_type {
+        SubjectType::Public => Ok(user_id.to_string()),
+        SubjectType::Pairwise => {
+            let salt = pairwise_salt.ok_or_else(|| anyhow!(
+                "Client {} uses pairwise subjects but security.pairwise_salt is not configured",
+                client.client_id
+            ))?;
+            let sector = sector_identifier(client)?;
+            Ok(pairwise_subject(&sector, user_id, salt))
+        }
+    }
+}
+
+/// Map a subject presented by `client` back to the local user.
+pub fn find_user_by_subject<'a>(
+    storage: &'a FileStorage,
+    client: &Client,
+    sub: &str,
+    pairwise_salt: Option<&str>,
+) -> Result<Option<&'a User>> {
+    match client.subject_type {
+        SubjectType::Public => Ok(storage.get_user(sub)),
+        SubjectType::Pairwise => {
+            let salt = pairwise_salt.ok_or_else(|| anyhow!(
+                "Client {} uses pairwise subjects but security.pairwise_salt is not configured",
+                client.client_id
+            ))?;
+            let sector = sector_identifier(client)?;
+            let user_id = storage.pairwise_index().find(&sector, salt, sub, || storage.get_all_users());
+            Ok(user_id.and_then(|id| storage.get_user(&id)))
+        }
+    }
+}
+
+/// Pairwise subjects already computed, per sector, so that mapping one back
+/// to its user does not hash every user on every request. User ids never
+/// change, so entries stay valid; users added since are hashed on a miss.
+#[derive(Debug, Default)]
+pub struct PairwiseIndex {
+    sectors: Mutex<HashMap<(String, String), SectorIndex>>, // (sector, salt) -> subjects
+}
+
+#[derive(Debug, Clone, Default)]
+struct SectorIndex {
+    subjects: HashMap<String, String>, // pairwise subject -> user_id
+    indexed: HashSet<String>,
+}
+
+impl PairwiseIndex {
+    fn find<'a, I>(&self, sector: &str, salt: &str, sub: &str, users: impl FnOnce() -> I) -> Option<String>
+    where
+        I: Iterator<Item = &'a User>,
+    {
+        let mut sectors = self.sectors.lock().unwrap_or_else(|e| e.into_inner());
+        let index = sectors.entry((sector.to_string(), salt.to_string())).or_default();
+        if let Some(user_id) = index.subjects.get(sub) {
+            return Some(user_id.clone());
+        }
+
+        for user in users() {
+            if index.indexed.insert(user.id.clone()) {
+                index.subjects.insert(pairwise_subject(sector, &user.id, salt), user.id.clone());
+            }
+        }
+        index.subjects.get(sub).cloned()
+    }
+}
+
+impl Clone for PairwiseIndex {
+    fn clone(&self) -> Self {
+        let sectors = self.sectors.lock().unwrap_or_else(|e| e.into_inner());
+        Self { sectors: Mutex::new(sectors.clone()) }
+    }
+}
+
+/// The sector a client belongs to: the host of its `sector_identifier_uri`,
+/// or the single host shared by all of its redirect URIs.
+pub fn sector_identifier(client: &Client) -> Result<String> {
+    if let Some(uri) = &client.sector_identifier_uri {
+        return host_of(uri);
+    }
+
+    let mut hosts = client.redirect_uris.iter().map(|u| host_of(u));
+    let first = hosts.next().ok_or_else(|| anyhow!(
+        "Client {} has no redirect URIs to derive a sector from; set sector_identifier_uri",
+        client.client_id
+    ))??;
+
+    for host in hosts {
+        if host? != first {
+            return Err(anyhow!(
+                "Client {} has redirect URIs on several hosts; set sector_identifier_uri",
+                client.client_id
+            ));
+        }
+    }
+
+    Ok(first)
+}
+
+/// base64url(SHA-256(sector | user_id | salt)), fields separated by NUL so
+/// that no two inputs concatenate to the same string.
+pub fn pairwise_subject(sector: &str, user_id: &str, salt: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(sector.as_bytes());
+    hasher.update([0u8]);
+    hasher.update(user_id.as_bytes());
+    hasher.update([0u8]);
+    hasher.update(salt.as_bytes());
+    URL_SAFE_NO_PAD.encode(hasher.finalize())
+}
+
+fn host_of(uri: &str) -> Result<String> {
+    let parsed: Uri = uri.parse()
+        .with_context(|| format!("Invalid URI: {}", uri))?;
+    parsed.host()
+        .map(|h| h.to_lowercase())
+        .ok_or_else(|| anyhow!("URI has no host: {}", uri))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{AccessTokenFormat, ClientType};
+    use time::OffsetDateTime;
+
+    fn client(redirect_uris: &[&str], sector_identifier_uri: Option<&str>) -> Client {
+        Client {
+            client_id: "partner".to_string(),
+            client_secret_hash: None,
+            name: "Partner".to_string(),
+            client_type: ClientType::Public,
+            redirect_uris: redirect_uris.iter().map(|s| s.to_string()).collect(),
+            allowed_scopes: vec!["openid".to_string()],
+            require_pkce: true,
+            grant_types: vec!["authorization_code".to_string()],
+            access_token_format: AccessTokenFormat::Jwt,
+            subject_type: SubjectType::Pairwise,
+            sector_identifier_uri: sector_identifier_uri.map(|s| s.to_string()),
+            min_acr: None,
+            max_auth_age: None,
+            session_policy: None,
+            created_at: OffsetDateTime::now_utc(),
+        }
+    }
+
+    #[test]
+    fn test_pairwise_subject_is_stable_per_sector() {
+        let a = client(&["https://app.partner.example/cb", "https://app.partner.example/cb2"], None);
+        let b = client(&["https://other.example/cb"], None);
+
+        let sub_a1 = subject_for("user-1", &a, Some("salt")).unwrap();
+        let sub_a2 = subject_for("user-1", &a, Some("salt")).unwrap();
+        let sub_b = subject_for("user-1", &b, Some("salt")).unwrap();
+
+        assert_eq!(sub_a1, sub_a2);
+        assert_ne!(sub_a1, sub_b);
+        assert_ne!(sub_a1, "user-1");
+    }
+
+    #[test]
+    fn test_sector_identifier_uri_groups_hosts() {
+        let a = client(&["https://a.partner.example/cb"], Some("https://partner.example/sector.json"));
+        let b = client(&["https://b.partner.example/cb"], Some("https://partner.example/sector.json"));
+
+        assert_eq!(
+            subject_for("user-1", &a, Some("salt")).unwrap(),
+            subject_for("user-1", &b, Some("salt")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_pairwise_requires_salt_and_single_host() {
+        let ok = client(&["https://app.partner.example/cb"], None);
+        assert!(subject_for("user-1", &ok, None).is_err());
+
+        let ambiguous = client(&["https://a.example/cb", "https://b.example/cb"], None);
+        assert!(sector_identifier(&ambiguous).is_err());
+    }
+
+    #[test]
+    fn test_pairwise_index_finds_users_added_later() {
+        let user = |id: &str| -> User {
+            serde_json::from_value(serde_json::json!({
+                "id": id,
+                "email": format!("{}@example.com", id),
+                "password_hash": "",
+                "first_name": "Test",
+                "last_name": "User",
+                "status": "active",
+                "verified": true,
+                "authenticated": null,
+                "admin": [],
+                "org": "default",
+                "claims": {},
+                "mfa_secret": null,
+                "created_at": "2024-01-01T00:00:00Z",
+                "updated_at": "2024-01-01T00:00:00Z"
+            }))
+            .unwrap()
+        };
+        let index = PairwiseIndex::default();
+        let mut users = vec![user("user-1")];
+        let sub_2 = pairwise_subject("partner.example", "user-2", "salt");
+
+        assert_eq!(index.find("partner.example", "salt", &sub_2, || users.iter()), None);
+        users.push(user("user-2"));
+        assert_eq!(index.find("partner.example", "salt", &sub_2, || users.iter()).as_deref(), Some("user-2"));
+
+        // Hits do not look at the users at all
+        assert_eq!(index.find("partner.example", "salt", &sub_2, || [].iter()).as_deref(), Some("user-2"));
+        // Another salt is another index
+        assert_eq!(index.find("partner.example", "pepper", &sub_2, || users.iter()), None);
+    }
+}
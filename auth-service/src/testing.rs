@@ -0,0 +1,255 @@
+//! Fixtures for tests that drive the whole app: a throwaway data directory,
+//! users in it, and requests against the router.
+
+use axum::{
+    body::Body,
+    extract::connect_info::MockConnectInfo,
+    http::{Request, StatusCode},
+    response::Response,
+    Router,
+};
+use serde_json::{json, Value};
+use std::{net::SocketAddr, path::Path, sync::Arc};
+use tokio::sync::RwLock;
+use tower::ServiceExt;
+
+use crate::{config::Config, password, runtime::Runtime, storage::FileStorage};
+
+/// Password of the users `write_user` creates unless told otherwise
+pub const PASSWORD: &str = "correct horse battery";
+
+/// A data directory under the system temp dir, removed when dropped.
+pub struct TempDir(String);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("um-oic-{}-{}", name, uuid::Uuid::new_v4().simple()));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path.to_string_lossy().to_string())
+    }
+
+    pub fn path(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Write a user into `data_dir`: Anna of the default organization, with
+/// `PASSWORD`, unless `fields` says otherwise. Returns the file's path.
+pub async fn write_user(data_dir: &str, fields: Value) -> String {
+    let fields = fields.as_object().unwrap();
+    let mut user = json!({
+        "id": "user-1",
+        "email": "anna@example.com",
+        "password_hash": "",
+        "first_name": "Anna",
+        "last_name": "Test",
+        "status": "active",
+        "verified": true,
+        "authenticated": null,
+        "admin": [],
+        "org": "default",
+        "claims": {},
+        "mfa_secret": null,
+        "created_at": "2024-01-01T00:00:00Z",
+        "updated_at": "2024-01-01T00:00:00Z"
+    });
+    if !fields.contains_key("password_hash") {
+        user["password_hash"] = json!(password::hash_password(PASSWORD).unwrap());
+    }
+    user.as_object_mut().unwrap().extend(fields.clone());
+
+    let path = format!("{}/users/{}/{}.json", data_dir, user["org"].as_str().unwrap(), user["id"].as_str().unwrap());
+    write_file(&path, user.to_string()).await;
+    path
+}
+
+/// Register a public client in `data_dir`, next to those already there:
+/// `client_id` with PKCE and the openid scope, unless `fields` says otherwise.
+pub async fn write_client(data_dir: &str, client_id: &str, redirect_uris: &[&str], grant_types: &[&str], fields: Value) {
+    let mut client = json!({
+        "client_id": client_id,
+        "name": client_id,
+        "client_type": "public",
+        "redirect_uris": redirect_uris,
+        "allowed_scopes": ["openid"],
+        "require_pkce": true,
+        "grant_types": grant_types,
+        "created_at": "2024-01-01T00:00:00Z"
+    });
+    client.as_object_mut().unwrap().extend(fields.as_object().unwrap().clone());
+
+    let path = format!("{}/clients.json", data_dir);
+    let mut clients = match tokio::fs::try_exists(&path).await.unwrap() {
+        true => read_json(&path).await,
+        false => json!({"clients": []}),
+    };
+    clients["clients"].as_array_mut().unwrap().push(client);
+    write_file(&path, clients.to_string()).await;
+}
+
+/// Write `contents` to `path`, creating its directory.
+pub async fn write_file(path: &str, contents: impl AsRef<[u8]>) {
+    if let Some(parent) = Path::new(path).parent() {
+        tokio::fs::create_dir_all(parent).await.unwrap();
+    }
+    tokio::fs::write(path, contents).await.unwrap();
+}
+
+pub async fn read_json(path: &str) -> Value {
+    serde_json::from_str(&tokio::fs::read_to_string(path).await.unwrap()).unwrap()
+}
+
+/// The app over the data in `data_dir`, with its storage and runtime.
+pub async fn test_app(data_dir: &str, config: &Config) -> (Router, Arc<RwLock<FileStorage>>, Arc<Runtime>) {
+    let storage = Arc::new(RwLock::new(FileStorage::load(data_dir).await.unwrap()));
+    let runtime = Arc::new(Runtime::load(data_dir, config).await.unwrap());
+    let app = app_with(storage.clone(), config, runtime.clone()).await;
+    (app, storage, runtime)
+}
+
+/// The app on the given state, answering as if called from 127.0.0.1.
+pub async fn app_with(storage: Arc<RwLock<FileStorage>>, config: &Config, runtime: Arc<Runtime>) -> Router {
+    crate::create_app(storage, config.clone(), runtime)
+        .await
+        .unwrap()
+        .layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4711))))
+}
+
+/// Login request body for `email` with `PASSWORD`.
+pub fn credentials(email: &str) -> Value {
+    json!({"email": email, "password": PASSWORD})
+}
+
+pub async fn get(app: &Router, path: &str) -> Response {
+    app.clone()
+        .oneshot(Request::get(path).body(Body::empty()).unwrap())
+        .await
+        .unwrap()
+}
+
+pub async fn body_json(response: Response) -> Value {
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    serde_json::from_slice(&body).unwrap_or(Value::Null)
+}
+
+pub async fn post_json(app: &Router, path: &str, body: Value) -> Value {
+    let response = app
+        .clone()
+        .oneshot(
+            Request::post(path)
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK, "POST {}", path);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+/// POST that may fail; the body is `Null` unless it is JSON.
+pub async fn post_json_as(app: &Router, path: &str, body: Value, bearer: Option<&str>) -> (StatusCode, Value) {
+    let mut request = Request::post(path).header("content-type", "application/json");
+    if let Some(token) = bearer {
+        request = request.header("authorization", format!("Bearer {}", token));
+    }
+    let response = app.clone().oneshot(request.body(Body::from(body.to_string())).unwrap()).await.unwrap();
+    (response.status(), body_json(response).await)
+}
+
+/// POST `fields` form-encoded, as OAuth clients do; the answer as in
+/// `post_json_as`. Null fields are left out.
+pub async fn post_form(app: &Router, path: &str, fields: Value) -> (StatusCode, Value) {
+    let body = fields
+        .as_object()
+        .unwrap()
+        .iter()
+        .filter(|(_, value)| !value.is_null())
+        .map(|(key, value)| {
+            let value = value.as_str().map(str::to_string).unwrap_or_else(|| value.to_string());
+            format!("{}={}", urlencoding::encode(key), urlencoding::encode(&value))
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    let request = Request::post(path).header("content-type", "application/x-www-form-urlencoded");
+    let response = app.clone().oneshot(request.body(Body::from(body)).unwrap()).await.unwrap();
+    (response.status(), body_json(response).await)
+}
+
+/// Any method with an optional JSON body; the answer as in `post_json_as`.
+pub async fn request_as(app: &Router, method: &str, path: &str, body: Option<Value>, bearer: &str) -> (StatusCode, Value) {
+    let request = Request::builder()
+        .method(method)
+        .uri(path)
+        .header("authorization", format!("Bearer {}", bearer));
+    let request = match body {
+        Some(body) => request.header("content-type", "application/json").body(Body::from(body.to_string())),
+        None => request.body(Body::empty()),
+    };
+    let response = app.clone().oneshot(request.unwrap()).await.unwrap();
+    (response.status(), body_json(response).await)
+}
+
+/// Wait up to five seconds for work the handlers leave to background tasks.
+pub async fn eventually(condition: impl Fn() -> bool) {
+    for _ in 0..50 {
+        if condition() {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+}
+
+/// Tokens `client_id` gets for the login behind `access_token`: the
+/// authorization code flow with PKCE, answered the way the login page does.
+/// The client needs the authorization_code grant and `redirect_uri`.
+pub async fn client_tokens(app: &Router, client_id: &str, redirect_uri: &str, scope: &str, access_token: &str) -> (StatusCode, Value) {
+    let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    let challenge = base64::Engine::encode(
+        &base64::engine::general_purpose::URL_SAFE_NO_PAD,
+        sha2::Digest::finalize(<sha2::Sha256 as sha2::Digest>::new_with_prefix(verifier)),
+    );
+    let query = |url: &str, name: &str| {
+        reqwest::Url::parse("http://localhost/")
+            .and_then(|base| base.join(url))
+            .unwrap()
+            .query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.to_string())
+            .unwrap()
+    };
+
+    let mut authorize = reqwest::Url::parse("http://localhost").unwrap().join(crate::routes::AUTHORIZE).unwrap();
+    authorize
+        .query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", client_id)
+        .append_pair("redirect_uri", redirect_uri)
+        .append_pair("scope", scope)
+        .append_pair("code_challenge", &challenge)
+        .append_pair("code_challenge_method", "S256");
+    let response = get(app, &format!("{}?{}", authorize.path(), authorize.query().unwrap())).await;
+    assert!(response.status().is_redirection(), "authorize {}: {}", client_id, response.status());
+    let login_page = response.headers()[axum::http::header::LOCATION].to_str().unwrap().to_string();
+
+    let complete = json!({"request": query(&login_page, "authorize")});
+    let (status, answer) = post_json_as(app, "/api/oauth2/authorize/complete", complete, Some(access_token)).await;
+    assert_eq!(status, StatusCode::OK, "{}", answer);
+    let code = query(answer["redirect_uri"].as_str().unwrap(), "code");
+
+    let exchange = json!({
+        "grant_type": "authorization_code",
+        "client_id": client_id,
+        "code": code,
+        "redirect_uri": redirect_uri,
+        "code_verifier": verifier
+    });
+    post_form(app, crate::routes::TOKEN, exchange).await
+}
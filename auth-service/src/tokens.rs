@@ -0,0 +1,409 @@
+use anyhow::{Context, Result};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
+use tracing::{info, warn};
+
+use crate::models::Claims;
+
+/// Prefix of opaque access tokens, used to tell them apart from JWTs.
+pub const OPAQUE_TOKEN_PREFIX: &str = "at_";
+
+/// Prefix of opaque refresh tokens, issued to clients of the opaque format.
+pub const OPAQUE_REFRESH_TOKEN_PREFIX: &str = "rt_";
+
+/// Server-side record of an opaque access or refresh token, told apart by
+/// the `typ` of its claims. Only the SHA-256 of the token is kept; the
+/// token itself is handed to the client once.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpaqueToken {
+    pub token_hash: String,
+    pub client_id: String,
+    pub user_id: String,
+    pub scope: String,
+    pub claims: Claims,
+    pub revoked: bool,
+}
+
+impl OpaqueToken {
+    pub fn is_active(&self) -> bool {
+        !self.revoked && self.claims.exp > OffsetDateTime::now_utc().unix_timestamp() as u64
+    }
+}
+
+/// Legacy single-file store, migrated to one file per token on load.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TokensFile {
+    tokens: Vec<OpaqueToken>,
+}
+
+/// A revoked refresh token, refused until it would have expired anyway.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RevokedRefreshToken {
+    jti: String,
+    client_id: String,
+    exp: u64,
+}
+
+/// Opaque tokens and revoked refresh tokens. Each record is its own file,
+/// `tokens/access/{token_hash}.json`, `tokens/refresh/{token_hash}.json` and
+/// `tokens/revoked_refresh/{jti}.json`, so issuing or revoking a token writes
+/// only that token; expired records are deleted on load and as tokens are
+/// issued.
+#[derive(Debug)]
+pub struct TokenStore {
+    tokens: HashMap<String, OpaqueToken>, // token_hash -> token
+    revoked_refresh: HashMap<String, RevokedRefreshToken>, // jti -> record
+    dir: PathBuf,
+}
+
+impl TokenStore {
+    pub async fn load(data_dir: &str) -> Result<Self> {
+        let dir = Path::new(data_dir).join("tokens");
+        let mut store = Self { tokens: HashMap::new(), revoked_refresh: HashMap::new(), dir };
+
+        for kind in ["access", "refresh"] {
+            for token in read_records::<OpaqueToken>(&store.dir.join(kind)).await? {
+                store.tokens.insert(token.token_hash.clone(), token);
+            }
+        }
+        for revoked in read_records::<RevokedRefreshToken>(&store.dir.join("revoked_refresh")).await? {
+            store.revoked_refresh.insert(revoked.jti.clone(), revoked);
+        }
+        store.migrate_legacy_file().await?;
+        store.prune().await?;
+
+        info!(
+            event = "token_store_loaded",
+            active_tokens = store.tokens.len(),
+            revoked_refresh_tokens = store.revoked_refresh.len()
+        );
+
+        Ok(store)
+    }
+
+    /// Split a `tokens/access_tokens.json` of older releases into per-token files.
+    async fn migrate_legacy_file(&mut self) -> Result<()> {
+        let path = self.dir.join("access_tokens.json");
+        let content = match tokio::fs::read_to_string(&path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read token store: {}", path.display())),
+        };
+        let tokens_file = serde_json::from_str::<TokensFile>(&content)
+            .with_context(|| format!("Failed to parse token store: {}", path.display()))?;
+
+        for token in tokens_file.tokens.into_iter().filter(|t| t.is_active()) {
+            self.write_token(&token).await?;
+            self.tokens.insert(token.token_hash.clone(), token);
+        }
+        tokio::fs::remove_file(&path).await
+            .with_context(|| format!("Failed to remove migrated token store: {}", path.display()))?;
+
+        info!(event = "token_store_migrated", active_tokens = self.tokens.len());
+        Ok(())
+    }
+
+    /// Store `claims` under a fresh random reference and return it, an
+    /// `rt_` handle for refresh claims and an `at_` one otherwise.
+    pub async fn issue(&mut self, claims: Claims, user_id: &str, client_id: &str, scope: &str) -> Result<String> {
+        let mut bytes = [0u8; 32];
+        rand::rngs::OsRng.fill_bytes(&mut bytes);
+        let prefix = if claims.is_refresh_token() { OPAQUE_REFRESH_TOKEN_PREFIX } else { OPAQUE_TOKEN_PREFIX };
+        let token = format!("{}{}", prefix, to_hex(&bytes));
+
+        let record = OpaqueToken {
+            token_hash: hash_token(&token),
+            client_id: client_id.to_string(),
+            user_id: user_id.to_string(),
+            scope: scope.to_string(),
+            claims,
+            revoked: false,
+        };
+
+        self.prune().await?;
+        self.write_token(&record).await?;
+        self.tokens.insert(record.token_hash.clone(), record);
+
+        Ok(token)
+    }
+
+    /// Look up an opaque token; inactive (expired or revoked) tokens resolve to `None`.
+    pub fn resolve(&self, token: &str) -> Option<&OpaqueToken> {
+        self.tokens
+            .get(&hash_token(token))
+            .filter(|t| t.is_active())
+    }
+
+    /// Revoke `token` if it was issued to `client_id`. Returns whether a token was revoked.
+    pub async fn revoke(&mut self, token: &str, client_id: &str) -> Result<bool> {
+        let record = match self.tokens.get_mut(&hash_token(token)) {
+            Some(record) if record.client_id == client_id && !record.revoked => record,
+            _ => return Ok(false),
+        };
+        record.revoked = true;
+        let record = record.clone();
+        self.write_token(&record).await?;
+
+        Ok(true)
+    }
+
+    /// Revoke every opaque token of `user_id`. Returns how many were revoked.
+    pub async fn revoke_user(&mut self, user_id: &str) -> Result<usize> {
+        self.revoke_where(|record| record.user_id == user_id).await
+    }
+
+    /// Revoke the opaque tokens `client_id` holds for `user_id`.
+    pub async fn revoke_user_client(&mut self, user_id: &str, client_id: &str) -> Result<usize> {
+        self.revoke_where(|record| record.user_id == user_id && record.client_id == client_id).await
+    }
+
+    async fn revoke_where(&mut self, matches: impl Fn(&OpaqueToken) -> bool) -> Result<usize> {
+        let mut revoked = Vec::new();
+        for record in self.tokens.values_mut() {
+            if matches(record) && !record.revoked {
+                record.revoked = true;
+                revoked.push(record.clone());
+            }
+        }
+
+        for record in &revoked {
+            self.write_token(record).await?;
+        }
+
+        Ok(revoked.len())
+    }
+
+    /// Refuse the refresh token `jti` from now until its expiry `exp`.
+    /// Returns whether it was not revoked before.
+    pub async fn revoke_refresh_token(&mut self, jti: &str, client_id: &str, exp: u64) -> Result<bool> {
+        if self.revoked_refresh.contains_key(jti) {
+            return Ok(false);
+        }
+        let record = RevokedRefreshToken { jti: jti.to_string(), client_id: client_id.to_string(), exp };
+        write_record(&self.dir.join("revoked_refresh"), jti, &record).await?;
+        self.revoked_refresh.insert(record.jti.clone(), record);
+        Ok(true)
+    }
+
+    pub fn is_refresh_token_revoked(&self, jti: &str) -> bool {
+        self.revoked_refresh.contains_key(jti)
+    }
+
+    /// Drop expired records, from memory and disk.
+    async fn prune(&mut self) -> Result<()> {
+        let now = OffsetDateTime::now_utc().unix_timestamp() as u64;
+        let expired_tokens: Vec<String> = self.tokens.values()
+            .filter(|t| t.claims.exp <= now)
+            .map(|t| t.token_hash.clone())
+            .collect();
+        for token_hash in expired_tokens {
+            if let Some(token) = self.tokens.remove(&token_hash) {
+                remove_record(&self.dir.join(record_kind(&token)), &token_hash).await?;
+            }
+        }
+
+        let expired_refresh: Vec<String> = self.revoked_refresh.values()
+            .filter(|r| r.exp <= now)
+            .map(|r| r.jti.clone())
+            .collect();
+        for jti in expired_refresh {
+            self.revoked_refresh.remove(&jti);
+            remove_record(&self.dir.join("revoked_refresh"), &jti).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn write_token(&self, token: &OpaqueToken) -> Result<()> {
+        write_record(&self.dir.join(record_kind(token)), &token.token_hash, token).await
+    }
+}
+
+/// Directory below `tokens` that holds `token`'s record.
+fn record_kind(token: &OpaqueToken) -> &'static str {
+    if token.claims.is_refresh_token() { "refresh" } else { "access" }
+}
+
+async fn read_records<T: for<'de> Deserialize<'de>>(dir: &Path) -> Result<Vec<T>> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+    };
+
+    let mut records = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_none_or(|e| e != "json") {
+            continue;
+        }
+        let content = tokio::fs::read_to_string(&path).await
+            .with_context(|| format!("Failed to read token record: {}", path.display()))?;
+        match serde_json::from_str(&content) {
+            Ok(record) => records.push(record),
+            Err(e) => warn!(event = "token_record_unreadable", path = %path.display(), error = %e),
+        }
+    }
+    Ok(records)
+}
+
+async fn write_record<T: Serialize>(dir: &Path, name: &str, record: &T) -> Result<()> {
+    tokio::fs::create_dir_all(dir).await
+        .context("Failed to create tokens directory")?;
+
+    let path = dir.join(format!("{}.json", name));
+    let temp_path = dir.join(format!("{}.json.tmp", name));
+    tokio::fs::write(&temp_path, serde_json::to_string_pretty(record)?)
+        .await
+        .context("Failed to write token record temp file")?;
+    tokio::fs::rename(&temp_path, &path)
+        .await
+        .context("Failed to rename token record file")?;
+
+    Ok(())
+}
+
+async fn remove_record(dir: &Path, name: &str) -> Result<()> {
+    match tokio::fs::remove_file(dir.join(format!("{}.json", name))).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).context("Failed to remove expired token record")
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn is_opaque_token(token: &str) -> bool {
+    token.starts_with(OPAQUE_TOKEN_PREFIX) || token.starts_with(OPAQUE_REFRESH_TOKEN_PREFIX)
+}
+
+pub fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::TokenUse;
+    use std::collections::HashMap;
+
+    fn test_claims(expires_in: i64) -> Claims {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        Claims {
+            sub: "user-1".to_string(),
+            email: "user@example.com".to_string(),
+            name: "Test User".to_string(),
+            org: "default".to_string(),
+            admin: vec![],
+            user_claims: HashMap::new(),
+            iss: "https://auth.example.com".to_string(),
+            aud: vec!["auth-service".to_string()],
+            exp: (now + expires_in) as u64,
+            iat: now as u64,
+            jti: "jti-1".to_string(),
+            azp: Some("app".to_string()),
+            auth_time: Some(now as u64),
+            acr: Some("pwd".to_string()),
+            amr: vec!["pwd".to_string()],
+            act: None,
+            sid: None,
+            typ: Some(TokenUse::Access),
+            scope: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_opaque_token_lifecycle() {
+        let data_dir = std::env::temp_dir().join(format!("um-oic-tokens-{}", uuid::Uuid::new_v4().simple()));
+        let data_dir = data_dir.to_string_lossy().to_string();
+
+        let mut store = TokenStore::load(&data_dir).await.unwrap();
+        let token = store.issue(test_claims(3600), "user-1", "app", "openid").await.unwrap();
+        let other = store.issue(test_claims(3600), "user-1", "app", "openid").await.unwrap();
+
+        assert!(is_opaque_token(&token));
+        assert_eq!(store.resolve(&token).unwrap().claims.sub, "user-1");
+
+        // Only the issuing client may revoke
+        assert!(!store.revoke(&token, "other-app").await.unwrap());
+        assert!(store.revoke(&token, "app").await.unwrap());
+        assert!(store.resolve(&token).is_none());
+
+        // Persisted state survives a reload
+        let reloaded = TokenStore::load(&data_dir).await.unwrap();
+        assert!(reloaded.resolve(&token).is_none());
+        assert_eq!(reloaded.resolve(&other).unwrap().client_id, "app");
+
+        tokio::fs::remove_dir_all(&data_dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expired_token_is_inactive() {
+        let data_dir = std::env::temp_dir().join(format!("um-oic-tokens-{}", uuid::Uuid::new_v4().simple()));
+        let data_dir = data_dir.to_string_lossy().to_string();
+
+        let mut store = TokenStore::load(&data_dir).await.unwrap();
+        let token = store.issue(test_claims(-10), "user-1", "app", "openid").await.unwrap();
+        assert!(store.resolve(&token).is_none());
+
+        // Expired records are pruned from disk with the next issuance
+        store.issue(test_claims(3600), "user-1", "app", "openid").await.unwrap();
+        let expired = Path::new(&data_dir).join("tokens/access").join(format!("{}.json", hash_token(&token)));
+        assert!(!expired.exists());
+
+        tokio::fs::remove_dir_all(&data_dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_revoked_refresh_token_survives_reload() {
+        let data_dir = std::env::temp_dir().join(format!("um-oic-tokens-{}", uuid::Uuid::new_v4().simple()));
+        let data_dir = data_dir.to_string_lossy().to_string();
+        let exp = (OffsetDateTime::now_utc().unix_timestamp() + 3600) as u64;
+
+        let mut store = TokenStore::load(&data_dir).await.unwrap();
+        assert!(store.revoke_refresh_token("refresh-1", "app", exp).await.unwrap());
+        assert!(!store.revoke_refresh_token("refresh-1", "app", exp).await.unwrap());
+        assert!(store.revoke_refresh_token("refresh-2", "app", exp - 7200).await.unwrap());
+
+        // Revocations outlive a restart until the token would have expired
+        let reloaded = TokenStore::load(&data_dir).await.unwrap();
+        assert!(reloaded.is_refresh_token_revoked("refresh-1"));
+        assert!(!reloaded.is_refresh_token_revoked("refresh-2"));
+        assert!(!reloaded.is_refresh_token_revoked("refresh-3"));
+
+        tokio::fs::remove_dir_all(&data_dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_legacy_token_file_is_migrated() {
+        let data_dir = std::env::temp_dir().join(format!("um-oic-tokens-{}", uuid::Uuid::new_v4().simple()));
+        let data_dir = data_dir.to_string_lossy().to_string();
+        let legacy = OpaqueToken {
+            token_hash: hash_token("at_legacy"),
+            client_id: "app".to_string(),
+            user_id: "user-1".to_string(),
+            scope: "openid".to_string(),
+            claims: test_claims(3600),
+            revoked: false,
+        };
+        tokio::fs::create_dir_all(format!("{}/tokens", data_dir)).await.unwrap();
+        let content = serde_json::to_string(&TokensFile { tokens: vec![legacy] }).unwrap();
+        tokio::fs::write(format!("{}/tokens/access_tokens.json", data_dir), content).await.unwrap();
+
+        let store = TokenStore::load(&data_dir).await.unwrap();
+        assert_eq!(store.resolve("at_legacy").unwrap().user_id, "user-1");
+        assert!(!Path::new(&format!("{}/tokens/access_tokens.json", data_dir)).exists());
+
+        let reloaded = TokenStore::load(&data_dir).await.unwrap();
+        assert!(reloaded.resolve("at_legacy").is_some());
+
+        tokio::fs::remove_dir_all(&data_dir).await.unwrap();
+    }
+}
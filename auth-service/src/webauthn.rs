@@ -0,0 +1,558 @@
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ciborium::value::Value;
+use rand::RngCore;
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use time::OffsetDateTime;
+
+use crate::config::WebAuthnConfig;
+use crate::models::{User, WebAuthnCredential};
+
+/// COSE algorithm identifier for ECDSA P-256 with SHA-256, the only one we accept.
+pub const COSE_ALG_ES256: i64 = -7;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_DATA: u8 = 0x40;
+
+/// What a challenge was issued for; it may only be answered by that ceremony.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ceremony {
+    Registration { user_id: String, nickname: String },
+    /// `user_id` is set for a second factor, `None` for passwordless login
+    Authentication { user_id: Option<String> },
+}
+
+#[derive(Debug)]
+struct PendingChallenge {
+    ceremony: Ceremony,
+    expires_at: i64,
+}
+
+/// Outstanding challenges, keyed by their base64url value. Single use.
+#[derive(Debug, Default)]
+pub struct ChallengeStore {
+    pending: HashMap<String, PendingChallenge>,
+}
+
+impl ChallengeStore {
+    pub fn issue(&mut self, ceremony: Ceremony, ttl: u64) -> String {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        self.pending.retain(|_, c| c.expires_at > now);
+
+        let mut bytes = [0u8; 32];
+        rand::rngs::OsRng.fill_bytes(&mut bytes);
+        let challenge = URL_SAFE_NO_PAD.encode(bytes);
+
+        self.pending.insert(challenge.clone(), PendingChallenge {
+            ceremony,
+            expires_at: now + ttl as i64,
+        });
+        challenge
+    }
+
+    /// Remove and return the ceremony for `challenge` if it is still valid.
+    pub fn take(&mut self, challenge: &str) -> Option<Ceremony> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        self.pending
+            .remove(challenge)
+            .filter(|c| c.expires_at > now)
+            .map(|c| c.ceremony)
+    }
+}
+
+/// `PublicKeyCredential` as returned by `navigator.credentials.create()`,
+/// binary fields base64url encoded.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrationCredential {
+    pub id: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub attestation_object: String,
+    #[serde(default)]
+    pub transports: Vec<String>,
+}
+
+/// `PublicKeyCredential` as returned by `navigator.credentials.get()`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionCredential {
+    pub id: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    pub user_handle: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    ceremony_type: String,
+    challenge: String,
+    origin: String,
+}
+
+/// Decoded `clientDataJSON`: the raw bytes (they are signed over) and the
+/// challenge, to look up which ceremony is being answered.
+pub struct CollectedClientData {
+    raw: Vec<u8>,
+    data: ClientData,
+}
+
+impl CollectedClientData {
+    pub fn parse(client_data_json: &str) -> Result<Self> {
+        let raw = decode(client_data_json).context("Invalid clientDataJSON encoding")?;
+        let data = serde_json::from_slice(&raw).context("Invalid clientDataJSON")?;
+        Ok(Self { raw, data })
+    }
+
+    pub fn challenge(&self) -> &str {
+        &self.data.challenge
+    }
+
+    fn check(&self, ceremony_type: &str, config: &WebAuthnConfig) -> Result<()> {
+        if self.data.ceremony_type != ceremony_type {
+            bail!("Expected client data type {}, got {}", ceremony_type, self.data.ceremony_type);
+        }
+        if self.data.origin != config.origin {
+            bail!("Origin {} does not match configured origin {}", self.data.origin, config.origin);
+        }
+        Ok(())
+    }
+}
+
+struct AuthenticatorData<'a> {
+    flags: u8,
+    sign_count: u32,
+    /// Attested credential data and extensions, if present
+    rest: &'a [u8],
+}
+
+fn parse_authenticator_data<'a>(data: &'a [u8], config: &WebAuthnConfig) -> Result<AuthenticatorData<'a>> {
+    if data.len() < 37 {
+        bail!("Authenticator data too short: {} bytes", data.len());
+    }
+    if data[..32] != Sha256::digest(config.rp_id.as_bytes())[..] {
+        bail!("Authenticator data is not for RP ID {}", config.rp_id);
+    }
+
+    let flags = data[32];
+    if flags & FLAG_USER_PRESENT == 0 {
+        bail!("User presence flag not set");
+    }
+
+    Ok(AuthenticatorData {
+        flags,
+        sign_count: u32::from_be_bytes([data[33], data[34], data[35], data[36]]),
+        rest: &data[37..],
+    })
+}
+
+/// Registration options for `navigator.credentials.create()`.
+pub fn registration_options(config: &WebAuthnConfig, rp_name: &str, user: &User, challenge: &str) -> JsonValue {
+    let exclude: Vec<JsonValue> = user
+        .webauthn_credentials
+        .iter()
+        .map(|c| json!({"type": "public-key", "id": c.credential_id, "transports": c.transports}))
+        .collect();
+
+    json!({
+        "publicKey": {
+            "challenge": challenge,
+            "rp": {"id": config.rp_id, "name": rp_name},
+            "user": {
+                "id": URL_SAFE_NO_PAD.encode(user.id.as_bytes()),
+                "name": user.email,
+                "displayName": user.full_name()
+            },
+            "pubKeyCredParams": [{"type": "public-key", "alg": COSE_ALG_ES256}],
+            "timeout": config.challenge_ttl * 1000,
+            "attestation": "none",
+            "excludeCredentials": exclude,
+            "authenticatorSelection": {
+                "residentKey": "preferred",
+                "userVerification": "preferred"
+            }
+        }
+    })
+}
+
+/// Authentication options for `navigator.credentials.get()`. Passwordless
+/// login (`user` is `None`) relies on discoverable credentials and demands
+/// user verification.
+pub fn authentication_options(config: &WebAuthnConfig, user: Option<&User>, challenge: &str) -> JsonValue {
+    let allow: Vec<JsonValue> = user
+        .map(|u| {
+            u.webauthn_credentials
+                .iter()
+                .map(|c| json!({"type": "public-key", "id": c.credential_id, "transports": c.transports}))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    json!({
+        "publicKey": {
+            "challenge": challenge,
+            "rpId": config.rp_id,
+            "timeout": config.challenge_ttl * 1000,
+            "allowCredentials": allow,
+            "userVerification": if user.is_some() { "preferred" } else { "required" }
+        }
+    })
+}
+
+/// Verify a registration response and return the credential to store.
+/// Attestation statements are not verified: we request `none` and do not
+/// restrict authenticator models.
+pub fn verify_registration(
+    config: &WebAuthnConfig,
+    client_data: &CollectedClientData,
+    credential: &RegistrationCredential,
+    nickname: &str,
+) -> Result<WebAuthnCredential> {
+    client_data.check("webauthn.create", config)?;
+
+    let attestation = decode(&credential.response.attestation_object)
+        .context("Invalid attestationObject encoding")?;
+    let attestation: Value = ciborium::de::from_reader(attestation.as_slice())
+        .context("Invalid attestationObject CBOR")?;
+    let auth_data = map_get(&attestation, &Value::Text("authData".to_string()))
+        .and_then(Value::as_bytes)
+        .ok_or_else(|| anyhow!("attestationObject has no authData"))?;
+
+    let auth_data = parse_authenticator_data(auth_data, config)?;
+    if auth_data.flags & FLAG_ATTESTED_DATA == 0 {
+        bail!("Registration without attested credential data");
+    }
+
+    // aaguid (16) | credentialIdLength (2) | credentialId | credentialPublicKey
+    let rest = auth_data.rest;
+    if rest.len() < 18 {
+        bail!("Attested credential data too short");
+    }
+    let id_len = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+    let credential_id = rest
+        .get(18..18 + id_len)
+        .ok_or_else(|| anyhow!("Credential ID exceeds authenticator data"))?;
+    let cose_key: Value = ciborium::de::from_reader(&rest[18 + id_len..])
+        .context("Invalid credential public key CBOR")?;
+
+    let credential_id = URL_SAFE_NO_PAD.encode(credential_id);
+    if credential_id != credential.id {
+        bail!("Credential ID in authenticator data does not match response id");
+    }
+
+    Ok(WebAuthnCredential {
+        credential_id,
+        public_key: URL_SAFE_NO_PAD.encode(es256_public_key(&cose_key)?),
+        algorithm: COSE_ALG_ES256,
+        sign_count: auth_data.sign_count,
+        transports: credential.response.transports.clone(),
+        nickname: nickname.to_string(),
+        created_at: OffsetDateTime::now_utc(),
+        last_used_at: None,
+    })
+}
+
+/// Verify an assertion against `stored` and return the new signature counter.
+pub fn verify_assertion(
+    config: &WebAuthnConfig,
+    client_data: &CollectedClientData,
+    credential: &AssertionCredential,
+    stored: &WebAuthnCredential,
+    require_user_verification: bool,
+) -> Result<u32> {
+    client_data.check("webauthn.get", config)?;
+
+    let raw_auth_data = decode(&credential.response.authenticator_data)
+        .context("Invalid authenticatorData encoding")?;
+    let auth_data = parse_authenticator_data(&raw_auth_data, config)?;
+    if require_user_verification && auth_data.flags & FLAG_USER_VERIFIED == 0 {
+        bail!("User verification required but not performed");
+    }
+
+    let mut signed = raw_auth_data.clone();
+    signed.extend_from_slice(&Sha256::digest(&client_data.raw));
+
+    let public_key = decode(&stored.public_key).context("Stored public key is not base64url")?;
+    let signature = decode(&credential.response.signature).context("Invalid signature encoding")?;
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, &public_key)
+        .verify(&signed, &signature)
+        .map_err(|_| anyhow!("Assertion signature is invalid"))?;
+
+    // A counter that does not advance hints at a cloned authenticator.
+    // Authenticators without counters always report 0.
+    if (auth_data.sign_count != 0 || stored.sign_count != 0) && auth_data.sign_count <= stored.sign_count {
+        bail!(
+            "Signature counter went from {} to {}; possible cloned authenticator",
+            stored.sign_count,
+            auth_data.sign_count
+        );
+    }
+
+    Ok(auth_data.sign_count)
+}
+
+/// Uncompressed SEC1 point from an EC2 / P-256 / ES256 COSE key.
+fn es256_public_key(cose_key: &Value) -> Result<Vec<u8>> {
+    let int = |label: i64| map_get(cose_key, &Value::Integer(label.into())).and_then(Value::as_integer).map(i128::from);
+    let bytes = |label: i64| map_get(cose_key, &Value::Integer(label.into())).and_then(Value::as_bytes);
+
+    // kty 2 = EC2, crv 1 = P-256
+    if int(1) != Some(2) || int(-1) != Some(1) {
+        bail!("Only EC2 P-256 credential keys are supported");
+    }
+    if int(3) != Some(COSE_ALG_ES256 as i128) {
+        bail!("Only ES256 credentials are supported");
+    }
+
+    let (x, y) = match (bytes(-2), bytes(-3)) {
+        (Some(x), Some(y)) if x.len() == 32 && y.len() == 32 => (x, y),
+        _ => bail!("Malformed EC2 coordinates in credential key"),
+    };
+
+    let mut point = Vec::with_capacity(65);
+    point.push(0x04);
+    point.extend_from_slice(x);
+    point.extend_from_slice(y);
+    Ok(point)
+}
+
+fn map_get<'a>(map: &'a Value, key: &Value) -> Option<&'a Value> {
+    map.as_map()?.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+}
+
+fn decode(value: &str) -> Result<Vec<u8>> {
+    // Browsers' toJSON() emits unpadded base64url; tolerate padding
+    Ok(URL_SAFE_NO_PAD.decode(value.trim_end_matches('='))?)
+}
+
+/// Software authenticator for tests: one ES256 key, `none` attestation.
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+
+    pub struct SoftAuthenticator {
+        key: EcdsaKeyPair,
+        pub credential_id: Vec<u8>,
+        pub sign_count: u32,
+        rng: SystemRandom,
+    }
+
+    impl SoftAuthenticator {
+        pub fn new() -> Self {
+            let rng = SystemRandom::new();
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+            let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng).unwrap();
+            let mut credential_id = vec![0u8; 16];
+            rand::rngs::OsRng.fill_bytes(&mut credential_id);
+            Self { key, credential_id, sign_count: 0, rng }
+        }
+
+        pub fn id(&self) -> String {
+            URL_SAFE_NO_PAD.encode(&self.credential_id)
+        }
+
+        fn client_data(ceremony_type: &str, challenge: &str, origin: &str) -> String {
+            let data = json!({"type": ceremony_type, "challenge": challenge, "origin": origin});
+            URL_SAFE_NO_PAD.encode(data.to_string())
+        }
+
+        fn auth_data(&self, rp_id: &str, flags: u8, attested: Option<Vec<u8>>) -> Vec<u8> {
+            let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+            data.push(flags);
+            data.extend_from_slice(&self.sign_count.to_be_bytes());
+            if let Some(attested) = attested {
+                data.extend_from_slice(&attested);
+            }
+            data
+        }
+
+        /// Answer `navigator.credentials.create()` options.
+        pub fn register(&mut self, options: &JsonValue, origin: &str) -> JsonValue {
+            let public_key = &options["publicKey"];
+            let challenge = public_key["challenge"].as_str().unwrap();
+            let rp_id = public_key["rp"]["id"].as_str().unwrap();
+
+            let point = self.key.public_key().as_ref();
+            let cose_key = Value::Map(vec![
+                (Value::Integer(1.into()), Value::Integer(2.into())),
+                (Value::Integer(3.into()), Value::Integer((-7).into())),
+                (Value::Integer((-1).into()), Value::Integer(1.into())),
+                (Value::Integer((-2).into()), Value::Bytes(point[1..33].to_vec())),
+                (Value::Integer((-3).into()), Value::Bytes(point[33..65].to_vec())),
+            ]);
+            let mut attested = vec![0u8; 16];
+            attested.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+            attested.extend_from_slice(&self.credential_id);
+            ciborium::ser::into_writer(&cose_key, &mut attested).unwrap();
+
+            let auth_data = self.auth_data(
+                rp_id,
+                FLAG_USER_PRESENT | FLAG_USER_VERIFIED | FLAG_ATTESTED_DATA,
+                Some(attested),
+            );
+            let attestation = Value::Map(vec![
+                (Value::Text("fmt".into()), Value::Text("none".into())),
+                (Value::Text("attStmt".into()), Value::Map(vec![])),
+                (Value::Text("authData".into()), Value::Bytes(auth_data)),
+            ]);
+            let mut attestation_object = Vec::new();
+            ciborium::ser::into_writer(&attestation, &mut attestation_object).unwrap();
+
+            json!({
+                "id": self.id(),
+                "rawId": self.id(),
+                "type": "public-key",
+                "response": {
+                    "clientDataJSON": Self::client_data("webauthn.create", challenge, origin),
+                    "attestationObject": URL_SAFE_NO_PAD.encode(attestation_object),
+                    "transports": ["internal"]
+                }
+            })
+        }
+
+        /// Answer `navigator.credentials.get()` options for `user_id`.
+        pub fn assert(&mut self, options: &JsonValue, origin: &str, user_id: &str) -> JsonValue {
+            let public_key = &options["publicKey"];
+            let challenge = public_key["challenge"].as_str().unwrap();
+            let rp_id = public_key["rpId"].as_str().unwrap();
+
+            self.sign_count += 1;
+            let auth_data = self.auth_data(rp_id, FLAG_USER_PRESENT | FLAG_USER_VERIFIED, None);
+            let client_data = Self::client_data("webauthn.get", challenge, origin);
+
+            let mut signed = auth_data.clone();
+            signed.extend_from_slice(&Sha256::digest(URL_SAFE_NO_PAD.decode(&client_data).unwrap()));
+            let signature = self.key.sign(&self.rng, &signed).unwrap();
+
+            json!({
+                "id": self.id(),
+                "rawId": self.id(),
+                "type": "public-key",
+                "response": {
+                    "clientDataJSON": client_data,
+                    "authenticatorData": URL_SAFE_NO_PAD.encode(auth_data),
+                    "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+                    "userHandle": URL_SAFE_NO_PAD.encode(user_id.as_bytes())
+                }
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::SoftAuthenticator;
+    use super::*;
+
+    fn config() -> WebAuthnConfig {
+        WebAuthnConfig {
+            rp_id: "auth.example.com".to_string(),
+            origin: "https://auth.example.com".to_string(),
+            challenge_ttl: 60,
+        }
+    }
+
+    fn user() -> User {
+        User::new(
+            "anna@example.com".to_string(),
+            "hash".to_string(),
+            "Anna".to_string(),
+            "Test".to_string(),
+            "default".to_string(),
+        )
+    }
+
+    #[test]
+    fn test_register_and_assert_with_soft_authenticator() {
+        let config = config();
+        let user = user();
+        let mut authenticator = SoftAuthenticator::new();
+
+        let options = registration_options(&config, "Test", &user, "cmVnLWNoYWxsZW5nZQ");
+        let response: RegistrationCredential =
+            serde_json::from_value(authenticator.register(&options, &config.origin)).unwrap();
+        let client_data = CollectedClientData::parse(&response.response.client_data_json).unwrap();
+        assert_eq!(client_data.challenge(), "cmVnLWNoYWxsZW5nZQ");
+
+        let stored = verify_registration(&config, &client_data, &response, "Laptop").unwrap();
+        assert_eq!(stored.credential_id, authenticator.id());
+        assert_eq!(stored.transports, vec!["internal".to_string()]);
+
+        let options = authentication_options(&config, None, "YXV0aC1jaGFsbGVuZ2U");
+        let assertion = authenticator.assert(&options, &config.origin, &user.id);
+        let assertion: AssertionCredential = serde_json::from_value(assertion).unwrap();
+        let client_data = CollectedClientData::parse(&assertion.response.client_data_json).unwrap();
+        let count = verify_assertion(&config, &client_data, &assertion, &stored, true).unwrap();
+        assert_eq!(count, 1);
+
+        // Replaying the same assertion: counter did not advance
+        let mut used = stored.clone();
+        used.sign_count = count;
+        assert!(verify_assertion(&config, &client_data, &assertion, &used, true).is_err());
+
+        // Same assertion checked against a different authenticator's key
+        let options = registration_options(&config, "Test", &user, "b3RoZXI");
+        let response: RegistrationCredential =
+            serde_json::from_value(SoftAuthenticator::new().register(&options, &config.origin)).unwrap();
+        let other_client_data = CollectedClientData::parse(&response.response.client_data_json).unwrap();
+        let mut other = verify_registration(&config, &other_client_data, &response, "Other").unwrap();
+        other.sign_count = 0;
+        assert!(verify_assertion(&config, &client_data, &assertion, &other, true).is_err());
+    }
+
+    #[test]
+    fn test_wrong_origin_and_rp_rejected() {
+        let config = config();
+        let user = user();
+        let mut authenticator = SoftAuthenticator::new();
+
+        let options = registration_options(&config, "Test", &user, "Y2hhbGxlbmdl");
+        let response: RegistrationCredential =
+            serde_json::from_value(authenticator.register(&options, "https://evil.example.com")).unwrap();
+        let client_data = CollectedClientData::parse(&response.response.client_data_json).unwrap();
+        assert!(verify_registration(&config, &client_data, &response, "x").is_err());
+
+        let mut other_rp = config.clone();
+        other_rp.rp_id = "other.example.com".to_string();
+        let options = registration_options(&other_rp, "Test", &user, "Y2hhbGxlbmdl");
+        let response: RegistrationCredential =
+            serde_json::from_value(authenticator.register(&options, &config.origin)).unwrap();
+        let client_data = CollectedClientData::parse(&response.response.client_data_json).unwrap();
+        assert!(verify_registration(&config, &client_data, &response, "x").is_err());
+    }
+
+    #[test]
+    fn test_challenges_are_single_use_and_typed() {
+        let mut store = ChallengeStore::default();
+        let ceremony = Ceremony::Authentication { user_id: None };
+        let challenge = store.issue(ceremony.clone(), 60);
+
+        assert_eq!(store.take(&challenge), Some(ceremony));
+        assert_eq!(store.take(&challenge), None);
+
+        let expired = store.issue(Ceremony::Authentication { user_id: None }, 0);
+        assert_eq!(store.take(&expired), None);
+    }
+}
@@ -0,0 +1,437 @@
+// UM-OIC Account JavaScript
+
+document.addEventListener('DOMContentLoaded', function() {
+    const errorMessage = document.getElementById('errorMessage');
+    const noticeMessage = document.getElementById('noticeMessage');
+    const accountUrl = window.location.origin + window.location.pathname;
+
+    // The login page sends the user back with ?token=<access token>
+    const urlParams = new URLSearchParams(window.location.search);
+    if (urlParams.get('token')) {
+        localStorage.setItem('auth_token', urlParams.get('token'));
+        history.replaceState(null, '', window.location.pathname);
+    }
+    const token = localStorage.getItem('auth_token');
+    if (!token) {
+        toLogin();
+        return;
+    }
+
+    let account = null;
+    let pendingRecoveryCodes = null;
+
+    loadAccount();
+    loadSessions();
+    loadConsents();
+    loadLogins();
+
+    document.getElementById('claimsForm').addEventListener('submit', async function(e) {
+        e.preventDefault();
+        hideMessages();
+
+        const claims = {};
+        Object.entries(account.editable_claims).forEach(([key, entry]) => {
+            const value = readClaimField(key, entry.definition);
+            if (JSON.stringify(value) !== JSON.stringify(entry.value)) {
+                claims[key] = value;
+            }
+        });
+        if (Object.keys(claims).length === 0) {
+            return;
+        }
+
+        const result = await api('PATCH', '/api/account/claims', { claims });
+        if (result && result.success) {
+            showNotice('Profil gespeichert');
+            loadAccount();
+        } else if (result) {
+            showError((result.errors || [result.error]).join('. '));
+        }
+    });
+
+    document.getElementById('passwordForm').addEventListener('submit', async function(e) {
+        e.preventDefault();
+        hideMessages();
+
+        const newPassword = document.getElementById('newPassword').value;
+        if (newPassword !== document.getElementById('newPasswordRepeat').value) {
+            showError('Die Passwörter stimmen nicht überein');
+            return;
+        }
+
+        const result = await api('POST', '/api/account/password', {
+            current_password: document.getElementById('currentPassword').value,
+            new_password: newPassword
+        });
+        if (result && result.success) {
+            // Every session has ended, this one included
+            localStorage.removeItem('auth_token');
+            alert('Passwort geändert. Bitte melden Sie sich mit dem neuen Passwort an.');
+            toLogin();
+        } else if (result) {
+            showError(result.error === 'invalid_password' ? 'Das aktuelle Passwort ist falsch' : result.message || result.error);
+        }
+    });
+
+    document.getElementById('totpEnrollStart').addEventListener('click', async function() {
+        hideMessages();
+        const enrollment = await api('POST', '/api/auth/mfa/enroll', {});
+        if (!enrollment || !enrollment.provisioning_uri) {
+            showError('Einrichtung der Zwei-Faktor-Authentifizierung fehlgeschlagen');
+            return;
+        }
+        document.getElementById('totpProvisioningUri').href = enrollment.provisioning_uri;
+        document.getElementById('totpSecret').textContent = enrollment.secret;
+        document.getElementById('totpEnrollSteps').style.display = 'block';
+        document.getElementById('totpEnrollStart').style.display = 'none';
+        document.getElementById('totpCode').focus();
+    });
+
+    document.getElementById('totpEnrollForm').addEventListener('submit', async function(e) {
+        e.preventDefault();
+        hideMessages();
+        const result = await api('POST', '/api/auth/mfa/enroll/confirm', {
+            code: document.getElementById('totpCode').value.trim()
+        });
+        if (result && result.success) {
+            pendingRecoveryCodes = result.recovery_codes;
+            document.getElementById('totpCode').value = '';
+            showNotice('Authenticator-App eingerichtet');
+            loadAccount();
+        } else if (result) {
+            showError('Der Code ist ungültig');
+        }
+    });
+
+    document.getElementById('totpRemove').addEventListener('click', async function() {
+        if (!confirm('Authenticator-App und Wiederherstellungscodes wirklich entfernen?')) {
+            return;
+        }
+        const result = await api('DELETE', '/api/account/mfa/totp');
+        if (result && result.success) {
+            showNotice('Authenticator-App entfernt');
+            loadAccount();
+        } else if (result) {
+            factorRemovalFailed(result.error);
+        }
+    });
+
+    document.getElementById('passkeyForm').addEventListener('submit', async function(e) {
+        e.preventDefault();
+        hideMessages();
+        if (!window.PublicKeyCredential) {
+            showError('Ihr Browser unterstützt keine Passkeys');
+            return;
+        }
+
+        try {
+            const options = await api('POST', '/api/auth/webauthn/register/start', {
+                nickname: document.getElementById('passkeyNickname').value.trim()
+            });
+            if (!options || !options.publicKey) {
+                showError('Passkey-Registrierung fehlgeschlagen');
+                return;
+            }
+            const credential = await navigator.credentials.create(decodeCreationOptions(options));
+            const result = await api('POST', '/api/auth/webauthn/register/finish', {
+                credential: encodeCredential(credential)
+            });
+            if (result && result.success) {
+                document.getElementById('passkeyNickname').value = '';
+                showNotice('Passkey hinzugefügt');
+                loadAccount();
+            } else if (result) {
+                showError('Passkey-Registrierung fehlgeschlagen');
+            }
+        } catch (error) {
+            console.error('Passkey error:', error);
+            showError('Passkey-Registrierung abgebrochen oder fehlgeschlagen');
+        }
+    });
+
+    document.getElementById('sessionsRevokeOthers').addEventListener('click', async function() {
+        const result = await api('DELETE', '/api/account/sessions');
+        if (result && result.success) {
+            showNotice(`${result.ended_sessions} Sitzung(en) beendet`);
+            loadSessions();
+        }
+    });
+
+    async function loadAccount() {
+        account = await api('GET', '/api/account');
+        if (!account) {
+            return;
+        }
+
+        document.getElementById('accountEmail').textContent = account.email;
+        document.getElementById('accountName').textContent = `${account.first_name} ${account.last_name} (${account.org})`;
+        renderClaimFields();
+
+        document.getElementById('passwordForm').style.display = account.password.can_change ? 'block' : 'none';
+        const unavailable = document.getElementById('passwordUnavailable');
+        unavailable.style.display = account.password.can_change ? 'none' : 'block';
+        unavailable.textContent = 'Das Passwort dieses Kontos wird nicht hier verwaltet.';
+
+        document.getElementById('totpEnrolled').style.display = account.mfa.totp ? 'block' : 'none';
+        document.getElementById('totpEnrollForm').style.display = account.mfa.totp ? 'none' : 'block';
+        document.getElementById('totpEnrollSteps').style.display = 'none';
+        document.getElementById('totpEnrollStart').style.display = 'block';
+        document.getElementById('recoveryCodesRemaining').textContent =
+            `Verbleibende Wiederherstellungscodes: ${account.mfa.recovery_codes_remaining}`;
+
+        // Fresh recovery codes are shown once, right after the enrollment
+        const codeList = document.getElementById('recoveryCodeList');
+        codeList.style.display = pendingRecoveryCodes ? 'block' : 'none';
+        codeList.textContent = pendingRecoveryCodes
+            ? 'Bewahren Sie diese Wiederherstellungscodes sicher auf:\n' + pendingRecoveryCodes.join('\n')
+            : '';
+        pendingRecoveryCodes = null;
+
+        renderList('passkeyList', account.mfa.passkeys, passkey => ({
+            text: `${passkey.nickname}, hinzugefügt ${formatTime(passkey.created_at)}`
+                + (passkey.last_used_at ? `, zuletzt verwendet ${formatTime(passkey.last_used_at)}` : ''),
+            action: 'Entfernen',
+            onAction: async () => {
+                const result = await api('DELETE', `/api/account/passkeys/${encodeURIComponent(passkey.credential_id)}`);
+                if (result && result.success) {
+                    showNotice('Passkey entfernt');
+                    loadAccount();
+                } else if (result) {
+                    factorRemovalFailed(result.error);
+                }
+            }
+        }), 'Keine Passkeys registriert');
+    }
+
+    async function loadSessions() {
+        const sessions = await api('GET', '/api/account/sessions');
+        if (!sessions) {
+            return;
+        }
+        renderList('sessionList', sessions, session => ({
+            text: `${session.user_agent || 'Unbekanntes Gerät'} (${session.ip_address || 'unbekannte Adresse'}), `
+                + `angemeldet ${formatTime(session.created_at)}`
+                + (session.current ? ' – dieses Gerät' : ''),
+            action: session.current ? null : 'Abmelden',
+            onAction: async () => {
+                if (await api('DELETE', `/api/account/sessions/${encodeURIComponent(session.id)}`) !== null) {
+                    loadSessions();
+                }
+            }
+        }), 'Keine aktiven Sitzungen');
+    }
+
+    async function loadConsents() {
+        const consents = await api('GET', '/api/account/consents');
+        if (!consents) {
+            return;
+        }
+        renderList('consentList', consents, consent => ({
+            text: `${consent.client_name || consent.client_id} (${consent.scope}), zuletzt ${formatTime(consent.last_used_at)}`,
+            action: 'Zugriff entziehen',
+            onAction: async () => {
+                if (!confirm(`Zugriff für ${consent.client_name || consent.client_id} entziehen?`)) {
+                    return;
+                }
+                if (await api('DELETE', `/api/account/consents/${encodeURIComponent(consent.client_id)}`) !== null) {
+                    loadConsents();
+                }
+            }
+        }), 'Keine Anwendungen');
+    }
+
+    async function loadLogins() {
+        const logins = await api('GET', '/api/account/logins');
+        if (!logins) {
+            return;
+        }
+        renderList('loginList', logins, login => ({
+            text: `${formatTime(login.at)}: ${login.success ? 'Anmeldung' : 'Fehlgeschlagen'}`
+                + (login.methods.length ? ` (${login.methods.join(', ')})` : '')
+                + ` von ${login.ip_address || 'unbekannter Adresse'}`
+        }), 'Keine Anmeldungen');
+    }
+
+    function renderClaimFields() {
+        const container = document.getElementById('claimFields');
+        container.innerHTML = '';
+        const entries = Object.entries(account.editable_claims);
+        document.getElementById('claimsSave').style.display = entries.length ? 'block' : 'none';
+
+        entries.sort(([a], [b]) => a.localeCompare(b)).forEach(([key, entry]) => {
+            const group = document.createElement('div');
+            group.className = 'form-group';
+            const label = document.createElement('label');
+            label.htmlFor = `claim-${key}`;
+            label.textContent = entry.definition.description || key;
+            group.appendChild(label);
+
+            const choices = entry.definition.items && entry.definition.items.enum;
+            let input;
+            if (entry.definition.type === 'boolean') {
+                input = document.createElement('input');
+                input.type = 'checkbox';
+                input.checked = entry.value === true;
+            } else if (choices && entry.definition.type !== 'array') {
+                input = document.createElement('select');
+                ['', ...choices].forEach(choice => {
+                    const option = document.createElement('option');
+                    option.value = choice;
+                    option.textContent = choice;
+                    input.appendChild(option);
+                });
+                input.value = entry.value ?? '';
+            } else {
+                input = document.createElement('input');
+                input.type = entry.definition.type === 'number' ? 'number' : 'text';
+                input.value = Array.isArray(entry.value) ? entry.value.join(', ') : (entry.value ?? '');
+                if (entry.definition.type === 'array') {
+                    input.placeholder = 'Mehrere Werte durch Komma trennen';
+                }
+            }
+            input.id = `claim-${key}`;
+            group.appendChild(input);
+            container.appendChild(group);
+        });
+    }
+
+    // Empty fields remove the claim
+    function readClaimField(key, definition) {
+        const input = document.getElementById(`claim-${key}`);
+        switch (definition.type) {
+            case 'boolean':
+                return input.checked;
+            case 'number':
+                return input.value === '' ? null : Number(input.value);
+            case 'array':
+                return input.value.trim() === '' ? null : input.value.split(',').map(v => v.trim()).filter(v => v);
+            default:
+                return input.value === '' ? null : input.value;
+        }
+    }
+
+    function renderList(id, items, describe, emptyText) {
+        const list = document.getElementById(id);
+        list.innerHTML = '';
+        if (items.length === 0) {
+            const item = document.createElement('li');
+            item.textContent = emptyText;
+            list.appendChild(item);
+            return;
+        }
+        items.forEach(entry => {
+            const { text, action, onAction } = describe(entry);
+            const item = document.createElement('li');
+            item.textContent = text;
+            if (action) {
+                const button = document.createElement('button');
+                button.type = 'button';
+                button.className = 'link-btn';
+                button.textContent = action;
+                button.onclick = onAction;
+                item.appendChild(button);
+            }
+            list.appendChild(item);
+        });
+    }
+
+    // Removing a factor needs a fresh login; the login page passes the
+    // second factor again and sends the user back here
+    function factorRemovalFailed(error) {
+        if (error === 'reauthentication_required') {
+            window.location.href = '/?step_up=1&redirect=' + encodeURIComponent(accountUrl);
+        } else if (error === 'mfa_required') {
+            showError('Ihr Konto benötigt mindestens einen zweiten Faktor. Richten Sie zuerst einen anderen ein.');
+        } else {
+            showError(error || 'Entfernen fehlgeschlagen');
+        }
+    }
+
+    // JSON of a successful answer, `{}` for one without body; `null` after
+    // an error, which is shown. An ended session leads back to the login.
+    async function api(method, path, body) {
+        try {
+            const response = await fetch(path, {
+                method,
+                headers: {
+                    'Authorization': 'Bearer ' + token,
+                    ...(body ? { 'Content-Type': 'application/json' } : {})
+                },
+                body: body ? JSON.stringify(body) : undefined
+            });
+            if (response.status === 401) {
+                localStorage.removeItem('auth_token');
+                toLogin();
+                return null;
+            }
+            if (!response.ok) {
+                showError(response.status === 403 ? 'Diese Sitzung darf das Konto nicht verwalten' : 'Anfrage fehlgeschlagen');
+                return null;
+            }
+            return response.status === 204 ? {} : await response.json();
+        } catch (error) {
+            showError('Verbindungsfehler');
+            return null;
+        }
+    }
+
+    function toLogin() {
+        window.location.href = '/?redirect=' + encodeURIComponent(accountUrl);
+    }
+
+    // WebAuthn options and results carry binary fields as base64url strings
+    function base64UrlToBuffer(value) {
+        const base64 = value.replace(/-/g, '+').replace(/_/g, '/');
+        const padded = base64 + '='.repeat((4 - base64.length % 4) % 4);
+        return Uint8Array.from(atob(padded), c => c.charCodeAt(0)).buffer;
+    }
+
+    function bufferToBase64Url(buffer) {
+        const bytes = String.fromCharCode(...new Uint8Array(buffer));
+        return btoa(bytes).replace(/\+/g, '-').replace(/\//g, '_').replace(/=+$/, '');
+    }
+
+    function decodeCreationOptions(options) {
+        const publicKey = options.publicKey;
+        publicKey.challenge = base64UrlToBuffer(publicKey.challenge);
+        publicKey.user.id = base64UrlToBuffer(publicKey.user.id);
+        publicKey.excludeCredentials = publicKey.excludeCredentials.map(c => ({ ...c, id: base64UrlToBuffer(c.id) }));
+        return { publicKey };
+    }
+
+    function encodeCredential(credential) {
+        const response = credential.response;
+        return {
+            id: credential.id,
+            rawId: bufferToBase64Url(credential.rawId),
+            type: credential.type,
+            response: {
+                clientDataJSON: bufferToBase64Url(response.clientDataJSON),
+                attestationObject: bufferToBase64Url(response.attestationObject),
+                transports: response.getTransports ? response.getTransports() : []
+            }
+        };
+    }
+
+    function formatTime(value) {
+        const date = typeof value === 'number' ? new Date(value * 1000) : new Date(value);
+        return date.toLocaleString('de-DE', { dateStyle: 'short', timeStyle: 'short' });
+    }
+
+    function showError(message) {
+        errorMessage.textContent = message;
+        errorMessage.style.display = 'block';
+        noticeMessage.style.display = 'none';
+    }
+
+    function showNotice(message) {
+        noticeMessage.textContent = message;
+        noticeMessage.style.display = 'block';
+        errorMessage.style.display = 'none';
+    }
+
+    function hideMessages() {
+        errorMessage.style.display = 'none';
+        noticeMessage.style.display = 'none';
+    }
+});
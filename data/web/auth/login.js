@@ -2,22 +2,296 @@
 
 document.addEventListener('DOMContentLoaded', function() {
     const loginForm = document.getElementById('loginForm');
+    const mfaForm = document.getElementById('mfaForm');
     const errorMessage = document.getElementById('errorMessage');
     const loginBtn = document.querySelector('.login-btn');
+    const mfaBtn = document.querySelector('.mfa-btn');
+    const passkeyButtons = document.querySelectorAll('.passkey-btn');
 
-    // Check for OAuth2 parameters in URL
+    // /oauth2/authorize sends the user here with ?authorize=<request>;
+    // client_id and scope are only shown
     const urlParams = new URLSearchParams(window.location.search);
+    const authorizeRequest = urlParams.get('authorize');
     const clientId = urlParams.get('client_id');
-    const redirectUri = urlParams.get('redirect_uri');
-    const responseType = urlParams.get('response_type');
     const scope = urlParams.get('scope');
-    const state = urlParams.get('state');
 
     // If this is an OAuth2 authorization request, show different UI
-    if (clientId && redirectUri && responseType) {
+    if (authorizeRequest && clientId) {
         showOAuth2Flow();
     }
 
+    // Reset links carry the token in the fragment: #reset_token=...
+    const resetToken = new URLSearchParams(window.location.hash.slice(1)).get('reset_token');
+    if (resetToken) {
+        history.replaceState(null, '', window.location.pathname + window.location.search);
+        showOnly('resetForm');
+    }
+
+    // Confirmation links for new accounts: #verify_token=...
+    const verifyToken = new URLSearchParams(window.location.hash.slice(1)).get('verify_token');
+    if (verifyToken) {
+        history.replaceState(null, '', window.location.pathname + window.location.search);
+        postJson('/api/auth/verify-email', { token: verifyToken }).then(result => {
+            if (result.success) {
+                alert('Ihre E-Mail-Adresse wurde bestätigt. Sie können sich jetzt anmelden.');
+            } else {
+                showError('Der Bestätigungslink ist ungültig oder abgelaufen.');
+            }
+        });
+    }
+
+    // Federated logins come back with #federation=<code> for their result,
+    // or #federation_error=<reason> when the provider's login did not count
+    const fragment = new URLSearchParams(window.location.hash.slice(1));
+    if (fragment.get('federation') || fragment.get('federation_error')) {
+        history.replaceState(null, '', window.location.pathname + window.location.search);
+    }
+    if (fragment.get('federation')) {
+        postJson('/api/auth/federation/complete', { code: fragment.get('federation') }).then(async result => {
+            if (result.success && result.requires_mfa) {
+                await showMfaStep(result);
+            } else if (result.success) {
+                await completeLogin(result);
+            } else {
+                showError('Die Anmeldung ist abgelaufen. Bitte erneut versuchen.');
+            }
+        });
+    } else if (fragment.get('federation_error')) {
+        showError(loginErrorMessage(fragment.get('federation_error')));
+    }
+
+    // Admins acting as a user arrive with #impersonation=<token>; the page
+    // keeps it as its session and says so for as long as it lasts
+    if (fragment.get('impersonation')) {
+        history.replaceState(null, '', window.location.pathname + window.location.search);
+        localStorage.setItem('auth_token', fragment.get('impersonation'));
+    }
+    showImpersonationBanner(localStorage.getItem('auth_token'));
+
+    // One "Anmelden mit ..." button per upstream identity provider; they
+    // return to this page with the same query, so OAuth2 flows continue
+    fetch('/api/auth/federation/providers').then(async response => {
+        if (!response.ok) {
+            return;
+        }
+        const container = document.getElementById('federationProviders');
+        (await response.json()).providers.forEach(provider => {
+            const link = document.createElement('a');
+            link.className = 'login-btn federation-btn';
+            link.textContent = `Anmelden mit ${provider.name}`;
+            link.href = `/api/auth/federation/${encodeURIComponent(provider.id)}/start?return_to=`
+                + encodeURIComponent(window.location.search.slice(1));
+            container.appendChild(link);
+        });
+    }).catch(error => console.error('Provider lookup failed:', error));
+
+    // Apps that need a stronger or more recent login send the user back with
+    // ?step_up=1: the existing session only has to pass its second factor again
+    const sessionToken = localStorage.getItem('auth_token');
+    if (urlParams.get('step_up') && sessionToken) {
+        postJson('/api/auth/step-up', {}, { 'Authorization': 'Bearer ' + sessionToken }).then(async result => {
+            if (result.success && result.requires_mfa) {
+                await showMfaStep(result);
+            }
+            // Otherwise the session has ended and a full login follows
+        });
+    }
+
+    // SAML service providers send the user here with ?saml=<request>; an
+    // existing session answers it right away, otherwise the login does
+    const samlRequest = urlParams.get('saml');
+    if (samlRequest && sessionToken) {
+        answerSamlRequest(sessionToken);
+    }
+
+    // Authorization requests likewise
+    if (authorizeRequest && sessionToken) {
+        answerAuthorizationRequest(sessionToken);
+    }
+
+    // Organizations open for self-registration; the link stays hidden without any
+    let registrationOrgs = [];
+    fetch('/api/auth/register/organizations').then(async response => {
+        if (!response.ok) {
+            return;
+        }
+        registrationOrgs = (await response.json()).organizations;
+        if (registrationOrgs.length === 0) {
+            return;
+        }
+        const select = document.getElementById('registerOrg');
+        registrationOrgs.forEach(org => {
+            const option = document.createElement('option');
+            option.value = org.id;
+            option.textContent = org.name;
+            select.appendChild(option);
+        });
+        updateInviteCodeField();
+        document.getElementById('registerLinkRow').style.display = 'block';
+    }).catch(error => console.error('Registration lookup failed:', error));
+
+    function updateInviteCodeField() {
+        const org = registrationOrgs.find(o => o.id === document.getElementById('registerOrg').value);
+        document.getElementById('inviteCodeGroup').style.display = org && org.invite_code_required ? 'block' : 'none';
+    }
+
+    // Second factor state after a successful password step
+    let mfaSession = null;
+    let mfaEnrollment = false;
+
+    if (!window.PublicKeyCredential) {
+        document.getElementById('passkeyLoginBtn').style.display = 'none';
+    }
+
+    // Passwordless login with a discoverable passkey
+    document.getElementById('passkeyLoginBtn').addEventListener('click', async function() {
+        await runPasskeyCeremony(async function() {
+            const options = await postJson('/api/auth/webauthn/authenticate/start', {});
+            const credential = await navigator.credentials.get(decodeRequestOptions(options));
+            return postJson('/api/auth/webauthn/authenticate/finish', {
+                credential: encodeCredential(credential)
+            });
+        });
+    });
+
+    // Passkey as second factor, or its registration during login
+    document.getElementById('passkeyMfaBtn').addEventListener('click', async function() {
+        await runPasskeyCeremony(async function() {
+            if (mfaEnrollment) {
+                const nickname = prompt('Name für diesen Passkey (z.B. "Laptop")');
+                if (!nickname) {
+                    return null;
+                }
+                const options = await postJson('/api/auth/webauthn/register/start', {
+                    mfa_session: mfaSession,
+                    nickname: nickname
+                });
+                const credential = await navigator.credentials.create(decodeCreationOptions(options));
+                return postJson('/api/auth/webauthn/register/finish', {
+                    mfa_session: mfaSession,
+                    credential: encodeCredential(credential)
+                });
+            }
+
+            const options = await postJson('/api/auth/webauthn/authenticate/start', { mfa_session: mfaSession });
+            const credential = await navigator.credentials.get(decodeRequestOptions(options));
+            return postJson('/api/auth/webauthn/authenticate/finish', {
+                mfa_session: mfaSession,
+                credential: encodeCredential(credential)
+            });
+        });
+    });
+
+    document.getElementById('forgotPasswordLink').addEventListener('click', function(e) {
+        e.preventDefault();
+        hideError();
+        document.getElementById('forgotEmail').value = document.getElementById('email').value;
+        showOnly('forgotForm');
+    });
+
+    document.getElementById('registerLink').addEventListener('click', function(e) {
+        e.preventDefault();
+        hideError();
+        showOnly('registerForm');
+    });
+
+    document.getElementById('registerOrg').addEventListener('change', updateInviteCodeField);
+
+    document.getElementById('registerForm').addEventListener('submit', async function(e) {
+        e.preventDefault();
+
+        try {
+            setLoading(true);
+            hideError();
+            const result = await postJson('/api/auth/register', {
+                org: document.getElementById('registerOrg').value,
+                invite_code: document.getElementById('inviteCode').value || null,
+                first_name: document.getElementById('firstName').value,
+                last_name: document.getElementById('lastName').value,
+                email: document.getElementById('registerEmail').value,
+                password: document.getElementById('registerPassword').value
+            });
+            if (result.success) {
+                const done = document.getElementById('registerDone');
+                done.textContent = 'Fast geschafft: Bitte bestätigen Sie Ihre E-Mail-Adresse über den Link, den wir Ihnen geschickt haben.';
+                done.style.display = 'block';
+            } else {
+                showError(result.message || result.error || 'Die Registrierung ist fehlgeschlagen');
+            }
+        } catch (error) {
+            console.error('Registration error:', error);
+            showError('Verbindungsfehler. Bitte versuchen Sie es erneut.');
+        } finally {
+            setLoading(false);
+        }
+    });
+
+    document.querySelectorAll('.back-to-login').forEach(link => {
+        link.addEventListener('click', function(e) {
+            e.preventDefault();
+            hideError();
+            showOnly('loginForm');
+        });
+    });
+
+    document.getElementById('forgotForm').addEventListener('submit', async function(e) {
+        e.preventDefault();
+
+        const email = document.getElementById('forgotEmail').value;
+        if (!email) {
+            showError('Bitte E-Mail eingeben');
+            return;
+        }
+
+        try {
+            setLoading(true);
+            hideError();
+            const result = await postJson('/api/auth/forgot-password', { email: email });
+            if (result.success) {
+                const done = document.getElementById('forgotDone');
+                done.textContent = 'Falls die Adresse bei uns bekannt ist, wurde ein Link zum Zurücksetzen verschickt.';
+                done.style.display = 'block';
+            } else {
+                showError('Das Zurücksetzen des Passworts ist nicht möglich.');
+            }
+        } catch (error) {
+            console.error('Forgot password error:', error);
+            showError('Verbindungsfehler. Bitte versuchen Sie es erneut.');
+        } finally {
+            setLoading(false);
+        }
+    });
+
+    document.getElementById('resetForm').addEventListener('submit', async function(e) {
+        e.preventDefault();
+
+        const newPassword = document.getElementById('newPassword').value;
+        if (newPassword !== document.getElementById('newPasswordRepeat').value) {
+            showError('Die Passwörter stimmen nicht überein');
+            return;
+        }
+
+        try {
+            setLoading(true);
+            hideError();
+            const result = await postJson('/api/auth/reset-password', {
+                token: resetToken,
+                new_password: newPassword
+            });
+            if (result.success) {
+                showOnly('loginForm');
+                alert('Ihr Passwort wurde geändert. Bitte melden Sie sich mit dem neuen Passwort an.');
+            } else {
+                showError(result.message || result.error || 'Das Passwort konnte nicht gesetzt werden');
+            }
+        } catch (error) {
+            console.error('Reset password error:', error);
+            showError('Verbindungsfehler. Bitte versuchen Sie es erneut.');
+        } finally {
+            setLoading(false);
+        }
+    });
+
     loginForm.addEventListener('submit', async function(e) {
         e.preventDefault();
 
@@ -34,33 +308,17 @@ document.addEventListener('DOMContentLoaded', function() {
             hideError();
 
             // Attempt login
-            const loginResult = await performLogin(email, password);
-
-            if (loginResult.success) {
-                // Store the access token for admin service access
-                if (loginResult.token) {
-                    localStorage.setItem('auth_token', loginResult.token);
-                }
+            const loginResult = await postJson('/api/auth/login', {
+                email: email,
+                password: password
+            });
 
-                // If this is an OAuth2 flow, proceed with authorization
-                if (clientId && redirectUri && responseType) {
-                    await handleOAuth2Authorization(loginResult.token);
-                } else {
-                    // Check if there's a redirect parameter
-                    const redirectUrl = urlParams.get('redirect');
-                    if (redirectUrl) {
-                        // Add token to URL and redirect
-                        const url = new URL(redirectUrl);
-                        url.searchParams.set('token', loginResult.token);
-                        window.location.href = url.toString();
-                    } else {
-                        // Use backend-provided redirect URL
-                        const redirectTo = loginResult.redirect_to || 'https://localhost:8445/';
-                        window.location.href = redirectTo;
-                    }
-                }
+            if (loginResult.success && loginResult.requires_mfa) {
+                await showMfaStep(loginResult);
+            } else if (loginResult.success) {
+                await completeLogin(loginResult);
             } else {
-                showError(loginResult.error || 'Login fehlgeschlagen');
+                showError(loginErrorMessage(loginResult.error) || 'Login fehlgeschlagen');
             }
         } catch (error) {
             console.error('Login error:', error);
@@ -70,30 +328,58 @@ document.addEventListener('DOMContentLoaded', function() {
         }
     });
 
-    async function performLogin(email, password) {
+    mfaForm.addEventListener('submit', async function(e) {
+        e.preventDefault();
+
+        const code = document.getElementById('mfaCode').value.trim();
+        if (!code) {
+            showError('Bitte Bestätigungscode eingeben');
+            return;
+        }
+
         try {
-            // Since we don't have a direct login endpoint in the current API,
-            // we'll simulate the login process and check if the credentials are valid
-            // by attempting to get user info or checking against the OAuth2 flow
+            setLoading(true);
+            hideError();
+
+            const endpoint = mfaEnrollment ? '/api/auth/mfa/enroll/confirm' : '/api/auth/mfa/verify';
+            const result = await postJson(endpoint, {
+                mfa_session: mfaSession,
+                code: code
+            });
 
-            const response = await fetch('/api/auth/login', {
+            if (result.success) {
+                await completeLogin(result);
+            } else if (result.mfa_session) {
+                document.getElementById('mfaCode').value = '';
+                showError('Ungültiger Code. Bitte erneut versuchen.');
+            } else {
+                // Session used up or expired: start over with the password
+                resetToPasswordStep();
+                showError('Zu viele Fehlversuche. Bitte erneut anmelden.');
+            }
+        } catch (error) {
+            console.error('MFA error:', error);
+            showError('Verbindungsfehler. Bitte versuchen Sie es erneut.');
+        } finally {
+            setLoading(false);
+        }
+    });
+
+    async function postJson(path, body, headers = {}) {
+        try {
+            const response = await fetch(path, {
                 method: 'POST',
                 headers: {
                     'Content-Type': 'application/json',
+                    ...headers
                 },
-                body: JSON.stringify({
-                    email: email,
-                    password: password
-                })
+                body: JSON.stringify(body)
             });
 
             if (response.ok) {
-                const tokenData = await response.json();
-                return {
-                    success: true,
-                    token: tokenData.access_token,
-                    redirect_to: tokenData.redirect_to
-                };
+                return await response.json();
+            } else if (response.status === 429) {
+                return { success: false, error: 'too_many_requests' };
             } else {
                 const errorData = await response.json().catch(() => ({}));
                 return { success: false, error: errorData.error_description || 'Ungültige Anmeldedaten' };
@@ -103,20 +389,274 @@ document.addEventListener('DOMContentLoaded', function() {
         }
     }
 
-    async function handleOAuth2Authorization(accessToken) {
+    async function runPasskeyCeremony(ceremony) {
         try {
-            // Build authorization URL
-            const authUrl = new URL('/oauth2/authorize', window.location.origin);
-            authUrl.searchParams.set('response_type', responseType);
-            authUrl.searchParams.set('client_id', clientId);
-            authUrl.searchParams.set('redirect_uri', redirectUri);
-            if (scope) authUrl.searchParams.set('scope', scope);
-            if (state) authUrl.searchParams.set('state', state);
-
-            // Redirect to authorization endpoint with user context
-            window.location.href = authUrl.toString();
+            setLoading(true);
+            hideError();
+
+            const result = await ceremony();
+            if (!result) {
+                return;
+            }
+            if (result.success) {
+                await completeLogin(result);
+            } else if (mfaSession && !result.mfa_session) {
+                resetToPasswordStep();
+                showError('Zu viele Fehlversuche. Bitte erneut anmelden.');
+            } else {
+                showError(loginErrorMessage(result.error) || 'Passkey-Anmeldung fehlgeschlagen');
+            }
         } catch (error) {
-            showError('OAuth2 Autorisierung fehlgeschlagen');
+            // The browser dialog was cancelled or no matching passkey exists
+            console.error('Passkey error:', error);
+            showError('Passkey-Anmeldung abgebrochen oder fehlgeschlagen');
+        } finally {
+            setLoading(false);
+        }
+    }
+
+    async function showMfaStep(loginResult) {
+        mfaSession = loginResult.mfa_session;
+        mfaEnrollment = loginResult.mfa_enrollment_required;
+        const methods = loginResult.mfa_methods || ['totp'];
+        const totpAllowed = methods.includes('totp');
+
+        document.getElementById('totpStep').style.display = totpAllowed ? 'block' : 'none';
+        document.getElementById('mfaCode').required = totpAllowed;
+        if (methods.includes('webauthn') && window.PublicKeyCredential) {
+            document.getElementById('passkeyHint').textContent = mfaEnrollment
+                ? 'Oder registrieren Sie einen Passkey für dieses Konto:'
+                : 'Oder bestätigen Sie die Anmeldung mit Ihrem Passkey:';
+            document.getElementById('passkeyStep').style.display = 'block';
+        } else if (!totpAllowed) {
+            showError('Dieses Konto erfordert einen Passkey, den Ihr Browser nicht unterstützt');
+            return;
+        }
+
+        if (mfaEnrollment && totpAllowed) {
+            const enrollment = await postJson('/api/auth/mfa/enroll', { mfa_session: mfaSession });
+            if (!enrollment.provisioning_uri) {
+                showError('Einrichtung der Zwei-Faktor-Authentifizierung fehlgeschlagen');
+                return;
+            }
+            document.getElementById('mfaProvisioningUri').href = enrollment.provisioning_uri;
+            document.getElementById('mfaSecret').textContent = enrollment.secret;
+            document.getElementById('mfaEnrollment').style.display = 'block';
+        }
+
+        loginForm.style.display = 'none';
+        mfaForm.style.display = 'block';
+        if (totpAllowed) {
+            document.getElementById('mfaCode').focus();
+        }
+    }
+
+    // WebAuthn options and results carry binary fields as base64url strings
+    function base64UrlToBuffer(value) {
+        const base64 = value.replace(/-/g, '+').replace(/_/g, '/');
+        const padded = base64 + '='.repeat((4 - base64.length % 4) % 4);
+        return Uint8Array.from(atob(padded), c => c.charCodeAt(0)).buffer;
+    }
+
+    function bufferToBase64Url(buffer) {
+        const bytes = String.fromCharCode(...new Uint8Array(buffer));
+        return btoa(bytes).replace(/\+/g, '-').replace(/\//g, '_').replace(/=+$/, '');
+    }
+
+    function decodeCreationOptions(options) {
+        const publicKey = options.publicKey;
+        publicKey.challenge = base64UrlToBuffer(publicKey.challenge);
+        publicKey.user.id = base64UrlToBuffer(publicKey.user.id);
+        publicKey.excludeCredentials = publicKey.excludeCredentials.map(c => ({ ...c, id: base64UrlToBuffer(c.id) }));
+        return { publicKey };
+    }
+
+    function decodeRequestOptions(options) {
+        const publicKey = options.publicKey;
+        publicKey.challenge = base64UrlToBuffer(publicKey.challenge);
+        publicKey.allowCredentials = publicKey.allowCredentials.map(c => ({ ...c, id: base64UrlToBuffer(c.id) }));
+        return { publicKey };
+    }
+
+    function encodeCredential(credential) {
+        const response = credential.response;
+        const encoded = {
+            id: credential.id,
+            rawId: bufferToBase64Url(credential.rawId),
+            type: credential.type,
+            response: {
+                clientDataJSON: bufferToBase64Url(response.clientDataJSON)
+            }
+        };
+        if (response.attestationObject) {
+            encoded.response.attestationObject = bufferToBase64Url(response.attestationObject);
+            encoded.response.transports = response.getTransports ? response.getTransports() : [];
+        } else {
+            encoded.response.authenticatorData = bufferToBase64Url(response.authenticatorData);
+            encoded.response.signature = bufferToBase64Url(response.signature);
+            encoded.response.userHandle = response.userHandle ? bufferToBase64Url(response.userHandle) : null;
+        }
+        return encoded;
+    }
+
+    function showOnly(formId) {
+        ['loginForm', 'forgotForm', 'resetForm', 'registerForm', 'mfaForm'].forEach(id => {
+            document.getElementById(id).style.display = id === formId ? 'block' : 'none';
+        });
+    }
+
+    function resetToPasswordStep() {
+        mfaSession = null;
+        mfaEnrollment = false;
+        mfaForm.style.display = 'none';
+        document.getElementById('mfaEnrollment').style.display = 'none';
+        document.getElementById('passkeyStep').style.display = 'none';
+        document.getElementById('mfaCode').value = '';
+        loginForm.style.display = 'block';
+        document.getElementById('password').value = '';
+        document.getElementById('password').focus();
+    }
+
+    async function completeLogin(result) {
+        // Fresh recovery codes are shown once; continue after the user saved them
+        if (result.recovery_codes) {
+            mfaForm.style.display = 'none';
+            document.getElementById('recoveryCodeList').textContent = result.recovery_codes.join('\n');
+            document.getElementById('recoveryCodes').style.display = 'block';
+            document.getElementById('recoveryCodesDone').onclick = function() {
+                completeLogin({ ...result, recovery_codes: null });
+            };
+            return;
+        }
+
+        if (result.recovery_codes_remaining !== undefined && result.recovery_codes_remaining !== null) {
+            alert(`Wiederherstellungscode verwendet. Verbleibende Codes: ${result.recovery_codes_remaining}`);
+        }
+
+        const token = result.access_token;
+
+        // Store the access token for admin service access
+        if (token) {
+            localStorage.setItem('auth_token', token);
+        }
+
+        if (samlRequest) {
+            await answerSamlRequest(token);
+        } else if (authorizeRequest) {
+            await answerAuthorizationRequest(token);
+        } else {
+            // Check if there's a redirect parameter
+            const redirectUrl = urlParams.get('redirect');
+            if (redirectUrl) {
+                // Add token to URL and redirect
+                const url = new URL(redirectUrl);
+                url.searchParams.set('token', token);
+                window.location.href = url.toString();
+            } else {
+                // Use backend-provided redirect URL
+                const redirectTo = result.redirect_to || 'https://localhost:8445/';
+                window.location.href = redirectTo;
+            }
+        }
+    }
+
+    // Send the user back to the client with a code. Without a usable
+    // session (401) the login form stays for the user to log in.
+    async function answerAuthorizationRequest(accessToken) {
+        try {
+            const response = await fetch('/api/oauth2/authorize/complete', {
+                method: 'POST',
+                headers: {
+                    'Content-Type': 'application/json',
+                    'Authorization': 'Bearer ' + accessToken
+                },
+                body: JSON.stringify({ request: authorizeRequest })
+            });
+            if (response.status === 401) {
+                return;
+            }
+            if (response.status === 403) {
+                showError('Im Auftrag eines anderen Benutzers ist diese Anmeldung nicht möglich.');
+                return;
+            }
+            if (!response.ok) {
+                showError('Die Anmeldeanfrage ist abgelaufen. Bitte erneut über die Anwendung anmelden.');
+                return;
+            }
+
+            const result = await response.json();
+            window.location.href = result.redirect_uri;
+        } catch (error) {
+            showError('Verbindungsfehler');
+        }
+    }
+
+    // Post the signed SAML response to the service provider. Without a
+    // usable session (401) the login form stays for the user to log in.
+    async function answerSamlRequest(accessToken) {
+        try {
+            const response = await fetch('/api/saml/sso/complete', {
+                method: 'POST',
+                headers: {
+                    'Content-Type': 'application/json',
+                    'Authorization': 'Bearer ' + accessToken
+                },
+                body: JSON.stringify({ request: samlRequest })
+            });
+            if (response.status === 401) {
+                return;
+            }
+            if (response.status === 403) {
+                showError('Im Auftrag eines anderen Benutzers ist diese Anmeldung nicht möglich.');
+                return;
+            }
+            if (!response.ok) {
+                showError('Die Anmeldeanfrage ist abgelaufen. Bitte erneut über die Anwendung anmelden.');
+                return;
+            }
+
+            const result = await response.json();
+            const form = document.createElement('form');
+            form.method = 'POST';
+            form.action = result.acs_url;
+            const fields = { SAMLResponse: result.saml_response, RelayState: result.relay_state };
+            Object.entries(fields).filter(([, value]) => value).forEach(([name, value]) => {
+                const input = document.createElement('input');
+                input.type = 'hidden';
+                input.name = name;
+                input.value = value;
+                form.appendChild(input);
+            });
+            document.body.appendChild(form);
+            form.submit();
+        } catch (error) {
+            showError('Verbindungsfehler');
+        }
+    }
+
+    // Tokens with an `act` claim belong to an admin acting as the user
+    function showImpersonationBanner(token) {
+        const claims = tokenClaims(token);
+        if (!claims || !claims.act || claims.exp * 1000 < Date.now()) {
+            return;
+        }
+        const until = new Date(claims.exp * 1000).toLocaleTimeString('de-DE', { hour: '2-digit', minute: '2-digit' });
+        document.getElementById('impersonationText').textContent =
+            `Angemeldet als ${claims.name} (${claims.email}) im Auftrag von ${claims.act.email}, bis ${until} Uhr.`;
+        document.getElementById('impersonationEnd').onclick = function() {
+            localStorage.removeItem('auth_token');
+            document.getElementById('impersonationBanner').style.display = 'none';
+        };
+        document.getElementById('impersonationBanner').style.display = 'block';
+    }
+
+    function tokenClaims(token) {
+        try {
+            const payload = token.split('.')[1].replace(/-/g, '+').replace(/_/g, '/');
+            const bytes = Uint8Array.from(atob(payload), c => c.charCodeAt(0));
+            return JSON.parse(new TextDecoder().decode(bytes));
+        } catch (error) {
+            return null;
         }
     }
 
@@ -125,10 +665,16 @@ document.addEventListener('DOMContentLoaded', function() {
         const header = document.querySelector('.header');
         const oauth2Info = document.createElement('div');
         oauth2Info.className = 'oauth2-info';
-        oauth2Info.innerHTML = `
-            <p><small>Anmeldung für Anwendung: <strong>${clientId}</strong></small></p>
-            <p><small>Berechtigung: <strong>${scope || 'openid'}</strong></small></p>
-        `;
+        // Both come from the URL: text only, never markup
+        [['Anmeldung für Anwendung: ', clientId], ['Berechtigung: ', scope || 'openid']].forEach(([label, value]) => {
+            const line = document.createElement('p');
+            const small = document.createElement('small');
+            const strong = document.createElement('strong');
+            strong.textContent = value;
+            small.append(label, strong);
+            line.appendChild(small);
+            oauth2Info.appendChild(line);
+        });
         header.appendChild(oauth2Info);
 
         // Add OAuth2 specific styles
@@ -147,6 +693,22 @@ document.addEventListener('DOMContentLoaded', function() {
         document.head.appendChild(style);
     }
 
+    // Reasons the server gives for refusing correct credentials
+    function loginErrorMessage(error) {
+        const messages = {
+            email_not_verified: 'Bitte bestätigen Sie zuerst Ihre E-Mail-Adresse über den Link in unserer Nachricht.',
+            session_limit_reached: 'Sie sind bereits auf zu vielen Geräten angemeldet. Bitte melden Sie sich zuerst auf einem anderen Gerät ab.',
+            upstream_error: 'Die Anmeldung beim Identitätsanbieter ist fehlgeschlagen.',
+            invalid_state: 'Die Anmeldung ist abgelaufen. Bitte erneut versuchen.',
+            upstream_email_unverified: 'Der Identitätsanbieter hat Ihre E-Mail-Adresse nicht bestätigt.',
+            email_missing: 'Der Identitätsanbieter hat keine E-Mail-Adresse übermittelt.',
+            not_provisioned: 'Für dieses Konto ist hier kein Zugang eingerichtet.',
+            linked_to_other_identity: 'Ihr Konto ist bereits mit einem anderen Konto dieses Anbieters verknüpft.',
+            too_many_requests: 'Zu viele Anmeldeversuche. Bitte warten Sie einen Moment.'
+        };
+        return messages[error] || error;
+    }
+
     function showError(message) {
         errorMessage.textContent = message;
         errorMessage.style.display = 'block';
@@ -157,6 +719,8 @@ document.addEventListener('DOMContentLoaded', function() {
     }
 
     function setLoading(loading) {
+        mfaBtn.disabled = loading;
+        passkeyButtons.forEach(button => { button.disabled = loading; });
         if (loading) {
             loginBtn.classList.add('loading');
             loginBtn.disabled = true;
@@ -168,6 +732,6 @@ document.addEventListener('DOMContentLoaded', function() {
         }
     }
 
-    // Auto-focus on email field
-    document.getElementById('email').focus();
+    // Auto-focus on the first field of the visible form
+    document.getElementById(resetToken ? 'newPassword' : 'email').focus();
 });
\ No newline at end of file
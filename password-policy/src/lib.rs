@@ -0,0 +1,194 @@
+//! Password requirements shared by auth-service, admin-service and auth-ops,
+//! so a password is judged the same wherever it is set.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::path::Path;
+
+/// Requirements a new password has to meet.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    /// How many of lowercase, uppercase, digits and symbols must appear
+    pub min_character_classes: usize,
+    /// Reject passwords containing parts of the email address or names
+    pub forbid_personal_data: bool,
+    /// Look the password up in `breached_passwords.txt` in the data directory
+    pub check_breached: bool,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 12,
+            min_character_classes: 1,
+            forbid_personal_data: true,
+            check_breached: false,
+        }
+    }
+}
+
+/// Name of the breached-password corpus in the data directory: one SHA-1 per
+/// line in uppercase hex, sorted, optionally followed by `:count` as in the
+/// Pwned Passwords download. Hashes may be truncated to a common prefix
+/// length to keep the file small.
+pub const BREACHED_CORPUS_FILE: &str = "breached_passwords.txt";
+
+/// Everything wrong with `password` under `policy`, as messages for the
+/// user. `personal` are the email address and names of the account.
+pub fn violations(
+    policy: &PasswordPolicy,
+    password: &str,
+    personal: &[&str],
+    data_dir: &str,
+) -> Result<Vec<String>> {
+    let mut violations = Vec::new();
+
+    if password.chars().count() < policy.min_length {
+        violations.push(format!("Password must be at least {} characters long", policy.min_length));
+    }
+
+    let classes = [
+        password.chars().any(|c| c.is_lowercase()),
+        password.chars().any(|c| c.is_uppercase()),
+        password.chars().any(|c| c.is_numeric()),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ];
+    if classes.iter().filter(|present| **present).count() < policy.min_character_classes {
+        violations.push(format!(
+            "Password must use at least {} of: lowercase letters, uppercase letters, digits, symbols",
+            policy.min_character_classes
+        ));
+    }
+
+    if policy.forbid_personal_data {
+        let lowered = password.to_lowercase();
+        let mut fragments: Vec<String> = personal
+            .iter()
+            .flat_map(|value| value.split(['@', '.', '_', '-', '+', ' ']))
+            .filter(|fragment| fragment.chars().count() >= 3)
+            .map(str::to_lowercase)
+            .collect();
+        fragments.sort();
+        fragments.dedup();
+        if fragments.iter().any(|fragment| lowered.contains(fragment.as_str())) {
+            violations.push("Password must not contain your name or email address".to_string());
+        }
+    }
+
+    if policy.check_breached && is_breached(&Path::new(data_dir).join(BREACHED_CORPUS_FILE), password)? {
+        violations.push("Password appears in a list of leaked passwords".to_string());
+    }
+
+    Ok(violations)
+}
+
+/// Binary search of the sorted corpus on disk; the file is never read whole.
+pub fn is_breached(corpus: &Path, password: &str) -> Result<bool> {
+    let digest: String = Sha1::digest(password.as_bytes()).iter().map(|b| format!("{:02X}", b)).collect();
+
+    let file = File::open(corpus)
+        .with_context(|| format!("Breached-password corpus {} is not readable", corpus.display()))?;
+    let mut reader = BufReader::new(file);
+    let (mut low, mut high) = (0u64, reader.get_ref().metadata()?.len());
+
+    // Lines starting before `low` sort below the digest, lines starting at
+    // or after `high` above it
+    while low < high {
+        let middle = low + (high - low) / 2;
+        let Some((line, next)) = line_at_or_after(&mut reader, middle)? else {
+            high = middle;
+            continue;
+        };
+
+        let hash = line.split(':').next().unwrap_or_default().trim().to_uppercase();
+        if hash.is_empty() || hash.len() > digest.len() || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            bail!("Malformed line in breached-password corpus {}: {:?}", corpus.display(), line);
+        }
+
+        match digest[..hash.len()].cmp(&hash) {
+            Ordering::Equal => return Ok(true),
+            Ordering::Greater => low = next,
+            Ordering::Less => high = middle,
+        }
+    }
+
+    Ok(false)
+}
+
+/// The first complete line starting at or after `offset`, and where the line
+/// after it starts.
+fn line_at_or_after(reader: &mut BufReader<File>, offset: u64) -> Result<Option<(String, u64)>> {
+    let mut start = offset;
+    if offset > 0 {
+        reader.seek(SeekFrom::Start(offset - 1))?;
+        let mut partial = Vec::new();
+        start = offset - 1 + reader.read_until(b'\n', &mut partial)? as u64;
+    } else {
+        reader.seek(SeekFrom::Start(0))?;
+    }
+
+    let mut line = String::new();
+    let read = reader.read_line(&mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    Ok(Some((line.trim_end().to_string(), start + read as u64)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha1_hex(password: &str) -> String {
+        Sha1::digest(password.as_bytes()).iter().map(|b| format!("{:02X}", b)).collect()
+    }
+
+    #[test]
+    fn test_policy_rules() {
+        let policy = PasswordPolicy {
+            min_length: 12,
+            min_character_classes: 3,
+            forbid_personal_data: true,
+            check_breached: false,
+        };
+        let personal = ["anna.schmidt@example.com", "Anna", "Schmidt"];
+
+        assert!(violations(&policy, "Kr4ftvoll-Blau-Tisch", &personal, "/nonexistent").unwrap().is_empty());
+        assert_eq!(violations(&policy, "Short1!", &personal, "/nonexistent").unwrap().len(), 1);
+        assert_eq!(violations(&policy, "onlylowercaseletters", &personal, "/nonexistent").unwrap().len(), 1);
+        assert_eq!(violations(&policy, "Schmidt-2024-Sommer", &personal, "/nonexistent").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_breached_corpus_lookup() {
+        let dir = std::env::temp_dir().join(format!("um-oic-corpus-{}", uuid::Uuid::new_v4().simple()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut lines: Vec<String> = (0..500).map(|i| format!("{}:{}", sha1_hex(&format!("leaked{}", i)), i + 1)).collect();
+        lines.push(sha1_hex("password123")[..20].to_string());
+        lines.sort();
+        std::fs::write(dir.join(BREACHED_CORPUS_FILE), lines.join("\r\n") + "\r\n").unwrap();
+        let corpus = dir.join(BREACHED_CORPUS_FILE);
+
+        for i in [0, 1, 250, 498, 499] {
+            assert!(is_breached(&corpus, &format!("leaked{}", i)).unwrap(), "leaked{}", i);
+        }
+        assert!(is_breached(&corpus, "password123").unwrap());
+        assert!(!is_breached(&corpus, "leaked500").unwrap());
+        assert!(!is_breached(&corpus, "Kr4ftvoll-Blau-Tisch").unwrap());
+
+        let policy = PasswordPolicy { check_breached: true, ..PasswordPolicy::default() };
+        let data_dir = dir.to_string_lossy().to_string();
+        assert_eq!(violations(&policy, "leaked7-with-a-suffix", &[], &data_dir).unwrap().len(), 0);
+        assert_eq!(violations(&policy, "leaked7", &[], &data_dir).unwrap().len(), 2);
+        assert!(violations(&policy, "anything", &[], "/nonexistent").is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
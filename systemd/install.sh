@@ -74,14 +74,68 @@ primary_color = "#00529F"
 issuer = "https://auth.example.com"
 
 [security]
-password_min_length = 12
 access_token_ttl = 3600
 refresh_token_ttl = 2592000
 require_mfa = false
+mfa_session_ttl = 300
+mfa_max_attempts = 5
+mfa_recovery_codes = 10
+password_reset_ttl = 1800
+email_verification_ttl = 172800
+
+[security.argon2]
+memory_kib = 19456
+iterations = 2
+parallelism = 1
+
+[security.lockout]
+max_failures = 5
+base_duration = 60
+max_duration = 3600
+
+[security.login_throttle]
+burst = 20
+per_minute = 10
+
+[security.sessions]
+
+[security.scope_requirements]
 
 [features]
 allow_registration = false
 allow_password_reset = true
+
+[webauthn]
+rp_id = "auth.example.com"
+origin = "https://auth.example.com"
+challenge_ttl = 300
+
+[mail]
+from = "Auth Service <noreply@example.com>"
+default_locale = "de"
+poll_interval = 10
+max_attempts = 8
+retry_delay = 60
+failed_retention_days = 30
+
+[mail.transport]
+kind = "maildir"
+path = "$AUTH_HOME/data/mail/maildir"
+
+[federation]
+
+[ldap]
+
+[saml]
+certificate = "$AUTH_HOME/certs/saml-cert.pem"
+private_key = "$AUTH_HOME/certs/saml-key.pem"
+assertion_lifetime = 300
+
+[oidc]
+signing_key = "$AUTH_HOME/certs/oidc-signing-key.pem"
+
+[audit]
+signing_key = "$AUTH_HOME/certs/audit-signing-key.pem"
 EOF
 
     chown $AUTH_USER:$AUTH_GROUP "$CONFIG_DIR/config.toml"
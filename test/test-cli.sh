@@ -174,6 +174,8 @@ echo "  auth-ops client list                - List OAuth clients"
 echo
 echo "🔧 System Operations:"
 echo "  auth-ops reload                     - Reload auth service data"
-echo "  auth-ops archive --days N           - Archive old audit logs"
+echo "  auth-ops archive --older-than-days N [--retention-days M] [--dry-run]"
+echo "                                      - Archive old audit logs, expire old archives"
+echo "  auth-ops audit verify --public-key F - Check the audit log hash chain"
 echo
 echo "🚀 CLI Tool is ready for production use!"
\ No newline at end of file